@@ -0,0 +1,66 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Shader stages by file extension, compiled to `<name>_<stage>.spv`
+const STAGES: [&str; 3] = ["vert", "frag", "comp"];
+
+/// Compiles every shader in `src/render/shader` to SPIR-V in `$OUT_DIR/shader`, which the renderer
+///  includes with `include_bytes!`. Needs `glslc` from the Vulkan SDK, found through `GLSLC`, then
+///  `VULKAN_SDK`, then the `PATH`.
+fn main() {
+    let source_dir = Path::new("src/render/shader");
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("Cargo sets OUT_DIR")).join("shader");
+    fs::create_dir_all(&out_dir).expect("Failed to create the shader output directory");
+
+    println!("cargo:rerun-if-changed={}", source_dir.display());
+    println!("cargo:rerun-if-env-changed=GLSLC");
+    println!("cargo:rerun-if-env-changed=VULKAN_SDK");
+
+    let glslc = glslc();
+    let mut sources = fs::read_dir(source_dir)
+        .expect("Failed to read the shader directory")
+        .map(|entry| entry.expect("Failed to read the shader directory").path())
+        .filter(|path| stage(path).is_some())
+        .collect::<Vec<_>>();
+    sources.sort();
+
+    for source in sources {
+        let name = source.file_stem().and_then(|s| s.to_str()).expect("Shader names are UTF-8");
+        let output = out_dir.join(format!("{}_{}.spv", name, stage(&source).unwrap()));
+
+        // The shaders share code through `#include`, resolved against the shader directory
+        let result = Command::new(&glslc)
+            .arg("--target-env=vulkan1.3")
+            .arg("-O")
+            .arg("-I")
+            .arg(source_dir)
+            .arg(&source)
+            .arg("-o")
+            .arg(&output)
+            .output()
+            .unwrap_or_else(|e| panic!("Failed to run `{}`, install the Vulkan SDK or set GLSLC: {}",
+                glslc.display(), e));
+        if !result.status.success() {
+            panic!("Failed to compile `{}`:\n{}", source.display(), String::from_utf8_lossy(&result.stderr));
+        }
+    }
+}
+
+fn stage(path: &Path) -> Option<&str> {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .filter(|e| STAGES.contains(e))
+}
+
+fn glslc() -> PathBuf {
+    if let Some(glslc) = env::var_os("GLSLC") {
+        return PathBuf::from(glslc);
+    }
+    let executable = if cfg!(windows) { "glslc.exe" } else { "glslc" };
+    env::var_os("VULKAN_SDK")
+        .map(|sdk| PathBuf::from(sdk).join("bin").join(executable))
+        .filter(|path| path.exists())
+        .unwrap_or_else(|| PathBuf::from(executable))
+}
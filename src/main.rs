@@ -1,27 +1,130 @@
-use anyhow::{Ok, Result};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Ok, Result};
 use winit::dpi::LogicalSize;
-use winit::event::{Event, WindowEvent};
+use winit::event::{ElementState, Event, WindowEvent};
 use winit::event_loop::EventLoop;
+use winit::keyboard::{KeyCode, PhysicalKey};
 use winit::window::WindowBuilder;
 
+use render::engine::{Engine, HdrCapture, RenderMode, ShadingPath};
+
 // Import the modules
 mod render;
 
-fn main() -> Result<()> 
+/// Command line options
+///  `--scene <file.gltf|file.glb>` loads a glTF scene instead of the test triangle
+///  `--reference <out.png|out.exr|out.hdr>` path traces a reference image headlessly and exits, a PNG
+///   tonemapped like the window and .exr or .hdr in cd/m²
+///  `--samples <n>` sample count for reference images
+///  `--size <w>x<h>` window (and reference image) size
+///  `--skybox <sky.hdr|sky.exr|px.png,nx.png,py.png,ny.png,pz.png,nz.png>` an equirectangular panorama or six cube faces
+///  `--sun <elevation>,<azimuth>` a procedural sky lit by a sun at those angles in degrees, instead of a skybox
+struct Args
+{
+    scene: Option<PathBuf>,
+    skybox: Vec<PathBuf>,
+    sun: Option<(f32, f32)>,
+    reference: Option<PathBuf>,
+    samples: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Args
+{
+    fn parse() -> Result<Self>
+    {
+        let mut args = Self { scene: None, skybox: Vec::new(), sun: None, reference: None, samples: 1024, width: 1024, height: 768 };
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            let mut value = || iter.next().ok_or_else(|| anyhow!("Missing value for `{}`.", arg));
+            match arg.as_str() {
+                "--scene" => args.scene = Some(PathBuf::from(value()?)),
+                "--skybox" => {
+                    args.skybox = value()?.split(',').map(PathBuf::from).collect();
+                    if args.skybox.len() != 1 && args.skybox.len() != 6 {
+                        return Err(anyhow!("Expected one panorama or six cube faces for `--skybox`."));
+                    }
+                },
+                "--sun" => {
+                    let angles = value()?;
+                    let (elevation, azimuth) = angles
+                        .split_once(',')
+                        .ok_or_else(|| anyhow!("Expected `<elevation>,<azimuth>`, got `{}`.", angles))?;
+                    args.sun = Some((elevation.parse()?, azimuth.parse()?));
+                },
+                "--reference" => args.reference = Some(PathBuf::from(value()?)),
+                "--samples" => args.samples = value()?.parse()?,
+                "--size" => {
+                    let size = value()?;
+                    let (w, h) = size
+                        .split_once('x')
+                        .ok_or_else(|| anyhow!("Expected `<width>x<height>`, got `{}`.", size))?;
+                    args.width = w.parse()?;
+                    args.height = h.parse()?;
+                },
+                _ => return Err(anyhow!("Unknown argument `{}`.", arg)),
+            }
+        }
+        if args.sun.is_some() && !args.skybox.is_empty() {
+            return Err(anyhow!("`--sun` and `--skybox` both replace the sky, pick one."));
+        }
+        Ok(args)
+    }
+}
+
+/// Loads the scene and the sky the command line asks for
+unsafe fn load_scene(render_engine: &mut Engine, args: &Args) -> Result<()>
+{
+    if let Some(scene) = &args.scene {
+        render_engine.load_gltf(scene)?;
+    }
+    match args.skybox.as_slice() {
+        [panorama] => render_engine.load_skybox_equirect(panorama)?,
+        [px, nx, py, ny, pz, nz] => {
+            let faces = [px, nx, py, ny, pz, nz].map(|face| face.as_path());
+            render_engine.load_skybox_faces(&faces)?
+        },
+        _ => {},
+    }
+    if let Some((elevation, azimuth)) = args.sun {
+        let settings = render_engine.atmosphere_settings_mut();
+        settings.sun_elevation = elevation.to_radians();
+        settings.sun_azimuth = azimuth.to_radians();
+        render_engine.update_procedural_sky()?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<()>
 {
     pretty_env_logger::init();
+    let args = Args::parse()?;
+
+    // Headless reference render, no window needed
+    if let Some(path) = &args.reference {
+        unsafe {
+            let mut render_engine = Engine::create_headless(args.width, args.height)?;
+            load_scene(&mut render_engine, &args)?;
+            render_engine.render_reference(path, args.samples)?;
+            render_engine.destroy();
+        }
+        return Ok(());
+    }
 
     // Window
 
     let event_loop = EventLoop::new()?;
     let window = WindowBuilder::new()
         .with_title("Vulkan Tutorial (Rust)")
-        .with_inner_size(LogicalSize::new(1024, 768))
+        .with_inner_size(LogicalSize::new(args.width, args.height))
         .build(&event_loop)?;
 
     // App
 
-    let mut render_engine = unsafe { render::engine::Engine::create(&window)? };
+    let mut render_engine = unsafe { Engine::create(&window)? };
+    unsafe { load_scene(&mut render_engine, &args)? };
+
     let mut minimized = false;
     event_loop.run(move |event, elwt| {
         match event {
@@ -29,8 +132,8 @@ fn main() -> Result<()>
             Event::AboutToWait => window.request_redraw(),
             Event::WindowEvent { event, .. } => match event {
                 // Render a frame if our Vulkan app is not being destroyed.
-                WindowEvent::RedrawRequested if !elwt.exiting() && !minimized => unsafe { 
-                    render_engine.render(&window) 
+                WindowEvent::RedrawRequested if !elwt.exiting() && !minimized => unsafe {
+                    render_engine.render(&window)
                 }.unwrap(),
                 // Destroy our Vulkan app.
                 WindowEvent::CloseRequested => {
@@ -45,6 +148,41 @@ fn main() -> Result<()>
                         render_engine.resize();
                     }
                 },
+                // Toggle between rasterizing and path tracing, cycle the shading paths, show the ambient occlusion,
+                //  toggle the fog and save the HDR target, scene referred in cd/m² or as the tonemapper sees it
+                WindowEvent::KeyboardInput { event, .. }
+                    if event.state == ElementState::Pressed && !event.repeat =>
+                {
+                    if event.physical_key == PhysicalKey::Code(KeyCode::KeyP) {
+                        let mode = match render_engine.render_mode() {
+                            RenderMode::Raster => RenderMode::PathTraced,
+                            RenderMode::PathTraced => RenderMode::Raster,
+                        };
+                        render_engine.set_render_mode(mode);
+                    } else if event.physical_key == PhysicalKey::Code(KeyCode::KeyG) {
+                        let path = match render_engine.shading_path() {
+                            ShadingPath::Forward => ShadingPath::Clustered,
+                            ShadingPath::Clustered => ShadingPath::Deferred,
+                            ShadingPath::Deferred => ShadingPath::Forward,
+                        };
+                        render_engine.set_shading_path(path);
+                    } else if event.physical_key == PhysicalKey::Code(KeyCode::KeyO) {
+                        let settings = render_engine.ambient_occlusion_settings_mut();
+                        settings.debug_view = !settings.debug_view;
+                    } else if event.physical_key == PhysicalKey::Code(KeyCode::KeyF) {
+                        let settings = render_engine.fog_settings_mut();
+                        settings.enabled = !settings.enabled;
+                    } else if event.physical_key == PhysicalKey::Code(KeyCode::F12) {
+                        if let Err(e) = render_engine.save_hdr_target(Path::new("capture.exr"), HdrCapture::Scene) {
+                            log::error!("Failed to save the HDR target: {}", e);
+                        }
+                    } else if event.physical_key == PhysicalKey::Code(KeyCode::F11) {
+                        let path = Path::new("capture_display.exr");
+                        if let Err(e) = render_engine.save_hdr_target(path, HdrCapture::Display) {
+                            log::error!("Failed to save the HDR target: {}", e);
+                        }
+                    }
+                },
                 _ => {}
             }
             _ => {}
@@ -52,4 +190,4 @@ fn main() -> Result<()>
     })?;
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,353 @@
+use std::mem::size_of;
+use cgmath::{vec3, InnerSpace};
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::camera::Vec3;
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::light::Light;
+use super::memory::{self, AllocatedImage};
+use super::post::{HDR_FORMAT, WORKGROUP_SIZE};
+use super::scene::LightKind;
+use super::shader;
+use super::skybox;
+use super::texture;
+
+/// Transmittance table, the distance to the top of the atmosphere across and the altitude down
+const TRANSMITTANCE_EXTENT: vk::Extent2D = vk::Extent2D { width: 256, height: 64 };
+
+/// Multiple scattering table, the sun's zenith angle across and the altitude down
+const MULTISCATTERING_EXTENT: vk::Extent2D = vk::Extent2D { width: 32, height: 32 };
+
+/// Sky view table, the azimuth across and the elevation down
+const SKY_VIEW_EXTENT: vk::Extent2D = vk::Extent2D { width: 192, height: 108 };
+
+/// Face size of the sky's cube, the sky view table has little detail to give it
+const SKY_CUBE_SIZE: u32 = 128;
+
+/// The sun's angular radius as seen from the earth, in radians
+const SUN_ANGULAR_RADIUS: f32 = 0.004_675;
+
+/// Earth's radius and the top of its atmosphere, in km
+const GROUND_RADIUS: f32 = 6360.0;
+const TOP_RADIUS: f32 = 6460.0;
+
+/// Rayleigh scattering per km at sea level and its density's scale height
+const RAYLEIGH_SCATTERING: [f32; 3] = [5.802e-3, 13.558e-3, 33.1e-3];
+const RAYLEIGH_SCALE_HEIGHT: f32 = 8.0;
+
+/// Mie scattering and absorption per km at sea level on a clear day, the scale height and the
+///  anisotropy of the aerosols' forward scattering
+const MIE_SCATTERING: f32 = 3.996e-3;
+const MIE_ABSORPTION: f32 = 0.444e-3;
+const MIE_SCALE_HEIGHT: f32 = 1.2;
+const MIE_ANISOTROPY: f32 = 0.8;
+
+/// Ozone absorption per km at the peak of its layer, the peak's altitude and the layer's width
+const OZONE_ABSORPTION: [f32; 3] = [0.650e-3, 1.881e-3, 0.085e-3];
+const OZONE_ALTITUDE: f32 = 25.0;
+const OZONE_WIDTH: f32 = 30.0;
+
+/// Where the sun is and what the air is like.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AtmosphereSettings {
+    /// Angle of the sun above the horizon, in radians
+    pub sun_elevation: f32,
+    /// Angle of the sun about the up axis, in radians from +Z towards +X
+    pub sun_azimuth: f32,
+    /// Illuminance of the sunlight above the atmosphere, in lux
+    pub sun_illuminance: f32,
+    /// Height of the viewer above the ground, in metres
+    pub altitude: f32,
+    /// Scale of the aerosols' density, 1 is a clear day and more is hazier
+    pub turbidity: f32,
+    /// Linear RGB albedo of the ground, which lights the sky from below
+    pub ground_albedo: [f32; 3],
+}
+
+impl Default for AtmosphereSettings {
+    fn default() -> Self {
+        Self {
+            sun_elevation: 0.6,
+            sun_azimuth: 0.5,
+            sun_illuminance: 120_000.0,
+            altitude: 0.0,
+            turbidity: 1.0,
+            ground_albedo: [0.3, 0.3, 0.3],
+        }
+    }
+}
+
+impl AtmosphereSettings {
+    /// Unit vector towards the sun
+    pub fn sun_direction(&self) -> Vec3 {
+        let (sin_elevation, cos_elevation) = self.sun_elevation.sin_cos();
+        let (sin_azimuth, cos_azimuth) = self.sun_azimuth.sin_cos();
+        vec3(cos_elevation * sin_azimuth, sin_elevation, cos_elevation * cos_azimuth)
+    }
+
+    /// Share of the sunlight reaching the viewer through the atmosphere, the directional light's
+    ///  colour. The same integral as the transmittance table's.
+    pub fn sun_transmittance(&self) -> Vec3 {
+        const STEPS: usize = 64;
+
+        let constants = self.push_constants();
+        let origin = vec3(0.0, GROUND_RADIUS + (self.altitude * 1e-3).max(1e-3), 0.0);
+        let sun = self.sun_direction();
+        if ray_sphere(origin, sun, GROUND_RADIUS).is_some() {
+            return vec3(0.0, 0.0, 0.0);
+        }
+
+        let dt = ray_sphere(origin, sun, TOP_RADIUS).unwrap_or(0.0) / STEPS as f32;
+        let optical_depth = (0..STEPS).fold(vec3(0.0, 0.0, 0.0), |depth, i| {
+            let altitude = (origin + sun * (i as f32 + 0.5) * dt).magnitude() - GROUND_RADIUS;
+            depth + constants.extinction(altitude) * dt
+        });
+        vec3((-optical_depth.x).exp(), (-optical_depth.y).exp(), (-optical_depth.z).exp())
+    }
+
+    fn push_constants(&self) -> AtmospherePushConstants {
+        let sun = self.sun_direction();
+        AtmospherePushConstants {
+            sun_direction: [sun.x, sun.y, sun.z, self.altitude * 1e-3],
+            rayleigh: [RAYLEIGH_SCATTERING[0], RAYLEIGH_SCATTERING[1], RAYLEIGH_SCATTERING[2], RAYLEIGH_SCALE_HEIGHT],
+            mie: [MIE_SCATTERING * self.turbidity, MIE_ABSORPTION * self.turbidity, MIE_SCALE_HEIGHT, MIE_ANISOTROPY],
+            ozone: [OZONE_ABSORPTION[0], OZONE_ABSORPTION[1], OZONE_ABSORPTION[2], OZONE_ALTITUDE],
+            ground: [self.ground_albedo[0], self.ground_albedo[1], self.ground_albedo[2], GROUND_RADIUS],
+            top_radius: TOP_RADIUS,
+            ozone_width: OZONE_WIDTH,
+            _padding: [0.0; 2],
+        }
+    }
+}
+
+/// Mirrors `Atmosphere` in `atmosphere.glsl`
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct AtmospherePushConstants {
+    sun_direction: [f32; 4],
+    rayleigh: [f32; 4],
+    mie: [f32; 4],
+    ozone: [f32; 4],
+    ground: [f32; 4],
+    top_radius: f32,
+    ozone_width: f32,
+    _padding: [f32; 2],
+}
+
+impl AtmospherePushConstants {
+    /// Extinction per km at an altitude in km, as `sample_medium` works it out
+    fn extinction(&self, altitude: f32) -> Vec3 {
+        let rayleigh = (-altitude / self.rayleigh[3]).exp();
+        let mie = (-altitude / self.mie[2]).exp() * (self.mie[0] + self.mie[1]);
+        let ozone = (1.0 - (altitude - self.ozone[3]).abs() * 2.0 / self.ozone_width).max(0.0);
+        vec3(self.rayleigh[0], self.rayleigh[1], self.rayleigh[2]) * rayleigh + vec3(mie, mie, mie)
+            + vec3(self.ozone[0], self.ozone[1], self.ozone[2]) * ozone
+    }
+}
+
+/// Distance along `d` to the nearest hit ahead of `p` on a sphere about the origin
+fn ray_sphere(p: Vec3, d: Vec3, radius: f32) -> Option<f32> {
+    let b = p.dot(d);
+    let h = b * b - (p.magnitude2() - radius * radius);
+    if h < 0.0 || -b + h.sqrt() < 0.0 {
+        return None;
+    }
+    let near = -b - h.sqrt();
+    Some(if near >= 0.0 { near } else { -b + h.sqrt() })
+}
+
+/// A procedural sky scattered by the earth's atmosphere after Hillaire's technique: transmittance,
+///  multiple scattering and sky view tables rendered with compute passes, then resampled into the
+///  skybox's cube, which the image based lighting integrates like any other sky. The sun becomes
+///  the scene's first directional light, coloured by the air it comes through, and its disk is
+///  drawn by the skybox. Rendered on request rather than every frame.
+#[derive(Clone, Debug, Default)]
+pub struct Atmosphere {
+    pub settings: AtmosphereSettings,
+    pub transmittance: AllocatedImage,
+    pub multiscattering: AllocatedImage,
+    pub sky_view: AllocatedImage,
+    sampler: vk::Sampler,
+    set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    transmittance_pipeline: vk::Pipeline,
+    multiscattering_pipeline: vk::Pipeline,
+    sky_view_pipeline: vk::Pipeline,
+    cube_pipeline: vk::Pipeline,
+}
+
+pub unsafe fn create_atmosphere(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let usage = vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED;
+    let transmittance = AllocatedImage::create(TRANSMITTANCE_EXTENT, HDR_FORMAT, usage, vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+    let multiscattering = AllocatedImage::create(MULTISCATTERING_EXTENT, HDR_FORMAT, usage,
+        vk::ImageAspectFlags::COLOR, instance, device, data)?;
+    let sky_view = AllocatedImage::create(SKY_VIEW_EXTENT, HDR_FORMAT, usage, vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+
+    let atmosphere = &mut data.atmosphere;
+    atmosphere.transmittance = transmittance;
+    atmosphere.multiscattering = multiscattering;
+    atmosphere.sky_view = sky_view;
+    atmosphere.sampler = descriptor::create_sampler(device, vk::Filter::LINEAR, 0.0)?;
+
+    // The three tables to read and the one image each pass writes
+    atmosphere.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(3, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    atmosphere.pipeline_layout = descriptor::create_pipeline_layout(device, &[atmosphere.set_layout],
+        size_of::<AtmospherePushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/atmosphere_transmittance_comp.spv"));
+    atmosphere.transmittance_pipeline = shader::create_compute_pipeline(device, &comp[..], atmosphere.pipeline_layout)?;
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/atmosphere_multiscattering_comp.spv"));
+    atmosphere.multiscattering_pipeline = shader::create_compute_pipeline(device, &comp[..],
+        atmosphere.pipeline_layout)?;
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/atmosphere_sky_view_comp.spv"));
+    atmosphere.sky_view_pipeline = shader::create_compute_pipeline(device, &comp[..], atmosphere.pipeline_layout)?;
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/atmosphere_cube_comp.spv"));
+    atmosphere.cube_pipeline = shader::create_compute_pipeline(device, &comp[..], atmosphere.pipeline_layout)?;
+
+    Ok(())
+}
+
+pub unsafe fn destroy_atmosphere(device: &Device, data: &mut EngineData)
+{
+    let atmosphere = &mut data.atmosphere;
+    atmosphere.transmittance.destroy(device);
+    atmosphere.multiscattering.destroy(device);
+    atmosphere.sky_view.destroy(device);
+    device.destroy_pipeline(atmosphere.transmittance_pipeline, None);
+    device.destroy_pipeline(atmosphere.multiscattering_pipeline, None);
+    device.destroy_pipeline(atmosphere.sky_view_pipeline, None);
+    device.destroy_pipeline(atmosphere.cube_pipeline, None);
+    device.destroy_pipeline_layout(atmosphere.pipeline_layout, None);
+    device.destroy_descriptor_set_layout(atmosphere.set_layout, None);
+    device.destroy_sampler(atmosphere.sampler, None);
+}
+
+/// Renders the sky for the current settings into a new skybox cube and points the sun at it,
+///  once the GPU is done with the old sky. The image based lighting has to be updated after.
+pub unsafe fn update_atmosphere(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let settings = data.atmosphere.settings;
+    let mip_levels = 32 - SKY_CUBE_SIZE.leading_zeros();
+    let cube = skybox::create_cube(SKY_CUBE_SIZE, mip_levels, instance, device, data)?;
+    render_sky(&cube, &settings.push_constants(), device, data)?;
+    texture::generate_mipmaps(&cube, mip_levels, 6, device, data)?;
+
+    // The cube holds the radiance of a lux of sunlight, the skybox scales it. Every sun angle is a
+    //  new sky, so its lighting isn't worth caching to disk.
+    skybox::set_cube(cube, true, None, device, data);
+    let skybox = &mut data.skybox;
+    skybox.sun_radius = SUN_ANGULAR_RADIUS;
+    skybox.settings.intensity = settings.sun_illuminance;
+    skybox.settings.rotation = 0.0;
+
+    // The sun travels away from its direction, coloured by the air in its way
+    let sun = Light::directional(-settings.sun_direction(), settings.sun_transmittance(), settings.sun_illuminance);
+    match data.lights.iter_mut().find(|l| l.kind == LightKind::Directional) {
+        Some(light) => {
+            light.direction = sun.direction;
+            light.color = sun.color;
+            light.intensity = sun.intensity;
+        },
+        None => data.lights.push(sun),
+    }
+    Ok(())
+}
+
+/// Fills the three tables in turn and resamples the sky view into the top mip of the cube, leaving
+///  the whole cube in `TRANSFER_DST_OPTIMAL` for the mip chain
+unsafe fn render_sky(cube: &AllocatedImage, constants: &AtmospherePushConstants, device: &Device,
+    data: &EngineData) -> Result<()>
+{
+    let atmosphere = &data.atmosphere;
+    let descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 3 * 4),
+        (vk::DescriptorType::STORAGE_IMAGE, 4),
+    ], 4)?;
+
+    // The faces of the top mip as an array the shader can store to
+    let face_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(6);
+    let view_info = vk::ImageViewCreateInfo::builder()
+        .image(cube.image)
+        .view_type(vk::ImageViewType::_2D_ARRAY)
+        .format(cube.format)
+        .subresource_range(face_range);
+    let faces = device.create_image_view(&view_info, None)?;
+
+    let passes = [
+        (atmosphere.transmittance_pipeline, atmosphere.transmittance.image_view, TRANSMITTANCE_EXTENT, 1),
+        (atmosphere.multiscattering_pipeline, atmosphere.multiscattering.image_view, MULTISCATTERING_EXTENT, 1),
+        (atmosphere.sky_view_pipeline, atmosphere.sky_view.image_view, SKY_VIEW_EXTENT, 1),
+        (atmosphere.cube_pipeline, faces, cube.extent_2d(), 6),
+    ];
+    let tables = [&atmosphere.transmittance, &atmosphere.multiscattering, &atmosphere.sky_view];
+
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    for table in tables {
+        memory::cmd_image_barrier(device, command_buffer, table.image, memory::color_subresource_range(),
+            (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+    }
+    let cube_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(vk::REMAINING_MIP_LEVELS)
+        .base_array_layer(0)
+        .layer_count(6)
+        .build();
+    memory::cmd_image_barrier(device, command_buffer, cube.image, cube_range,
+        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+        (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+
+    for (i, (pipeline, target, extent, layers)) in passes.into_iter().enumerate() {
+        let set = descriptor::allocate_set(device, descriptor_pool, atmosphere.set_layout)?;
+        for (binding, table) in tables.iter().enumerate() {
+            descriptor::write_image(device, set, binding as u32, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                table.image_view, vk::ImageLayout::GENERAL, atmosphere.sampler);
+        }
+        descriptor::write_image(device, set, 3, vk::DescriptorType::STORAGE_IMAGE, target,
+            vk::ImageLayout::GENERAL, vk::Sampler::null());
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, pipeline);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            atmosphere.pipeline_layout, 0, &[set], &[]);
+        shader::cmd_push_constants(device, command_buffer, atmosphere.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, constants);
+        device.cmd_dispatch(command_buffer, (extent.width + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE,
+            (extent.height + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, layers);
+
+        // Every pass reads the tables before it
+        if let Some(table) = tables.get(i) {
+            memory::cmd_image_barrier(device, command_buffer, table.image, memory::color_subresource_range(),
+                (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ));
+        }
+    }
+
+    memory::cmd_image_barrier(device, command_buffer, cube.image, cube_range,
+        (vk::ImageLayout::GENERAL, vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+        (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::TRANSFER_WRITE));
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    device.destroy_image_view(faces, None);
+    device.destroy_descriptor_pool(descriptor_pool, None);
+    Ok(())
+}
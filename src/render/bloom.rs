@@ -0,0 +1,288 @@
+use std::mem::size_of;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::{self, AllocatedImage};
+use super::post::{self, HDR_FORMAT};
+use super::shader;
+
+/// Most levels of the downsample chain, the first one being half the screen's resolution
+const MAX_BLOOM_MIPS: u32 = 6;
+
+/// Strength and spread of the bloom.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BloomSettings {
+    pub enabled: bool,
+    /// How much of the blurred image is blended over the scene
+    pub intensity: f32,
+    /// Scale of the upsampling filter in texels of each level, larger spreads the glow further
+    pub radius: f32,
+}
+
+impl Default for BloomSettings {
+    fn default() -> Self {
+        Self { enabled: true, intensity: 0.04, radius: 1.0 }
+    }
+}
+
+/// Mirrors `Bloom` in `bloom.glsl`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct BloomPushConstants {
+    source_lod: f32,
+    radius: f32,
+    intensity: f32,
+    karis_average: u32,
+    level_count: u32,
+}
+
+/// A progressive downsample and upsample chain over the HDR target, blended back over it.
+///  Every pass binds its own set: its source, the level it writes and the HDR target.
+#[derive(Clone, Debug, Default)]
+pub struct Bloom {
+    pub settings: BloomSettings,
+    chain: AllocatedImage,
+    mip_views: Vec<vk::ImageView>,
+    downsample_sets: Vec<vk::DescriptorSet>,
+    upsample_sets: Vec<vk::DescriptorSet>,
+    composite_set: vk::DescriptorSet,
+    sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    downsample_pipeline: vk::Pipeline,
+    upsample_pipeline: vk::Pipeline,
+    composite_pipeline: vk::Pipeline,
+}
+
+impl Bloom {
+    fn chain_range(&self) -> vk::ImageSubresourceRange {
+        vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(self.mip_views.len() as u32)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build()
+    }
+
+    fn mip_extent(&self, level: usize) -> vk::Extent2D {
+        let extent = self.chain.extent_2d();
+        vk::Extent2D {
+            width: (extent.width >> level).max(1),
+            height: (extent.height >> level).max(1),
+        }
+    }
+
+    unsafe fn cmd_pass(&self, device: &Device, command_buffer: vk::CommandBuffer, set: vk::DescriptorSet,
+        push_constants: &BloomPushConstants, extent: vk::Extent2D)
+    {
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 0, &[set], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, push_constants);
+        post::cmd_dispatch_pixels(device, command_buffer, extent);
+
+        // Each level is read by the next pass
+        memory::cmd_image_barrier(device, command_buffer, self.chain.image, self.chain_range(),
+            (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE));
+    }
+
+    /// Blurs the bright parts of the HDR target down the chain, back up, and blends the result over it
+    pub unsafe fn cmd_render(&self, device: &Device, command_buffer: vk::CommandBuffer,
+        scratch: &AllocatedImage, hdr: &AllocatedImage)
+    {
+        if !self.settings.enabled || self.mip_views.is_empty() {
+            return;
+        }
+
+        let mut push_constants = BloomPushConstants {
+            source_lod: 0.0,
+            radius: self.settings.radius.max(0.0),
+            intensity: self.settings.intensity.clamp(0.0, 1.0),
+            karis_average: 1,
+            level_count: self.mip_views.len() as u32,
+        };
+
+        // The first downsample reads the HDR target and tames fireflies with a Karis average
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.downsample_pipeline);
+        for (level, set) in self.downsample_sets.iter().enumerate() {
+            push_constants.source_lod = level.saturating_sub(1) as f32;
+            push_constants.karis_average = (level == 0) as u32;
+            self.cmd_pass(device, command_buffer, *set, &push_constants, self.mip_extent(level));
+        }
+
+        // Upsample sets are stored from the smallest destination to the largest
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.upsample_pipeline);
+        let levels = self.mip_views.len();
+        for (i, set) in self.upsample_sets.iter().enumerate() {
+            let level = levels - 2 - i;
+            push_constants.source_lod = (level + 1) as f32;
+            self.cmd_pass(device, command_buffer, *set, &push_constants, self.mip_extent(level));
+        }
+
+        push_constants.source_lod = 0.0;
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.composite_pipeline);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 0, &[self.composite_set], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, &push_constants);
+        post::cmd_dispatch_pixels(device, command_buffer, hdr.extent_2d());
+        post::cmd_write_back(device, command_buffer, scratch, hdr);
+    }
+}
+
+pub unsafe fn create_bloom(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let bloom = &mut data.bloom;
+    bloom.settings = BloomSettings::default();
+    bloom.sampler = descriptor::create_sampler(device, vk::Filter::LINEAR, MAX_BLOOM_MIPS as f32)?;
+    bloom.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    bloom.pipeline_layout = descriptor::create_pipeline_layout(device, &[bloom.set_layout],
+        size_of::<BloomPushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+
+    let downsample = include_bytes!(concat!(env!("OUT_DIR"), "/shader/bloom_downsample_comp.spv"));
+    bloom.downsample_pipeline = shader::create_compute_pipeline(device, &downsample[..], bloom.pipeline_layout)?;
+    let upsample = include_bytes!(concat!(env!("OUT_DIR"), "/shader/bloom_upsample_comp.spv"));
+    bloom.upsample_pipeline = shader::create_compute_pipeline(device, &upsample[..], bloom.pipeline_layout)?;
+    let composite = include_bytes!(concat!(env!("OUT_DIR"), "/shader/bloom_composite_comp.spv"));
+    bloom.composite_pipeline = shader::create_compute_pipeline(device, &composite[..], bloom.pipeline_layout)?;
+
+    create_bloom_targets(instance, device, data)
+}
+
+/// Creates the mip chain for the current swapchain size and the sets of every pass over it
+pub unsafe fn create_bloom_targets(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let extent = vk::Extent2D {
+        width: (data.swapchain_extent.width / 2).max(1),
+        height: (data.swapchain_extent.height / 2).max(1),
+    };
+    let levels = (extent.width.min(extent.height).max(1).ilog2() + 1).min(MAX_BLOOM_MIPS);
+
+    let info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::_2D)
+        .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+        .mip_levels(levels)
+        .array_layers(1)
+        .format(HDR_FORMAT)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(vk::SampleCountFlags::_1);
+    let chain = AllocatedImage::from_info(&info, vk::ImageViewType::_2D, vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+
+    let mip_views = (0..levels)
+        .map(|level| {
+            let range = vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .base_mip_level(level)
+                .level_count(1)
+                .base_array_layer(0)
+                .layer_count(1);
+
+            let view_info = vk::ImageViewCreateInfo::builder()
+                .image(chain.image)
+                .view_type(vk::ImageViewType::_2D)
+                .format(HDR_FORMAT)
+                .subresource_range(range);
+
+            device.create_image_view(&view_info, None)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // The chain stays in the general layout, sampled and stored alike
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    let range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(levels)
+        .base_array_layer(0)
+        .layer_count(1)
+        .build();
+    memory::cmd_image_barrier(device, command_buffer, chain.image, range,
+        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+        (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    // One set per downsample, per upsample and the composite
+    let set_count = levels * 2;
+    let descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, set_count * 2),
+        (vk::DescriptorType::STORAGE_IMAGE, set_count),
+    ], set_count)?;
+
+    let bloom = &data.bloom;
+    let hdr_view = data.hdr_image.image_view;
+    let write_set = |source: (vk::ImageView, vk::ImageLayout), destination: vk::ImageView| -> Result<vk::DescriptorSet> {
+        let set = descriptor::allocate_set(device, descriptor_pool, bloom.set_layout)?;
+        descriptor::write_image(device, set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            source.0, source.1, bloom.sampler);
+        descriptor::write_image(device, set, 1, vk::DescriptorType::STORAGE_IMAGE,
+            destination, vk::ImageLayout::GENERAL, vk::Sampler::null());
+        descriptor::write_image(device, set, 2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            hdr_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, bloom.sampler);
+        Ok(set)
+    };
+
+    let chain_source = (chain.image_view, vk::ImageLayout::GENERAL);
+    let downsample_sets = mip_views
+        .iter()
+        .enumerate()
+        .map(|(level, view)| {
+            let source = if level == 0 { (hdr_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL) } else { chain_source };
+            write_set(source, *view)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let upsample_sets = mip_views
+        .iter()
+        .rev()
+        .skip(1)
+        .map(|view| write_set(chain_source, *view))
+        .collect::<Result<Vec<_>>>()?;
+    let composite_set = write_set(chain_source, data.post_image.image_view)?;
+
+    let bloom = &mut data.bloom;
+    bloom.chain = chain;
+    bloom.mip_views = mip_views;
+    bloom.descriptor_pool = descriptor_pool;
+    bloom.downsample_sets = downsample_sets;
+    bloom.upsample_sets = upsample_sets;
+    bloom.composite_set = composite_set;
+    Ok(())
+}
+
+pub unsafe fn destroy_bloom_targets(device: &Device, data: &mut EngineData)
+{
+    let bloom = &mut data.bloom;
+    device.destroy_descriptor_pool(bloom.descriptor_pool, None);
+    bloom.mip_views
+        .iter()
+        .for_each(|v| device.destroy_image_view(*v, None));
+    bloom.chain.destroy(device);
+    bloom.mip_views.clear();
+    bloom.downsample_sets.clear();
+    bloom.upsample_sets.clear();
+}
+
+pub unsafe fn destroy_bloom(device: &Device, data: &mut EngineData)
+{
+    let bloom = &mut data.bloom;
+    device.destroy_pipeline(bloom.downsample_pipeline, None);
+    device.destroy_pipeline(bloom.upsample_pipeline, None);
+    device.destroy_pipeline(bloom.composite_pipeline, None);
+    device.destroy_pipeline_layout(bloom.pipeline_layout, None);
+    device.destroy_descriptor_set_layout(bloom.set_layout, None);
+    device.destroy_sampler(bloom.sampler, None);
+}
@@ -0,0 +1,403 @@
+use cgmath::vec3;
+
+type Vec3 = cgmath::Vector3<f32>;
+
+const MAX_LEAF_SIZE: usize = 4;
+const BIN_COUNT: usize = 8;
+
+/// Deepest level a node can sit at below the root, deeper nodes become leaves however many triangles
+///  they hold. The traversal in pathtrace.comp keeps at most one pending node per level plus the one
+///  it pops, so its `STACK_SIZE` has to stay above this.
+pub const MAX_DEPTH: usize = 48;
+
+/// A flattened BVH node, laid out to match `BvhNode` in the path tracing shader.
+///  Interior nodes have a `count` of 0 and their children at `left_first` and `left_first + 1`,
+///  leaves reference `count` triangles starting at `left_first`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BvhNode {
+    pub min: [f32; 3],
+    pub left_first: u32,
+    pub max: [f32; 3],
+    pub count: u32,
+}
+
+/// A bounding volume hierarchy over a list of triangles.
+///  `indices` maps the BVH's triangle order back to the input order.
+#[derive(Clone, Debug, Default)]
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    pub indices: Vec<u32>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Aabb {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Aabb {
+    fn empty() -> Self {
+        Self {
+            min: vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: vec3(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    fn grow(&mut self, p: Vec3) {
+        self.min = vec3(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z));
+        self.max = vec3(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z));
+    }
+
+    /// Componentwise, so merging an empty box leaves this one as it is
+    fn merge(&mut self, other: &Aabb) {
+        self.min = vec3(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z));
+        self.max = vec3(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z));
+    }
+
+    fn area(&self) -> f32 {
+        let e = self.max - self.min;
+        if e.x < 0.0 || e.y < 0.0 || e.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+}
+
+impl Bvh {
+    /// Builds the hierarchy with binned SAH splits
+    pub fn build(triangles: &[[Vec3; 3]]) -> Self {
+        Self::build_to_depth(triangles, MAX_DEPTH)
+    }
+
+    fn build_to_depth(triangles: &[[Vec3; 3]], max_depth: usize) -> Self {
+        let centroids = triangles
+            .iter()
+            .map(|t| (t[0] + t[1] + t[2]) / 3.0)
+            .collect::<Vec<_>>();
+
+        let mut bvh = Self {
+            nodes: vec![BvhNode::default()],
+            indices: (0..triangles.len() as u32).collect(),
+        };
+
+        // An empty root can never be hit
+        let empty = Aabb::empty();
+        bvh.nodes[0] = BvhNode::new(&empty, 0, 0);
+        if !triangles.is_empty() {
+            bvh.subdivide(0, max_depth, 0, triangles.len(), triangles, &centroids);
+        }
+
+        bvh
+    }
+
+    fn subdivide(&mut self, node: usize, levels_left: usize, first: usize, count: usize, triangles: &[[Vec3; 3]],
+        centroids: &[Vec3])
+    {
+        let mut bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for &i in &self.indices[first..first + count] {
+            triangles[i as usize].iter().for_each(|v| bounds.grow(*v));
+            centroid_bounds.grow(centroids[i as usize]);
+        }
+
+        self.nodes[node] = BvhNode::new(&bounds, first as u32, count as u32);
+        if count <= MAX_LEAF_SIZE || levels_left == 0 {
+            return;
+        }
+
+        // Find the cheapest split over every axis
+        let mut best: Option<(usize, usize, f32)> = None;
+        for axis in [0, 1, 2] {
+            let lo = centroid_bounds.min[axis];
+            let hi = centroid_bounds.max[axis];
+            if hi - lo <= f32::EPSILON {
+                continue;
+            }
+
+            let scale = BIN_COUNT as f32 / (hi - lo);
+            let mut bins = [(Aabb::empty(), 0usize); BIN_COUNT];
+            for &i in &self.indices[first..first + count] {
+                let bin = (((centroids[i as usize][axis] - lo) * scale) as usize).min(BIN_COUNT - 1);
+                triangles[i as usize].iter().for_each(|v| bins[bin].0.grow(*v));
+                bins[bin].1 += 1;
+            }
+
+            for split in 1..BIN_COUNT {
+                let mut left = Aabb::empty();
+                let mut right = Aabb::empty();
+                let mut left_count = 0;
+                let mut right_count = 0;
+                for (bounds, n) in &bins[..split] {
+                    left.merge(bounds);
+                    left_count += n;
+                }
+                for (bounds, n) in &bins[split..] {
+                    right.merge(bounds);
+                    right_count += n;
+                }
+                if left_count == 0 || right_count == 0 {
+                    continue;
+                }
+
+                let cost = left.area() * left_count as f32 + right.area() * right_count as f32;
+                if best.is_none_or(|(_, _, c)| cost < c) {
+                    best = Some((axis, split, cost));
+                }
+            }
+        }
+
+        let (axis, split, cost) = match best {
+            Some(best) => best,
+            None => return,
+        };
+        if cost >= bounds.area() * count as f32 {
+            return;
+        }
+
+        // Partition the triangles around the split plane
+        let lo = centroid_bounds.min[axis];
+        let scale = BIN_COUNT as f32 / (centroid_bounds.max[axis] - lo);
+        let bin_of = |i: u32| (((centroids[i as usize][axis] - lo) * scale) as usize).min(BIN_COUNT - 1);
+
+        let slice = &mut self.indices[first..first + count];
+        let mut i = 0;
+        let mut j = count;
+        while i < j {
+            if bin_of(slice[i]) < split {
+                i += 1;
+            } else {
+                j -= 1;
+                slice.swap(i, j);
+            }
+        }
+
+        if i == 0 || i == count {
+            return;
+        }
+
+        let left = self.nodes.len();
+        self.nodes.push(BvhNode::default());
+        self.nodes.push(BvhNode::default());
+        self.nodes[node].left_first = left as u32;
+        self.nodes[node].count = 0;
+
+        self.subdivide(left, levels_left - 1, first, i, triangles, centroids);
+        self.subdivide(left + 1, levels_left - 1, first + i, count - i, triangles, centroids);
+    }
+}
+
+impl BvhNode {
+    fn new(bounds: &Aabb, left_first: u32, count: u32) -> Self {
+        Self {
+            min: bounds.min.into(),
+            left_first,
+            max: bounds.max.into(),
+            count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::InnerSpace;
+
+    /// Deterministic xorshift numbers in 0..1, so failures reproduce
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> f32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            (self.0 >> 8) as f32 / (1 << 24) as f32
+        }
+
+        fn vec3(&mut self, scale: f32) -> Vec3 {
+            vec3(self.next() - 0.5, self.next() - 0.5, self.next() - 0.5) * scale
+        }
+    }
+
+    fn random_triangles(rng: &mut Rng, count: usize) -> Vec<[Vec3; 3]> {
+        (0..count)
+            .map(|_| {
+                let center = rng.vec3(20.0);
+                [center + rng.vec3(2.0), center + rng.vec3(2.0), center + rng.vec3(2.0)]
+            })
+            .collect()
+    }
+
+    // Möller-Trumbore like `intersect_triangle` in pathtrace.comp
+    fn intersect_triangle(origin: Vec3, direction: Vec3, t: &[Vec3; 3]) -> Option<f32> {
+        let e1 = t[1] - t[0];
+        let e2 = t[2] - t[0];
+        let p = direction.cross(e2);
+        let det = e1.dot(p);
+        if det.abs() < 1e-8 {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let s = origin - t[0];
+        let u = s.dot(p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let q = s.cross(e1);
+        let v = direction.dot(q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let distance = e2.dot(q) * inv_det;
+        (distance > 1e-4).then_some(distance)
+    }
+
+    fn intersects_node(origin: Vec3, direction: Vec3, node: &BvhNode, t_max: f32) -> bool {
+        let mut near = 0.0f32;
+        let mut far = t_max;
+        for axis in 0..3 {
+            let t0 = (node.min[axis] - origin[axis]) / direction[axis];
+            let t1 = (node.max[axis] - origin[axis]) / direction[axis];
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+        near <= far
+    }
+
+    /// Closest hit as an index into the input triangles, walking the BVH like `trace` in pathtrace.comp
+    fn trace_bvh(bvh: &Bvh, triangles: &[[Vec3; 3]], origin: Vec3, direction: Vec3) -> Option<(f32, u32)> {
+        let mut closest: Option<(f32, u32)> = None;
+        let mut stack = vec![0usize];
+        while let Some(index) = stack.pop() {
+            let node = &bvh.nodes[index];
+            let t_max = closest.map_or(f32::INFINITY, |(t, _)| t);
+            if !intersects_node(origin, direction, node, t_max) {
+                continue;
+            }
+
+            if node.count > 0 {
+                let first = node.left_first as usize;
+                for &triangle in &bvh.indices[first..first + node.count as usize] {
+                    if let Some(t) = intersect_triangle(origin, direction, &triangles[triangle as usize]) {
+                        if closest.is_none_or(|(c, _)| t < c) {
+                            closest = Some((t, triangle));
+                        }
+                    }
+                }
+            } else {
+                stack.push(node.left_first as usize);
+                stack.push(node.left_first as usize + 1);
+            }
+        }
+        closest
+    }
+
+    /// Levels below the root of the deepest leaf
+    fn depth(bvh: &Bvh, node: usize) -> usize {
+        let node = &bvh.nodes[node];
+        if node.count > 0 || bvh.nodes.len() == 1 {
+            return 0;
+        }
+        let left = node.left_first as usize;
+        1 + depth(bvh, left).max(depth(bvh, left + 1))
+    }
+
+    fn trace_brute_force(triangles: &[[Vec3; 3]], origin: Vec3, direction: Vec3) -> Option<(f32, u32)> {
+        triangles
+            .iter()
+            .enumerate()
+            .filter_map(|(i, t)| intersect_triangle(origin, direction, t).map(|d| (d, i as u32)))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+    }
+
+    #[test]
+    fn indices_cover_every_triangle_once() {
+        let triangles = random_triangles(&mut Rng(1), 500);
+        let bvh = Bvh::build(&triangles);
+
+        let mut indices = bvh.indices.clone();
+        indices.sort_unstable();
+        assert_eq!(indices, (0..triangles.len() as u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn leaves_bound_their_triangles() {
+        let triangles = random_triangles(&mut Rng(2), 500);
+        let bvh = Bvh::build(&triangles);
+
+        for node in bvh.nodes.iter().filter(|n| n.count > 0) {
+            let first = node.left_first as usize;
+            for &triangle in &bvh.indices[first..first + node.count as usize] {
+                for v in &triangles[triangle as usize] {
+                    for axis in 0..3 {
+                        assert!(node.min[axis] <= v[axis] && v[axis] <= node.max[axis]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hits_match_brute_force() {
+        let mut rng = Rng(3);
+        let triangles = random_triangles(&mut rng, 500);
+        let bvh = Bvh::build(&triangles);
+        assert!(bvh.nodes.len() > 1, "500 triangles should be split");
+
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let origin = rng.vec3(40.0);
+            let direction = (rng.vec3(2.0) - origin * 0.05).normalize();
+            let expected = trace_brute_force(&triangles, origin, direction);
+            let actual = trace_bvh(&bvh, &triangles, origin, direction);
+            match (expected, actual) {
+                (Some((t, _)), Some((u, _))) => {
+                    // Ties between triangles at the same distance may resolve either way
+                    assert!((t - u).abs() <= 1e-5 * t.max(1.0), "hit at {} instead of {}", u, t);
+                    hits += 1;
+                },
+                (None, None) => {},
+                _ => panic!("BVH hit {:?}, brute force hit {:?}", actual, expected),
+            }
+        }
+        assert!(hits > 100, "only {} of the rays hit anything", hits);
+    }
+
+    #[test]
+    fn depth_is_capped() {
+        let mut rng = Rng(4);
+        let triangles = random_triangles(&mut rng, 500);
+        assert!(depth(&Bvh::build(&triangles), 0) > 3);
+
+        // Past the cap everything left stays in one leaf, which traversal still finds
+        let bvh = Bvh::build_to_depth(&triangles, 3);
+        assert_eq!(depth(&bvh, 0), 3);
+        assert!(bvh.nodes.iter().any(|n| n.count as usize > MAX_LEAF_SIZE));
+        for _ in 0..500 {
+            let origin = rng.vec3(40.0);
+            let direction = (rng.vec3(2.0) - origin * 0.05).normalize();
+            let expected = trace_brute_force(&triangles, origin, direction).map(|(t, _)| t);
+            let actual = trace_bvh(&bvh, &triangles, origin, direction).map(|(t, _)| t);
+            match (expected, actual) {
+                (Some(t), Some(u)) => assert!((t - u).abs() <= 1e-5 * t.max(1.0), "hit at {} instead of {}", u, t),
+                (None, None) => {},
+                _ => panic!("BVH hit {:?}, brute force hit {:?}", actual, expected),
+            }
+        }
+    }
+
+    #[test]
+    fn traversal_stack_fits_the_deepest_tree() {
+        // The shader pops a node before pushing its two children, so it holds one more than the depth
+        let shader = include_str!("shader/pathtrace.comp");
+        let stack_size = shader
+            .lines()
+            .find_map(|line| line.strip_prefix("const int STACK_SIZE = "))
+            .and_then(|size| size.trim_end_matches(';').parse::<usize>().ok())
+            .expect("pathtrace.comp declares STACK_SIZE");
+        assert!(MAX_DEPTH + 1 < stack_size);
+    }
+}
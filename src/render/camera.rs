@@ -0,0 +1,136 @@
+use cgmath::{point3, vec3, Deg, InnerSpace, Matrix4, Rad, SquareMatrix};
+
+pub type Point3 = cgmath::Point3<f32>;
+pub type Vec3 = cgmath::Vector3<f32>;
+pub type Mat4 = cgmath::Matrix4<f32>;
+
+/// Maps OpenGL clip space, which cgmath's projections produce, to Vulkan's (Y down, depth in 0..1)
+pub const VULKAN_CLIP: Mat4 = Mat4::new(
+    1.0,  0.0,       0.0, 0.0,
+    0.0, -1.0,       0.0, 0.0,
+    0.0,  0.0, 1.0 / 2.0, 0.0,
+    0.0,  0.0, 1.0 / 2.0, 1.0,
+);
+
+/// The lens and sensor settings of a real camera. Lengths are in millimetres.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PhysicalCamera {
+    pub focal_length: f32,
+    /// Width and height of the sensor, a full frame sensor is 36x24
+    pub sensor_size: [f32; 2],
+    /// The f-number, focal length divided by the aperture's diameter
+    pub aperture: f32,
+    /// Exposure time in seconds
+    pub shutter_speed: f32,
+    /// Sensor sensitivity, ISO 100 being the reference of EV100
+    pub iso: f32,
+    /// Distance in metres of the plane in perfect focus
+    pub focus_distance: f32,
+}
+
+impl Default for PhysicalCamera {
+    /// A 50mm lens on a full frame sensor at f/16, 1/125s and ISO 100, the sunny 16 rule
+    fn default() -> Self {
+        Self {
+            focal_length: 50.0,
+            sensor_size: [36.0, 24.0],
+            aperture: 16.0,
+            shutter_speed: 1.0 / 125.0,
+            iso: 100.0,
+            focus_distance: 10.0,
+        }
+    }
+}
+
+impl PhysicalCamera {
+    /// Vertical field of view, the sensor's height seen through the lens
+    pub fn fov_y(&self) -> Deg<f32> {
+        Deg::from(Rad(2.0 * (self.sensor_size[1] / (2.0 * self.focal_length)).atan()))
+    }
+
+    /// Horizontal field of view, which the viewport can crop when its aspect differs from the sensor's
+    pub fn fov_x(&self) -> Deg<f32> {
+        Deg::from(Rad(2.0 * (self.sensor_size[0] / (2.0 * self.focal_length)).atan()))
+    }
+
+    /// Picks the focal length giving a vertical field of view, like zooming
+    pub fn set_fov_y(&mut self, fov_y: Deg<f32>) {
+        let half = Rad::from(fov_y).0.clamp(1e-3, 3.1) / 2.0;
+        self.focal_length = self.sensor_size[1] / (2.0 * half.tan());
+    }
+
+    /// Diameter of the entrance pupil in millimetres
+    pub fn aperture_diameter(&self) -> f32 {
+        self.focal_length / self.aperture.max(0.5)
+    }
+
+    /// Exposure value at ISO 100: `log2(N² / t) - log2(S / 100)`
+    pub fn ev100(&self) -> f32 {
+        let n = self.aperture.max(0.5);
+        let t = self.shutter_speed.max(1e-6);
+        let s = self.iso.max(1.0);
+        (n * n / t).log2() - (s / 100.0).log2()
+    }
+}
+
+/// A perspective camera looking from `position` towards `target`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Camera {
+    pub position: Point3,
+    pub target: Point3,
+    pub up: Vec3,
+    /// Used when there is no physical camera
+    pub fov_y: Deg<f32>,
+    pub near: f32,
+    pub far: f32,
+    /// Lens and sensor settings, which override `fov_y` and the engine's exposure value when set
+    pub physical: Option<PhysicalCamera>,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            position: point3(0.0, 0.0, 2.0),
+            target: point3(0.0, 0.0, 0.0),
+            up: vec3(0.0, 1.0, 0.0),
+            fov_y: Deg(45.0),
+            near: 0.1,
+            far: 100.0,
+            physical: None,
+        }
+    }
+}
+
+impl Camera {
+    pub fn view(&self) -> Mat4 {
+        Matrix4::look_at_rh(self.position, self.target, self.up)
+    }
+
+    /// A right handed projection with Vulkan's clip space (Y down, depth in 0..1)
+    pub fn projection(&self, aspect: f32) -> Mat4 {
+        VULKAN_CLIP * cgmath::perspective(self.vertical_fov(), aspect, self.near, self.far)
+    }
+
+    /// The field of view actually rendered, derived from the lens when there is a physical camera
+    pub fn vertical_fov(&self) -> Deg<f32> {
+        self.physical.map_or(self.fov_y, |p| p.fov_y())
+    }
+
+    /// The exposure value set by the physical camera, if there is one
+    pub fn ev100(&self) -> Option<f32> {
+        self.physical.map(|p| p.ev100())
+    }
+
+    /// Unit vector from the position towards the target
+    pub fn forward(&self) -> Vec3 {
+        (self.target - self.position).normalize()
+    }
+
+    pub fn inverse_view(&self) -> Mat4 {
+        self.view().invert().unwrap_or_else(Mat4::identity)
+    }
+
+    pub fn inverse_projection(&self, aspect: f32) -> Mat4 {
+        self.projection(aspect).invert().unwrap_or_else(Mat4::identity)
+    }
+}
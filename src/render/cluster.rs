@@ -0,0 +1,186 @@
+use std::mem::size_of;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+use log::*;
+
+use super::camera::Camera;
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::{self, AllocatedBuffer};
+use super::post::SCENE_COLOR_ATTACHMENTS;
+use super::shader;
+
+/// Clusters across, down and in depth. Mirrors `CLUSTER_GRID` in `clusters.glsl`.
+pub const CLUSTER_GRID: [u32; 3] = [16, 9, 24];
+
+/// Lights a single cluster can hold, the rest are dropped with a warning. Mirrors `MAX_CLUSTER_LIGHTS` in
+///  `clusters.glsl`.
+const MAX_CLUSTER_LIGHTS: u32 = 127;
+
+/// Workgroup size of the culling shader, one invocation per cluster
+const CULL_WORKGROUP_SIZE: u32 = 64;
+
+/// Controls of the light culling.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClusteredLightingSettings {
+    /// Illuminance in lux at which lights without a range are considered out of reach
+    pub light_cutoff: f32,
+}
+
+impl Default for ClusteredLightingSettings {
+    fn default() -> Self {
+        Self { light_cutoff: 0.1 }
+    }
+}
+
+/// Mirrors `Cull` in `cluster_cull.comp`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct CullPushConstants {
+    light_cutoff: f32,
+}
+
+/// Clustered forward shading: a compute pass sorts the lights into a grid of froxels, exponentially
+///  sliced in depth, and the forward shader only loops over the lights of its fragment's cluster.
+///  The light lists live in the frame set, next to the lights themselves. The culling also records the
+///  most lights any cluster had in reach, read back a frame late to warn once about full lists.
+#[derive(Clone, Debug, Default)]
+pub struct ClusteredLighting {
+    pub settings: ClusteredLightingSettings,
+    /// Forward pipelines reading the clusters, sharing the forward pipelines' layout
+    pub pipeline: vk::Pipeline,
+    pub double_sided_pipeline: vk::Pipeline,
+    pub blended_pipeline: vk::Pipeline,
+    pub double_sided_blended_pipeline: vk::Pipeline,
+    buffer: AllocatedBuffer,
+    /// Host visible, holds the largest light count of an overflowing cluster, 0 until one overflows
+    overflow_buffer: AllocatedBuffer,
+    overflow_pending: bool,
+    overflow_warned: bool,
+    pipeline_layout: vk::PipelineLayout,
+    cull_pipeline: vk::Pipeline,
+}
+
+impl ClusteredLighting {
+    /// Fills the clusters' light lists for this frame, before the scene pass reads them
+    pub unsafe fn cmd_cull(&mut self, device: &Device, command_buffer: vk::CommandBuffer,
+        frame_set: vk::DescriptorSet)
+    {
+        let push_constants = CullPushConstants { light_cutoff: self.settings.light_cutoff.max(1e-6) };
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.cull_pipeline);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 0, &[frame_set], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, &push_constants);
+        device.cmd_dispatch(command_buffer, cluster_count().div_ceil(CULL_WORKGROUP_SIZE), 1, 1);
+
+        memory::cmd_buffer_barrier(device, command_buffer, self.buffer.buffer,
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ));
+        if !self.overflow_warned {
+            memory::cmd_buffer_barrier(device, command_buffer, self.overflow_buffer.buffer,
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+                (vk::PipelineStageFlags::HOST, vk::AccessFlags::HOST_READ));
+            self.overflow_pending = true;
+        }
+    }
+}
+
+fn cluster_count() -> u32 {
+    CLUSTER_GRID.iter().product()
+}
+
+/// Scale and bias taking the log of a view depth to its depth slice, then the size of a cluster
+///  in pixels. Mirrors `clusters` in `frame.glsl`.
+pub fn cluster_params(camera: &Camera, extent: vk::Extent2D) -> [f32; 4] {
+    let slices = CLUSTER_GRID[2] as f32;
+    let log_range = (camera.far / camera.near).ln();
+    [
+        slices / log_range,
+        slices * camera.near.ln() / log_range,
+        extent.width as f32 / CLUSTER_GRID[0] as f32,
+        extent.height as f32 / CLUSTER_GRID[1] as f32,
+    ]
+}
+
+pub unsafe fn create_clustered_lighting(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    // Every cluster holds its light count followed by the indices
+    let buffer = AllocatedBuffer::allocate(
+        (size_of::<u32>() as u32 * cluster_count() * (MAX_CLUSTER_LIGHTS + 1)) as u64,
+        vk::BufferUsageFlags::STORAGE_BUFFER,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        instance, device, data)?;
+    descriptor::write_buffer(device, data.frame.set, 7, vk::DescriptorType::STORAGE_BUFFER, buffer.buffer);
+
+    let overflow_buffer = AllocatedBuffer::create(
+        &0u32,
+        size_of::<u32>(),
+        vk::BufferUsageFlags::STORAGE_BUFFER,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)?;
+    descriptor::write_buffer(device, data.frame.set, 14, vk::DescriptorType::STORAGE_BUFFER,
+        overflow_buffer.buffer);
+
+    let clusters = &mut data.clusters;
+    clusters.settings = ClusteredLightingSettings::default();
+    clusters.buffer = buffer;
+    clusters.overflow_buffer = overflow_buffer;
+    clusters.overflow_pending = false;
+    clusters.overflow_warned = false;
+    clusters.pipeline_layout = descriptor::create_pipeline_layout(device, &[data.frame.set_layout],
+        size_of::<CullPushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/cluster_cull_comp.spv"));
+    clusters.cull_pipeline = shader::create_compute_pipeline(device, &comp[..], clusters.pipeline_layout)?;
+
+    create_clustered_lighting_targets(device, data)
+}
+
+/// Reads back whether a cluster ran out of room in the last culled frame, warning the first time one does
+pub unsafe fn update_clustered_lighting(device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let clusters = &mut data.clusters;
+    if !clusters.overflow_pending {
+        return Ok(());
+    }
+    clusters.overflow_pending = false;
+
+    let lights = clusters.overflow_buffer.read::<u32>(device)?;
+    if lights > MAX_CLUSTER_LIGHTS {
+        warn!("A light cluster had {} lights in reach, only the first {} are shaded.", lights, MAX_CLUSTER_LIGHTS);
+        clusters.overflow_warned = true;
+    }
+    Ok(())
+}
+
+/// Creates the clustered forward pipelines for the current render pass
+pub unsafe fn create_clustered_lighting_targets(device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let frag = include_bytes!(concat!(env!("OUT_DIR"), "/shader/clustered_frag.spv"));
+    (data.clusters.pipeline, data.clusters.double_sided_pipeline) = shader::create_mesh_pipelines(device,
+        &frag[..], data.pipeline_layout, data.render_pass, 0, SCENE_COLOR_ATTACHMENTS, data.swapchain_extent, false)?;
+    (data.clusters.blended_pipeline, data.clusters.double_sided_blended_pipeline) = shader::create_mesh_pipelines(
+        device, &frag[..], data.pipeline_layout, data.render_pass, 0, SCENE_COLOR_ATTACHMENTS, data.swapchain_extent,
+        true)?;
+    Ok(())
+}
+
+pub unsafe fn destroy_clustered_lighting_targets(device: &Device, data: &mut EngineData)
+{
+    device.destroy_pipeline(data.clusters.pipeline, None);
+    device.destroy_pipeline(data.clusters.double_sided_pipeline, None);
+    device.destroy_pipeline(data.clusters.blended_pipeline, None);
+    device.destroy_pipeline(data.clusters.double_sided_blended_pipeline, None);
+}
+
+pub unsafe fn destroy_clustered_lighting(device: &Device, data: &mut EngineData)
+{
+    let clusters = &mut data.clusters;
+    device.destroy_pipeline(clusters.cull_pipeline, None);
+    device.destroy_pipeline_layout(clusters.pipeline_layout, None);
+    clusters.buffer.destroy(device);
+    clusters.overflow_buffer.destroy(device);
+}
@@ -0,0 +1,276 @@
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::AllocatedImage;
+use super::post::{HDR_FORMAT, NORMAL_FORMAT, SPECULAR_FORMAT, VELOCITY_FORMAT};
+use super::shader;
+
+/// Formats of the albedo, normal, material and emission attachments, `gbuffer.glsl` describes
+///  what they hold
+const GBUFFER_FORMATS: [vk::Format; 4] = [
+    vk::Format::R8G8B8A8_SRGB,
+    HDR_FORMAT,
+    vk::Format::R8G8B8A8_UNORM,
+    HDR_FORMAT,
+];
+
+/// Attachments the deferred pass adds after the HDR target, velocity buffer, depth, normals and
+///  specular reflectance it shares with the forward pass
+pub const GBUFFER_ATTACHMENTS: usize = GBUFFER_FORMATS.len();
+
+/// Index of the first G-buffer attachment in the deferred pass
+const FIRST_GBUFFER_ATTACHMENT: u32 = 5;
+
+/// Renders the scene in a single pass of two subpasses: the meshes write their surfaces into the
+///  G-buffer, then a fullscreen triangle lights every pixel once by reading it back as input
+///  attachments. The G-buffer never has to leave tile memory on tile based GPUs. Blended materials
+///  can't be stored in the G-buffer, they are shaded forward over the lit scene in the second subpass.
+#[derive(Clone, Debug, Default)]
+pub struct DeferredRenderer {
+    pub render_pass: vk::RenderPass,
+    pub framebuffer: vk::Framebuffer,
+    /// Mesh pipelines of the G-buffer subpass, sharing the forward pipelines' layout
+    pub pipeline: vk::Pipeline,
+    pub double_sided_pipeline: vk::Pipeline,
+    /// Forward pipelines of the lighting subpass for blended materials
+    pub blended_pipeline: vk::Pipeline,
+    pub double_sided_blended_pipeline: vk::Pipeline,
+    gbuffer: Vec<AllocatedImage>,
+    descriptor_pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+    set: vk::DescriptorSet,
+    lighting_layout: vk::PipelineLayout,
+    lighting_pipeline: vk::Pipeline,
+}
+
+impl DeferredRenderer {
+    /// Moves on to the lighting subpass and shades every pixel of the G-buffer into the HDR target
+    pub unsafe fn cmd_light(&self, device: &Device, command_buffer: vk::CommandBuffer, frame_set: vk::DescriptorSet,
+        extent: vk::Extent2D)
+    {
+        device.cmd_next_subpass(command_buffer, vk::SubpassContents::INLINE);
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.lighting_pipeline);
+        shader::cmd_set_full_viewport(device, command_buffer, extent);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+            self.lighting_layout, 0, &[frame_set, self.set], &[]);
+        device.cmd_draw(command_buffer, 3, 1, 0, 0);
+    }
+}
+
+pub unsafe fn create_deferred(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let deferred = &mut data.deferred;
+    let bindings = (0..=GBUFFER_ATTACHMENTS as u32)
+        .map(|i| descriptor::layout_binding(i, vk::DescriptorType::INPUT_ATTACHMENT, vk::ShaderStageFlags::FRAGMENT))
+        .collect::<Vec<_>>();
+    deferred.set_layout = descriptor::create_set_layout(device, &bindings)?;
+    deferred.descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::INPUT_ATTACHMENT, GBUFFER_ATTACHMENTS as u32 + 1),
+    ], 1)?;
+    deferred.set = descriptor::allocate_set(device, deferred.descriptor_pool, deferred.set_layout)?;
+
+    // The lights and shadows come from the frame set like in the forward pass
+    deferred.lighting_layout = descriptor::create_pipeline_layout(device,
+        &[data.frame.set_layout, deferred.set_layout], 0, vk::ShaderStageFlags::FRAGMENT)?;
+
+    create_deferred_targets(instance, device, data)
+}
+
+unsafe fn create_render_pass(device: &Device, data: &EngineData) -> Result<vk::RenderPass>
+{
+    let attachment = |format: vk::Format, load_op: vk::AttachmentLoadOp, store_op: vk::AttachmentStoreOp,
+        final_layout: vk::ImageLayout|
+    {
+        vk::AttachmentDescription::builder()
+            .format(format)
+            .samples(vk::SampleCountFlags::_1)
+            .load_op(load_op)
+            .store_op(store_op)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(final_layout)
+            .build()
+    };
+
+    // The forward pass' attachments end up as after it. The lighting subpass writes every pixel of
+    //  the HDR target, and the G-buffer isn't needed afterwards.
+    let mut attachments = vec![
+        attachment(HDR_FORMAT, vk::AttachmentLoadOp::DONT_CARE, vk::AttachmentStoreOp::STORE,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        attachment(VELOCITY_FORMAT, vk::AttachmentLoadOp::CLEAR, vk::AttachmentStoreOp::STORE,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        attachment(data.depth_format, vk::AttachmentLoadOp::CLEAR, vk::AttachmentStoreOp::STORE,
+            vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL),
+        attachment(NORMAL_FORMAT, vk::AttachmentLoadOp::CLEAR, vk::AttachmentStoreOp::STORE,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        attachment(SPECULAR_FORMAT, vk::AttachmentLoadOp::CLEAR, vk::AttachmentStoreOp::STORE,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+    ];
+    attachments.extend(GBUFFER_FORMATS.iter().map(|format| attachment(*format, vk::AttachmentLoadOp::CLEAR,
+        vk::AttachmentStoreOp::DONT_CARE, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)));
+
+    let reference = |attachment: u32, layout: vk::ImageLayout| vk::AttachmentReference::builder()
+        .attachment(attachment)
+        .layout(layout)
+        .build();
+    let gbuffer = FIRST_GBUFFER_ATTACHMENT..FIRST_GBUFFER_ATTACHMENT + GBUFFER_ATTACHMENTS as u32;
+
+    // The G-buffer subpass writes the velocity buffer, the G-buffer, then the normals and specular reflectance
+    let mut gbuffer_colors = vec![reference(1, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+    gbuffer_colors.extend(gbuffer.clone().map(|i| reference(i, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)));
+    gbuffer_colors.extend([3, 4].map(|i| reference(i, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)));
+    let depth_attachment = reference(2, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+    let gbuffer_subpass = vk::SubpassDescription::builder()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&gbuffer_colors)
+        .depth_stencil_attachment(&depth_attachment);
+
+    // The lighting subpass reads it back with depth last, and writes the HDR target. Blended
+    //  materials test against the same depth, read only.
+    let lighting_colors = [reference(0, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+    let mut lighting_inputs = gbuffer.map(|i| reference(i, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)).collect::<Vec<_>>();
+    lighting_inputs.push(reference(2, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL));
+    let lighting_depth = reference(2, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
+    let lighting_subpass = vk::SubpassDescription::builder()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&lighting_colors)
+        .input_attachments(&lighting_inputs)
+        .depth_stencil_attachment(&lighting_depth);
+
+    // The previous frame's tonemap pass may still be reading the HDR target
+    let dependency = vk::SubpassDependency::builder()
+        .src_subpass(vk::SUBPASS_EXTERNAL)
+        .dst_subpass(0)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+            | vk::PipelineStageFlags::FRAGMENT_SHADER)
+        .src_access_mask(vk::AccessFlags::empty())
+        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE);
+
+    // Lighting reads only the pixel it shades, so tiles can move on independently
+    let gbuffer_dependency = vk::SubpassDependency::builder()
+        .src_subpass(0)
+        .dst_subpass(1)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+        .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER
+            | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+            | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+        .dst_access_mask(vk::AccessFlags::INPUT_ATTACHMENT_READ
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ)
+        .dependency_flags(vk::DependencyFlags::BY_REGION);
+
+    // Post effects sample the velocity buffer and depth written by the first subpass, and the HDR
+    //  target written by the second
+    let exit_dependency = |subpass: u32| vk::SubpassDependency::builder()
+        .src_subpass(subpass)
+        .dst_subpass(vk::SUBPASS_EXTERNAL)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+        .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER
+            | vk::PipelineStageFlags::COMPUTE_SHADER)
+        .dst_access_mask(vk::AccessFlags::SHADER_READ)
+        .build();
+
+    let subpasses = &[gbuffer_subpass, lighting_subpass];
+    let dependencies = &[dependency.build(), gbuffer_dependency.build(), exit_dependency(0), exit_dependency(1)];
+    let info = vk::RenderPassCreateInfo::builder()
+        .attachments(&attachments)
+        .subpasses(subpasses)
+        .dependencies(dependencies);
+
+    Ok(device.create_render_pass(&info, None)?)
+}
+
+/// Creates the render pass, G-buffer and pipelines for the current swapchain, after the forward
+///  pipelines and the scene's targets
+pub unsafe fn create_deferred_targets(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let render_pass = create_render_pass(device, data)?;
+
+    // Transient, the G-buffer only lives for the duration of the pass
+    let gbuffer = GBUFFER_FORMATS
+        .iter()
+        .map(|format| AllocatedImage::create(
+            data.swapchain_extent,
+            *format,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::INPUT_ATTACHMENT
+                | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+            vk::ImageAspectFlags::COLOR,
+            instance, device, data))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut attachments = vec![data.hdr_image.image_view, data.velocity_image.image_view, data.depth_image.image_view,
+        data.normal_image.image_view, data.specular_image.image_view];
+    attachments.extend(gbuffer.iter().map(|image| image.image_view));
+    let info = vk::FramebufferCreateInfo::builder()
+        .render_pass(render_pass)
+        .attachments(&attachments)
+        .width(data.swapchain_extent.width)
+        .height(data.swapchain_extent.height)
+        .layers(1);
+    let framebuffer = device.create_framebuffer(&info, None)?;
+
+    let deferred = &data.deferred;
+    for (i, image) in gbuffer.iter().enumerate() {
+        descriptor::write_image(device, deferred.set, i as u32, vk::DescriptorType::INPUT_ATTACHMENT,
+            image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::Sampler::null());
+    }
+    descriptor::write_image(device, deferred.set, GBUFFER_ATTACHMENTS as u32, vk::DescriptorType::INPUT_ATTACHMENT,
+        data.depth_image.image_view, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL, vk::Sampler::null());
+
+    let frag = include_bytes!(concat!(env!("OUT_DIR"), "/shader/gbuffer_frag.spv"));
+    let (pipeline, double_sided_pipeline) = shader::create_mesh_pipelines(device, &frag[..], data.pipeline_layout,
+        render_pass, 0, 3 + GBUFFER_ATTACHMENTS as u32, data.swapchain_extent, false)?;
+    let forward = include_bytes!(concat!(env!("OUT_DIR"), "/shader/shader_frag.spv"));
+    let (blended_pipeline, double_sided_blended_pipeline) = shader::create_mesh_pipelines(device, &forward[..],
+        data.pipeline_layout, render_pass, 1, 1, data.swapchain_extent, true)?;
+    let lighting = include_bytes!(concat!(env!("OUT_DIR"), "/shader/deferred_lighting_frag.spv"));
+    let lighting_pipeline = shader::create_fullscreen_pipeline(device, &lighting[..], deferred.lighting_layout,
+        render_pass, 1, 1)?;
+
+    let deferred = &mut data.deferred;
+    deferred.render_pass = render_pass;
+    deferred.framebuffer = framebuffer;
+    deferred.gbuffer = gbuffer;
+    deferred.pipeline = pipeline;
+    deferred.double_sided_pipeline = double_sided_pipeline;
+    deferred.blended_pipeline = blended_pipeline;
+    deferred.double_sided_blended_pipeline = double_sided_blended_pipeline;
+    deferred.lighting_pipeline = lighting_pipeline;
+    Ok(())
+}
+
+pub unsafe fn destroy_deferred_targets(device: &Device, data: &mut EngineData)
+{
+    let deferred = &mut data.deferred;
+    device.destroy_pipeline(deferred.pipeline, None);
+    device.destroy_pipeline(deferred.double_sided_pipeline, None);
+    device.destroy_pipeline(deferred.blended_pipeline, None);
+    device.destroy_pipeline(deferred.double_sided_blended_pipeline, None);
+    device.destroy_pipeline(deferred.lighting_pipeline, None);
+    device.destroy_framebuffer(deferred.framebuffer, None);
+    device.destroy_render_pass(deferred.render_pass, None);
+    deferred.gbuffer
+        .iter_mut()
+        .for_each(|image| image.destroy(device));
+    deferred.gbuffer.clear();
+}
+
+pub unsafe fn destroy_deferred(device: &Device, data: &mut EngineData)
+{
+    let deferred = &mut data.deferred;
+    device.destroy_pipeline_layout(deferred.lighting_layout, None);
+    device.destroy_descriptor_pool(deferred.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(deferred.set_layout, None);
+}
@@ -0,0 +1,132 @@
+use anyhow::{Ok, Result};
+use vulkanalia::prelude::v1_3::*;
+
+/// Describes a single descriptor at `binding`
+pub fn layout_binding(binding: u32, descriptor_type: vk::DescriptorType, stages: vk::ShaderStageFlags) -> vk::DescriptorSetLayoutBinding {
+    vk::DescriptorSetLayoutBinding::builder()
+        .binding(binding)
+        .descriptor_type(descriptor_type)
+        .descriptor_count(1)
+        .stage_flags(stages)
+        .build()
+}
+
+pub unsafe fn create_set_layout(device: &Device, bindings: &[vk::DescriptorSetLayoutBinding]) -> Result<vk::DescriptorSetLayout> {
+    let info = vk::DescriptorSetLayoutCreateInfo::builder()
+        .bindings(bindings);
+
+    Ok(device.create_descriptor_set_layout(&info, None)?)
+}
+
+pub unsafe fn create_pool(device: &Device, sizes: &[(vk::DescriptorType, u32)], max_sets: u32) -> Result<vk::DescriptorPool> {
+    let pool_sizes = sizes
+        .iter()
+        .map(|(t, n)| {
+            vk::DescriptorPoolSize::builder()
+                .type_(*t)
+                .descriptor_count(*n)
+        })
+        .collect::<Vec<_>>();
+
+    let info = vk::DescriptorPoolCreateInfo::builder()
+        .pool_sizes(&pool_sizes)
+        .max_sets(max_sets);
+
+    Ok(device.create_descriptor_pool(&info, None)?)
+}
+
+pub unsafe fn allocate_set(device: &Device, pool: vk::DescriptorPool, layout: vk::DescriptorSetLayout) -> Result<vk::DescriptorSet> {
+    let layouts = &[layout];
+    let info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(pool)
+        .set_layouts(layouts);
+
+    Ok(device.allocate_descriptor_sets(&info)?[0])
+}
+
+pub unsafe fn create_pipeline_layout(device: &Device, set_layouts: &[vk::DescriptorSetLayout], push_constant_size: u32,
+    push_constant_stages: vk::ShaderStageFlags) -> Result<vk::PipelineLayout>
+{
+    let push_constant_range = vk::PushConstantRange::builder()
+        .stage_flags(push_constant_stages)
+        .offset(0)
+        .size(push_constant_size);
+
+    let push_constant_ranges = &[push_constant_range];
+    let mut info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(set_layouts);
+
+    if push_constant_size > 0 {
+        info = info.push_constant_ranges(push_constant_ranges);
+    }
+
+    Ok(device.create_pipeline_layout(&info, None)?)
+}
+
+/// Points a buffer descriptor at the whole of `buffer`
+pub unsafe fn write_buffer(device: &Device, set: vk::DescriptorSet, binding: u32, descriptor_type: vk::DescriptorType,
+    buffer: vk::Buffer)
+{
+    let info = vk::DescriptorBufferInfo::builder()
+        .buffer(buffer)
+        .offset(0)
+        .range(vk::WHOLE_SIZE);
+
+    let buffer_info = &[info];
+    let write = vk::WriteDescriptorSet::builder()
+        .dst_set(set)
+        .dst_binding(binding)
+        .dst_array_element(0)
+        .descriptor_type(descriptor_type)
+        .buffer_info(buffer_info);
+
+    device.update_descriptor_sets(&[write], &[] as &[vk::CopyDescriptorSet]);
+}
+
+/// Points an image descriptor at `view`, `sampler` may be null for storage images
+pub unsafe fn write_image(device: &Device, set: vk::DescriptorSet, binding: u32, descriptor_type: vk::DescriptorType,
+    view: vk::ImageView, layout: vk::ImageLayout, sampler: vk::Sampler)
+{
+    let info = vk::DescriptorImageInfo::builder()
+        .image_layout(layout)
+        .image_view(view)
+        .sampler(sampler);
+
+    let image_info = &[info];
+    let write = vk::WriteDescriptorSet::builder()
+        .dst_set(set)
+        .dst_binding(binding)
+        .dst_array_element(0)
+        .descriptor_type(descriptor_type)
+        .image_info(image_info);
+
+    device.update_descriptor_sets(&[write], &[] as &[vk::CopyDescriptorSet]);
+}
+
+/// Creates a clamped sampler with the given filter
+pub unsafe fn create_sampler(device: &Device, filter: vk::Filter, max_lod: f32) -> Result<vk::Sampler> {
+    let mipmap_mode = if filter == vk::Filter::LINEAR {
+        vk::SamplerMipmapMode::LINEAR
+    } else {
+        vk::SamplerMipmapMode::NEAREST
+    };
+
+    let info = vk::SamplerCreateInfo::builder()
+        .mag_filter(filter)
+        .min_filter(filter)
+        .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        .anisotropy_enable(false)
+        .max_anisotropy(1.0)
+        .border_color(vk::BorderColor::FLOAT_OPAQUE_BLACK)
+        .unnormalized_coordinates(false)
+        .compare_enable(false)
+        .compare_op(vk::CompareOp::ALWAYS)
+        .mipmap_mode(mipmap_mode)
+        .mip_lod_bias(0.0)
+        .min_lod(0.0)
+        .max_lod(max_lod);
+
+    Ok(device.create_sampler(&info, None)?)
+}
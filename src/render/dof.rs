@@ -0,0 +1,200 @@
+use std::mem::size_of;
+use cgmath::{Deg, Rad};
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::camera::Camera;
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::{self, AllocatedBuffer, AllocatedImage};
+use super::post;
+use super::shader;
+
+/// Depth of field controls. The blur itself follows the physical camera's lens.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DepthOfFieldSettings {
+    pub enabled: bool,
+    /// Number of aperture blades shaping the bokeh, fewer than 3 gives round bokeh
+    pub blade_count: u32,
+    pub blade_rotation: Deg<f32>,
+    /// Largest circle of confusion radius in pixels, also the gather radius
+    pub max_coc: f32,
+    /// Screen point in 0..1 to keep in focus, `None` uses the camera's focus distance as is
+    pub autofocus: Option<[f32; 2]>,
+    /// Fraction of the way to the metered distance the focus moves every frame
+    pub autofocus_speed: f32,
+}
+
+impl Default for DepthOfFieldSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            blade_count: 6,
+            blade_rotation: Deg(0.0),
+            max_coc: 16.0,
+            autofocus: None,
+            autofocus_speed: 0.2,
+        }
+    }
+}
+
+/// Mirrors `DepthOfField` in `dof.glsl`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct DofPushConstants {
+    coc_scale: f32,
+    focus_distance: f32,
+    near: f32,
+    far: f32,
+    max_coc: f32,
+    blade_count: u32,
+    blade_rotation: f32,
+    _padding: f32,
+    focus_point: [f32; 2],
+}
+
+/// Circle of confusion and bokeh gather over the HDR target, with an autofocus probe read back a frame late.
+#[derive(Clone, Debug, Default)]
+pub struct DepthOfField {
+    pub settings: DepthOfFieldSettings,
+    focus_buffer: AllocatedBuffer,
+    focus_pending: bool,
+    sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+    set: vk::DescriptorSet,
+    pipeline_layout: vk::PipelineLayout,
+    gather_pipeline: vk::Pipeline,
+    focus_pipeline: vk::Pipeline,
+}
+
+impl DepthOfField {
+    fn push_constants(&self, camera: &Camera, extent: vk::Extent3D) -> Option<DofPushConstants> {
+        let lens = camera.physical.filter(|_| self.settings.enabled)?;
+
+        // Thin lens: CoC = A f (D - S) / (D (S - f)), with A the aperture diameter, on the sensor in mm
+        let f = lens.focal_length;
+        let s = (lens.focus_distance * 1000.0).max(f * 1.001);
+        let coc_on_sensor = lens.aperture_diameter() * f / (s - f);
+        let pixels_per_mm = extent.height as f32 / lens.sensor_size[1];
+
+        Some(DofPushConstants {
+            coc_scale: coc_on_sensor * pixels_per_mm,
+            focus_distance: s / 1000.0,
+            near: camera.near,
+            far: camera.far,
+            max_coc: self.settings.max_coc.max(1.0),
+            blade_count: self.settings.blade_count,
+            blade_rotation: Rad::from(self.settings.blade_rotation).0,
+            _padding: 0.0,
+            focus_point: self.settings.autofocus.unwrap_or([0.5, 0.5]),
+        })
+    }
+
+    /// Blurs the HDR target through the scratch image, does nothing without a physical camera
+    pub unsafe fn cmd_render(&mut self, device: &Device, command_buffer: vk::CommandBuffer, camera: &Camera,
+        scratch: &AllocatedImage, hdr: &AllocatedImage)
+    {
+        let Some(push_constants) = self.push_constants(camera, hdr.extent) else {
+            return;
+        };
+
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 0, &[self.set], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, &push_constants);
+
+        if self.settings.autofocus.is_some() {
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.focus_pipeline);
+            device.cmd_dispatch(command_buffer, 1, 1, 1);
+
+            memory::cmd_buffer_barrier(device, command_buffer, self.focus_buffer.buffer,
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+                (vk::PipelineStageFlags::HOST, vk::AccessFlags::HOST_READ));
+            self.focus_pending = true;
+        }
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.gather_pipeline);
+        post::cmd_dispatch_pixels(device, command_buffer, hdr.extent_2d());
+        post::cmd_write_back(device, command_buffer, scratch, hdr);
+    }
+}
+
+pub unsafe fn create_depth_of_field(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let focus_buffer = AllocatedBuffer::allocate(
+        size_of::<f32>() as u64,
+        vk::BufferUsageFlags::STORAGE_BUFFER,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)?;
+
+    let dof = &mut data.depth_of_field;
+    dof.focus_buffer = focus_buffer;
+    dof.sampler = descriptor::create_sampler(device, vk::Filter::NEAREST, 0.0)?;
+    dof.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(2, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(3, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    dof.descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 2),
+        (vk::DescriptorType::STORAGE_IMAGE, 1),
+        (vk::DescriptorType::STORAGE_BUFFER, 1),
+    ], 1)?;
+    dof.set = descriptor::allocate_set(device, dof.descriptor_pool, dof.set_layout)?;
+    descriptor::write_buffer(device, dof.set, 3, vk::DescriptorType::STORAGE_BUFFER, dof.focus_buffer.buffer);
+
+    dof.pipeline_layout = descriptor::create_pipeline_layout(device, &[dof.set_layout],
+        size_of::<DofPushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+    let gather = include_bytes!(concat!(env!("OUT_DIR"), "/shader/dof_comp.spv"));
+    dof.gather_pipeline = shader::create_compute_pipeline(device, &gather[..], dof.pipeline_layout)?;
+    let focus = include_bytes!(concat!(env!("OUT_DIR"), "/shader/dof_focus_comp.spv"));
+    dof.focus_pipeline = shader::create_compute_pipeline(device, &focus[..], dof.pipeline_layout)?;
+    dof.settings = DepthOfFieldSettings::default();
+
+    create_depth_of_field_targets(device, data);
+    Ok(())
+}
+
+/// Points the descriptors at the current HDR, depth and scratch images
+pub unsafe fn create_depth_of_field_targets(device: &Device, data: &mut EngineData)
+{
+    let dof = &data.depth_of_field;
+    descriptor::write_image(device, dof.set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        data.hdr_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, dof.sampler);
+    descriptor::write_image(device, dof.set, 1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        data.depth_image.image_view, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL, dof.sampler);
+    descriptor::write_image(device, dof.set, 2, vk::DescriptorType::STORAGE_IMAGE,
+        data.post_image.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+}
+
+pub unsafe fn destroy_depth_of_field(device: &Device, data: &mut EngineData)
+{
+    let dof = &mut data.depth_of_field;
+    device.destroy_pipeline(dof.gather_pipeline, None);
+    device.destroy_pipeline(dof.focus_pipeline, None);
+    device.destroy_pipeline_layout(dof.pipeline_layout, None);
+    device.destroy_descriptor_pool(dof.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(dof.set_layout, None);
+    device.destroy_sampler(dof.sampler, None);
+    dof.focus_buffer.destroy(device);
+}
+
+/// Eases the camera's focus towards the distance the autofocus probe measured last frame
+pub unsafe fn update_depth_of_field(device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let dof = &mut data.depth_of_field;
+    if !dof.focus_pending {
+        return Ok(());
+    }
+    dof.focus_pending = false;
+
+    let measured = dof.focus_buffer.read::<f32>(device)?;
+    let speed = dof.settings.autofocus_speed.clamp(0.0, 1.0);
+    if let Some(lens) = data.camera.physical.as_mut() {
+        lens.focus_distance += (measured - lens.focus_distance) * speed;
+    }
+    Ok(())
+}
@@ -7,7 +7,10 @@
 
 use std::collections::HashSet;
 use std::ffi::CStr;
+use std::mem::size_of;
 use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+use cgmath::{vec3, EuclideanSpace, InnerSpace, SquareMatrix};
 use anyhow::{anyhow, Ok, Result};
 use log::*;
 use thiserror::Error;
@@ -21,16 +24,62 @@ use vulkanalia::vk::ExtDebugUtilsExtension;
 use vulkanalia::vk::KhrSurfaceExtension;
 use vulkanalia::vk::KhrSwapchainExtension;
 
-// TODO: Move to shader mod
-use vulkanalia::bytecode::Bytecode;
-
+use super::atmosphere::{self, create_atmosphere, destroy_atmosphere, AtmosphereSettings};
+use super::bloom::{create_bloom, create_bloom_targets, destroy_bloom, destroy_bloom_targets, BloomSettings};
+use super::camera::{Camera, Mat4, PhysicalCamera};
+use super::cluster::{create_clustered_lighting, create_clustered_lighting_targets, destroy_clustered_lighting,
+    destroy_clustered_lighting_targets, update_clustered_lighting, ClusteredLightingSettings};
+use super::deferred::{create_deferred, create_deferred_targets, destroy_deferred, destroy_deferred_targets,
+    GBUFFER_ATTACHMENTS};
+use super::dof::{create_depth_of_field, create_depth_of_field_targets, destroy_depth_of_field,
+    update_depth_of_field, DepthOfFieldSettings};
 use super::engine_data::EngineData;
+use super::exposure::{create_auto_exposure, create_auto_exposure_targets, destroy_auto_exposure,
+    update_auto_exposure, AutoExposureSettings};
+use super::fog::{create_fog, create_fog_targets, destroy_fog, destroy_fog_targets, FogSettings};
+use super::frame::{self, create_frame_resources, destroy_frame_resources, update_frame_uniforms};
+use super::gltf_loader;
+use super::grading::{self, create_color_grading, destroy_color_grading, update_color_grading, ColorGradingSettings};
+use super::ibl::{self, create_image_based_lighting, destroy_image_based_lighting};
+use super::image_io;
+use super::lens::{create_lens, create_lens_targets, destroy_lens, LensSettings, SensorConstants};
+use super::lut::CubeLut;
+use super::light::{self, upload_lights, Light};
+use super::material::{self, create_material_library, destroy_material_library};
+use super::memory::{self, AllocatedImage};
+use super::mesh::{Mesh, MeshPushConstants};
+use super::motion_blur::{create_motion_blur, create_motion_blur_targets, destroy_motion_blur,
+    destroy_motion_blur_targets, MotionBlurSettings};
+use super::point_shadow::{create_point_shadows, destroy_point_shadows, update_point_shadows};
+use super::post::{create_post_image, HDR_FORMAT, NORMAL_FORMAT, SCENE_COLOR_ATTACHMENTS, SPECULAR_FORMAT,
+    VELOCITY_FORMAT};
+use super::post_aa::{create_post_aa, create_post_aa_targets, destroy_post_aa, destroy_post_aa_targets,
+    PostAaSettings};
+use super::pathtrace::{self, create_path_tracer, create_path_tracer_targets, destroy_path_tracer,
+    destroy_path_tracer_targets, update_path_tracer};
+use super::scene::MeshInstance;
+use super::shader;
+use super::shadow::{create_shadow_maps, destroy_shadow_maps, update_shadow_maps, ShadowSettings};
+use super::skybox::{self, create_skybox, create_skybox_targets, destroy_skybox, destroy_skybox_targets,
+    SkyboxSettings};
+use super::spot_shadow::{create_spot_shadows, destroy_spot_shadows, update_spot_shadows};
+use super::ssao::{create_ambient_occlusion, create_ambient_occlusion_targets, destroy_ambient_occlusion,
+    destroy_ambient_occlusion_targets, SsaoSettings};
+use super::ssr::{create_screen_space_reflections, create_screen_space_reflections_targets,
+    destroy_screen_space_reflections, destroy_screen_space_reflections_targets, SsrSettings};
+use super::taa::{create_temporal_aa, create_temporal_aa_targets, destroy_temporal_aa, destroy_temporal_aa_targets,
+    update_temporal_aa, TemporalAaSettings};
+use super::tonemap::{create_tonemapper, create_tonemapper_targets, destroy_tonemapper, destroy_tonemapper_targets,
+    TonemapSettings};
 
 const MAX_FRAMES_IN_FLIGHT: usize = 2;
 const PORTABILITY_MACOS_VERSION: Version = Version::new(1, 3, 216);
 const VALIDATION_ENABLED: bool = cfg!(debug_assertions);
 const VALIDATION_LAYER: vk::ExtensionName = vk::ExtensionName::from_bytes(b"VK_LAYER_KHRONOS_validation");
 
+// Samples traced per submission when rendering headless references
+const REFERENCE_BATCH_SIZE: u32 = 16;
+
 // Arrays of extensions needed for features (like functioning)
 const REQUIRED_DEVICE_EXTENSIONS: &[vk::ExtensionName] = &[
     vk::KHR_SWAPCHAIN_EXTENSION.name,
@@ -40,13 +89,48 @@ const MESH_SHADER_EXTENSIONS: &[vk::ExtensionName] = &[
     vk::EXT_MESH_SHADER_EXTENSION.name,
 ];
 
-const RAY_TRACING_EXTENSIONS: &[vk::ExtensionName] = &[
-    vk::KHR_RAY_TRACING_PIPELINE_EXTENSION.name,
-    vk::KHR_RAY_TRACING_POSITION_FETCH_EXTENSION.name,
-    vk::KHR_RAY_TRACING_MAINTENANCE1_EXTENSION.name,
-    vk::KHR_ACCELERATION_STRUCTURE_EXTENSION.name,
-    vk::KHR_DEFERRED_HOST_OPERATIONS_EXTENSION.name,
-];
+/// How frames are produced.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RenderMode
+{
+    /// Rasterize the meshes
+    Raster,
+    /// Progressively path trace the scene
+    PathTraced,
+}
+
+/// How rasterised frames are lit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShadingPath
+{
+    /// Every mesh fragment loops over all the lights
+    Forward,
+    /// Forward shading where every fragment only loops over the lights culled into its cluster
+    Clustered,
+    /// Meshes fill a G-buffer and every pixel is lit once afterwards, which scales to many lights
+    Deferred,
+}
+
+/// Where in the frame `Engine::save_hdr_target` copies the HDR target.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HdrCapture
+{
+    /// Scene referred: the lit scene with its reflections and fog, before the anti-aliasing and the
+    ///  post effects, in cd/m² with the pre-exposure divided out like the path traced references
+    Scene,
+    /// Display referred: the HDR target as the tonemap pass reads it after every post effect, still
+    ///  exposed so that 1 saturates the sensor
+    Display,
+}
+
+/// A capture of the HDR target waiting for its frame to be drawn and then finish.
+#[derive(Clone, Debug)]
+struct PendingCapture {
+    path: PathBuf,
+    capture: HdrCapture,
+    /// The copy of the HDR target, once a frame has recorded it
+    image: Option<AllocatedImage>,
+}
 
 /// Our Vulkan app.
 #[derive(Clone, Debug)]
@@ -58,166 +142,142 @@ pub struct Engine
     device: Device,
     frame: usize,
     resized: bool,
-}
-
-// TODO: Move to shader mod
-unsafe fn create_shader_module(device: &Device, bytecode: &[u8],) -> Result<vk::ShaderModule> 
-{
-    let bytecode = Bytecode::new(bytecode).unwrap();
-    let info = vk::ShaderModuleCreateInfo::builder()
-        .code_size(bytecode.code_size())
-        .code(bytecode.code());
-
-    Ok(device.create_shader_module(&info, None)?)
+    render_mode: RenderMode,
+    shading_path: ShadingPath,
+    capture: Option<PendingCapture>,
 }
 
 // TODO: Move to shader mod
 unsafe fn create_pipeline(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()> 
 {
-    // Shaders
-    let vert = include_bytes!("shader/vert.spv");
-    let frag = include_bytes!("shader/frag.spv");
-
-    let vert_shader_module = create_shader_module(device, &vert[..])?;
-    let frag_shader_module = create_shader_module(device, &frag[..])?;
-
-    // Shader stages
-    let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
-        .stage(vk::ShaderStageFlags::VERTEX)
-        .module(vert_shader_module)
-        .name(b"main\0");
-
-    let frag_stage = vk::PipelineShaderStageCreateInfo::builder()
-        .stage(vk::ShaderStageFlags::FRAGMENT)
-        .module(frag_shader_module)
-        .name(b"main\0");
-
-    // Input Assembly State
-    let binding_descriptions = &[data.meshes[0].binding_description()];
-    let attribute_descriptions = data.meshes[0].attribute_descriptions();
-    let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
-        .vertex_binding_descriptions(binding_descriptions)
-        .vertex_attribute_descriptions(&attribute_descriptions);
-
-    let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
-        .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
-        .primitive_restart_enable(false);
-
-    // Viewports and scissors
-    let viewport = vk::Viewport::builder()
-        .x(0.0)
-        .y(0.0)
-        .width(data.swapchain_extent.width as f32)
-        .height(data.swapchain_extent.height as f32)
-        .min_depth(0.0)
-        .max_depth(1.0);
-
-    let scissor = vk::Rect2D::builder()
-        .offset(vk::Offset2D { x: 0, y: 0 })
-        .extent(data.swapchain_extent);
-
-    let viewports = &[viewport];
-    let scissors = &[scissor];
-    let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
-        .viewports(viewports)
-        .scissors(scissors);
-
-    // Rasterization state
-    let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
-       .depth_clamp_enable(false)
-       .rasterizer_discard_enable(false)
-       .polygon_mode(vk::PolygonMode::FILL)
-       .line_width(1.0)
-       .cull_mode(vk::CullModeFlags::BACK)
-       .front_face(vk::FrontFace::CLOCKWISE)
-       .depth_bias_enable(false);
-
-    // Multisampling state
-    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
-       .sample_shading_enable(false)
-       .rasterization_samples(vk::SampleCountFlags::_1);
-
-    // Color Blend State
-    let attachment = vk::PipelineColorBlendAttachmentState::builder()
-       .color_write_mask(vk::ColorComponentFlags::all())
-       .blend_enable(false);
-
-    let attachments = &[attachment];
-    let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
-        .logic_op_enable(false)
-        .logic_op(vk::LogicOp::COPY)
-        .attachments(attachments)
-        .blend_constants([0.0, 0.0, 0.0, 0.0]);
-    
-    // Dynamic States
-    let dynamic_states = &[
-        vk::DynamicState::VIEWPORT,
-        vk::DynamicState::LINE_WIDTH,
-    ];
-    let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
-        .dynamic_states(dynamic_states);
-
     // Layout
-    let layout_info = vk::PipelineLayoutCreateInfo::builder();
+    let push_constant_range = vk::PushConstantRange::builder()
+        .stage_flags(vk::ShaderStageFlags::VERTEX)
+        .offset(0)
+        .size(size_of::<MeshPushConstants>() as u32);
+
+    let set_layouts = &[data.frame.set_layout, data.materials.set_layout];
+    let push_constant_ranges = &[push_constant_range];
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(set_layouts)
+        .push_constant_ranges(push_constant_ranges);
     data.pipeline_layout = device.create_pipeline_layout(&layout_info, None)?;
-    
-    // Creation
-    let stages = &[vert_stage, frag_stage];
-    let info = vk::GraphicsPipelineCreateInfo::builder()
-        .stages(stages)
-        .vertex_input_state(&vertex_input_state)
-        .input_assembly_state(&input_assembly_state)
-        .viewport_state(&viewport_state)
-        .rasterization_state(&rasterization_state)
-        .multisample_state(&multisample_state)
-        .color_blend_state(&color_blend_state)
-        .layout(data.pipeline_layout)
-        .render_pass(data.render_pass)
-        .subpass(0);
-
-    data.pipeline = device
-        .create_graphics_pipelines(vk::PipelineCache::null(), &[info], None)?
-        .0[0];
 
-    // Cleanup
-    device.destroy_shader_module(vert_shader_module, None);
-    device.destroy_shader_module(frag_shader_module, None);
+    // Writes the HDR target and the velocity buffer
+    let frag = include_bytes!(concat!(env!("OUT_DIR"), "/shader/shader_frag.spv"));
+    (data.pipeline, data.double_sided_pipeline) = shader::create_mesh_pipelines(device, &frag[..],
+        data.pipeline_layout, data.render_pass, 0, SCENE_COLOR_ATTACHMENTS, data.swapchain_extent, false)?;
+    (data.blended_pipeline, data.double_sided_blended_pipeline) = shader::create_mesh_pipelines(device, &frag[..],
+        data.pipeline_layout, data.render_pass, 0, SCENE_COLOR_ATTACHMENTS, data.swapchain_extent, true)?;
 
     Ok(())
 }
 
 unsafe fn create_render_pass(instance: &Instance, device: &Device, data: &mut EngineData, ) -> Result<()> 
 {
+    data.depth_format = get_depth_format(instance, data)?;
+
+    // The scene is drawn into the HDR target, post effects and the tonemap pass sample it afterwards
     let color_attachment = vk::AttachmentDescription::builder()
-        .format(data.swapchain_format)
+        .format(HDR_FORMAT)
+        .samples(vk::SampleCountFlags::_1)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+
+    // Screen space motion of every pixel, for motion blur. Fullscreen draws leave it cleared to no motion.
+    let velocity_attachment = vk::AttachmentDescription::builder()
+        .format(VELOCITY_FORMAT)
+        .samples(vk::SampleCountFlags::_1)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+    
+    // Normals and specular reflectance, which the screen space reflections read. Cleared to no reflection.
+    let reflection_attachment = |format: vk::Format| vk::AttachmentDescription::builder()
+        .format(format)
+        .samples(vk::SampleCountFlags::_1)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+        .build();
+    let normal_attachment = reflection_attachment(NORMAL_FORMAT);
+    let specular_attachment = reflection_attachment(SPECULAR_FORMAT);
+
+    let depth_stencil_attachment = vk::AttachmentDescription::builder()
+        .format(data.depth_format)
         .samples(vk::SampleCountFlags::_1)
         .load_op(vk::AttachmentLoadOp::CLEAR)
         .store_op(vk::AttachmentStoreOp::STORE)
         .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
         .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
         .initial_layout(vk::ImageLayout::UNDEFINED)
-        .final_layout(vk::ImageLayout::PRESENT_SRC_KHR);
+        .final_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
     
     let color_attachment_ref = vk::AttachmentReference::builder()
         .attachment(0)
         .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
+    let velocity_attachment_ref = vk::AttachmentReference::builder()
+        .attachment(1)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
+    let depth_stencil_attachment_ref = vk::AttachmentReference::builder()
+        .attachment(2)
+        .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+    let normal_attachment_ref = vk::AttachmentReference::builder()
+        .attachment(3)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
+    let specular_attachment_ref = vk::AttachmentReference::builder()
+        .attachment(4)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
     
-    let color_attachments = &[color_attachment_ref];
+    let color_attachments = &[color_attachment_ref, velocity_attachment_ref, normal_attachment_ref,
+        specular_attachment_ref];
     let subpass = vk::SubpassDescription::builder()
         .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-        .color_attachments(color_attachments);
+        .color_attachments(color_attachments)
+        .depth_stencil_attachment(&depth_stencil_attachment_ref);
         
+    // The previous frame's tonemap pass may still be reading the HDR target
     let dependency = vk::SubpassDependency::builder()
         .src_subpass(vk::SUBPASS_EXTERNAL)
         .dst_subpass(0)
-        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+            | vk::PipelineStageFlags::FRAGMENT_SHADER)
         .src_access_mask(vk::AccessFlags::empty())
-        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
-
-    let attachments = &[color_attachment];
+        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE);
+
+    let exit_dependency = vk::SubpassDependency::builder()
+        .src_subpass(0)
+        .dst_subpass(vk::SUBPASS_EXTERNAL)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+        .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER
+            | vk::PipelineStageFlags::COMPUTE_SHADER)
+        .dst_access_mask(vk::AccessFlags::SHADER_READ);
+
+    let attachments = &[color_attachment.build(), velocity_attachment.build(), depth_stencil_attachment.build(),
+        normal_attachment, specular_attachment];
     let subpasses = &[subpass];
-    let dependencies = &[dependency];
+    let dependencies = &[dependency, exit_dependency];
     let info = vk::RenderPassCreateInfo::builder()
         .attachments(attachments)
         .subpasses(subpasses)
@@ -231,32 +291,95 @@ impl Engine
 {
     /// Creates our Vulkan app.
     pub unsafe fn create(window: &Window) -> Result<Self> 
+    {
+        let mut engine = Self::create_device(Some(window))?;
+        create_swapchain(window, &engine.instance, &engine.device, &mut engine.data)?;
+        create_swapchain_image_views(&engine.device, &mut engine.data)?;
+        engine.create_renderers()?;
+        Ok(engine)
+    }
+
+    /// Creates our Vulkan app without a window, surface or swapchain. Frames are drawn into an
+    ///  offscreen image of the given size instead, which is all `render_reference` needs; `render`
+    ///  can't be used.
+    pub unsafe fn create_headless(width: u32, height: u32) -> Result<Self> 
+    {
+        let mut engine = Self::create_device(None)?;
+        let extent = vk::Extent2D { width, height };
+        create_output_image(extent, &engine.instance, &engine.device, &mut engine.data)?;
+        engine.create_renderers()?;
+        Ok(engine)
+    }
+
+    /// The instance, the device and everything that doesn't depend on the output's size. Only a
+    ///  window gets a surface to present to.
+    unsafe fn create_device(window: Option<&Window>) -> Result<Self> 
     {
         let loader = LibloadingLoader::new(LIBRARY)?;
         let entry = Entry::new(loader).map_err(|b| anyhow!("{}", b))?;
         let mut data = EngineData::default();
         let instance = create_instance(window, &entry, &mut data)?;
-        data.surface = vk_window::create_surface(&instance, &window, &window)?;
+        if let Some(window) = window {
+            data.surface = vk_window::create_surface(&instance, &window, &window)?;
+        }
         pick_physical_device(&instance, &mut data)?;
         let device = create_logical_device(&entry, &instance, &mut data)?;
         let frame = 0;
         let resized = false;
+        let render_mode = RenderMode::Raster;
+        let shading_path = ShadingPath::Forward;
 
         // Create the test mesh
         let mesh = super::mesh::create_test_mesh(&instance, &device, &data)?;
         data.meshes.push(mesh);
-        
-        create_swapchain(window, &instance, &device, &mut data)?;
-        create_swapchain_image_views(&device, &mut data)?;
-        create_render_pass(&instance, &device, &mut data)?;
-        create_pipeline(&instance, &device, &mut data)?;
-        create_framebuffers(&device, &mut data)?;
+        data.instances.push(MeshInstance { mesh: 0, material: None, transform: Mat4::identity() });
+        data.lights.push(Light::directional(vec3(-0.3, -1.0, -0.5), vec3(1.0, 0.98, 0.95), 100_000.0));
+        data.scene_version += 1;
+
+        // Texture uploads need the command pool before anything else
         create_command_pool(&instance, &device, &mut data)?;
-        create_command_buffers(&device, &mut data)?;
-        create_sync_objects(&device, &mut data)?;
-        
-        Ok(Self { entry, instance, data, device, frame, resized})
-    }    
+        create_frame_resources(&instance, &device, &mut data)?;
+        create_material_library(&instance, &device, &mut data)?;
+        create_shadow_maps(&instance, &device, &mut data)?;
+        create_point_shadows(&instance, &device, &mut data)?;
+        create_spot_shadows(&instance, &device, &mut data)?;
+
+        Ok(Self { entry, instance, data, device, frame, resized, render_mode, shading_path, capture: None })
+    }
+
+    /// The targets and renderers drawing at the output's size, once the swapchain or the offscreen
+    ///  output exists
+    unsafe fn create_renderers(&mut self) -> Result<()> 
+    {
+        let (instance, device, data) = (&self.instance, &self.device, &mut self.data);
+        create_render_pass(instance, device, data)?;
+        create_pipeline(instance, device, data)?;
+        create_depth_objects(instance, device, data)?;
+        create_hdr_objects(instance, device, data)?;
+        create_post_image(instance, device, data)?;
+        create_framebuffer(device, data)?;
+        create_deferred(instance, device, data)?;
+        create_clustered_lighting(instance, device, data)?;
+        create_ambient_occlusion(instance, device, data)?;
+        create_screen_space_reflections(instance, device, data)?;
+        create_fog(instance, device, data)?;
+        create_skybox(instance, device, data)?;
+        create_atmosphere(instance, device, data)?;
+        create_image_based_lighting(instance, device, data)?;
+        create_color_grading(instance, device, data)?;
+        create_tonemapper(device, data)?;
+        create_temporal_aa(instance, device, data)?;
+        create_post_aa(instance, device, data)?;
+        create_depth_of_field(instance, device, data)?;
+        create_motion_blur(instance, device, data)?;
+        create_auto_exposure(instance, device, data)?;
+        create_bloom(instance, device, data)?;
+        create_lens(device, data)?;
+        create_command_buffers(device, data)?;
+        create_sync_objects(device, data)?;
+        create_path_tracer(instance, device, data)?;
+        Ok(())
+    }
 
     /// Renders a frame for our Vulkan app.
     pub unsafe fn render(&mut self, window: &Window) -> Result<()> 
@@ -291,6 +414,8 @@ impl Engine
                 u64::MAX,
             )?;
         }
+
+        self.update_command_buffer(image_index)?;
         
         // Set up the submission
         let wait_semaphores = &[self.data.image_available_semaphores[self.frame]];
@@ -332,6 +457,15 @@ impl Engine
         // Wait for the presentation to finish
         self.device.queue_wait_idle(self.data.present_queue)?;
 
+        // A failed capture shouldn't take the frame down with it
+        if self.capture.as_ref().is_some_and(|c| c.image.is_some()) {
+            let capture = self.capture.take().unwrap();
+            if let Err(e) = self.write_capture(&capture) {
+                error!("Failed to save the HDR target to `{}`: {}", capture.path.display(), e);
+            }
+            capture.image.unwrap().destroy(&self.device);
+        }
+
         // Increase the current frame index
         self.frame = (self.frame + 1) % MAX_FRAMES_IN_FLIGHT;
         Ok(())
@@ -340,6 +474,9 @@ impl Engine
     /// Destroys our Vulkan app.
     pub unsafe fn destroy(&mut self) 
     {
+        if let Some(mut image) = self.capture.take().and_then(|c| c.image) {
+            image.destroy(&self.device);
+        }
         self.data.meshes
             .iter_mut()
             .for_each(|m| {
@@ -348,6 +485,29 @@ impl Engine
             });
         
         self.destroy_swapchain();
+        destroy_path_tracer(&self.device, &mut self.data);
+        destroy_deferred(&self.device, &mut self.data);
+        destroy_clustered_lighting(&self.device, &mut self.data);
+        destroy_ambient_occlusion(&self.device, &mut self.data);
+        destroy_screen_space_reflections(&self.device, &mut self.data);
+        destroy_fog(&self.device, &mut self.data);
+        destroy_atmosphere(&self.device, &mut self.data);
+        destroy_skybox(&self.device, &mut self.data);
+        destroy_image_based_lighting(&self.device, &mut self.data);
+        destroy_tonemapper(&self.device, &mut self.data);
+        destroy_temporal_aa(&self.device, &mut self.data);
+        destroy_post_aa(&self.device, &mut self.data);
+        destroy_color_grading(&self.device, &mut self.data);
+        destroy_depth_of_field(&self.device, &mut self.data);
+        destroy_motion_blur(&self.device, &mut self.data);
+        destroy_auto_exposure(&self.device, &mut self.data);
+        destroy_bloom(&self.device, &mut self.data);
+        destroy_lens(&self.device, &mut self.data);
+        destroy_material_library(&self.device, &mut self.data);
+        destroy_shadow_maps(&self.device, &mut self.data);
+        destroy_point_shadows(&self.device, &mut self.data);
+        destroy_spot_shadows(&self.device, &mut self.data);
+        destroy_frame_resources(&self.device, &mut self.data);
         
         // Destroy the sync objects
         self.data.in_flight_fences
@@ -362,7 +522,9 @@ impl Engine
 
         self.device.destroy_command_pool(self.data.command_pool, None);
         self.device.destroy_device(None);
-        self.instance.destroy_surface_khr(self.data.surface, None);
+        if !self.data.surface.is_null() {
+            self.instance.destroy_surface_khr(self.data.surface, None);
+        }
         if VALIDATION_ENABLED {
             self.instance.destroy_debug_utils_messenger_ext(self.data.messenger, None);
         }
@@ -376,8 +538,26 @@ impl Engine
         create_swapchain_image_views(&self.device, &mut self.data)?;
         create_render_pass(&self.instance, &self.device, &mut self.data)?;
         create_pipeline(&self.instance, &self.device, &mut self.data)?;
-        create_framebuffers(&self.device, &mut self.data)?;
+        create_depth_objects(&self.instance, &self.device, &mut self.data)?;
+        create_hdr_objects(&self.instance, &self.device, &mut self.data)?;
+        create_post_image(&self.instance, &self.device, &mut self.data)?;
+        create_framebuffer(&self.device, &mut self.data)?;
+        create_deferred_targets(&self.instance, &self.device, &mut self.data)?;
+        create_clustered_lighting_targets(&self.device, &mut self.data)?;
+        create_ambient_occlusion_targets(&self.instance, &self.device, &mut self.data)?;
+        create_screen_space_reflections_targets(&self.instance, &self.device, &mut self.data)?;
+        create_fog_targets(&self.instance, &self.device, &mut self.data)?;
+        create_skybox_targets(&self.device, &mut self.data)?;
+        create_tonemapper_targets(&self.device, &mut self.data)?;
+        create_temporal_aa_targets(&self.instance, &self.device, &mut self.data)?;
+        create_post_aa_targets(&self.instance, &self.device, &mut self.data)?;
+        create_depth_of_field_targets(&self.device, &mut self.data);
+        create_motion_blur_targets(&self.instance, &self.device, &mut self.data)?;
+        create_auto_exposure_targets(&self.device, &mut self.data);
+        create_bloom_targets(&self.instance, &self.device, &mut self.data)?;
+        create_lens_targets(&self.device, &mut self.data);
         create_command_buffers(&self.device, &mut self.data)?;
+        create_path_tracer_targets(&self.instance, &self.device, &mut self.data)?;
         self.data
             .images_in_flight
             .resize(self.data.swapchain_images.len(), vk::Fence::null());
@@ -385,27 +565,700 @@ impl Engine
     }
 
     unsafe fn destroy_swapchain(&mut self) {
-        self.data.framebuffers
-            .iter()
-            .for_each(|f| self.device.destroy_framebuffer(*f, None));
+        destroy_path_tracer_targets(&self.device, &mut self.data);
+        destroy_tonemapper_targets(&self.device, &mut self.data);
+        destroy_temporal_aa_targets(&self.device, &mut self.data);
+        destroy_post_aa_targets(&self.device, &mut self.data);
+        destroy_bloom_targets(&self.device, &mut self.data);
+        destroy_motion_blur_targets(&self.device, &mut self.data);
+        destroy_deferred_targets(&self.device, &mut self.data);
+        destroy_clustered_lighting_targets(&self.device, &mut self.data);
+        destroy_ambient_occlusion_targets(&self.device, &mut self.data);
+        destroy_screen_space_reflections_targets(&self.device, &mut self.data);
+        destroy_fog_targets(&self.device, &mut self.data);
+        destroy_skybox_targets(&self.device, &mut self.data);
+        self.data.depth_image.destroy(&self.device);
+        self.data.hdr_image.destroy(&self.device);
+        self.data.velocity_image.destroy(&self.device);
+        self.data.normal_image.destroy(&self.device);
+        self.data.specular_image.destroy(&self.device);
+        self.data.post_image.destroy(&self.device);
+        self.device.destroy_framebuffer(self.data.framebuffer, None);
         self.device.free_command_buffers(self.data.command_pool, &self.data.command_buffers);
         self.device.destroy_pipeline(self.data.pipeline, None);
+        self.device.destroy_pipeline(self.data.double_sided_pipeline, None);
+        self.device.destroy_pipeline(self.data.blended_pipeline, None);
+        self.device.destroy_pipeline(self.data.double_sided_blended_pipeline, None);
         self.device.destroy_pipeline_layout(self.data.pipeline_layout, None);
         self.device.destroy_render_pass(self.data.render_pass, None);
-        self.data.swapchain_image_views
-            .iter()
-            .for_each(|v| self.device.destroy_image_view(*v, None));
-        self.device.destroy_swapchain_khr(self.data.swapchain, None);
+        if self.data.is_headless() {
+            self.data.output_image.destroy(&self.device);
+        } else {
+            self.data.swapchain_image_views
+                .iter()
+                .for_each(|v| self.device.destroy_image_view(*v, None));
+            self.device.destroy_swapchain_khr(self.data.swapchain, None);
+        }
     }
 
     pub fn resize(&mut self) {
         self.resized = true;
     }
+
+    /// Records the commands for the frame drawn into the given swapchain image
+    unsafe fn update_command_buffer(&mut self, image_index: usize) -> Result<()> 
+    {
+        let command_buffer = self.data.command_buffers[image_index];
+        self.device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())?;
+
+        let info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        self.device.begin_command_buffer(command_buffer, &info)?;
+        update_auto_exposure(&self.device, &mut self.data)?;
+        update_clustered_lighting(&self.device, &mut self.data)?;
+        update_point_shadows(&self.device, &mut self.data)?;
+        update_spot_shadows(&mut self.data);
+        upload_lights(&self.instance, &self.device, &mut self.data)?;
+        update_temporal_aa(&mut self.data);
+        update_frame_uniforms(&self.device, &mut self.data)?;
+        update_shadow_maps(&self.device, &mut self.data)?;
+        update_depth_of_field(&self.device, &mut self.data)?;
+        update_color_grading(&self.device, &self.data)?;
+
+        // Shadow maps are their own render passes and have to be drawn before the scene samples them, and the
+        //  clusters' light lists and the ambient occlusion are computed outside the render pass too
+        if self.render_mode == RenderMode::Raster {
+            let (meshes, instances, materials) = (&self.data.meshes, &self.data.instances, &self.data.materials);
+            self.data.shadows.cmd_render(&self.device, command_buffer, meshes, instances, materials);
+            self.data.point_shadows.cmd_render(&self.device, command_buffer, meshes, instances, materials);
+            self.data.spot_shadows.cmd_render(&self.device, command_buffer, meshes, instances, materials);
+            if self.shading_path == ShadingPath::Clustered {
+                self.data.clusters.cmd_cull(&self.device, command_buffer, self.data.frame.set);
+            }
+            self.data.ambient_occlusion.cmd_render(&self.device, command_buffer, self.data.frame.set,
+                &self.data.meshes, &self.data.instances);
+        }
+
+        // Trace before the render pass, compute can't be dispatched inside one
+        if self.render_mode == RenderMode::PathTraced {
+            update_path_tracer(&self.instance, &self.device, &mut self.data)?;
+            if !self.data.path_tracer.is_converged() {
+                self.data.path_tracer.cmd_trace(&self.device, command_buffer, self.data.frame.set);
+            }
+        }
+
+        // Start the render pass
+        let render_area = vk::Rect2D::builder()
+            .offset(vk::Offset2D::default())
+            .extent(self.data.swapchain_extent);
+    
+        let color_clear_value = vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: [0.0, 0.0, 0.0, 1.0],
+            },
+        };
+
+        let depth_clear_value = vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 },
+        };
+    
+        let velocity_clear_value = vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: [0.0, 0.0, 0.0, 0.0],
+            },
+        };
+
+        // The deferred pass shares the forward pass' attachments and clears its G-buffer after them
+        let deferred = self.render_mode == RenderMode::Raster && self.shading_path == ShadingPath::Deferred;
+        let mut clear_values = vec![color_clear_value, velocity_clear_value, depth_clear_value, velocity_clear_value,
+            velocity_clear_value];
+        let (render_pass, framebuffer, pipelines, blended_pipelines) = if deferred {
+            clear_values.extend([velocity_clear_value; GBUFFER_ATTACHMENTS]);
+            let d = &self.data.deferred;
+            (d.render_pass, d.framebuffer, (d.pipeline, d.double_sided_pipeline),
+                (d.blended_pipeline, d.double_sided_blended_pipeline))
+        } else if self.shading_path == ShadingPath::Clustered {
+            let c = &self.data.clusters;
+            (self.data.render_pass, self.data.framebuffer, (c.pipeline, c.double_sided_pipeline),
+                (c.blended_pipeline, c.double_sided_blended_pipeline))
+        } else {
+            let d = &self.data;
+            (d.render_pass, d.framebuffer, (d.pipeline, d.double_sided_pipeline),
+                (d.blended_pipeline, d.double_sided_blended_pipeline))
+        };
+
+        let info = vk::RenderPassBeginInfo::builder()
+            .render_pass(render_pass)
+            .framebuffer(framebuffer)
+            .render_area(render_area)
+            .clear_values(&clear_values);
+
+        self.device.cmd_begin_render_pass(command_buffer, &info, vk::SubpassContents::INLINE);
+        match self.render_mode {
+            RenderMode::Raster => {
+                let viewport = vk::Viewport::builder()
+                    .width(self.data.swapchain_extent.width as f32)
+                    .height(self.data.swapchain_extent.height as f32)
+                    .max_depth(1.0);
+
+                self.device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+                self.device.cmd_set_line_width(command_buffer, 1.0);
+                let opaque = (0..self.data.instances.len())
+                    .filter(|i| !self.data.materials.get(self.data.instances[*i].material).blended())
+                    .collect::<Vec<_>>();
+                self.cmd_draw_instances(command_buffer, pipelines, &opaque);
+
+                // The sky goes behind the meshes, in the G-buffer when deferred
+                self.data.skybox.cmd_draw(&self.device, command_buffer, self.data.frame.set,
+                    self.data.swapchain_extent, deferred);
+                if deferred {
+                    self.data.deferred.cmd_light(&self.device, command_buffer, self.data.frame.set,
+                        self.data.swapchain_extent);
+                }
+
+                // Blended surfaces go over the lit scene and the sky, farthest first so each one
+                //  blends over those behind it
+                let camera_position = self.data.camera.position.to_vec();
+                let mut blended = (0..self.data.instances.len())
+                    .filter(|i| self.data.materials.get(self.data.instances[*i].material).blended())
+                    .map(|i| (i, (self.data.instances[i].transform.w.truncate() - camera_position).magnitude2()))
+                    .collect::<Vec<_>>();
+                blended.sort_by(|a, b| b.1.total_cmp(&a.1));
+                let blended = blended.into_iter().map(|(i, _)| i).collect::<Vec<_>>();
+                self.cmd_draw_instances(command_buffer, blended_pipelines, &blended);
+                self.data.previous_transforms = self.data.instances.iter().map(|i| i.transform).collect();
+            },
+            RenderMode::PathTraced => {
+                self.data.path_tracer.cmd_display(&self.device, command_buffer, self.data.frame.set,
+                    self.data.swapchain_extent);
+            },
+        }
+        self.device.cmd_end_render_pass(command_buffer);
+
+        // Post effects run on the HDR target in place, after adding the reflections and the fog,
+        //  resolving the anti-aliasing and metering the unprocessed scene
+        if self.render_mode == RenderMode::Raster {
+            self.data.reflections.cmd_render(&self.device, command_buffer, &self.data.camera, self.data.frame.set,
+                &self.data.post_image, &self.data.hdr_image);
+            self.data.fog.cmd_render(&self.device, command_buffer, &self.data.camera, self.data.frame.set,
+                &self.data.post_image, &self.data.hdr_image);
+        }
+        self.cmd_capture(command_buffer, HdrCapture::Scene)?;
+        if self.render_mode == RenderMode::Raster {
+            self.data.temporal_aa.cmd_render(&self.device, command_buffer, &self.data.camera,
+                &self.data.post_image, &self.data.hdr_image);
+            self.data.post_aa.cmd_render(&self.device, command_buffer, &self.data.post_image, &self.data.hdr_image);
+            let ev100 = frame::scene_ev100(&self.data);
+            self.data.exposure.cmd_meter(&self.device, command_buffer, &self.data.hdr_image, ev100);
+            self.data.depth_of_field.cmd_render(&self.device, command_buffer, &self.data.camera,
+                &self.data.post_image, &self.data.hdr_image);
+            self.data.motion_blur.cmd_render(&self.device, command_buffer, &self.data.camera,
+                &self.data.post_image, &self.data.hdr_image);
+            self.data.bloom.cmd_render(&self.device, command_buffer, &self.data.post_image, &self.data.hdr_image);
+            self.data.lens.cmd_render(&self.device, command_buffer, &self.data.camera,
+                &self.data.post_image, &self.data.hdr_image);
+            self.data.ambient_occlusion.cmd_debug(&self.device, command_buffer, self.data.frame.set,
+                &self.data.post_image, &self.data.hdr_image);
+        }
+
+        self.cmd_capture(command_buffer, HdrCapture::Display)?;
+
+        // Map the HDR target into the swapchain image, the path traced reference skips the lens altogether
+        let sensor = match self.render_mode {
+            RenderMode::Raster => self.data.lens.sensor(&self.data.camera, self.data.swapchain_extent),
+            RenderMode::PathTraced => SensorConstants::default(),
+        };
+        self.data.tonemapper.cmd_draw(&self.device, command_buffer, image_index, self.data.swapchain_extent,
+            self.data.grading.set, sensor);
+
+        self.device.end_command_buffer(command_buffer)?;
+        Ok(())
+    }
+
+    /// Draws the instances in the given order with the first of `pipelines`, or the second for
+    ///  double sided materials
+    unsafe fn cmd_draw_instances(&self, command_buffer: vk::CommandBuffer, pipelines: (vk::Pipeline, vk::Pipeline),
+        instances: &[usize])
+    {
+        let layout = self.data.pipeline_layout;
+        let mut bound_pipeline = vk::Pipeline::null();
+        for &i in instances {
+            let instance = &self.data.instances[i];
+            let material = self.data.materials.get(instance.material);
+            let material_pipeline = if material.double_sided() { pipelines.1 } else { pipelines.0 };
+            if material_pipeline != bound_pipeline {
+                self.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, material_pipeline);
+                if bound_pipeline.is_null() {
+                    self.device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout,
+                        0, &[self.data.frame.set], &[]);
+                }
+                bound_pipeline = material_pipeline;
+            }
+            self.device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout,
+                1, &[material.set], &[]);
+
+            let previous_model = self.data.previous_transforms.get(i).copied().unwrap_or(instance.transform);
+            let push_constants = MeshPushConstants { model: instance.transform, previous_model };
+            shader::cmd_push_constants(&self.device, command_buffer, layout, vk::ShaderStageFlags::VERTEX,
+                &push_constants);
+            self.data.meshes[instance.mesh].cmd_draw(&self.device, command_buffer);
+        }
+    }
+
+    /// Copies the HDR target for a pending capture taken at `capture`, leaving the copy ready to be read back
+    unsafe fn cmd_capture(&mut self, command_buffer: vk::CommandBuffer, capture: HdrCapture) -> Result<()>
+    {
+        let Some(pending) = self.capture.as_mut().filter(|c| c.capture == capture && c.image.is_none()) else {
+            return Ok(());
+        };
+        let hdr = &self.data.hdr_image;
+        let image = AllocatedImage::create(hdr.extent_2d(), HDR_FORMAT,
+            vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::SAMPLED,
+            vk::ImageAspectFlags::COLOR, &self.instance, &self.device, &self.data)?;
+
+        let range = memory::color_subresource_range();
+        memory::cmd_image_barrier(&self.device, command_buffer, hdr.image, range,
+            (vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+            (vk::PipelineStageFlags::ALL_COMMANDS, vk::AccessFlags::MEMORY_WRITE),
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ));
+        memory::cmd_image_barrier(&self.device, command_buffer, image.image, range,
+            (vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+            (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE));
+
+        let subresource = vk::ImageSubresourceLayers::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(0)
+            .base_array_layer(0)
+            .layer_count(1);
+
+        let region = vk::ImageCopy::builder()
+            .src_subresource(subresource)
+            .dst_subresource(subresource)
+            .extent(hdr.extent);
+
+        self.device.cmd_copy_image(command_buffer,
+            hdr.image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            image.image, vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[region]);
+
+        memory::cmd_image_barrier(&self.device, command_buffer, hdr.image, range,
+            (vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::empty()),
+            (vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ));
+        memory::cmd_image_barrier(&self.device, command_buffer, image.image, range,
+            (vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ));
+
+        pending.image = Some(image);
+        Ok(())
+    }
+
+    pub fn render_mode(&self) -> RenderMode {
+        self.render_mode
+    }
+
+    pub fn shading_path(&self) -> ShadingPath {
+        self.shading_path
+    }
+
+    /// Switches between forward, clustered and deferred shading of the rasterised scene, all are always ready
+    pub fn set_shading_path(&mut self, shading_path: ShadingPath) {
+        if shading_path != self.shading_path {
+            info!("Switching to {:?} shading.", shading_path);
+            self.shading_path = shading_path;
+        }
+    }
+
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        if render_mode != self.render_mode {
+            info!("Switching to {:?} rendering.", render_mode);
+            self.render_mode = render_mode;
+            self.data.path_tracer.reset();
+            self.data.temporal_aa.reset();
+            self.data.fog.reset();
+        }
+    }
+
+    /// Replaces the current scene with a .gltf or .glb file, taking over its first camera and
+    ///  its lights if it has any
+    pub unsafe fn load_gltf(&mut self, path: &Path) -> Result<()> 
+    {
+        let (scene, geometry) = gltf_loader::import(path)?;
+
+        // Swap the geometry once the GPU is done with the old one
+        self.device.device_wait_idle()?;
+        self.data.meshes
+            .iter_mut()
+            .for_each(|m| m.destroy(&self.device));
+        self.data.meshes = geometry
+            .into_iter()
+            .map(|(verts, inds)| Mesh::from_vectors(verts, inds, &self.instance, &self.device, &self.data))
+            .collect::<Result<Vec<_>>>()?;
+
+        self.data.instances = scene.instances();
+        self.data.previous_transforms.clear();
+        if let Some(mut camera) = scene.first_camera() {
+            // Keep the exposure settings, zooming the lens to the imported field of view
+            camera.physical = self.data.camera.physical.map(|mut p| {
+                p.set_fov_y(camera.fov_y);
+                p
+            });
+            self.data.camera = camera;
+            self.data.frame.previous_view_projection = None;
+            self.data.temporal_aa.reset();
+            self.data.fog.reset();
+        }
+        let lights = light::scene_lights(&scene);
+        if !lights.is_empty() {
+            self.data.lights = lights;
+        }
+        self.data.scene = scene;
+        self.data.scene_version += 1;
+        material::upload_materials(&self.instance, &self.device, &mut self.data)?;
+        Ok(())
+    }
+
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        &mut self.data.camera
+    }
+
+    /// Moves an instance of the scene, it is motion blurred from where it was drawn last frame
+    pub fn set_instance_transform(&mut self, index: usize, transform: Mat4) {
+        if let Some(instance) = self.data.instances.get_mut(index) {
+            instance.transform = transform;
+            self.data.scene_version += 1;
+        }
+    }
+
+    /// Gives the camera a lens and sensor, driving its field of view and exposure
+    pub fn set_physical_camera(&mut self, physical: Option<PhysicalCamera>) {
+        self.data.camera.physical = physical;
+    }
+
+    /// The exposure value the scene is currently rendered at
+    pub fn ev100(&self) -> f32 {
+        frame::scene_ev100(&self.data)
+    }
+
+    /// The exposure value matching the average luminance of the last metered frame, before adaptation
+    pub fn metered_ev100(&self) -> Option<f32> {
+        self.data.exposure.metered_ev100
+    }
+
+    /// Histogram range, percentiles and adaptation speeds of the automatic exposure
+    pub fn auto_exposure_settings_mut(&mut self) -> &mut AutoExposureSettings {
+        &mut self.data.exposure.settings
+    }
+
+    /// Sets the exposure value used when the camera has no physical settings and auto exposure is off
+    pub fn set_ev100(&mut self, ev100: f32) {
+        self.data.frame.ev100 = ev100;
+    }
+
+    /// Adds a light to the scene and returns its index in `lights()`
+    pub fn add_light(&mut self, light: Light) -> usize {
+        self.data.lights.push(light);
+        self.data.lights.len() - 1
+    }
+
+    pub fn lights(&self) -> &[Light] {
+        &self.data.lights
+    }
+
+    /// The lights are uploaded every frame, so they can be edited freely
+    pub fn lights_mut(&mut self) -> &mut Vec<Light> {
+        &mut self.data.lights
+    }
+
+    /// Depth bias, filtering and cascade split controls of the sun's shadow, applied from the next frame
+    pub fn shadow_settings_mut(&mut self) -> &mut ShadowSettings {
+        &mut self.data.shadows.settings
+    }
+
+    /// Bokeh shape, blur limit and autofocus of the depth of field, which needs a physical camera
+    pub fn depth_of_field_settings_mut(&mut self) -> &mut DepthOfFieldSettings {
+        &mut self.data.depth_of_field.settings
+    }
+
+    /// Jitter sequence and history weight of the temporal anti-aliasing
+    pub fn temporal_aa_settings_mut(&mut self) -> &mut TemporalAaSettings {
+        &mut self.data.temporal_aa.settings
+    }
+
+    /// Radius, intensity, sample count and debug view of the screen space ambient occlusion
+    pub fn ambient_occlusion_settings_mut(&mut self) -> &mut SsaoSettings {
+        &mut self.data.ambient_occlusion.settings
+    }
+
+    /// Density, height falloff and reach of the volumetric fog
+    pub fn fog_settings_mut(&mut self) -> &mut FogSettings {
+        &mut self.data.fog.settings
+    }
+
+    /// Ray length, thickness and roughness cutoff of the screen space reflections
+    pub fn reflection_settings_mut(&mut self) -> &mut SsrSettings {
+        &mut self.data.reflections.settings
+    }
+
+    /// Light cutoff of the clustered shading's light culling
+    pub fn clustered_lighting_settings_mut(&mut self) -> &mut ClusteredLightingSettings {
+        &mut self.data.clusters.settings
+    }
+
+    /// FXAA or SMAA on the rasterised scene, a cheaper alternative to the temporal anti-aliasing
+    pub fn post_aa_settings_mut(&mut self) -> &mut PostAaSettings {
+        &mut self.data.post_aa.settings
+    }
+
+    /// Sample count of the motion blur, whose length follows the physical camera's shutter speed
+    pub fn motion_blur_settings_mut(&mut self) -> &mut MotionBlurSettings {
+        &mut self.data.motion_blur.settings
+    }
+
+    /// Intensity and radius of the bloom
+    pub fn bloom_settings_mut(&mut self) -> &mut BloomSettings {
+        &mut self.data.bloom.settings
+    }
+
+    /// Vignetting, chromatic aberration, distortion and grain of the physical camera's lens
+    pub fn lens_settings_mut(&mut self) -> &mut LensSettings {
+        &mut self.data.lens.settings
+    }
+
+    /// Where a point on screen, in 0..1, is in the scene's undistorted render. Picked points go
+    ///  through this before being unprojected with the camera.
+    pub fn undistort_point(&self, point: [f32; 2]) -> [f32; 2] {
+        match self.data.lens.active_settings(&self.data.camera) {
+            Some(lens) => lens.undistort(point, self.aspect()),
+            None => point,
+        }
+    }
+
+    /// Where a projected point of the scene, in 0..1, is drawn on screen once the lens distorts it
+    pub fn distort_point(&self, point: [f32; 2]) -> [f32; 2] {
+        match self.data.lens.active_settings(&self.data.camera) {
+            Some(lens) => lens.distort(point, self.aspect()),
+            None => point,
+        }
+    }
+
+    fn aspect(&self) -> f32 {
+        let extent = self.data.swapchain_extent;
+        extent.width as f32 / extent.height.max(1) as f32
+    }
+
+    /// Loads a sky cube map from six PNG faces in +X, -X, +Y, -Y, +Z, -Z order, drawn behind the
+    ///  scene and lighting it
+    pub unsafe fn load_skybox_faces(&mut self, faces: &[&Path; 6]) -> Result<()> {
+        self.device.device_wait_idle()?;
+        skybox::load_skybox_faces(faces, &self.instance, &self.device, &mut self.data)?;
+        self.data.path_tracer.reset();
+        ibl::update_image_based_lighting(&self.instance, &self.device, &mut self.data)
+    }
+
+    /// Loads an equirectangular Radiance (.hdr) or OpenEXR (.exr) panorama as the sky, converted to a cube map on the GPU
+    pub unsafe fn load_skybox_equirect(&mut self, path: &Path) -> Result<()> {
+        self.device.device_wait_idle()?;
+        skybox::load_skybox_equirect(path, &self.instance, &self.device, &mut self.data)?;
+        self.data.path_tracer.reset();
+        ibl::update_image_based_lighting(&self.instance, &self.device, &mut self.data)
+    }
+
+    /// Removes the sky, leaving a black background and the uniform ambient
+    pub unsafe fn clear_skybox(&mut self) -> Result<()> {
+        self.device.device_wait_idle()?;
+        skybox::clear_skybox(&self.instance, &self.device, &mut self.data)?;
+        self.data.path_tracer.reset();
+        ibl::update_image_based_lighting(&self.instance, &self.device, &mut self.data)
+    }
+
+    /// Luminance and rotation of the sky map
+    pub fn skybox_settings_mut(&mut self) -> &mut SkyboxSettings {
+        &mut self.data.skybox.settings
+    }
+
+    /// The sun and air of the procedural sky, applied by `update_procedural_sky`
+    pub fn atmosphere_settings_mut(&mut self) -> &mut AtmosphereSettings {
+        &mut self.data.atmosphere.settings
+    }
+
+    /// Replaces the sky with one scattered by the atmosphere under the current settings. The sun
+    ///  takes over the first directional light, or adds one, and the skybox's settings.
+    pub unsafe fn update_procedural_sky(&mut self) -> Result<()> {
+        self.device.device_wait_idle()?;
+        atmosphere::update_atmosphere(&self.instance, &self.device, &mut self.data)?;
+        self.data.path_tracer.reset();
+        ibl::update_image_based_lighting(&self.instance, &self.device, &mut self.data)
+    }
+
+    /// White balance, contrast, saturation and lift/gamma/gain, applied from the next frame
+    pub fn color_grading_settings_mut(&mut self) -> &mut ColorGradingSettings {
+        &mut self.data.grading.settings
+    }
+
+    /// Loads a 3D LUT from an Adobe/Resolve .cube file, applied after tonemapping
+    pub unsafe fn load_lut(&mut self, path: &Path) -> Result<()> {
+        let lut = CubeLut::load(path)?;
+        self.device.device_wait_idle()?;
+        grading::upload_lut(&lut, &self.instance, &self.device, &mut self.data)
+    }
+
+    pub fn clear_lut(&mut self) {
+        grading::clear_lut(&mut self.data);
+    }
+
+    /// The tonemapping operator and white point, applied from the next frame
+    pub fn tonemap_settings_mut(&mut self) -> &mut TonemapSettings {
+        &mut self.data.tonemapper.settings
+    }
+
+    /// Sets the uniform sky luminance lighting everything, in cd/m²
+    pub fn set_ambient(&mut self, luminance: [f32; 3]) {
+        self.data.frame.ambient = luminance;
+    }
+
+    /// Sets how many samples the path tracer accumulates before it stops, 0 never stops
+    pub fn set_sample_budget(&mut self, samples: u32) {
+        self.data.path_tracer.sample_budget = samples;
+    }
+
+    /// Sets how many times a path may bounce off the scene before it is terminated
+    pub fn set_max_bounces(&mut self, bounces: u32) {
+        self.data.path_tracer.max_bounces = bounces;
+        self.data.path_tracer.reset();
+    }
+
+    /// Restarts path traced accumulation, needed after changes the engine can't see
+    pub fn reset_accumulation(&mut self) {
+        self.data.path_tracer.reset();
+    }
+
+    pub fn accumulated_samples(&self) -> u32 {
+        self.data.path_tracer.sample_count
+    }
+
+    /// Saves the HDR target of the next frame to a .hdr or .exr file once the frame has finished,
+    ///  copied at the `capture` point. Scene referred captures are in cd/m² like the path traced
+    ///  references, display referred ones hold what the tonemap pass maps. Only windowed engines
+    ///  draw frames of their own.
+    pub fn save_hdr_target(&mut self, path: &Path, capture: HdrCapture) -> Result<()> {
+        if !image_io::is_linear_image(path) {
+            return Err(anyhow!("HDR capture `{}` must be a .hdr or .exr file.", path.display()));
+        }
+
+        self.capture = Some(PendingCapture { path: path.to_path_buf(), capture, image: None });
+        Ok(())
+    }
+
+    /// Reads back a recorded capture and writes its file, unexposing scene referred ones
+    unsafe fn write_capture(&self, capture: &PendingCapture) -> Result<()> {
+        let image = capture.image.as_ref().ok_or_else(|| anyhow!("No frame has copied the HDR target yet."))?;
+        let extent = image.extent_2d();
+        let bytes = memory::download_image(image.image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, extent,
+            8, &self.instance, &self.device, &self.data)?;
+
+        let scale = match capture.capture {
+            HdrCapture::Scene => 1.0 / self.data.frame.exposure,
+            HdrCapture::Display => 1.0,
+        };
+        let pixels = bytes
+            .chunks_exact(8)
+            .flat_map(|texel| {
+                let c = texel
+                    .chunks_exact(2)
+                    .map(|b| image_io::f16_to_f32(u16::from_ne_bytes([b[0], b[1]])))
+                    .collect::<Vec<_>>();
+                [c[0] * scale, c[1] * scale, c[2] * scale, 1.0]
+            })
+            .collect::<Vec<_>>();
+
+        image_io::write_linear_image(&capture.path, extent.width, extent.height, &pixels)?;
+        let referred = match capture.capture {
+            HdrCapture::Scene => "scene",
+            HdrCapture::Display => "display",
+        };
+        info!("Wrote the {} referred HDR target to `{}`.", referred, capture.path.display());
+        Ok(())
+    }
+
+    /// Path traces `samples` samples per pixel without presenting and writes the converged image,
+    ///  linear to a .hdr or .exr file in cd/m², unexposed, and otherwise to a PNG exposed,
+    ///  tonemapped and graded like the display. PNGs are drawn into the output image, so they need
+    ///  a headless engine.
+    pub unsafe fn render_reference(&mut self, path: &Path, samples: u32) -> Result<()> 
+    {
+        let linear = image_io::is_linear_image(path);
+        if !linear && !self.data.is_headless() {
+            return Err(anyhow!("PNG reference `{}` needs a headless engine, write a .hdr or .exr instead.",
+                path.display()));
+        }
+
+        self.device.device_wait_idle()?;
+        self.set_render_mode(RenderMode::PathTraced);
+        upload_lights(&self.instance, &self.device, &mut self.data)?;
+        update_frame_uniforms(&self.device, &mut self.data)?;
+        update_path_tracer(&self.instance, &self.device, &mut self.data)?;
+        self.data.path_tracer.reset();
+
+        // Trace in batches so a single submission never runs for too long
+        while self.data.path_tracer.sample_count < samples {
+            let batch = (samples - self.data.path_tracer.sample_count).min(REFERENCE_BATCH_SIZE);
+            let command_buffer = memory::begin_single_time_commands(&self.device, &self.data)?;
+            for _ in 0..batch {
+                self.data.path_tracer.cmd_trace(&self.device, command_buffer, self.data.frame.set);
+            }
+            memory::end_single_time_commands(&self.device, &self.data, command_buffer)?;
+            debug!("Traced {}/{} samples.", self.data.path_tracer.sample_count, samples);
+        }
+
+        if linear {
+            // Read back and average the accumulated samples
+            let accumulation = &self.data.path_tracer.accumulation;
+            let extent = accumulation.extent_2d();
+            let bytes = memory::download_image(accumulation.image, vk::ImageLayout::GENERAL, extent,
+                16, &self.instance, &self.device, &self.data)?;
+
+            let pixels = bytes
+                .chunks_exact(16)
+                .flat_map(|texel| {
+                    let c = texel
+                        .chunks_exact(4)
+                        .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+                        .collect::<Vec<_>>();
+                    let n = c[3].max(1.0);
+                    [c[0] / n, c[1] / n, c[2] / n, 1.0]
+                })
+                .collect::<Vec<_>>();
+
+            image_io::write_linear_image(path, extent.width, extent.height, &pixels)?;
+        } else {
+            // Record an ordinary frame of the converged accumulation, it stops tracing at the budget
+            self.data.path_tracer.sample_budget = samples.max(1);
+            self.update_command_buffer(0)?;
+            let command_buffers = &[self.data.command_buffers[0]];
+            let submit_info = vk::SubmitInfo::builder()
+                .command_buffers(command_buffers);
+            self.device.queue_submit(self.data.graphics_queue, &[submit_info], vk::Fence::null())?;
+            self.device.queue_wait_idle(self.data.graphics_queue)?;
+
+            // The output image is sRGB, the tonemapper's writes are already encoded
+            let output = &self.data.output_image;
+            let extent = output.extent_2d();
+            let bytes = memory::download_image(output.image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, extent,
+                4, &self.instance, &self.device, &self.data)?;
+            image_io::write_png(path, extent.width, extent.height, &bytes)?;
+        }
+        info!("Wrote {}-sample reference to `{}`.", samples, path.display());
+        Ok(())
+    }
 }
 
 
-/// Creates the vulkan instance
-unsafe fn create_instance(window: &Window, entry: &Entry, data: &mut EngineData) -> Result<Instance> 
+/// Creates the vulkan instance, with the surface extensions when there is a window to present to
+unsafe fn create_instance(window: Option<&Window>, entry: &Entry, data: &mut EngineData) -> Result<Instance> 
 {
     let application_info = vk::ApplicationInfo::builder()
         .application_name(b"Vulkan Tutorial\0")
@@ -414,7 +1267,9 @@ unsafe fn create_instance(window: &Window, entry: &Entry, data: &mut EngineData)
         .engine_version(vk::make_version(1, 0, 0))
         .api_version(vk::make_version(1, 3, 279));
 
-    let mut extensions = vk_window::get_required_instance_extensions(window)
+    let mut extensions = window
+        .map(|w| vk_window::get_required_instance_extensions(w))
+        .unwrap_or(&[])
         .iter()
         .map(|e| e.as_ptr())
         .collect::<Vec<_>>();
@@ -543,11 +1398,14 @@ unsafe fn check_physical_device(instance: &Instance, data: &EngineData,
     }
 
     QueueFamilyIndices::get(instance, data, physical_device)?;
-    check_physical_device_extensions(instance, REQUIRED_DEVICE_EXTENSIONS, physical_device)?;
 
-    let support = SwapchainSupport::get(instance, data, physical_device)?;
-    if support.formats.is_empty() || support.present_modes.is_empty() {
-        return Err(anyhow!(SuitabilityError("Insufficient swapchain support.")));
+    // Only presenting needs the swapchain
+    if !data.is_headless() {
+        check_physical_device_extensions(instance, REQUIRED_DEVICE_EXTENSIONS, physical_device)?;
+        let support = SwapchainSupport::get(instance, data, physical_device)?;
+        if support.formats.is_empty() || support.present_modes.is_empty() {
+            return Err(anyhow!(SuitabilityError("Insufficient swapchain support.")));
+        }
     }
 
     Ok(())
@@ -595,8 +1453,9 @@ unsafe fn create_logical_device( entry: &Entry, instance: &Instance, data: &mut
         vec![]
     };
 
-    // Extensions
-    let mut extensions = REQUIRED_DEVICE_EXTENSIONS
+    // Extensions, headless devices don't present
+    let required_extensions: &[vk::ExtensionName] = if data.is_headless() { &[] } else { REQUIRED_DEVICE_EXTENSIONS };
+    let mut extensions = required_extensions
         .iter()
         .map(|n| n.as_ptr())
         .collect::<Vec<_>>()
@@ -616,24 +1475,25 @@ unsafe fn create_logical_device( entry: &Entry, instance: &Instance, data: &mut
         data.allow_mesh_shaders = true;
     }
 
-    // Raytracing extensions
-    if check_physical_device_extensions(instance, RAY_TRACING_EXTENSIONS, data.physical_device).is_ok() {
-        extensions.extend(RAY_TRACING_EXTENSIONS
-            .iter()
-            .map(|n| n.as_ptr()))
-            ;
-        data.allow_raytracing = true;
-    }
+    // Multiview renders every face of a point light's cube map in one pass
+    let mut multiview_features = vk::PhysicalDeviceMultiviewFeatures::builder();
+    let mut supported_features = vk::PhysicalDeviceFeatures2::builder()
+        .push_next(&mut multiview_features);
+    instance.get_physical_device_features2(data.physical_device, &mut supported_features);
+    data.allow_multiview = multiview_features.multiview == vk::TRUE;
 
     // Features
     let features = vk::PhysicalDeviceFeatures::builder();
+    let mut vulkan_11_features = vk::PhysicalDeviceVulkan11Features::builder()
+        .multiview(data.allow_multiview);
 
     // Create the logical device
     let info = vk::DeviceCreateInfo::builder()        
         .queue_create_infos(&queue_infos)
         .enabled_layer_names(&layers)
         .enabled_extension_names(&extensions)
-        .enabled_features(&features);
+        .enabled_features(&features)
+        .push_next(&mut vulkan_11_features);
 
     let device = instance.create_device(data.physical_device, &info, None)?;
 
@@ -733,6 +1593,27 @@ unsafe fn create_swapchain(window: &Window,instance: &Instance, device: &Device,
     Ok(())
 }
 
+/// Stands in for the swapchain when headless: a single sRGB image the tonemapper draws into and
+///  frames are read back from
+unsafe fn create_output_image(extent: vk::Extent2D, instance: &Instance, device: &Device, data: &mut EngineData)
+    -> Result<()> 
+{
+    let format = vk::Format::R8G8B8A8_SRGB;
+    let output_image = AllocatedImage::create(
+        extent,
+        format,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+
+    data.swapchain_images = vec![output_image.image];
+    data.swapchain_image_views = vec![output_image.image_view];
+    data.swapchain_format = format;
+    data.swapchain_extent = extent;
+    data.output_image = output_image;
+    Ok(())
+}
+
 unsafe fn create_swapchain_image_views(device: &Device,data: &mut EngineData, ) -> Result<()> 
 {
     data.swapchain_image_views = data
@@ -785,15 +1666,20 @@ impl QueueFamilyIndices
             .position(|p| p.queue_flags.contains(vk::QueueFlags::GRAPHICS))
             .map(|i| i as u32);
 
+        // Without a surface nothing is presented, the graphics queue stands in
         let mut present = None;
-        for (index, properties) in properties.iter().enumerate() {
-            if instance.get_physical_device_surface_support_khr(
-                physical_device,
-                index as u32,
-                data.surface,
-            )? {
-                present = Some(index as u32);
-                break;
+        if data.is_headless() {
+            present = graphics;
+        } else {
+            for (index, properties) in properties.iter().enumerate() {
+                if instance.get_physical_device_surface_support_khr(
+                    physical_device,
+                    index as u32,
+                    data.surface,
+                )? {
+                    present = Some(index as u32);
+                    break;
+                }
             }
         }
 
@@ -831,23 +1717,84 @@ impl SwapchainSupport
     }
 }
 
-unsafe fn create_framebuffers(device: &Device, data: &mut EngineData) -> Result<()> 
+unsafe fn get_depth_format(instance: &Instance, data: &EngineData) -> Result<vk::Format> 
 {
-    data.framebuffers = data
-        .swapchain_image_views
+    let candidates = &[
+        vk::Format::D32_SFLOAT,
+        vk::Format::D32_SFLOAT_S8_UINT,
+        vk::Format::D24_UNORM_S8_UINT,
+    ];
+
+    candidates
         .iter()
-        .map(|i| {
-            let attachments = &[*i];
-            let create_info = vk::FramebufferCreateInfo::builder()
-                .render_pass(data.render_pass)
-                .attachments(attachments)
-                .width(data.swapchain_extent.width)
-                .height(data.swapchain_extent.height)
-                .layers(1);
-            
-            device.create_framebuffer(&create_info, None)
+        .cloned()
+        .find(|f| {
+            let properties = instance.get_physical_device_format_properties(data.physical_device, *f);
+            properties.optimal_tiling_features.contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
         })
-        .collect::<Result<Vec<_>, _>>()?;
+        .ok_or_else(|| anyhow!("Failed to find supported depth format."))
+}
+
+unsafe fn create_depth_objects(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()> 
+{
+    data.depth_image = AllocatedImage::create(
+        data.swapchain_extent,
+        data.depth_format,
+        vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED
+            | vk::ImageUsageFlags::INPUT_ATTACHMENT,
+        vk::ImageAspectFlags::DEPTH,
+        instance, device, data)?;
+    Ok(())
+}
+
+/// The HDR color target the scene is rendered into before tonemapping, and the velocity, normal and
+///  specular buffers beside it
+unsafe fn create_hdr_objects(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()> 
+{
+    data.normal_image = AllocatedImage::create(
+        data.swapchain_extent,
+        NORMAL_FORMAT,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+
+    data.specular_image = AllocatedImage::create(
+        data.swapchain_extent,
+        SPECULAR_FORMAT,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+
+    data.velocity_image = AllocatedImage::create(
+        data.swapchain_extent,
+        VELOCITY_FORMAT,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+
+    data.hdr_image = AllocatedImage::create(
+        data.swapchain_extent,
+        HDR_FORMAT,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_SRC
+            | vk::ImageUsageFlags::TRANSFER_DST,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+    Ok(())
+}
+
+/// A single scene framebuffer is enough, the queue is idle before every frame is recorded
+unsafe fn create_framebuffer(device: &Device, data: &mut EngineData) -> Result<()> 
+{
+    let attachments = &[data.hdr_image.image_view, data.velocity_image.image_view, data.depth_image.image_view,
+        data.normal_image.image_view, data.specular_image.image_view];
+    let create_info = vk::FramebufferCreateInfo::builder()
+        .render_pass(data.render_pass)
+        .attachments(attachments)
+        .width(data.swapchain_extent.width)
+        .height(data.swapchain_extent.height)
+        .layers(1);
+
+    data.framebuffer = device.create_framebuffer(&create_info, None)?;
     Ok(())
 }
 
@@ -856,56 +1803,21 @@ unsafe fn create_command_pool(instance: &Instance, device: &Device, data: &mut E
     let indices = QueueFamilyIndices::get(instance, data, data.physical_device)?;
 
     let info = vk::CommandPoolCreateInfo::builder()
-        .flags(vk::CommandPoolCreateFlags::empty()) // Optional.
+        .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
         .queue_family_index(indices.graphics);
 
     data.command_pool = device.create_command_pool(&info, None)?;
     Ok(())
 }
 
+/// Allocates a command buffer per swapchain image, they are recorded every frame in `update_command_buffer`
 unsafe fn create_command_buffers(device: &Device, data: &mut EngineData) -> Result<()> {
     let allocate_info = vk::CommandBufferAllocateInfo::builder()
         .command_pool(data.command_pool)
         .level(vk::CommandBufferLevel::PRIMARY)
-        .command_buffer_count(data.framebuffers.len() as u32);
+        .command_buffer_count(data.swapchain_images.len() as u32);
 
     data.command_buffers = device.allocate_command_buffers(&allocate_info)?;
-    for (i, command_buffer) in data.command_buffers.iter().enumerate() {
-        let inheritance = vk::CommandBufferInheritanceInfo::builder();
-    
-        let info = vk::CommandBufferBeginInfo::builder()
-            .flags(vk::CommandBufferUsageFlags::empty()) // Optional.
-            .inheritance_info(&inheritance);             // Optional.
-    
-        device.begin_command_buffer(*command_buffer, &info)?;
-
-        // Start the render pass
-        let render_area = vk::Rect2D::builder()
-        .offset(vk::Offset2D::default())
-        .extent(data.swapchain_extent);
-    
-        let color_clear_value = vk::ClearValue {
-            color: vk::ClearColorValue {
-                float32: [0.0, 0.0, 0.0, 1.0],
-            },
-        };
-    
-        let clear_values = &[color_clear_value];
-        let info = vk::RenderPassBeginInfo::builder()
-            .render_pass(data.render_pass)
-            .framebuffer(data.framebuffers[i])
-            .render_area(render_area)
-            .clear_values(clear_values);
-
-        device.cmd_begin_render_pass(*command_buffer, &info, vk::SubpassContents::INLINE);
-        device.cmd_bind_pipeline(*command_buffer, vk::PipelineBindPoint::GRAPHICS, data.pipeline);
-        device.cmd_bind_vertex_buffers(*command_buffer, 0, &[data.meshes[0].vertex_buffer.buffer], &[0]);
-            device.cmd_draw(*command_buffer, 3, 1, 0, 0);
-        device.cmd_end_render_pass(*command_buffer);
-        device.end_command_buffer(*command_buffer)?;
-
-    }
-
     Ok(())
 }
 
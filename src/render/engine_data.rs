@@ -1,6 +1,33 @@
 use vulkanalia::prelude::v1_3::*;
 
+use super::atmosphere::Atmosphere;
+use super::bloom::Bloom;
+use super::camera::{Camera, Mat4};
+use super::cluster::ClusteredLighting;
+use super::deferred::DeferredRenderer;
+use super::dof::DepthOfField;
+use super::exposure::AutoExposure;
+use super::fog::VolumetricFog;
+use super::frame::FrameResources;
+use super::grading::ColorGrading;
+use super::ibl::ImageBasedLighting;
+use super::lens::Lens;
+use super::light::Light;
+use super::material::MaterialLibrary;
+use super::motion_blur::MotionBlur;
+use super::memory::AllocatedImage;
 use super::mesh::Mesh;
+use super::pathtrace::PathTracer;
+use super::point_shadow::PointShadowMaps;
+use super::post_aa::PostAa;
+use super::scene::{MeshInstance, Scene};
+use super::shadow::ShadowMaps;
+use super::skybox::Skybox;
+use super::spot_shadow::SpotShadowMaps;
+use super::ssao::AmbientOcclusion;
+use super::ssr::ScreenSpaceReflections;
+use super::taa::TemporalAa;
+use super::tonemap::Tonemapper;
 
 /// The Vulkan handles and associated properties used by our Vulkan app.
 #[derive(Clone, Debug, Default)]
@@ -16,10 +43,22 @@ pub struct EngineData
     pub swapchain_extent: vk::Extent2D,
     pub swapchain_images: Vec<vk::Image>,
     pub swapchain_image_views: Vec<vk::ImageView>,
+    /// Stands in for the swapchain's images when headless
+    pub output_image: AllocatedImage,
+    pub depth_format: vk::Format,
+    pub depth_image: AllocatedImage,
+    pub hdr_image: AllocatedImage,
+    pub velocity_image: AllocatedImage,
+    pub normal_image: AllocatedImage,
+    pub specular_image: AllocatedImage,
+    pub post_image: AllocatedImage,
     pub render_pass: vk::RenderPass,
     pub pipeline_layout: vk::PipelineLayout,
     pub pipeline: vk::Pipeline,
-    pub framebuffers: Vec<vk::Framebuffer>,
+    pub double_sided_pipeline: vk::Pipeline,
+    pub blended_pipeline: vk::Pipeline,
+    pub double_sided_blended_pipeline: vk::Pipeline,
+    pub framebuffer: vk::Framebuffer,
     pub command_pool: vk::CommandPool,
     pub command_buffers: Vec<vk::CommandBuffer>,
     pub image_available_semaphores: Vec<vk::Semaphore>,
@@ -27,8 +66,48 @@ pub struct EngineData
     pub in_flight_fences: Vec<vk::Fence>,
     pub images_in_flight: Vec<vk::Fence>,
     pub meshes: Vec<Mesh>,
+    pub scene: Scene,
+    pub instances: Vec<MeshInstance>,
+    /// Instance transforms the last frame was drawn with, for the velocity buffer
+    pub previous_transforms: Vec<Mat4>,
+    pub scene_version: usize,
+    pub camera: Camera,
+    pub lights: Vec<Light>,
+    pub shadows: ShadowMaps,
+    pub point_shadows: PointShadowMaps,
+    pub spot_shadows: SpotShadowMaps,
+    pub frame: FrameResources,
+    pub materials: MaterialLibrary,
+
+    // Renderers
+    pub path_tracer: PathTracer,
+    pub deferred: DeferredRenderer,
+    pub clusters: ClusteredLighting,
+    pub ambient_occlusion: AmbientOcclusion,
+    pub reflections: ScreenSpaceReflections,
+    pub fog: VolumetricFog,
+    pub skybox: Skybox,
+    pub atmosphere: Atmosphere,
+    pub ibl: ImageBasedLighting,
+    pub tonemapper: Tonemapper,
+    pub temporal_aa: TemporalAa,
+    pub post_aa: PostAa,
+    pub grading: ColorGrading,
+    pub depth_of_field: DepthOfField,
+    pub motion_blur: MotionBlur,
+    pub exposure: AutoExposure,
+    pub bloom: Bloom,
+    pub lens: Lens,
 
     // Features
     pub allow_mesh_shaders: bool,
-    pub allow_raytracing: bool,
-}
\ No newline at end of file
+    pub allow_multiview: bool,
+}
+
+impl EngineData
+{
+    /// Whether frames go into the offscreen output image instead of a window's swapchain
+    pub fn is_headless(&self) -> bool {
+        self.surface.is_null()
+    }
+}
@@ -0,0 +1,231 @@
+use std::mem::size_of;
+use std::time::Instant;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::frame::{exposure_from_ev100, DEFAULT_EV100};
+use super::memory::{self, AllocatedBuffer, AllocatedImage};
+use super::post;
+use super::shader;
+
+/// Bins of the log luminance histogram, one per invocation of the averaging pass
+const HISTOGRAM_BINS: usize = 256;
+
+/// Metering and adaptation of the automatic exposure. Exposure values are at ISO 100.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AutoExposureSettings {
+    /// Replaces the camera's exposure with the metered one when set
+    pub enabled: bool,
+    /// Darkest exposure value the histogram covers, anything darker is ignored
+    pub min_ev: f32,
+    /// Brightest exposure value the histogram covers
+    pub max_ev: f32,
+    /// Fraction of the darkest pixels left out of the average
+    pub low_percentile: f32,
+    /// Fraction of the pixels, from the darkest, after which the brightest are left out
+    pub high_percentile: f32,
+    /// Adaptation rate in 1/s when the scene gets brighter
+    pub speed_up: f32,
+    /// Adaptation rate in 1/s when the scene gets darker
+    pub speed_down: f32,
+    /// Stops added to the metered exposure, positive brightens the image
+    pub compensation: f32,
+}
+
+impl Default for AutoExposureSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_ev: -4.0,
+            max_ev: 20.0,
+            low_percentile: 0.5,
+            high_percentile: 0.95,
+            speed_up: 3.0,
+            speed_down: 1.0,
+            compensation: 0.0,
+        }
+    }
+}
+
+/// Mirrors `Exposure` in `exposure.glsl`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct ExposurePushConstants {
+    min_ev: f32,
+    max_ev: f32,
+    pre_exposure: f32,
+    low_percentile: f32,
+    high_percentile: f32,
+    speed_up: f32,
+    speed_down: f32,
+    delta_time: f32,
+    compensation: f32,
+}
+
+/// Mirrors `State` in `exposure.glsl`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct ExposureState {
+    adapted_ev100: f32,
+    metered_ev100: f32,
+}
+
+/// Meters the HDR target with a log luminance histogram and adapts the exposure towards it on the GPU.
+///  The result is read back a frame late, like the pre-exposure it corrects.
+#[derive(Clone, Debug, Default)]
+pub struct AutoExposure {
+    pub settings: AutoExposureSettings,
+    /// Exposure value the scene is currently rendered at while enabled
+    pub ev100: f32,
+    /// Exposure value of the last frame's average luminance, before adaptation
+    pub metered_ev100: Option<f32>,
+    last_frame: Option<Instant>,
+    pending: bool,
+    histogram: AllocatedBuffer,
+    state: AllocatedBuffer,
+    sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+    set: vk::DescriptorSet,
+    pipeline_layout: vk::PipelineLayout,
+    histogram_pipeline: vk::Pipeline,
+    average_pipeline: vk::Pipeline,
+}
+
+impl AutoExposure {
+    /// Builds this frame's histogram and adapts the exposure, `ev100` being the one the HDR target was exposed at
+    pub unsafe fn cmd_meter(&mut self, device: &Device, command_buffer: vk::CommandBuffer, hdr: &AllocatedImage,
+        ev100: f32)
+    {
+        if !self.settings.enabled {
+            self.last_frame = None;
+            return;
+        }
+
+        let now = Instant::now();
+        let delta_time = self.last_frame.map_or(0.0, |t| (now - t).as_secs_f32());
+        self.last_frame = Some(now);
+
+        let push_constants = ExposurePushConstants {
+            min_ev: self.settings.min_ev,
+            max_ev: self.settings.max_ev.max(self.settings.min_ev + 1.0),
+            pre_exposure: exposure_from_ev100(ev100),
+            low_percentile: self.settings.low_percentile.clamp(0.0, 1.0),
+            high_percentile: self.settings.high_percentile.clamp(self.settings.low_percentile, 1.0),
+            speed_up: self.settings.speed_up.max(0.0),
+            speed_down: self.settings.speed_down.max(0.0),
+            delta_time,
+            compensation: self.settings.compensation,
+        };
+
+        device.cmd_fill_buffer(command_buffer, self.histogram.buffer, 0, vk::WHOLE_SIZE, 0);
+        memory::cmd_buffer_barrier(device, command_buffer, self.histogram.buffer,
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE));
+
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 0, &[self.set], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, &push_constants);
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.histogram_pipeline);
+        post::cmd_dispatch_pixels(device, command_buffer, hdr.extent_2d());
+        memory::cmd_buffer_barrier(device, command_buffer, self.histogram.buffer,
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ));
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.average_pipeline);
+        device.cmd_dispatch(command_buffer, 1, 1, 1);
+        memory::cmd_buffer_barrier(device, command_buffer, self.state.buffer,
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::HOST, vk::AccessFlags::HOST_READ));
+        self.pending = true;
+    }
+}
+
+pub unsafe fn create_auto_exposure(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let histogram = AllocatedBuffer::allocate(
+        (size_of::<u32>() * HISTOGRAM_BINS) as u64,
+        vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        instance, device, data)?;
+
+    // Adaptation starts from the default exposure
+    let initial = ExposureState { adapted_ev100: DEFAULT_EV100, metered_ev100: DEFAULT_EV100 };
+    let state = AllocatedBuffer::create(
+        &initial,
+        size_of::<ExposureState>(),
+        vk::BufferUsageFlags::STORAGE_BUFFER,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)?;
+
+    let exposure = &mut data.exposure;
+    exposure.settings = AutoExposureSettings::default();
+    exposure.ev100 = DEFAULT_EV100;
+    exposure.histogram = histogram;
+    exposure.state = state;
+    exposure.sampler = descriptor::create_sampler(device, vk::Filter::NEAREST, 0.0)?;
+    exposure.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(2, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    exposure.descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1),
+        (vk::DescriptorType::STORAGE_BUFFER, 2),
+    ], 1)?;
+    exposure.set = descriptor::allocate_set(device, exposure.descriptor_pool, exposure.set_layout)?;
+    descriptor::write_buffer(device, exposure.set, 1, vk::DescriptorType::STORAGE_BUFFER, exposure.histogram.buffer);
+    descriptor::write_buffer(device, exposure.set, 2, vk::DescriptorType::STORAGE_BUFFER, exposure.state.buffer);
+
+    exposure.pipeline_layout = descriptor::create_pipeline_layout(device, &[exposure.set_layout],
+        size_of::<ExposurePushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+    let histogram = include_bytes!(concat!(env!("OUT_DIR"), "/shader/exposure_histogram_comp.spv"));
+    exposure.histogram_pipeline = shader::create_compute_pipeline(device, &histogram[..], exposure.pipeline_layout)?;
+    let average = include_bytes!(concat!(env!("OUT_DIR"), "/shader/exposure_average_comp.spv"));
+    exposure.average_pipeline = shader::create_compute_pipeline(device, &average[..], exposure.pipeline_layout)?;
+
+    create_auto_exposure_targets(device, data);
+    Ok(())
+}
+
+/// Points the histogram pass at the current HDR target
+pub unsafe fn create_auto_exposure_targets(device: &Device, data: &mut EngineData)
+{
+    let exposure = &data.exposure;
+    descriptor::write_image(device, exposure.set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        data.hdr_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, exposure.sampler);
+}
+
+pub unsafe fn destroy_auto_exposure(device: &Device, data: &mut EngineData)
+{
+    let exposure = &mut data.exposure;
+    device.destroy_pipeline(exposure.histogram_pipeline, None);
+    device.destroy_pipeline(exposure.average_pipeline, None);
+    device.destroy_pipeline_layout(exposure.pipeline_layout, None);
+    device.destroy_descriptor_pool(exposure.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(exposure.set_layout, None);
+    device.destroy_sampler(exposure.sampler, None);
+    exposure.histogram.destroy(device);
+    exposure.state.destroy(device);
+}
+
+/// Picks up the exposure adapted during the last frame
+pub unsafe fn update_auto_exposure(device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let exposure = &mut data.exposure;
+    if !exposure.pending {
+        return Ok(());
+    }
+    exposure.pending = false;
+
+    let state = exposure.state.read::<ExposureState>(device)?;
+    exposure.ev100 = state.adapted_ev100;
+    exposure.metered_ev100 = Some(state.metered_ev100);
+    Ok(())
+}
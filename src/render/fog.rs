@@ -0,0 +1,290 @@
+use std::mem::size_of;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::camera::Camera;
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::{self, AllocatedImage};
+use super::post::{self, HDR_FORMAT, WORKGROUP_SIZE};
+use super::shader;
+
+/// Pixels along each side of a froxel's screen tile
+const FROXEL_TILE_SIZE: u32 = 8;
+
+/// Depth slices of the froxel volume
+const FROXEL_SLICES: u32 = 64;
+
+/// Density, colour and reach of the volumetric fog.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FogSettings {
+    pub enabled: bool,
+    /// Extinction per metre everywhere, 0.001 is a slight haze
+    pub density: f32,
+    /// Extinction per metre added at and below `height`, thinning out above it
+    pub height_density: f32,
+    /// World height the height fog starts thinning out at, in metres
+    pub height: f32,
+    /// How quickly the height fog thins out going up, per metre
+    pub height_falloff: f32,
+    /// Linear RGB share of the extinguished light that is scattered rather than absorbed
+    pub albedo: [f32; 3],
+    /// Henyey-Greenstein anisotropy, positive values scatter forward and bring out light shafts
+    pub anisotropy: f32,
+    /// Distance the froxel volume reaches, in metres. Fog further away is left out.
+    pub max_distance: f32,
+    /// Weight of the reprojected last frames in every froxel, higher is smoother and slower to react
+    pub history_weight: f32,
+}
+
+impl Default for FogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            density: 0.002,
+            height_density: 0.03,
+            height: 0.0,
+            height_falloff: 0.3,
+            albedo: [0.9, 0.9, 0.9],
+            anisotropy: 0.6,
+            max_distance: 64.0,
+            history_weight: 0.9,
+        }
+    }
+}
+
+/// Mirrors `Fog` in `fog.glsl`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct FogPushConstants {
+    albedo: [f32; 4],
+    density: f32,
+    height_density: f32,
+    height: f32,
+    height_falloff: f32,
+    near: f32,
+    max_distance: f32,
+    history_weight: f32,
+    jitter: f32,
+}
+
+/// Froxel based volumetric fog. Every froxel of a volume aligned with the camera's frustum gathers
+///  the light the shadowed lights and the environment scatter in it, blended with its reprojected
+///  history, then every column is integrated front to back and the scene is dimmed and lit by the
+///  fog in front of each pixel before the anti-aliasing. The scattering volume has two images
+///  taking turns being the history, like the temporal anti-aliasing. Every pass binds the frame
+///  set and one of the two sets.
+#[derive(Clone, Debug, Default)]
+pub struct VolumetricFog {
+    pub settings: FogSettings,
+    scattering: [AllocatedImage; 2],
+    integrated: AllocatedImage,
+    history_valid: bool,
+    current: usize,
+    frame_index: u32,
+    sampler: vk::Sampler,
+    point_sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+    /// Set i writes scattering image i and reads the other one as the history
+    sets: [vk::DescriptorSet; 2],
+    pipeline_layout: vk::PipelineLayout,
+    scatter_pipeline: vk::Pipeline,
+    integrate_pipeline: vk::Pipeline,
+    apply_pipeline: vk::Pipeline,
+}
+
+impl VolumetricFog {
+    /// Throws the history away, for camera cuts
+    pub fn reset(&mut self) {
+        self.history_valid = false;
+    }
+
+    /// Fogs the HDR target through the scratch image
+    pub unsafe fn cmd_render(&mut self, device: &Device, command_buffer: vk::CommandBuffer, camera: &Camera,
+        frame_set: vk::DescriptorSet, scratch: &AllocatedImage, hdr: &AllocatedImage)
+    {
+        if !self.settings.enabled {
+            self.history_valid = false;
+            return;
+        }
+
+        // The samples move through their froxels along the golden ratio's sequence
+        self.frame_index = self.frame_index.wrapping_add(1);
+        let push_constants = FogPushConstants {
+            albedo: [self.settings.albedo[0], self.settings.albedo[1], self.settings.albedo[2],
+                self.settings.anisotropy.clamp(-0.99, 0.99)],
+            density: self.settings.density.max(0.0),
+            height_density: self.settings.height_density.max(0.0),
+            height: self.settings.height,
+            height_falloff: self.settings.height_falloff.max(0.0),
+            near: camera.near,
+            max_distance: self.settings.max_distance.max(camera.near * 2.0),
+            history_weight: if self.history_valid { self.settings.history_weight.clamp(0.0, 0.98) } else { 0.0 },
+            jitter: (self.frame_index as f32 * 0.618_034).fract(),
+        };
+
+        // The last frame's passes are done reading what this one writes
+        let written = &self.scattering[self.current];
+        for image in [written, &self.integrated] {
+            memory::cmd_image_barrier(device, command_buffer, image.image, memory::color_subresource_range(),
+                (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ),
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+        }
+
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 0, &[frame_set, self.sets[self.current]], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, &push_constants);
+
+        let extent = self.integrated.extent;
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.scatter_pipeline);
+        device.cmd_dispatch(command_buffer, extent.width.div_ceil(WORKGROUP_SIZE),
+            extent.height.div_ceil(WORKGROUP_SIZE), extent.depth);
+        memory::cmd_image_barrier(device, command_buffer, written.image, memory::color_subresource_range(),
+            (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ));
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.integrate_pipeline);
+        post::cmd_dispatch_pixels(device, command_buffer, self.integrated.extent_2d());
+        memory::cmd_image_barrier(device, command_buffer, self.integrated.image, memory::color_subresource_range(),
+            (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ));
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.apply_pipeline);
+        post::cmd_dispatch_pixels(device, command_buffer, hdr.extent_2d());
+        post::cmd_write_back(device, command_buffer, scratch, hdr);
+
+        self.current = 1 - self.current;
+        self.history_valid = true;
+    }
+}
+
+pub unsafe fn create_fog(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let fog = &mut data.fog;
+    fog.settings = FogSettings::default();
+    fog.sampler = descriptor::create_sampler(device, vk::Filter::LINEAR, 0.0)?;
+    fog.point_sampler = descriptor::create_sampler(device, vk::Filter::NEAREST, 0.0)?;
+    fog.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(3, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(4, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(5, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(6, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(7, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    fog.descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 10),
+        (vk::DescriptorType::STORAGE_IMAGE, 6),
+    ], 2)?;
+    fog.sets = [
+        descriptor::allocate_set(device, fog.descriptor_pool, fog.set_layout)?,
+        descriptor::allocate_set(device, fog.descriptor_pool, fog.set_layout)?,
+    ];
+
+    // The scattering reads the lights, shadows and environment from the frame set
+    fog.pipeline_layout = descriptor::create_pipeline_layout(device, &[data.frame.set_layout, fog.set_layout],
+        size_of::<FogPushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/fog_scatter_comp.spv"));
+    fog.scatter_pipeline = shader::create_compute_pipeline(device, &comp[..], fog.pipeline_layout)?;
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/fog_integrate_comp.spv"));
+    fog.integrate_pipeline = shader::create_compute_pipeline(device, &comp[..], fog.pipeline_layout)?;
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/fog_apply_comp.spv"));
+    fog.apply_pipeline = shader::create_compute_pipeline(device, &comp[..], fog.pipeline_layout)?;
+
+    create_fog_targets(instance, device, data)
+}
+
+/// A froxel volume covering the current swapchain in RGBA16F
+unsafe fn create_volume(instance: &Instance, device: &Device, data: &EngineData) -> Result<AllocatedImage>
+{
+    let extent = data.swapchain_extent;
+    let info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::_3D)
+        .extent(vk::Extent3D {
+            width: extent.width.div_ceil(FROXEL_TILE_SIZE).max(1),
+            height: extent.height.div_ceil(FROXEL_TILE_SIZE).max(1),
+            depth: FROXEL_SLICES,
+        })
+        .mip_levels(1)
+        .array_layers(1)
+        .format(HDR_FORMAT)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(vk::SampleCountFlags::_1);
+
+    AllocatedImage::from_info(&info, vk::ImageViewType::_3D, vk::ImageAspectFlags::COLOR, instance, device, data)
+}
+
+/// Creates the froxel volumes for the current swapchain size, the history starts over
+pub unsafe fn create_fog_targets(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let scattering = [create_volume(instance, device, data)?, create_volume(instance, device, data)?];
+    let integrated = create_volume(instance, device, data)?;
+
+    // The volumes stay in the general layout, sampled and stored alike
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    for image in scattering.iter().chain([&integrated]) {
+        memory::cmd_image_barrier(device, command_buffer, image.image, memory::color_subresource_range(),
+            (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE));
+    }
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    let fog = &data.fog;
+    for (i, set) in fog.sets.iter().enumerate() {
+        descriptor::write_image(device, *set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            scattering[1 - i].image_view, vk::ImageLayout::GENERAL, fog.sampler);
+        descriptor::write_image(device, *set, 1, vk::DescriptorType::STORAGE_IMAGE,
+            scattering[i].image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+        descriptor::write_image(device, *set, 2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            scattering[i].image_view, vk::ImageLayout::GENERAL, fog.point_sampler);
+        descriptor::write_image(device, *set, 3, vk::DescriptorType::STORAGE_IMAGE,
+            integrated.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+        descriptor::write_image(device, *set, 4, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            integrated.image_view, vk::ImageLayout::GENERAL, fog.sampler);
+        descriptor::write_image(device, *set, 5, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            data.depth_image.image_view, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL, fog.point_sampler);
+        descriptor::write_image(device, *set, 6, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            data.hdr_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, fog.point_sampler);
+        descriptor::write_image(device, *set, 7, vk::DescriptorType::STORAGE_IMAGE,
+            data.post_image.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+    }
+
+    let fog = &mut data.fog;
+    fog.scattering = scattering;
+    fog.integrated = integrated;
+    fog.history_valid = false;
+    Ok(())
+}
+
+pub unsafe fn destroy_fog_targets(device: &Device, data: &mut EngineData)
+{
+    let fog = &mut data.fog;
+    fog.scattering
+        .iter_mut()
+        .chain([&mut fog.integrated])
+        .for_each(|v| v.destroy(device));
+}
+
+pub unsafe fn destroy_fog(device: &Device, data: &mut EngineData)
+{
+    let fog = &mut data.fog;
+    device.destroy_pipeline(fog.scatter_pipeline, None);
+    device.destroy_pipeline(fog.integrate_pipeline, None);
+    device.destroy_pipeline(fog.apply_pipeline, None);
+    device.destroy_pipeline_layout(fog.pipeline_layout, None);
+    device.destroy_descriptor_pool(fog.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(fog.set_layout, None);
+    device.destroy_sampler(fog.sampler, None);
+    device.destroy_sampler(fog.point_sampler, None);
+}
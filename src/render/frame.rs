@@ -0,0 +1,170 @@
+use std::mem::size_of;
+use cgmath::SquareMatrix;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::camera::Mat4;
+use super::cluster;
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::light::{create_light_buffer, LightBuffer};
+use super::memory::AllocatedBuffer;
+
+/// Exposure value of a sunny day, used when the camera isn't a physical one
+pub const DEFAULT_EV100: f32 = 15.0;
+
+/// Camera and global shading values shared by every draw, set 0 of the scene pipelines.
+///  Mirrors `Frame` in the shaders (std140).
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct FrameUniforms {
+    pub view: Mat4,
+    pub projection: Mat4,
+    pub view_projection: Mat4,
+    /// The last frame's view projection, for the velocity buffer
+    pub previous_view_projection: Mat4,
+    /// From NDC back to world space, for passes reconstructing positions from depth
+    pub inverse_view_projection: Mat4,
+    pub camera_position: [f32; 4],
+    pub ambient: [f32; 4],
+    /// Subpixel offsets of this and the last frame's projections in NDC, taken out of the velocities
+    pub jitter: [f32; 4],
+    /// Depth slice scale and bias and the cluster size in pixels, see `cluster::cluster_params`
+    pub clusters: [f32; 4],
+    /// Luminance of the sky map in cd/m², 0 without one, its rotation about the up axis and the
+    ///  angular radius of the sun disks drawn into it
+    pub environment: [f32; 4],
+    pub exposure: f32,
+    pub light_count: u32,
+    pub _padding: [u32; 2],
+}
+
+/// The per-frame uniform buffer and the descriptor set pointing at it.
+#[derive(Clone, Debug, Default)]
+pub struct FrameResources {
+    pub set_layout: vk::DescriptorSetLayout,
+    pub descriptor_pool: vk::DescriptorPool,
+    pub set: vk::DescriptorSet,
+    pub buffer: AllocatedBuffer,
+    pub lights: LightBuffer,
+    /// Uniform sky luminance in cd/m²
+    pub ambient: [f32; 3],
+    /// Exposure value of cameras without physical settings
+    pub ev100: f32,
+    /// View projection uploaded last frame, `None` until the first one
+    pub previous_view_projection: Option<Mat4>,
+    /// Pre-exposure of the last uploaded frame, which scene referred captures divide out
+    pub exposure: f32,
+}
+
+/// Scale from luminance to the 0..1 range of a sensor at `ev100`, saturating at 1.2x the metered value
+pub fn exposure_from_ev100(ev100: f32) -> f32 {
+    1.0 / (1.2 * 2f32.powf(ev100))
+}
+
+/// The exposure value the scene is rendered at: metered when auto exposure is on, otherwise the
+///  physical camera's or the engine's manual one
+pub fn scene_ev100(data: &EngineData) -> f32 {
+    if data.exposure.settings.enabled {
+        data.exposure.ev100
+    } else {
+        data.camera.ev100().unwrap_or(data.frame.ev100)
+    }
+}
+
+pub unsafe fn create_frame_resources(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let buffer = AllocatedBuffer::allocate(
+        size_of::<FrameUniforms>() as u64,
+        vk::BufferUsageFlags::UNIFORM_BUFFER,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)?;
+
+    let frame = &mut data.frame;
+    frame.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::UNIFORM_BUFFER,
+            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::STORAGE_BUFFER,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(2, vk::DescriptorType::UNIFORM_BUFFER,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(3, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(4, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(5, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(6, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(7, vk::DescriptorType::STORAGE_BUFFER,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(8, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT),
+        descriptor::layout_binding(9, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(10, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(11, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(12, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(13, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(14, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    frame.descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::UNIFORM_BUFFER, 2),
+        (vk::DescriptorType::STORAGE_BUFFER, 3),
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 10),
+    ], 1)?;
+    frame.set = descriptor::allocate_set(device, frame.descriptor_pool, frame.set_layout)?;
+    descriptor::write_buffer(device, frame.set, 0, vk::DescriptorType::UNIFORM_BUFFER, buffer.buffer);
+
+    frame.buffer = buffer;
+    frame.ambient = [3000.0, 3000.0, 3000.0];
+    frame.ev100 = DEFAULT_EV100;
+
+    create_light_buffer(instance, device, data)
+}
+
+pub unsafe fn destroy_frame_resources(device: &Device, data: &mut EngineData)
+{
+    let frame = &mut data.frame;
+    frame.buffer.destroy(device);
+    frame.lights.buffer.destroy(device);
+    device.destroy_descriptor_pool(frame.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(frame.set_layout, None);
+}
+
+/// Uploads this frame's camera and exposure, remembering the camera for the next frame's velocities
+pub unsafe fn update_frame_uniforms(device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let extent = data.swapchain_extent;
+    let aspect = extent.width as f32 / extent.height.max(1) as f32;
+    let view = data.camera.view();
+    let projection = data.temporal_aa.jittered(data.camera.projection(aspect));
+    let position = data.camera.position;
+    let ambient = data.frame.ambient;
+    let (jitter, previous_jitter) = (data.temporal_aa.jitter, data.temporal_aa.previous_jitter);
+    let view_projection = projection * view;
+    let previous_view_projection = data.frame.previous_view_projection.replace(view_projection);
+    data.frame.exposure = exposure_from_ev100(scene_ev100(data));
+
+    let uniforms = FrameUniforms {
+        view,
+        projection,
+        view_projection,
+        previous_view_projection: previous_view_projection.unwrap_or(view_projection),
+        inverse_view_projection: view_projection.invert().unwrap_or_else(Mat4::identity),
+        camera_position: [position.x, position.y, position.z, 1.0],
+        ambient: [ambient[0], ambient[1], ambient[2], 0.0],
+        jitter: [jitter[0], jitter[1], previous_jitter[0], previous_jitter[1]],
+        clusters: cluster::cluster_params(&data.camera, extent),
+        environment: data.skybox.environment_params(),
+        exposure: data.frame.exposure,
+        light_count: data.lights.len() as u32,
+        _padding: [0; 2],
+    };
+
+    data.frame.buffer.write(device, &uniforms, size_of::<FrameUniforms>())
+}
@@ -0,0 +1,399 @@
+use std::fs;
+use std::path::Path;
+use cgmath::{vec2, vec3, vec4, Matrix4};
+use anyhow::{Ok, Result};
+use log::*;
+use thiserror::Error;
+
+use gltf::khr_lights_punctual::Kind;
+
+use super::mesh::{self, Vertex};
+use super::scene::{AlphaMode, ImageData, LightKind, MaterialInfo, Node, Primitive, Projection, Scene, SceneCamera,
+    SceneLight, SceneMesh, TextureData, TextureRef, WrapMode};
+
+/// Extensions the importer understands, anything else in `extensionsRequired` is an error.
+const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "KHR_lights_punctual",
+    "KHR_materials_emissive_strength",
+];
+
+#[derive(Debug, Error)]
+pub enum GltfError {
+    #[error("Unsupported required glTF extension `{0}`.")]
+    UnsupportedExtension(String),
+    #[error("Unsupported primitive mode {0:?} in mesh `{1}`, only triangle lists are supported.")]
+    UnsupportedPrimitiveMode(gltf::mesh::Mode, String),
+    #[error("Primitive in mesh `{0}` has no positions.")]
+    MissingPositions(String),
+    #[error("Unsupported image format {0:?} in image {1}.")]
+    UnsupportedImageFormat(gltf::image::Format, usize),
+    #[error("Texture in material `{1}` reads TEXCOORD_{0}, only TEXCOORD_0 is supported.")]
+    UnsupportedTexCoord(u32, String),
+    #[error("Orthographic camera `{0}` isn't supported, the renderer only has perspective projections.")]
+    OrthographicCamera(String),
+    #[error("The glTF file has no scenes.")]
+    NoScene,
+}
+
+/// Vertices and indices of a primitive, ready to be uploaded as a `Mesh`
+pub type Geometry = (Vec<Vertex>, Vec<u32>);
+
+/// Imports a .gltf or .glb file. `Primitive::mesh` indexes the returned geometry.
+pub fn import(path: &Path) -> Result<(Scene, Vec<Geometry>)> {
+    let bytes = fs::read(path)?;
+
+    // Check the extensions first so unsupported files fail with a clear error instead of a validation one
+    let unvalidated = gltf::Gltf::from_slice_without_validation(&bytes)?;
+    if let Some(extension) = unvalidated
+        .extensions_required()
+        .find(|e| !SUPPORTED_EXTENSIONS.contains(e))
+    {
+        return Err(GltfError::UnsupportedExtension(extension.to_string()).into());
+    }
+    unvalidated
+        .extensions_used()
+        .filter(|e| !SUPPORTED_EXTENSIONS.contains(e))
+        .for_each(|e| warn!("Ignoring optional glTF extension `{}`.", e));
+
+    let gltf = gltf::Gltf::from_slice(&bytes)?;
+    let base = path.parent();
+    let buffers = gltf::import_buffers(&gltf.document, base, gltf.blob.clone())?;
+    let images = gltf::import_images(&gltf.document, base, &buffers)?;
+    let document = gltf.document;
+
+    let mut scene = Scene::default();
+    let mut geometry = Vec::new();
+
+    // Meshes
+    for mesh in document.meshes() {
+        let name = mesh.name().unwrap_or("<unnamed>").to_string();
+        let mut primitives = Vec::new();
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                return Err(GltfError::UnsupportedPrimitiveMode(primitive.mode(), name).into());
+            }
+
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let positions = reader
+                .read_positions()
+                .ok_or_else(|| GltfError::MissingPositions(name.clone()))?;
+            let mut normals = reader.read_normals();
+            let mut colors = reader.read_colors(0).map(|c| c.into_rgb_f32());
+            let mut uvs = reader.read_tex_coords(0).map(|t| t.into_f32());
+            let mut tangents = reader.read_tangents();
+            let has_normals = normals.is_some();
+            let has_tangents = tangents.is_some();
+
+            let mut vertices = positions
+                .map(|p| Vertex {
+                    pos: p.into(),
+                    norm: normals
+                        .as_mut()
+                        .and_then(|n| n.next())
+                        .map_or(vec3(0.0, 0.0, 1.0), |n| n.into()),
+                    color: colors
+                        .as_mut()
+                        .and_then(|c| c.next())
+                        .map_or(vec3(1.0, 1.0, 1.0), |c| c.into()),
+                    uv: uvs
+                        .as_mut()
+                        .and_then(|t| t.next())
+                        .map_or(vec2(0.0, 0.0), |t| t.into()),
+                    tangent: tangents
+                        .as_mut()
+                        .and_then(|t| t.next())
+                        .map_or(vec4(1.0, 0.0, 0.0, 1.0), |t| t.into()),
+                })
+                .collect::<Vec<_>>();
+
+            // Unindexed primitives draw their vertices in order
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..vertices.len() as u32).collect(),
+            };
+
+            // The spec asks for flat normals and MikkTSpace tangents when they are missing, smooth ones are close enough here
+            if !has_normals {
+                mesh::generate_normals(&mut vertices, &indices);
+            }
+            if !has_tangents {
+                mesh::generate_tangents(&mut vertices, &indices);
+            }
+
+            primitives.push(Primitive {
+                mesh: geometry.len(),
+                material: primitive.material().index(),
+            });
+            geometry.push((vertices, indices));
+        }
+
+        scene.meshes.push(SceneMesh { name: mesh.name().map(str::to_string), primitives });
+    }
+
+    // Materials. Only the first UV set is read, textures mapped by another one are refused rather than
+    //  sampled at the wrong coordinates.
+    let texture_ref = |material: &gltf::Material, texture: gltf::Texture, tex_coord: u32| {
+        if tex_coord != 0 {
+            let name = material.name().unwrap_or("<unnamed>").to_string();
+            return Err(GltfError::UnsupportedTexCoord(tex_coord, name).into());
+        }
+        Ok(TextureRef { texture: texture.index(), tex_coord })
+    };
+
+    for material in document.materials() {
+        let pbr = material.pbr_metallic_roughness();
+        let info_ref = |info: Option<gltf::texture::Info>| {
+            info.map(|i| texture_ref(&material, i.texture(), i.tex_coord())).transpose()
+        };
+        scene.materials.push(MaterialInfo {
+            name: material.name().map(str::to_string),
+            base_color_factor: pbr.base_color_factor(),
+            base_color_texture: info_ref(pbr.base_color_texture())?,
+            metallic_factor: pbr.metallic_factor(),
+            roughness_factor: pbr.roughness_factor(),
+            metallic_roughness_texture: info_ref(pbr.metallic_roughness_texture())?,
+            normal_texture: material
+                .normal_texture()
+                .map(|t| texture_ref(&material, t.texture(), t.tex_coord()))
+                .transpose()?,
+            normal_scale: material.normal_texture().map_or(1.0, |t| t.scale()),
+            occlusion_texture: material
+                .occlusion_texture()
+                .map(|t| texture_ref(&material, t.texture(), t.tex_coord()))
+                .transpose()?,
+            occlusion_strength: material.occlusion_texture().map_or(1.0, |t| t.strength()),
+            emissive_factor: material.emissive_factor(),
+            emissive_strength: material.emissive_strength().unwrap_or(1.0),
+            emissive_texture: info_ref(material.emissive_texture())?,
+            alpha_mode: match material.alpha_mode() {
+                gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+                gltf::material::AlphaMode::Mask => AlphaMode::Mask,
+                gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+            },
+            alpha_cutoff: material.alpha_cutoff().unwrap_or(0.5),
+            double_sided: material.double_sided(),
+        });
+    }
+
+    // Images, converted to RGBA8 once however many textures sample them
+    for (index, (image, data)) in document.images().zip(&images).enumerate() {
+        scene.images.push(ImageData {
+            name: image.name().map(str::to_string),
+            width: data.width,
+            height: data.height,
+            pixels: to_rgba8(data, index)?,
+        });
+    }
+
+    // Textures
+    for texture in document.textures() {
+        let sampler = texture.sampler();
+        let wrap = |mode: gltf::texture::WrappingMode| match mode {
+            gltf::texture::WrappingMode::Repeat => WrapMode::Repeat,
+            gltf::texture::WrappingMode::MirroredRepeat => WrapMode::MirroredRepeat,
+            gltf::texture::WrappingMode::ClampToEdge => WrapMode::ClampToEdge,
+        };
+
+        scene.textures.push(TextureData {
+            name: texture.name().map(str::to_string),
+            image: texture.source().index(),
+            linear_filter: sampler.mag_filter() != Some(gltf::texture::MagFilter::Nearest),
+            wrap: [wrap(sampler.wrap_s()), wrap(sampler.wrap_t())],
+        });
+    }
+
+    // Cameras
+    for camera in document.cameras() {
+        let projection = match camera.projection() {
+            gltf::camera::Projection::Perspective(p) => Projection::Perspective {
+                yfov: p.yfov(),
+                aspect: p.aspect_ratio(),
+                znear: p.znear(),
+                zfar: p.zfar(),
+            },
+            gltf::camera::Projection::Orthographic(_) => {
+                let name = camera.name().unwrap_or("<unnamed>").to_string();
+                return Err(GltfError::OrthographicCamera(name).into());
+            },
+        };
+        scene.cameras.push(SceneCamera { name: camera.name().map(str::to_string), projection });
+    }
+
+    // Lights
+    for light in document.lights().into_iter().flatten() {
+        let kind = match light.kind() {
+            Kind::Directional => LightKind::Directional,
+            Kind::Point => LightKind::Point,
+            Kind::Spot { inner_cone_angle, outer_cone_angle } => LightKind::Spot { inner_cone_angle, outer_cone_angle },
+        };
+        scene.lights.push(SceneLight {
+            name: light.name().map(str::to_string),
+            kind,
+            color: light.color(),
+            intensity: light.intensity(),
+            range: light.range(),
+        });
+    }
+
+    // Node hierarchy
+    for node in document.nodes() {
+        scene.nodes.push(Node {
+            name: node.name().map(str::to_string),
+            transform: Matrix4::from(node.transform().matrix()),
+            mesh: node.mesh().map(|m| m.index()),
+            camera: node.camera().map(|c| c.index()),
+            light: node.light().map(|l| l.index()),
+            children: node.children().map(|c| c.index()).collect(),
+            ..Default::default()
+        });
+    }
+    for index in 0..scene.nodes.len() {
+        for child in scene.nodes[index].children.clone() {
+            scene.nodes[child].parent = Some(index);
+        }
+    }
+
+    let root_scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or(GltfError::NoScene)?;
+    scene.roots = root_scene.nodes().map(|n| n.index()).collect();
+    scene.update_transforms();
+
+    info!("Imported `{}`: {} nodes, {} primitives, {} materials, {} textures, {} images, {} cameras, {} lights.",
+        path.display(), scene.nodes.len(), geometry.len(), scene.materials.len(),
+        scene.textures.len(), scene.images.len(), scene.cameras.len(), scene.lights.len());
+
+    Ok((scene, geometry))
+}
+
+/// Expands any 8-bit image to RGBA8
+fn to_rgba8(image: &gltf::image::Data, index: usize) -> Result<Vec<u8>> {
+    use gltf::image::Format;
+
+    let pixels = &image.pixels;
+    let rgba = match image.format {
+        Format::R8G8B8A8 => pixels.clone(),
+        Format::R8G8B8 => pixels.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+        Format::R8G8 => pixels.chunks_exact(2).flat_map(|p| [p[0], p[1], 0, 255]).collect(),
+        Format::R8 => pixels.iter().flat_map(|p| [*p, *p, *p, 255]).collect(),
+        format => return Err(GltfError::UnsupportedImageFormat(format, index).into()),
+    };
+
+    Ok(rgba)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A single triangle with positions only, its buffer embedded as a data URI
+    const TRIANGLE: &str = r#"{
+        "asset": { "version": "2.0" },
+        "buffers": [{
+            "byteLength": 36,
+            "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA"
+        }],
+        "bufferViews": [{ "buffer": 0, "byteLength": 36 }],
+        "accessors": [{
+            "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+            "min": [0, 0, 0], "max": [1, 1, 0]
+        }],
+        "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 } }] }],
+        "nodes": [{ "mesh": 0 }],
+        "scenes": [{ "nodes": [0] }],
+        "scene": 0
+    }"#;
+
+    /// An orange 1x1 PNG
+    const ORANGE_PNG: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAIAAACQd1PeAAAADElEQVR4nGP438AAAAQBAYDFKhhdAAAAAElFTkSuQmCC";
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("fstop_gltf_loader_{}_{}.gltf", std::process::id(), name))
+    }
+
+    /// `TRIANGLE` with `fields` added to its top level object
+    fn triangle_with(fields: &str) -> String {
+        TRIANGLE.replacen('{', &format!("{{ {},", fields), 1)
+    }
+
+    fn import_json(name: &str, json: &str) -> Result<(Scene, Vec<Geometry>)> {
+        let path = temp_path(name);
+        fs::write(&path, json).unwrap();
+        let imported = import(&path);
+        fs::remove_file(&path).unwrap();
+        imported
+    }
+
+    #[test]
+    fn imports_a_minimal_embedded_gltf() {
+        let (scene, geometry) = import_json("minimal", TRIANGLE).unwrap();
+
+        assert_eq!(geometry.len(), 1);
+        let (vertices, indices) = &geometry[0];
+        assert_eq!(indices, &[0, 1, 2]);
+        let positions = vertices.iter().map(|v| v.pos).collect::<Vec<_>>();
+        assert_eq!(positions, [vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0)]);
+        // Missing normals are generated from the winding
+        assert!(vertices.iter().all(|v| v.norm == vec3(0.0, 0.0, 1.0)));
+
+        assert_eq!(scene.roots, [0]);
+        assert_eq!(scene.nodes[0].mesh, Some(0));
+        assert_eq!(scene.meshes[0].primitives[0].mesh, 0);
+        assert_eq!(scene.meshes[0].primitives[0].material, None);
+        assert_eq!(scene.instances().len(), 1);
+    }
+
+    #[test]
+    fn textures_sharing_an_image_decode_it_once() {
+        let fields = format!(r#"
+            "images": [{{ "uri": "data:image/png;base64,{}" }}],
+            "samplers": [{{ "magFilter": 9728 }}],
+            "textures": [{{ "source": 0 }}, {{ "source": 0, "sampler": 0 }}]"#, ORANGE_PNG);
+        let (scene, _) = import_json("shared_image", &triangle_with(&fields)).unwrap();
+
+        assert_eq!(scene.images.len(), 1);
+        assert_eq!((scene.images[0].width, scene.images[0].height), (1, 1));
+        assert_eq!(scene.images[0].pixels, [255, 128, 0, 255]);
+        assert_eq!(scene.textures.iter().map(|t| t.image).collect::<Vec<_>>(), [0, 0]);
+        assert!(scene.textures[0].linear_filter);
+        assert!(!scene.textures[1].linear_filter);
+    }
+
+    #[test]
+    fn rejects_unsupported_required_extensions() {
+        let json = triangle_with(r#"
+            "extensionsUsed": ["KHR_draco_mesh_compression"],
+            "extensionsRequired": ["KHR_draco_mesh_compression"]"#);
+        let error = import_json("required_extension", &json).unwrap_err();
+
+        match error.downcast_ref::<GltfError>() {
+            Some(GltfError::UnsupportedExtension(extension)) => assert_eq!(extension, "KHR_draco_mesh_compression"),
+            _ => panic!("Expected an unsupported extension, got `{}`.", error),
+        }
+    }
+
+    #[test]
+    fn accepts_supported_required_extensions() {
+        let json = triangle_with(r#"
+            "extensionsUsed": ["KHR_materials_emissive_strength"],
+            "extensionsRequired": ["KHR_materials_emissive_strength"]"#);
+        assert!(import_json("supported_extension", &json).is_ok());
+    }
+
+    #[test]
+    fn rejects_orthographic_cameras() {
+        let json = triangle_with(r#"
+            "cameras": [{
+                "name": "top", "type": "orthographic",
+                "orthographic": { "xmag": 1, "ymag": 1, "znear": 0.1, "zfar": 10 }
+            }]"#);
+        let error = import_json("orthographic", &json).unwrap_err();
+
+        match error.downcast_ref::<GltfError>() {
+            Some(GltfError::OrthographicCamera(name)) => assert_eq!(name, "top"),
+            _ => panic!("Expected an orthographic camera error, got `{}`.", error),
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use std::mem::size_of;
+use cgmath::{Matrix3, SquareMatrix};
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::image_io::f32_to_f16;
+use super::lut::CubeLut;
+use super::memory::{AllocatedBuffer, AllocatedImage};
+use super::texture::{self, Texture};
+
+const LUT_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+/// Colour temperature in Kelvin the white balance treats as neutral
+pub const NEUTRAL_TEMPERATURE: f32 = 6500.0;
+
+/// Creative controls applied around the tonemap pass. White balance is applied to the scene's
+///  linear radiance, everything else to the tonemapped image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ColorGradingSettings {
+    /// Colour temperature of the light to render as white, lower values cool the image like a camera would
+    pub temperature: f32,
+    /// Green-magenta shift of that white, positive makes the image more magenta
+    pub tint: f32,
+    /// Contrast around middle grey, 1 changes nothing
+    pub contrast: f32,
+    /// 0 is greyscale, 1 changes nothing
+    pub saturation: f32,
+    /// Offsets the shadows
+    pub lift: [f32; 3],
+    /// Power on the midtones, greater than 1 brightens them
+    pub gamma: [f32; 3],
+    /// Multiplies the highlights
+    pub gain: [f32; 3],
+    /// Blend between the graded image and the loaded LUT's output
+    pub lut_strength: f32,
+}
+
+impl Default for ColorGradingSettings {
+    fn default() -> Self {
+        Self {
+            temperature: NEUTRAL_TEMPERATURE,
+            tint: 0.0,
+            contrast: 1.0,
+            saturation: 1.0,
+            lift: [0.0; 3],
+            gamma: [1.0; 3],
+            gain: [1.0; 3],
+            lut_strength: 1.0,
+        }
+    }
+}
+
+impl ColorGradingSettings {
+    /// Chromatic adaptation from the chosen white to the neutral one, in linear sRGB
+    pub fn white_balance(&self) -> Matrix3<f32> {
+        let source = daylight_xy(self.temperature, self.tint);
+        let target = daylight_xy(NEUTRAL_TEMPERATURE, 0.0);
+
+        // Von Kries scaling of Bradford cone responses
+        let to_lms = |xy: [f32; 2]| BRADFORD * cgmath::vec3(xy[0] / xy[1], 1.0, (1.0 - xy[0] - xy[1]) / xy[1]);
+        let (source, target) = (to_lms(source), to_lms(target));
+        let scale = Matrix3::from_diagonal(cgmath::vec3(target.x / source.x, target.y / source.y, target.z / source.z));
+
+        let bradford_inverse = BRADFORD.invert().unwrap_or_else(Matrix3::identity);
+        let xyz_to_srgb = SRGB_TO_XYZ.invert().unwrap_or_else(Matrix3::identity);
+        xyz_to_srgb * bradford_inverse * scale * BRADFORD * SRGB_TO_XYZ
+    }
+}
+
+// Column major, as cgmath expects
+const SRGB_TO_XYZ: Matrix3<f32> = Matrix3::new(
+    0.4124564, 0.2126729, 0.0193339,
+    0.3575761, 0.7151522, 0.119192,
+    0.1804375, 0.0721750, 0.9503041,
+);
+
+const BRADFORD: Matrix3<f32> = Matrix3::new(
+    0.8951, -0.7502, 0.0389,
+    0.2664, 1.7135, -0.0685,
+    -0.1614, 0.0367, 1.0296,
+);
+
+/// CIE xy chromaticity of a black body at `temperature`, shifted off the locus by `tint`.
+///  Uses Kim et al.'s cubic spline fit, valid from 1667K to 25000K.
+fn daylight_xy(temperature: f32, tint: f32) -> [f32; 2] {
+    let t = temperature.clamp(1667.0, 25000.0);
+    let (t2, t3) = (t * t, t * t * t);
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / t3 + 2.107038e6 / t2 + 0.2226347e3 / t + 0.240390
+    };
+
+    let (x2, x3) = (x * x, x * x * x);
+    let y = if t <= 2222.0 {
+        -1.1063814 * x3 - 1.3481102 * x2 + 2.1855583 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x3 - 1.3741859 * x2 + 2.09137 * x - 0.16748867
+    } else {
+        3.081758 * x3 - 5.873387 * x2 + 3.7511299 * x - 0.37001483
+    };
+
+    // A magenta tint corrects for a greener light, which sits above the locus
+    [x, y + tint * 0.02]
+}
+
+/// Mirrors `Grading` in `tonemap.frag` (std140)
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct GradingUniform {
+    white_balance: [[f32; 4]; 3],
+    lift: [f32; 4],
+    gamma: [f32; 4],
+    gain: [f32; 4],
+    /// Contrast, saturation, LUT strength and whether a LUT is loaded
+    params: [f32; 4],
+    lut_domain_min: [f32; 4],
+    lut_domain_max: [f32; 4],
+}
+
+/// The grading uniforms and LUT, bound as set 1 of the tonemap pass.
+#[derive(Clone, Debug, Default)]
+pub struct ColorGrading {
+    pub settings: ColorGradingSettings,
+    pub set_layout: vk::DescriptorSetLayout,
+    pub set: vk::DescriptorSet,
+    descriptor_pool: vk::DescriptorPool,
+    uniform_buffer: AllocatedBuffer,
+    lut: Texture,
+    lut_domain: ([f32; 3], [f32; 3]),
+    lut_loaded: bool,
+}
+
+pub unsafe fn create_color_grading(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let uniform_buffer = AllocatedBuffer::allocate(
+        size_of::<GradingUniform>() as u64,
+        vk::BufferUsageFlags::UNIFORM_BUFFER,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)?;
+
+    let grading = &mut data.grading;
+    grading.settings = ColorGradingSettings::default();
+    grading.uniform_buffer = uniform_buffer;
+    grading.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::UNIFORM_BUFFER, vk::ShaderStageFlags::FRAGMENT),
+        descriptor::layout_binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT),
+    ])?;
+    grading.descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::UNIFORM_BUFFER, 1),
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1),
+    ], 1)?;
+    grading.set = descriptor::allocate_set(device, grading.descriptor_pool, grading.set_layout)?;
+    descriptor::write_buffer(device, grading.set, 0, vk::DescriptorType::UNIFORM_BUFFER, grading.uniform_buffer.buffer);
+
+    // An identity LUT keeps the binding valid until one is loaded
+    upload_lut(&CubeLut::identity(2)?, instance, device, data)?;
+    data.grading.lut_loaded = false;
+    Ok(())
+}
+
+pub unsafe fn destroy_color_grading(device: &Device, data: &mut EngineData)
+{
+    let grading = &mut data.grading;
+    grading.lut.destroy(device);
+    grading.uniform_buffer.destroy(device);
+    device.destroy_descriptor_pool(grading.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(grading.set_layout, None);
+}
+
+/// Replaces the LUT with a 3D texture of `lut`. Only safe while the GPU isn't sampling it.
+pub unsafe fn upload_lut(lut: &CubeLut, instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::_3D)
+        .extent(vk::Extent3D { width: lut.size, height: lut.size, depth: lut.size })
+        .mip_levels(1)
+        .array_layers(1)
+        .format(LUT_FORMAT)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(vk::SampleCountFlags::_1);
+    let image = AllocatedImage::from_info(&info, vk::ImageViewType::_3D, vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+
+    let pixels = lut.entries
+        .iter()
+        .flat_map(|e| [e[0], e[1], e[2], 1.0])
+        .flat_map(|c| f32_to_f16(c).to_le_bytes())
+        .collect::<Vec<_>>();
+    texture::upload_pixels(&image, &pixels, 0, 1, instance, device, data)?;
+    texture::generate_mipmaps(&image, 1, 1, device, data)?;
+    let sampler = descriptor::create_sampler(device, vk::Filter::LINEAR, 0.0)?;
+
+    let grading = &mut data.grading;
+    descriptor::write_image(device, grading.set, 1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, sampler);
+    grading.lut.destroy(device);
+    grading.lut = Texture { image, sampler, mip_levels: 1 };
+    grading.lut_domain = (lut.domain_min, lut.domain_max);
+    grading.lut_loaded = true;
+    Ok(())
+}
+
+/// Stops applying the loaded LUT
+pub fn clear_lut(data: &mut EngineData) {
+    data.grading.lut_loaded = false;
+}
+
+/// Uploads this frame's grading controls
+pub unsafe fn update_color_grading(device: &Device, data: &EngineData) -> Result<()>
+{
+    let grading = &data.grading;
+    let settings = &grading.settings;
+    let white_balance = settings.white_balance();
+    let column = |i: usize| [white_balance[i].x, white_balance[i].y, white_balance[i].z, 0.0];
+    let rgb = |c: [f32; 3], w: f32| [c[0], c[1], c[2], w];
+    let (min, max) = grading.lut_domain;
+
+    let uniform = GradingUniform {
+        white_balance: [column(0), column(1), column(2)],
+        lift: rgb(settings.lift, 0.0),
+        gamma: rgb(settings.gamma.map(|g| g.max(1e-3)), 0.0),
+        gain: rgb(settings.gain, 0.0),
+        params: [
+            settings.contrast.max(0.0),
+            settings.saturation.max(0.0),
+            settings.lut_strength.clamp(0.0, 1.0),
+            if grading.lut_loaded { 1.0 } else { 0.0 },
+        ],
+        lut_domain_min: rgb(min, 0.0),
+        lut_domain_max: rgb(max, grading.lut.image.extent.width as f32),
+    };
+
+    grading.uniform_buffer.write(device, &uniform, size_of::<GradingUniform>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Matrix3<f32>, b: Matrix3<f32>) {
+        let (a, b): (&[[f32; 3]; 3], &[[f32; 3]; 3]) = (a.as_ref(), b.as_ref());
+        for (column_a, column_b) in a.iter().zip(b) {
+            for (a, b) in column_a.iter().zip(column_b) {
+                assert!((a - b).abs() < 1e-5, "Expected {:?}, got {:?}.", b, a);
+            }
+        }
+    }
+
+    #[test]
+    fn neutral_white_balance_is_the_identity() {
+        assert_close(ColorGradingSettings::default().white_balance(), Matrix3::identity());
+    }
+
+    #[test]
+    fn warmer_lights_are_balanced_towards_blue() {
+        let settings = ColorGradingSettings { temperature: 3200.0, ..Default::default() };
+        let white = settings.white_balance() * cgmath::vec3(1.0, 1.0, 1.0);
+        assert!(white.z > white.y && white.y > white.x, "Expected more blue than red, got {:?}.", white);
+
+        // A tint towards magenta corrects a green light by pulling green down
+        let settings = ColorGradingSettings { tint: 1.0, ..Default::default() };
+        let white = settings.white_balance() * cgmath::vec3(1.0, 1.0, 1.0);
+        assert!(white.y < white.x && white.y < white.z, "Expected less green, got {:?}.", white);
+    }
+}
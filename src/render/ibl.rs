@@ -0,0 +1,467 @@
+use std::fs;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+use log::*;
+
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::{self, AllocatedBuffer, AllocatedImage};
+use super::post::{HDR_FORMAT, WORKGROUP_SIZE};
+use super::shader;
+use super::skybox;
+
+/// Face size of the irradiance cube, irradiance changes slowly over directions
+const IRRADIANCE_SIZE: u32 = 32;
+
+/// Face size of the prefiltered cube's sharpest level
+const PREFILTERED_SIZE: u32 = 128;
+
+/// Levels of the prefiltered cube, evenly spaced in roughness from 0 to 1
+const PREFILTERED_LEVELS: u32 = 6;
+
+const BRDF_LUT_SIZE: u32 = 256;
+
+/// Samples per texel of every integration
+const SAMPLE_COUNT: u32 = 1024;
+
+/// Bytes of an RGBA16F texel
+const TEXEL_SIZE: usize = 8;
+
+/// Where the integrated lighting is kept between runs, relative to the working directory
+const CACHE_DIR: &str = "cache";
+
+/// Skies whose lighting is kept in the cache, the least recently used ones are deleted past it
+const MAX_CACHED_ENVIRONMENTS: usize = 16;
+
+/// Start of every cache file, followed by the sizes it was integrated at
+const CACHE_MAGIC: &[u8; 4] = b"IBL1";
+
+/// Mirrors `Ibl` in `ibl.glsl`
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct IblPushConstants {
+    roughness: f32,
+    sample_count: u32,
+}
+
+/// Image based lighting from the sky: its cosine weighted irradiance, its radiance prefiltered with
+///  the GGX lobe at increasing roughness, and the split sum's BRDF integral, bindings 10 to 12 of
+///  the frame set. Integrated with compute passes when a sky is loaded and cached to disk by the
+///  sky's contents, the least recently used skies evicted past a bound, the BRDF's once. Procedural
+///  skies aren't cached. All three stay in the general layout.
+#[derive(Clone, Debug, Default)]
+pub struct ImageBasedLighting {
+    pub irradiance: AllocatedImage,
+    pub prefiltered: AllocatedImage,
+    pub brdf_lut: AllocatedImage,
+    sampler: vk::Sampler,
+    set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    irradiance_pipeline: vk::Pipeline,
+    prefilter_pipeline: vk::Pipeline,
+    brdf_pipeline: vk::Pipeline,
+}
+
+/// FNV-1a hash of an environment's pixels, naming its cache file
+pub fn cache_key(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+pub unsafe fn create_image_based_lighting(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let ibl = &mut data.ibl;
+    ibl.sampler = descriptor::create_sampler(device, vk::Filter::LINEAR, vk::LOD_CLAMP_NONE)?;
+    ibl.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    ibl.pipeline_layout = descriptor::create_pipeline_layout(device, &[ibl.set_layout],
+        size_of::<IblPushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/ibl_irradiance_comp.spv"));
+    ibl.irradiance_pipeline = shader::create_compute_pipeline(device, &comp[..], ibl.pipeline_layout)?;
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/ibl_prefilter_comp.spv"));
+    ibl.prefilter_pipeline = shader::create_compute_pipeline(device, &comp[..], ibl.pipeline_layout)?;
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/ibl_brdf_comp.spv"));
+    ibl.brdf_pipeline = shader::create_compute_pipeline(device, &comp[..], ibl.pipeline_layout)?;
+
+    // The BRDF doesn't depend on the sky
+    let extent = vk::Extent2D { width: BRDF_LUT_SIZE, height: BRDF_LUT_SIZE };
+    let brdf_lut = AllocatedImage::create(
+        extent,
+        HDR_FORMAT,
+        vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_SRC
+            | vk::ImageUsageFlags::TRANSFER_DST,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+
+    let path = cache_path("brdf_lut");
+    let header = cache_header(&[BRDF_LUT_SIZE, SAMPLE_COUNT]);
+    let size = memory::image_levels_size(extent, 1, 1, TEXEL_SIZE);
+    match read_cache(&path, &header, size) {
+        Some(bytes) => upload_levels(&brdf_lut, &bytes, extent, 1, 1, instance, device, data)?,
+        None => {
+            integrate_brdf(&brdf_lut, device, data)?;
+            let bytes = memory::download_image_levels(brdf_lut.image, vk::ImageLayout::GENERAL, extent, 1, 1,
+                TEXEL_SIZE, instance, device, data)?;
+            write_cache(&path, &header, &[&bytes]);
+        },
+    }
+
+    let ibl = &mut data.ibl;
+    ibl.brdf_lut = brdf_lut;
+    descriptor::write_image(device, data.frame.set, 12, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        ibl.brdf_lut.image_view, vk::ImageLayout::GENERAL, ibl.sampler);
+
+    update_image_based_lighting(instance, device, data)
+}
+
+pub unsafe fn destroy_image_based_lighting(device: &Device, data: &mut EngineData)
+{
+    let ibl = &mut data.ibl;
+    ibl.irradiance.destroy(device);
+    ibl.prefiltered.destroy(device);
+    ibl.brdf_lut.destroy(device);
+    device.destroy_pipeline(ibl.irradiance_pipeline, None);
+    device.destroy_pipeline(ibl.prefilter_pipeline, None);
+    device.destroy_pipeline(ibl.brdf_pipeline, None);
+    device.destroy_pipeline_layout(ibl.pipeline_layout, None);
+    device.destroy_descriptor_set_layout(ibl.set_layout, None);
+    device.destroy_sampler(ibl.sampler, None);
+}
+
+/// Integrates the current sky, or reads it back from the cache, after it changed and the GPU is
+///  done with the old one. Without a sky the shaders use the uniform ambient and the cubes are
+///  single black texels.
+pub unsafe fn update_image_based_lighting(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    data.ibl.irradiance.destroy(device);
+    data.ibl.prefiltered.destroy(device);
+
+    if !data.skybox.loaded {
+        let irradiance = skybox::create_cube(1, 1, instance, device, data)?;
+        let prefiltered = skybox::create_cube(1, 1, instance, device, data)?;
+        clear_cubes(&[&irradiance, &prefiltered], device, data)?;
+        set_cubes(irradiance, prefiltered, device, data);
+        return Ok(());
+    }
+
+    let irradiance = skybox::create_cube(IRRADIANCE_SIZE, 1, instance, device, data)?;
+    let prefiltered = skybox::create_cube(PREFILTERED_SIZE, PREFILTERED_LEVELS, instance, device, data)?;
+    let irradiance_extent = irradiance.extent_2d();
+    let prefiltered_extent = prefiltered.extent_2d();
+
+    let path = data.skybox.key.map(|key| cache_path(&format!("environment_{:016x}", key)));
+    let header = cache_header(&[IRRADIANCE_SIZE, PREFILTERED_SIZE, PREFILTERED_LEVELS, SAMPLE_COUNT]);
+    let irradiance_size = memory::image_levels_size(irradiance_extent, 1, 6, TEXEL_SIZE);
+    let prefiltered_size = memory::image_levels_size(prefiltered_extent, PREFILTERED_LEVELS, 6, TEXEL_SIZE);
+
+    let cached = path.as_deref().and_then(|path| read_cache(path, &header, irradiance_size + prefiltered_size));
+    match cached {
+        Some(bytes) => {
+            if let Some(path) = &path {
+                touch_cache(path);
+            }
+            let (irradiance_bytes, prefiltered_bytes) = bytes.split_at(irradiance_size);
+            upload_levels(&irradiance, irradiance_bytes, irradiance_extent, 1, 6, instance, device, data)?;
+            upload_levels(&prefiltered, prefiltered_bytes, prefiltered_extent, PREFILTERED_LEVELS, 6,
+                instance, device, data)?;
+        },
+        None => {
+            info!("Integrating the image based lighting of the sky.");
+            integrate_environment(&irradiance, &prefiltered, device, data)?;
+            // Procedural skies are integrated in memory only
+            if let Some(path) = path {
+                let irradiance_bytes = memory::download_image_levels(irradiance.image, vk::ImageLayout::GENERAL,
+                    irradiance_extent, 1, 6, TEXEL_SIZE, instance, device, data)?;
+                let prefiltered_bytes = memory::download_image_levels(prefiltered.image,
+                    vk::ImageLayout::GENERAL, prefiltered_extent, PREFILTERED_LEVELS, 6, TEXEL_SIZE, instance,
+                    device, data)?;
+                write_cache(&path, &header, &[&irradiance_bytes, &prefiltered_bytes]);
+                evict_environments();
+            }
+        },
+    }
+
+    set_cubes(irradiance, prefiltered, device, data);
+    Ok(())
+}
+
+/// Points the frame set at new irradiance and prefiltered cubes
+unsafe fn set_cubes(irradiance: AllocatedImage, prefiltered: AllocatedImage, device: &Device, data: &mut EngineData)
+{
+    let ibl = &mut data.ibl;
+    ibl.irradiance = irradiance;
+    ibl.prefiltered = prefiltered;
+    descriptor::write_image(device, data.frame.set, 10, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        ibl.prefiltered.image_view, vk::ImageLayout::GENERAL, ibl.sampler);
+    descriptor::write_image(device, data.frame.set, 11, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        ibl.irradiance.image_view, vk::ImageLayout::GENERAL, ibl.sampler);
+}
+
+/// Convolves the sky into the irradiance cube and every level of the prefiltered cube
+unsafe fn integrate_environment(irradiance: &AllocatedImage, prefiltered: &AllocatedImage, device: &Device,
+    data: &EngineData) -> Result<()>
+{
+    let ibl = &data.ibl;
+    let descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1 + PREFILTERED_LEVELS),
+        (vk::DescriptorType::STORAGE_IMAGE, 1 + PREFILTERED_LEVELS),
+    ], 1 + PREFILTERED_LEVELS)?;
+
+    // Every level is written through its faces as an array, the sky is read as a cube
+    let targets = std::iter::once((irradiance, 0, 0.0))
+        .chain((0..PREFILTERED_LEVELS).map(|level| {
+            (prefiltered, level, level as f32 / (PREFILTERED_LEVELS - 1) as f32)
+        }))
+        .collect::<Vec<_>>();
+    let views = targets
+        .iter()
+        .map(|(image, level, _)| create_face_view(device, image, *level))
+        .collect::<Result<Vec<_>>>()?;
+
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    for image in [irradiance, prefiltered] {
+        memory::cmd_image_barrier(device, command_buffer, image.image, cube_range(),
+            (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+    }
+
+    for (i, ((image, level, roughness), view)) in targets.iter().zip(&views).enumerate() {
+        let set = descriptor::allocate_set(device, descriptor_pool, ibl.set_layout)?;
+        descriptor::write_image(device, set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            data.skybox.cube.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, ibl.sampler);
+        descriptor::write_image(device, set, 1, vk::DescriptorType::STORAGE_IMAGE, *view,
+            vk::ImageLayout::GENERAL, vk::Sampler::null());
+
+        let pipeline = if i == 0 { ibl.irradiance_pipeline } else { ibl.prefilter_pipeline };
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, pipeline);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            ibl.pipeline_layout, 0, &[set], &[]);
+        shader::cmd_push_constants(device, command_buffer, ibl.pipeline_layout, vk::ShaderStageFlags::COMPUTE,
+            &IblPushConstants { roughness: *roughness, sample_count: SAMPLE_COUNT });
+
+        let size = (image.extent.width >> level).max(1);
+        let groups = (size + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE;
+        device.cmd_dispatch(command_buffer, groups, groups, 6);
+    }
+
+    for image in [irradiance, prefiltered] {
+        memory::cmd_image_barrier(device, command_buffer, image.image, cube_range(),
+            (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_READ));
+    }
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    views.iter().for_each(|v| device.destroy_image_view(*v, None));
+    device.destroy_descriptor_pool(descriptor_pool, None);
+    Ok(())
+}
+
+/// Integrates the split sum's BRDF into the lookup table
+unsafe fn integrate_brdf(brdf_lut: &AllocatedImage, device: &Device, data: &EngineData) -> Result<()>
+{
+    let ibl = &data.ibl;
+    let descriptor_pool = descriptor::create_pool(device, &[(vk::DescriptorType::STORAGE_IMAGE, 1)], 1)?;
+    let set = descriptor::allocate_set(device, descriptor_pool, ibl.set_layout)?;
+    descriptor::write_image(device, set, 1, vk::DescriptorType::STORAGE_IMAGE, brdf_lut.image_view,
+        vk::ImageLayout::GENERAL, vk::Sampler::null());
+
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    memory::cmd_image_barrier(device, command_buffer, brdf_lut.image, memory::color_subresource_range(),
+        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+        (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+
+    device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, ibl.brdf_pipeline);
+    device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+        ibl.pipeline_layout, 0, &[set], &[]);
+    shader::cmd_push_constants(device, command_buffer, ibl.pipeline_layout, vk::ShaderStageFlags::COMPUTE,
+        &IblPushConstants { roughness: 0.0, sample_count: SAMPLE_COUNT });
+    let groups = (BRDF_LUT_SIZE + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE;
+    device.cmd_dispatch(command_buffer, groups, groups, 1);
+
+    memory::cmd_image_barrier(device, command_buffer, brdf_lut.image, memory::color_subresource_range(),
+        (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+        (vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ));
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    device.destroy_descriptor_pool(descriptor_pool, None);
+    Ok(())
+}
+
+/// Clears the placeholder cubes to black in the general layout
+unsafe fn clear_cubes(cubes: &[&AllocatedImage], device: &Device, data: &EngineData) -> Result<()>
+{
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    for cube in cubes {
+        memory::cmd_image_barrier(device, command_buffer, cube.image, cube_range(),
+            (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE));
+        let black = vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 1.0] };
+        device.cmd_clear_color_image(command_buffer, cube.image, vk::ImageLayout::GENERAL, &black, &[cube_range()]);
+        memory::cmd_image_barrier(device, command_buffer, cube.image, cube_range(),
+            (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+            (vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_READ));
+    }
+    memory::end_single_time_commands(device, data, command_buffer)
+}
+
+/// Copies cached levels into an image, leaving it in the general layout
+unsafe fn upload_levels(image: &AllocatedImage, bytes: &[u8], extent: vk::Extent2D, mip_levels: u32,
+    layer_count: u32, instance: &Instance, device: &Device, data: &EngineData) -> Result<()>
+{
+    let mut staging = AllocatedBuffer::create(
+        bytes.as_ptr(),
+        bytes.len(),
+        vk::BufferUsageFlags::TRANSFER_SRC,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)?;
+
+    let range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(mip_levels)
+        .base_array_layer(0)
+        .layer_count(layer_count)
+        .build();
+
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    memory::cmd_image_barrier(device, command_buffer, image.image, range,
+        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+        (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+        (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE));
+
+    let regions = memory::level_copy_regions(extent, mip_levels, layer_count, TEXEL_SIZE);
+    device.cmd_copy_buffer_to_image(command_buffer, staging.buffer, image.image,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL, &regions);
+
+    memory::cmd_image_barrier(device, command_buffer, image.image, range,
+        (vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::GENERAL),
+        (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+        (vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ));
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    staging.destroy(device);
+    Ok(())
+}
+
+/// Every level and face of a cube
+fn cube_range() -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(vk::REMAINING_MIP_LEVELS)
+        .base_array_layer(0)
+        .layer_count(6)
+        .build()
+}
+
+/// The six faces of one level of a cube as a 2D array for storage
+unsafe fn create_face_view(device: &Device, cube: &AllocatedImage, level: u32) -> Result<vk::ImageView>
+{
+    let range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(level)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(6);
+    let view_info = vk::ImageViewCreateInfo::builder()
+        .image(cube.image)
+        .view_type(vk::ImageViewType::_2D_ARRAY)
+        .format(cube.format)
+        .subresource_range(range);
+    Ok(device.create_image_view(&view_info, None)?)
+}
+
+fn cache_path(name: &str) -> PathBuf {
+    PathBuf::from(CACHE_DIR).join(format!("{}.ibl", name))
+}
+
+fn cache_header(sizes: &[u32]) -> Vec<u8> {
+    let mut header = CACHE_MAGIC.to_vec();
+    header.extend(sizes.iter().flat_map(|size| size.to_le_bytes()));
+    header
+}
+
+/// The cached texels if the file exists and was integrated with the same sizes
+fn read_cache(path: &Path, header: &[u8], size: usize) -> Option<Vec<u8>> {
+    let bytes = fs::read(path).ok()?;
+    if bytes.len() != header.len() + size || !bytes.starts_with(header) {
+        warn!("Ignoring the outdated lighting cache `{}`.", path.display());
+        return None;
+    }
+    Some(bytes[header.len()..].to_vec())
+}
+
+/// Failing to cache only costs integrating again next time, so it's only a warning
+fn write_cache(path: &Path, header: &[u8], parts: &[&[u8]]) {
+    let result = fs::create_dir_all(CACHE_DIR).and_then(|_| fs::write(path, [header, &parts.concat()].concat()));
+    if let Err(e) = result {
+        warn!("Failed to write the lighting cache `{}`: {}", path.display(), e);
+    }
+}
+
+/// Marks a cache file as just used, so it's the last one evicted
+fn touch_cache(path: &Path) {
+    let result = fs::File::options().write(true).open(path).and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(e) = result {
+        warn!("Failed to touch the lighting cache `{}`: {}", path.display(), e);
+    }
+}
+
+/// Deletes the least recently used environments past `MAX_CACHED_ENVIRONMENTS`, each is over a
+///  megabyte and every sky loaded adds one
+fn evict_environments() {
+    let Some(entries) = fs::read_dir(CACHE_DIR).ok() else { return };
+    let environments = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_str()
+            .is_some_and(|name| name.starts_with("environment_") && name.ends_with(".ibl")))
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .collect();
+
+    for path in least_recently_used(environments, MAX_CACHED_ENVIRONMENTS) {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Failed to evict the lighting cache `{}`: {}", path.display(), e);
+        }
+    }
+}
+
+/// The files past the `keep` most recently used ones
+fn least_recently_used(mut files: Vec<(SystemTime, PathBuf)>, keep: usize) -> Vec<PathBuf> {
+    files.sort_by(|a, b| b.0.cmp(&a.0));
+    files.into_iter().skip(keep).map(|(_, path)| path).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let at = |seconds| SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+        let files = vec![
+            (at(30), PathBuf::from("c")),
+            (at(10), PathBuf::from("a")),
+            (at(40), PathBuf::from("d")),
+            (at(20), PathBuf::from("b")),
+        ];
+        assert_eq!(least_recently_used(files.clone(), 2), [PathBuf::from("b"), PathBuf::from("a")]);
+        assert!(least_recently_used(files, 4).is_empty());
+    }
+}
@@ -0,0 +1,787 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use anyhow::{anyhow, Ok, Result};
+
+/// Writes 8-bit RGBA pixels to a PNG file
+pub fn write_png(path: &Path, width: u32, height: u32, pixels: &[u8]) -> Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    Ok(())
+}
+
+/// Decodes a PNG file into 8-bit RGBA pixels, expanding palettes, greyscale and 16-bit channels
+pub fn read_png(path: &Path) -> Result<(u32, u32, Vec<u8>)> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+
+    let bytes = &buffer[..info.buffer_size()];
+    let pixels = match info.color_type {
+        png::ColorType::Rgba => bytes.to_vec(),
+        png::ColorType::Rgb => bytes.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+        png::ColorType::GrayscaleAlpha => bytes.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        png::ColorType::Grayscale => bytes.iter().flat_map(|&g| [g, g, g, 255]).collect(),
+        png::ColorType::Indexed => return Err(anyhow!("Unexpanded palette in `{}`.", path.display())),
+    };
+    Ok((info.width, info.height, pixels))
+}
+
+/// Decodes a Radiance RGBE (.hdr) file into linear RGBA floats, top row first
+pub fn read_hdr(path: &Path) -> Result<(u32, u32, Vec<f32>)> {
+    let bytes = std::fs::read(path)?;
+    let mut position = 0;
+
+    let magic = read_line(&bytes, &mut position)?;
+    if !magic.starts_with("#?") {
+        return Err(anyhow!("`{}` is not a Radiance file.", path.display()));
+    }
+    loop {
+        let line = read_line(&bytes, &mut position)?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(anyhow!("Unsupported Radiance format `{}`.", format));
+            }
+        }
+    }
+
+    // Only the standard orientation, rows from the top with pixels from the left
+    let resolution = read_line(&bytes, &mut position)?;
+    let (width, height) = match resolution.split_whitespace().collect::<Vec<_>>()[..] {
+        ["-Y", height, "+X", width] => (width.parse::<u32>()?, height.parse::<u32>()?),
+        _ => return Err(anyhow!("Unsupported Radiance orientation `{}`.", resolution)),
+    };
+
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+    let mut scanline = vec![[0u8; 4]; width as usize];
+    for _ in 0..height {
+        read_hdr_scanline(&bytes, &mut position, &mut scanline)?;
+        pixels.extend(scanline.iter().flat_map(|&rgbe| rgbe_to_rgba(rgbe)));
+    }
+    Ok((width, height, pixels))
+}
+
+fn read_line<'a>(bytes: &'a [u8], position: &mut usize) -> Result<&'a str> {
+    let rest = bytes.get(*position..).unwrap_or_default();
+    let end = rest
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| anyhow!("Truncated Radiance header."))?;
+    *position += end + 1;
+    Ok(std::str::from_utf8(&rest[..end])?.trim_end())
+}
+
+/// Reads one scanline of RGBE pixels, either flat or with the adaptive run length encoding that
+///  stores every channel separately. The old per-pixel run length encoding isn't supported.
+fn read_hdr_scanline(bytes: &[u8], position: &mut usize, scanline: &mut [[u8; 4]]) -> Result<()> {
+    let truncated = || anyhow!("Truncated Radiance pixel data.");
+    let width = scanline.len();
+    let header = bytes.get(*position..*position + 4).ok_or_else(truncated)?;
+
+    let encoded = (8..0x8000).contains(&width) && header[0] == 2 && header[1] == 2 && header[2] & 0x80 == 0;
+    if !encoded {
+        for pixel in scanline.iter_mut() {
+            pixel.copy_from_slice(bytes.get(*position..*position + 4).ok_or_else(truncated)?);
+            *position += 4;
+        }
+        return Ok(());
+    }
+
+    if ((header[2] as usize) << 8 | header[3] as usize) != width {
+        return Err(anyhow!("Radiance scanline width doesn't match the image."));
+    }
+    *position += 4;
+
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let count = *bytes.get(*position).ok_or_else(truncated)? as usize;
+            *position += 1;
+            if count > 128 {
+                // A run of one value
+                let count = count - 128;
+                let value = *bytes.get(*position).ok_or_else(truncated)?;
+                *position += 1;
+                let run = scanline.get_mut(x..x + count).ok_or_else(truncated)?;
+                run.iter_mut().for_each(|pixel| pixel[channel] = value);
+                x += count;
+            } else {
+                // A run of distinct values
+                if count == 0 {
+                    return Err(anyhow!("Empty run in Radiance pixel data."));
+                }
+                let values = bytes.get(*position..*position + count).ok_or_else(truncated)?;
+                *position += count;
+                let run = scanline.get_mut(x..x + count).ok_or_else(truncated)?;
+                run.iter_mut().zip(values).for_each(|(pixel, &value)| pixel[channel] = value);
+                x += count;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Shared exponent to linear floats, alpha is opaque
+fn rgbe_to_rgba(rgbe: [u8; 4]) -> [f32; 4] {
+    if rgbe[3] == 0 {
+        return [0.0, 0.0, 0.0, 1.0];
+    }
+    let scale = 2f32.powi(rgbe[3] as i32 - 136);
+    [rgbe[0] as f32 * scale, rgbe[1] as f32 * scale, rgbe[2] as f32 * scale, 1.0]
+}
+
+/// Encodes linear RGBA floats as a Radiance RGBE (.hdr) file, top row first, dropping alpha
+pub fn write_hdr(path: &Path, width: u32, height: u32, pixels: &[f32]) -> Result<()> {
+    check_image_size(width, height, pixels)?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    write!(writer, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width)?;
+    for row in pixels.chunks_exact(width as usize * 4) {
+        let scanline = row.chunks_exact(4).map(rgb_to_rgbe).collect::<Vec<_>>();
+        writer.write_all(&encode_hdr_scanline(&scanline))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Adaptive run length encoding of one scanline, flat where the width can't be encoded
+fn encode_hdr_scanline(scanline: &[[u8; 4]]) -> Vec<u8> {
+    let width = scanline.len();
+    if !(8..0x8000).contains(&width) {
+        return scanline.concat();
+    }
+
+    let mut encoded = vec![2, 2, (width >> 8) as u8, (width & 0xff) as u8];
+    for channel in 0..4 {
+        let values = scanline.iter().map(|pixel| pixel[channel]).collect::<Vec<_>>();
+        let run_length = |x: usize, limit: usize| values[x..].iter().take(limit).take_while(|&&v| v == values[x]).count();
+
+        let mut x = 0;
+        while x < width {
+            let run = run_length(x, 127);
+            if run >= 3 {
+                encoded.extend([128 + run as u8, values[x]]);
+                x += run;
+                continue;
+            }
+
+            // Distinct values up to the next run worth encoding
+            let mut end = x;
+            while end < width && end - x < 128 && run_length(end, 3) < 3 {
+                end += 1;
+            }
+            encoded.push((end - x) as u8);
+            encoded.extend_from_slice(&values[x..end]);
+            x = end;
+        }
+    }
+    encoded
+}
+
+/// Linear floats to a shared exponent, the largest channel keeps 8 bits of precision
+fn rgb_to_rgbe(pixel: &[f32]) -> [u8; 4] {
+    let max = pixel[0].max(pixel[1]).max(pixel[2]);
+    if max.is_nan() || max <= 1e-32 {
+        return [0; 4];
+    }
+    let exponent = (max.log2().floor() as i32 + 1).min(127);
+    let scale = 256.0 / 2f32.powi(exponent);
+    let encode = |c: f32| (c.max(0.0) * scale).min(255.0) as u8;
+    [encode(pixel[0]), encode(pixel[1]), encode(pixel[2]), (exponent + 128) as u8]
+}
+
+/// Decodes a scanline OpenEXR file into linear RGBA floats, top row first. Half and float
+///  channels stored uncompressed or with RLE or ZIP compression are supported, a missing alpha
+///  is opaque and a lone luminance channel is grey.
+pub fn read_exr(path: &Path) -> Result<(u32, u32, Vec<f32>)> {
+    let bytes = std::fs::read(path)?;
+    let mut cursor = ByteCursor { bytes: &bytes, position: 0 };
+
+    if cursor.take(4)? != EXR_MAGIC {
+        return Err(anyhow!("`{}` is not an OpenEXR file.", path.display()));
+    }
+    // Tiled, deep and multipart files
+    let version = cursor.u32()?;
+    if version & 0xff != 2 || version & 0x1a00 != 0 {
+        return Err(anyhow!("Only single part scanline OpenEXR files are supported."));
+    }
+
+    let mut channels = Vec::new();
+    let mut compression = 0;
+    let mut data_window = None;
+    loop {
+        let name = cursor.string()?;
+        if name.is_empty() {
+            break;
+        }
+        let _kind = cursor.string()?;
+        let size = cursor.u32()? as usize;
+        let mut value = ByteCursor { bytes: cursor.take(size)?, position: 0 };
+        match name {
+            "channels" => channels = read_exr_channels(&mut value)?,
+            "compression" => compression = value.u8()?,
+            "dataWindow" => data_window = Some([value.i32()?, value.i32()?, value.i32()?, value.i32()?]),
+            _ => {},
+        }
+    }
+
+    let [x_min, y_min, x_max, y_max] = data_window.ok_or_else(|| anyhow!("OpenEXR file without a data window."))?;
+    if x_max < x_min || y_max < y_min {
+        return Err(anyhow!("Empty OpenEXR data window."));
+    }
+    // A window spanning most of the i32 range is as corrupt as an inverted one
+    let span = |min: i32, max: i32| {
+        max.checked_sub(min)
+            .and_then(|span| span.checked_add(1))
+            .and_then(|span| usize::try_from(span).ok())
+            .ok_or_else(|| anyhow!("OpenEXR data window too large."))
+    };
+    let width = span(x_min, x_max)?;
+    let height = span(y_min, y_max)?;
+    let pixel_count = width
+        .checked_mul(height)
+        .filter(|count| count.checked_mul(4).is_some())
+        .ok_or_else(|| anyhow!("OpenEXR data window too large."))?;
+    let lines_per_chunk = match compression {
+        0..=2 => 1,
+        3 => 16,
+        _ => return Err(anyhow!("Unsupported OpenEXR compression {}.", compression)),
+    };
+    let line_size = channels.iter().map(|c| c.size * width).sum::<usize>();
+
+    let mut pixels = [0.0, 0.0, 0.0, 1.0].repeat(pixel_count);
+    let chunk_count = height.div_ceil(lines_per_chunk);
+    let offsets = (0..chunk_count).map(|_| cursor.u64()).collect::<Result<Vec<_>>>()?;
+    for offset in offsets {
+        let mut chunk = ByteCursor { bytes: &bytes, position: offset as usize };
+        let y = chunk.i32()?
+            .checked_sub(y_min)
+            .and_then(|y| usize::try_from(y).ok())
+            .filter(|y| *y < height)
+            .ok_or_else(|| anyhow!("OpenEXR chunk outside the data window."))?;
+        let size = chunk.u32()? as usize;
+        let stored = chunk.take(size)?;
+
+        let lines = lines_per_chunk.min(height.saturating_sub(y));
+        let expected = line_size * lines;
+        // Chunks that wouldn't shrink are stored as they are
+        let data = if size == expected {
+            stored.to_vec()
+        } else if compression == 1 {
+            exr_unpredict(exr_rle_decode(stored, expected)?)
+        } else {
+            let inflated = miniz_oxide::inflate::decompress_to_vec_zlib(stored)
+                .map_err(|e| anyhow!("Corrupt OpenEXR chunk: {:?}.", e))?;
+            exr_unpredict(inflated)
+        };
+        if data.len() != expected {
+            return Err(anyhow!("OpenEXR chunk of the wrong size."));
+        }
+
+        // Each line holds every channel's row in turn
+        let mut values = ByteCursor { bytes: &data, position: 0 };
+        for line in y..y + lines {
+            for channel in &channels {
+                for x in 0..width {
+                    let value = if channel.size == 2 { f16_to_f32(values.u16()?) } else { values.f32()? };
+                    let pixel = &mut pixels[(line * width + x) * 4..][..4];
+                    match channel.name.as_str() {
+                        "R" => pixel[0] = value,
+                        "G" => pixel[1] = value,
+                        "B" => pixel[2] = value,
+                        "A" => pixel[3] = value,
+                        "Y" => pixel[..3].fill(value),
+                        _ => {},
+                    }
+                }
+            }
+        }
+    }
+    Ok((width as u32, height as u32, pixels))
+}
+
+/// Encodes linear RGBA floats as a ZIP compressed OpenEXR file of half float RGB, top row first
+pub fn write_exr(path: &Path, width: u32, height: u32, pixels: &[f32]) -> Result<()> {
+    check_image_size(width, height, pixels)?;
+    let mut header = EXR_MAGIC.to_vec();
+    header.extend(2u32.to_le_bytes());
+
+    let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+        header.extend([name.as_bytes(), &[0], kind.as_bytes(), &[0]].concat());
+        header.extend((value.len() as u32).to_le_bytes());
+        header.extend(value);
+    };
+
+    // Channels are listed alphabetically, as half floats without subsampling
+    let channels = ["B", "G", "R"]
+        .iter()
+        .flat_map(|name| [name.as_bytes(), &[0], &1i32.to_le_bytes(), &[0; 4], &1i32.to_le_bytes(),
+            &1i32.to_le_bytes()].concat())
+        .chain([0])
+        .collect::<Vec<_>>();
+    let window = [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect::<Vec<_>>();
+    attribute("channels", "chlist", &channels);
+    attribute("compression", "compression", &[3]);
+    attribute("dataWindow", "box2i", &window);
+    attribute("displayWindow", "box2i", &window);
+    attribute("lineOrder", "lineOrder", &[0]);
+    attribute("pixelAspectRatio", "float", &1f32.to_le_bytes());
+    attribute("screenWindowCenter", "v2f", &[0u8; 8]);
+    attribute("screenWindowWidth", "float", &1f32.to_le_bytes());
+    header.push(0);
+
+    let width = width as usize;
+    let rows = pixels.chunks_exact(width * 4).collect::<Vec<_>>();
+    let chunks = rows
+        .chunks(16)
+        .enumerate()
+        .map(|(i, lines)| {
+            let raw = lines
+                .iter()
+                .flat_map(|row| [2, 1, 0].into_iter().flat_map(move |channel| {
+                    row.chunks_exact(4).flat_map(move |pixel| f32_to_f16(pixel[channel]).to_le_bytes())
+                }))
+                .collect::<Vec<_>>();
+            let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&exr_predict(&raw), 6);
+            let data = if compressed.len() < raw.len() { compressed } else { raw };
+            [&((i * 16) as i32).to_le_bytes()[..], &(data.len() as u32).to_le_bytes(), &data[..]].concat()
+        })
+        .collect::<Vec<_>>();
+
+    // The offset table points at every chunk from the start of the file
+    let mut offset = (header.len() + chunks.len() * 8) as u64;
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&header)?;
+    for chunk in &chunks {
+        writer.write_all(&offset.to_le_bytes())?;
+        offset += chunk.len() as u64;
+    }
+    for chunk in &chunks {
+        writer.write_all(chunk)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+const EXR_MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+
+struct ExrChannel {
+    name: String,
+    /// Bytes per value, 2 for half and 4 for float
+    size: usize,
+}
+
+fn read_exr_channels(cursor: &mut ByteCursor) -> Result<Vec<ExrChannel>> {
+    let mut channels = Vec::new();
+    loop {
+        let name = cursor.string()?;
+        if name.is_empty() {
+            return Ok(channels);
+        }
+        let pixel_type = cursor.i32()?;
+        cursor.take(4)?;
+        let sampling = [cursor.i32()?, cursor.i32()?];
+        let size = match pixel_type {
+            1 => 2,
+            2 => 4,
+            _ => return Err(anyhow!("Unsupported OpenEXR channel type of `{}`.", name)),
+        };
+        if sampling != [1, 1] {
+            return Err(anyhow!("Subsampled OpenEXR channel `{}`.", name));
+        }
+        channels.push(ExrChannel { name: name.to_string(), size });
+    }
+}
+
+/// Splits the bytes into the even then the odd ones and stores each as the difference to the
+///  previous, which ZIP and RLE compressed OpenEXR chunks are filtered with
+fn exr_predict(raw: &[u8]) -> Vec<u8> {
+    let mut filtered = raw.iter().step_by(2).chain(raw.iter().skip(1).step_by(2)).copied().collect::<Vec<_>>();
+    for i in (1..filtered.len()).rev() {
+        filtered[i] = filtered[i].wrapping_sub(filtered[i - 1]).wrapping_add(128);
+    }
+    filtered
+}
+
+fn exr_unpredict(mut filtered: Vec<u8>) -> Vec<u8> {
+    for i in 1..filtered.len() {
+        filtered[i] = filtered[i - 1].wrapping_add(filtered[i]).wrapping_sub(128);
+    }
+    let half = filtered.len().div_ceil(2);
+    (0..filtered.len())
+        .map(|i| if i % 2 == 0 { filtered[i / 2] } else { filtered[half + i / 2] })
+        .collect()
+}
+
+/// Negative counts are followed by that many distinct bytes, others repeat the next byte count + 1 times
+fn exr_rle_decode(encoded: &[u8], size: usize) -> Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(size);
+    let mut cursor = ByteCursor { bytes: encoded, position: 0 };
+    while cursor.position < encoded.len() && decoded.len() < size {
+        let count = cursor.u8()? as i8;
+        if count < 0 {
+            decoded.extend_from_slice(cursor.take(-(count as i32) as usize)?);
+        } else {
+            let value = cursor.u8()?;
+            decoded.extend(std::iter::repeat_n(value, count as usize + 1));
+        }
+    }
+    Ok(decoded)
+}
+
+/// Little endian reads through a byte slice
+struct ByteCursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteCursor<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        let bytes = self.bytes
+            .get(self.position..self.position + count)
+            .ok_or_else(|| anyhow!("Unexpected end of file."))?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    /// A null terminated string
+    fn string(&mut self) -> Result<&'a str> {
+        let rest = self.bytes.get(self.position..).unwrap_or_default();
+        let end = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| anyhow!("Unterminated string."))?;
+        self.position += end + 1;
+        Ok(std::str::from_utf8(&rest[..end])?)
+    }
+}
+
+/// Whether the path names a linear HDR image format, .hdr or .exr
+pub fn is_linear_image(path: &Path) -> bool {
+    matches!(lowercase_extension(path).as_deref(), Some("hdr" | "exr"))
+}
+
+/// Reads a Radiance .hdr or OpenEXR .exr file by its extension
+pub fn read_linear_image(path: &Path) -> Result<(u32, u32, Vec<f32>)> {
+    match lowercase_extension(path).as_deref() {
+        Some("hdr") => read_hdr(path),
+        Some("exr") => read_exr(path),
+        _ => Err(anyhow!("`{}` isn't a .hdr or .exr image.", path.display())),
+    }
+}
+
+/// Writes linear RGBA floats to a Radiance .hdr or OpenEXR .exr file by its extension
+pub fn write_linear_image(path: &Path, width: u32, height: u32, pixels: &[f32]) -> Result<()> {
+    match lowercase_extension(path).as_deref() {
+        Some("hdr") => write_hdr(path, width, height, pixels),
+        Some("exr") => write_exr(path, width, height, pixels),
+        _ => Err(anyhow!("`{}` isn't a .hdr or .exr image.", path.display())),
+    }
+}
+
+/// Writers need at least one pixel, and exactly `width` by `height` of them
+fn check_image_size(width: u32, height: u32, pixels: &[f32]) -> Result<()> {
+    if width == 0 || height == 0 {
+        return Err(anyhow!("Can't write an empty {}x{} image.", width, height));
+    }
+    if pixels.len() != width as usize * height as usize * 4 {
+        return Err(anyhow!("{} floats don't make a {}x{} RGBA image.", pixels.len(), width, height));
+    }
+    Ok(())
+}
+
+fn lowercase_extension(path: &Path) -> Option<String> {
+    path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase())
+}
+
+/// Decodes an sRGB encoded value back to linear
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts to an IEEE half float, rounding to nearest even and saturating to infinity
+pub fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    // NaN stays NaN, infinity stays infinity
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let half_exponent = exponent - 127 + 15;
+    if half_exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    if half_exponent <= 0 {
+        // Subnormal or zero
+        if half_exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - half_exponent) as u32;
+        let half_mantissa = mantissa >> shift;
+        let remainder = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        let round = (remainder > halfway || (remainder == halfway && half_mantissa & 1 == 1)) as u32;
+        return sign | (half_mantissa + round) as u16;
+    }
+
+    let half_mantissa = mantissa >> 13;
+    let remainder = mantissa & 0x1fff;
+    let round = (remainder > 0x1000 || (remainder == 0x1000 && half_mantissa & 1 == 1)) as u32;
+    // A carry out of the mantissa correctly bumps the exponent
+    sign | (((half_exponent as u32) << 10) + half_mantissa + round) as u16
+}
+
+/// Converts an IEEE half float, exactly
+pub fn f16_to_f32(half: u16) -> f32 {
+    let sign = ((half & 0x8000) as u32) << 16;
+    let exponent = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+    match exponent {
+        // Zero and subnormals, mantissa * 2^-24
+        0 => {
+            let magnitude = mantissa as f32 / 16_777_216.0;
+            if sign != 0 { -magnitude } else { magnitude }
+        },
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exponent + 127 - 15) << 23) | (mantissa << 13)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("fstop_image_io_{}_{}", std::process::id(), name))
+    }
+
+    /// A gradient over several orders of magnitude with a flat blue channel, wide enough for the
+    ///  Radiance run length encoding and tall enough for several OpenEXR chunks
+    fn gradient(width: u32, height: u32) -> Vec<f32> {
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                let t = (x + y * width) as f32 / (width * height - 1) as f32;
+                [0.001 * 10f32.powf(5.0 * t), 1.0 - t, 0.5, 1.0]
+            })
+            .collect()
+    }
+
+    /// Writes then reads the gradient, checking every channel against a tolerance relative to the
+    ///  pixel's brightest channel
+    fn round_trip(name: &str, tolerance: f32) {
+        let (width, height) = (9, 18);
+        let pixels = gradient(width, height);
+        let path = temp_path(name);
+        write_linear_image(&path, width, height, &pixels).unwrap();
+        let read = read_linear_image(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let (read_width, read_height, read) = read.unwrap();
+        assert_eq!((read_width, read_height), (width, height));
+        assert_eq!(read.len(), pixels.len());
+        for (expected, actual) in pixels.chunks_exact(4).zip(read.chunks_exact(4)) {
+            let max = expected[0].max(expected[1]).max(expected[2]);
+            for channel in 0..3 {
+                let error = (expected[channel] - actual[channel]).abs();
+                assert!(error <= max * tolerance, "{:?} read back as {:?}", expected, actual);
+            }
+            assert_eq!(actual[3], 1.0);
+        }
+    }
+
+    #[test]
+    fn hdr_round_trips_a_gradient() {
+        // 8 bits of mantissa on the brightest channel
+        round_trip("gradient.hdr", 1.0 / 128.0);
+    }
+
+    #[test]
+    fn exr_round_trips_a_gradient() {
+        // 11 bits of half float precision, relative to each channel on its own
+        round_trip("gradient.exr", 1.0 / 1024.0);
+    }
+
+    /// Reads back a 1x1 OpenEXR file after `patch` corrupted its bytes
+    fn read_patched_exr(name: &str, patch: impl FnOnce(&mut Vec<u8>)) -> Result<(u32, u32, Vec<f32>)> {
+        let path = temp_path(name);
+        write_exr(&path, 1, 1, &[1.0, 1.0, 1.0, 1.0]).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        patch(&mut bytes);
+        std::fs::write(&path, &bytes).unwrap();
+        let result = read_exr(&path);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    /// Where the data window's x_min, y_min, x_max, y_max start, after the attribute's name, type
+    ///  and size
+    fn data_window(bytes: &[u8]) -> usize {
+        let name = b"dataWindow\0box2i\0";
+        bytes.windows(name.len()).position(|w| w == name).unwrap() + name.len() + 4
+    }
+
+    #[test]
+    fn exr_rejects_an_inverted_data_window() {
+        let result = read_patched_exr("inverted.exr", |bytes| {
+            let start = data_window(bytes);
+            bytes[start + 8..start + 12].copy_from_slice(&(-5i32).to_le_bytes());
+        });
+        assert!(result.unwrap_err().to_string().contains("data window"));
+    }
+
+    #[test]
+    fn exr_rejects_an_overflowing_data_window() {
+        let result = read_patched_exr("overflowing.exr", |bytes| {
+            let start = data_window(bytes);
+            bytes[start..start + 4].copy_from_slice(&i32::MIN.to_le_bytes());
+            bytes[start + 8..start + 12].copy_from_slice(&i32::MAX.to_le_bytes());
+        });
+        assert!(result.unwrap_err().to_string().contains("too large"));
+    }
+
+    #[test]
+    fn exr_rejects_a_chunk_outside_the_data_window() {
+        let result = read_patched_exr("outside.exr", |bytes| {
+            // The single chunk follows its offset right after the header, and starts with its first line
+            let offset = (0..bytes.len() - 8)
+                .map(|p| p + 8)
+                .find(|&p| u64::from_le_bytes(bytes[p - 8..p].try_into().unwrap()) == p as u64)
+                .unwrap();
+            bytes[offset..offset + 4].copy_from_slice(&7i32.to_le_bytes());
+        });
+        assert!(result.unwrap_err().to_string().contains("outside the data window"));
+    }
+
+    #[test]
+    fn writers_reject_empty_images() {
+        for name in ["empty.hdr", "empty.exr"] {
+            let path = temp_path(name);
+            for (width, height) in [(0, 4), (4, 0), (0, 0)] {
+                let error = write_linear_image(&path, width, height, &[]).unwrap_err();
+                assert!(error.to_string().contains("empty"), "{}", error);
+            }
+            assert!(!path.exists());
+        }
+    }
+
+    #[test]
+    fn exr_unpredict_inverts_predict() {
+        // The even bytes then the odd ones, each stored as the difference to the previous plus 128
+        assert_eq!(exr_predict(&[1, 2, 3, 4]), [1, 130, 127, 130]);
+
+        for length in [0, 1, 2, 7, 64, 255] {
+            let raw = (0..length).map(|i| (i * 37 % 256) as u8).collect::<Vec<_>>();
+            assert_eq!(exr_unpredict(exr_predict(&raw)), raw);
+        }
+    }
+
+    #[test]
+    fn exr_rle_decodes_literal_and_run_packets() {
+        // 3 distinct bytes, then 7 repeated 3 times
+        let encoded = [(-3i8) as u8, 1, 2, 3, 2, 7];
+        assert_eq!(exr_rle_decode(&encoded, 6).unwrap(), [1, 2, 3, 7, 7, 7]);
+
+        // Decoding stops once the expected size is reached
+        assert_eq!(exr_rle_decode(&encoded, 3).unwrap(), [1, 2, 3]);
+
+        // A literal packet running past the end of the data
+        assert!(exr_rle_decode(&[(-4i8) as u8, 1, 2], 4).is_err());
+    }
+
+    #[test]
+    fn f16_round_trips_every_value() {
+        for half in 0..=u16::MAX {
+            let value = f16_to_f32(half);
+            if value.is_nan() {
+                continue;
+            }
+            assert_eq!(f32_to_f16(value), half, "{:#06x} converted through {}", half, value);
+        }
+    }
+
+    #[test]
+    fn f16_handles_subnormals() {
+        let smallest = 2f32.powi(-24);
+        assert_eq!(f16_to_f32(0x0001), smallest);
+        assert_eq!(f16_to_f32(0x03ff), 1023.0 * smallest);
+        assert_eq!(f16_to_f32(0x8001), -smallest);
+        assert_eq!(f32_to_f16(smallest), 0x0001);
+
+        // Halfway cases round to even, anything smaller than half the smallest flushes to zero
+        assert_eq!(f32_to_f16(0.5 * smallest), 0x0000);
+        assert_eq!(f32_to_f16(1.5 * smallest), 0x0002);
+        assert_eq!(f32_to_f16(2.5 * smallest), 0x0002);
+        assert_eq!(f32_to_f16(0.25 * smallest), 0x0000);
+        assert_eq!(f32_to_f16(-0.0), 0x8000);
+
+        // The largest subnormal rounds up into the smallest normal
+        assert_eq!(f32_to_f16(1023.75 * smallest), 0x0400);
+    }
+
+    #[test]
+    fn f16_handles_infinity_and_nan() {
+        assert_eq!(f32_to_f16(f32::INFINITY), 0x7c00);
+        assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfc00);
+        assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+        assert_eq!(f16_to_f32(0xfc00), f32::NEG_INFINITY);
+
+        // The largest half, and the first value rounding past it saturates to infinity
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(65520.0), 0x7c00);
+        assert_eq!(f32_to_f16(1e10), 0x7c00);
+
+        let nan = f32_to_f16(f32::NAN);
+        assert_eq!(nan & 0x7c00, 0x7c00);
+        assert_ne!(nan & 0x03ff, 0);
+        assert!(f16_to_f32(0x7e00).is_nan());
+        assert!(f16_to_f32(0xfe01).is_nan());
+    }
+}
@@ -0,0 +1,324 @@
+use std::mem::size_of;
+use cgmath::Rad;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::camera::Camera;
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::AllocatedImage;
+use super::post;
+use super::shader;
+
+/// Strongest pincushion distortion, past it the mapping stops being invertible
+const MIN_DISTORTION: f32 = -0.3;
+
+/// Grain amplitude at ISO 100 with `grain` at 1
+const BASE_GRAIN: f32 = 0.02;
+
+/// The imperfections of the physical camera's lens and sensor. Distances are relative to the
+///  half diagonal of the image, so the corners are at 1.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LensSettings {
+    pub enabled: bool,
+    /// Scales the darkening of the corners, which is strongest with the aperture wide open
+    pub vignetting: f32,
+    /// How far apart red and blue are magnified at the corners, 0.002 looks like a cheap lens
+    pub chromatic_aberration: f32,
+    /// Radial distortion, positive bows straight lines outwards (barrel), negative pinches them (pincushion)
+    pub distortion: f32,
+    /// Scales the film grain, which grows with the camera's ISO
+    pub grain: f32,
+}
+
+impl Default for LensSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            vignetting: 1.0,
+            chromatic_aberration: 0.0,
+            distortion: 0.0,
+            grain: 1.0,
+        }
+    }
+}
+
+impl LensSettings {
+    fn distortion_coefficient(&self) -> f32 {
+        self.distortion.clamp(MIN_DISTORTION, 1.0)
+    }
+
+    /// Zoom keeping the corners of a barrel distorted image inside the rendered one
+    fn distortion_scale(&self) -> f32 {
+        1.0 / (1.0 + self.distortion_coefficient().max(0.0))
+    }
+
+    /// Where a point of the distorted image, in 0..1, was in the rendered one
+    pub fn undistort(&self, point: [f32; 2], aspect: f32) -> [f32; 2] {
+        self.channel_sources(point, aspect)[1]
+    }
+
+    /// Where the red, green and blue of a point of the distorted image are read from in the rendered
+    ///  one, like lens.comp does. Red and blue are magnified apart around the centre.
+    pub fn channel_sources(&self, point: [f32; 2], aspect: f32) -> [[f32; 2]; 3] {
+        let (x, y) = to_centered(point, aspect);
+        let k = self.distortion_coefficient();
+        let scale = (1.0 + k * (x * x + y * y)) * self.distortion_scale();
+        let ca = self.chromatic_aberration;
+        [1.0 + ca, 1.0, 1.0 - ca].map(|magnification| {
+            from_centered(x * scale * magnification, y * scale * magnification, aspect)
+        })
+    }
+
+    /// Where a point of the rendered image, in 0..1, ends up after the distortion. Inverts `undistort`.
+    pub fn distort(&self, point: [f32; 2], aspect: f32) -> [f32; 2] {
+        let (x, y) = to_centered(point, aspect);
+        let target = (x * x + y * y).sqrt();
+        if target < 1e-6 {
+            return point;
+        }
+
+        // Newton's method on scale * (r + k r³) = target, which is monotonic for the allowed coefficients
+        let k = self.distortion_coefficient();
+        let scale = self.distortion_scale();
+        let mut r = target;
+        for _ in 0..8 {
+            let f = scale * (r + k * r * r * r) - target;
+            let df = scale * (1.0 + 3.0 * k * r * r);
+            r -= f / df;
+        }
+
+        let ratio = r / target;
+        from_centered(x * ratio, y * ratio, aspect)
+    }
+}
+
+/// Screen coordinates centred on the image, with the corners at a distance of 1
+fn to_centered(point: [f32; 2], aspect: f32) -> (f32, f32) {
+    let half_diagonal = (aspect * aspect + 1.0).sqrt();
+    ((point[0] * 2.0 - 1.0) * aspect / half_diagonal, (point[1] * 2.0 - 1.0) / half_diagonal)
+}
+
+fn from_centered(x: f32, y: f32, aspect: f32) -> [f32; 2] {
+    let half_diagonal = (aspect * aspect + 1.0).sqrt();
+    [(x * half_diagonal / aspect + 1.0) / 2.0, (y * half_diagonal + 1.0) / 2.0]
+}
+
+/// Mirrors `Lens` in `lens.comp`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct LensPushConstants {
+    aspect: f32,
+    distortion: f32,
+    distortion_scale: f32,
+    chromatic_aberration: f32,
+}
+
+/// Mirrors the sensor members of `Tonemap` in `tonemap.frag`. The default leaves the image untouched.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SensorConstants {
+    aspect: f32,
+    distortion: f32,
+    distortion_scale: f32,
+    tan_half_diagonal: f32,
+    optical_vignetting: f32,
+    vignetting: f32,
+    grain: f32,
+    seed: u32,
+}
+
+/// The distortion and chromatic aberration of the physical camera's lens, applied to the HDR target
+///  before it is tonemapped. Vignetting and grain are left to the tonemap pass, which gets them from
+///  `sensor` and vignettes the linear scene ahead of the curve.
+#[derive(Clone, Debug, Default)]
+pub struct Lens {
+    pub settings: LensSettings,
+    frame_index: u32,
+    sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+    set: vk::DescriptorSet,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+}
+
+impl Lens {
+    /// The settings in effect for `camera`, the lens only exists on a physical camera
+    pub fn active_settings(&self, camera: &Camera) -> Option<LensSettings> {
+        camera.physical.map(|_| self.settings).filter(|s| s.enabled)
+    }
+
+    fn push_constants(&self, camera: &Camera, extent: vk::Extent3D) -> Option<LensPushConstants> {
+        let settings = self.active_settings(camera)?;
+        Some(LensPushConstants {
+            aspect: extent.width as f32 / extent.height as f32,
+            distortion: settings.distortion_coefficient(),
+            distortion_scale: settings.distortion_scale(),
+            chromatic_aberration: settings.chromatic_aberration,
+        })
+    }
+
+    /// The vignetting and grain the tonemap pass applies for `camera`, none without a physical camera
+    pub fn sensor(&self, camera: &Camera, extent: vk::Extent2D) -> SensorConstants {
+        let (Some(settings), Some(lens)) = (self.active_settings(camera), camera.physical) else {
+            return SensorConstants::default();
+        };
+        let aspect = extent.width as f32 / extent.height as f32;
+
+        // Angle between the lens axis and the corners, for the cos⁴ falloff of natural vignetting
+        let tan_half_fov = (Rad::from(camera.vertical_fov()).0 / 2.0).tan();
+        let tan_half_diagonal = tan_half_fov * (aspect * aspect + 1.0).sqrt();
+
+        // The lens barrel clips the light reaching the corners wide open, which stopping down hides
+        let optical_vignetting = (2.0 / lens.aperture.max(0.5)).min(1.0);
+
+        SensorConstants {
+            aspect,
+            distortion: settings.distortion_coefficient(),
+            distortion_scale: settings.distortion_scale(),
+            tan_half_diagonal,
+            optical_vignetting,
+            vignetting: settings.vignetting.max(0.0),
+            grain: settings.grain.max(0.0) * BASE_GRAIN * (lens.iso.max(1.0) / 100.0).sqrt(),
+            seed: self.frame_index,
+        }
+    }
+
+    /// Runs the lens pass over the HDR target through the scratch image, does nothing without a physical
+    ///  camera or when the lens neither distorts nor splits the colours. Moves the grain on either way.
+    pub unsafe fn cmd_render(&mut self, device: &Device, command_buffer: vk::CommandBuffer, camera: &Camera,
+        scratch: &AllocatedImage, hdr: &AllocatedImage)
+    {
+        let Some(push_constants) = self.push_constants(camera, hdr.extent) else {
+            return;
+        };
+        self.frame_index = self.frame_index.wrapping_add(1);
+        if push_constants.distortion == 0.0 && push_constants.chromatic_aberration == 0.0 {
+            return;
+        }
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 0, &[self.set], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, &push_constants);
+        post::cmd_dispatch_pixels(device, command_buffer, hdr.extent_2d());
+        post::cmd_write_back(device, command_buffer, scratch, hdr);
+    }
+}
+
+pub unsafe fn create_lens(device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let lens = &mut data.lens;
+    lens.settings = LensSettings::default();
+    lens.sampler = descriptor::create_sampler(device, vk::Filter::LINEAR, 0.0)?;
+    lens.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    lens.descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1),
+        (vk::DescriptorType::STORAGE_IMAGE, 1),
+    ], 1)?;
+    lens.set = descriptor::allocate_set(device, lens.descriptor_pool, lens.set_layout)?;
+
+    lens.pipeline_layout = descriptor::create_pipeline_layout(device, &[lens.set_layout],
+        size_of::<LensPushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/lens_comp.spv"));
+    lens.pipeline = shader::create_compute_pipeline(device, &comp[..], lens.pipeline_layout)?;
+
+    create_lens_targets(device, data);
+    Ok(())
+}
+
+/// Points the descriptors at the current HDR and scratch images
+pub unsafe fn create_lens_targets(device: &Device, data: &mut EngineData)
+{
+    let lens = &data.lens;
+    descriptor::write_image(device, lens.set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        data.hdr_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, lens.sampler);
+    descriptor::write_image(device, lens.set, 1, vk::DescriptorType::STORAGE_IMAGE,
+        data.post_image.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+}
+
+pub unsafe fn destroy_lens(device: &Device, data: &mut EngineData)
+{
+    let lens = &mut data.lens;
+    device.destroy_pipeline(lens.pipeline, None);
+    device.destroy_pipeline_layout(lens.pipeline_layout, None);
+    device.destroy_descriptor_pool(lens.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(lens.set_layout, None);
+    device.destroy_sampler(lens.sampler, None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASPECTS: [f32; 3] = [1.0, 16.0 / 9.0, 0.5];
+
+    fn lens(distortion: f32, chromatic_aberration: f32) -> LensSettings {
+        LensSettings { distortion, chromatic_aberration, ..Default::default() }
+    }
+
+    /// A grid over the whole image, corners and edges included
+    fn points() -> impl Iterator<Item = [f32; 2]> {
+        (0..=10).flat_map(|x| (0..=10).map(move |y| [x as f32 / 10.0, y as f32 / 10.0]))
+    }
+
+    fn assert_near(actual: [f32; 2], expected: [f32; 2]) {
+        let error = (actual[0] - expected[0]).abs().max((actual[1] - expected[1]).abs());
+        assert!(error < 1e-4, "{:?} instead of {:?}", actual, expected);
+    }
+
+    #[test]
+    fn distort_inverts_undistort() {
+        for distortion in [MIN_DISTORTION, -0.1, 0.0, 0.25, 1.0] {
+            let lens = lens(distortion, 0.0);
+            for aspect in ASPECTS {
+                for point in points() {
+                    assert_near(lens.distort(lens.undistort(point, aspect), aspect), point);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn undistort_inverts_distort_for_barrel_distortion() {
+        // Pincushion distortion pulls the corners in, so the rendered corners have no distorted point
+        for distortion in [0.0, 0.25, 1.0] {
+            let lens = lens(distortion, 0.0);
+            for aspect in ASPECTS {
+                for point in points() {
+                    assert_near(lens.undistort(lens.distort(point, aspect), aspect), point);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn barrel_distortion_keeps_the_corners_inside() {
+        for distortion in [0.25, 1.0] {
+            for aspect in ASPECTS {
+                let corner = lens(distortion, 0.0).undistort([1.0, 1.0], aspect);
+                assert_near(corner, [1.0, 1.0]);
+            }
+        }
+    }
+
+    #[test]
+    fn chromatic_aberration_vanishes_at_the_centre() {
+        let lens = lens(0.2, 0.01);
+        for aspect in ASPECTS {
+            for source in lens.channel_sources([0.5, 0.5], aspect) {
+                assert_near(source, [0.5, 0.5]);
+            }
+
+            // Away from it red is read further out than green and blue further in
+            let [red, green, blue] = lens.channel_sources([0.9, 0.8], aspect);
+            assert!(red[0] > green[0] && green[0] > blue[0]);
+            assert!(red[1] > green[1] && green[1] > blue[1]);
+        }
+    }
+}
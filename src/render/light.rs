@@ -0,0 +1,184 @@
+use std::mem::size_of;
+use cgmath::{point3, vec3, Deg, InnerSpace, Rad};
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::camera::{Point3, Vec3};
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::AllocatedBuffer;
+use super::point_shadow::DEFAULT_POINT_SHADOW_SIZE;
+use super::scene::{LightKind, Scene};
+use super::shadow::CASCADE_SIZE;
+use super::spot_shadow::SPOT_SHADOW_SIZE;
+
+/// Lights the storage buffer has room for before it first grows
+const INITIAL_LIGHT_CAPACITY: usize = 16;
+
+/// A punctual light. Intensities are physical: lux (lm/m²) for directional lights
+///  and candela (lm/sr) for point and spot lights, the same units as KHR_lights_punctual.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    /// Linear RGB tint, multiplied by `intensity`
+    pub color: Vec3,
+    pub intensity: f32,
+    /// Distance at which point and spot lights fade out completely, `None` falls off with the inverse square forever
+    pub range: Option<f32>,
+    pub position: Point3,
+    /// The direction the light travels, ignored by point lights
+    pub direction: Vec3,
+    /// Requested shadow map size, 0 casts no shadow. Spot lights all share `SPOT_SHADOW_SIZE`.
+    pub shadow_resolution: u32,
+}
+
+impl Light {
+    /// A light infinitely far away, like the sun (~100 000 lux at noon)
+    pub fn directional(direction: Vec3, color: Vec3, illuminance: f32) -> Self {
+        Self {
+            kind: LightKind::Directional,
+            color,
+            intensity: illuminance,
+            range: None,
+            position: point3(0.0, 0.0, 0.0),
+            direction: direction.normalize(),
+            shadow_resolution: CASCADE_SIZE,
+        }
+    }
+
+    /// A light radiating equally in every direction, like a bulb (a 100 W incandescent is ~140 cd)
+    pub fn point(position: Point3, color: Vec3, intensity: f32, range: Option<f32>) -> Self {
+        Self {
+            kind: LightKind::Point,
+            color,
+            intensity,
+            range,
+            position,
+            direction: vec3(0.0, 0.0, -1.0),
+            shadow_resolution: DEFAULT_POINT_SHADOW_SIZE,
+        }
+    }
+
+    /// A point light restricted to a cone, fading between the inner and outer angles
+    pub fn spot(position: Point3, direction: Vec3, color: Vec3, intensity: f32, range: Option<f32>,
+        inner_cone_angle: Deg<f32>, outer_cone_angle: Deg<f32>) -> Self
+    {
+        Self {
+            kind: LightKind::Spot {
+                inner_cone_angle: Rad::from(inner_cone_angle).0,
+                outer_cone_angle: Rad::from(outer_cone_angle).0,
+            },
+            color,
+            intensity,
+            range,
+            position,
+            direction: direction.normalize(),
+            shadow_resolution: SPOT_SHADOW_SIZE,
+        }
+    }
+
+    /// The packed form read by the shaders, `shadow` is filled in by the point or spot shadow pass
+    fn to_gpu(&self, shadow: [f32; 4]) -> GpuLight {
+        let (kind, cone) = match self.kind {
+            LightKind::Directional => (0.0, [0.0; 4]),
+            LightKind::Point => (1.0, [0.0; 4]),
+            LightKind::Spot { inner_cone_angle, outer_cone_angle } => {
+                // Angular attenuation as in the KHR_lights_punctual reference
+                let cos_outer = outer_cone_angle.cos();
+                let cos_inner = inner_cone_angle.cos();
+                let scale = 1.0 / (cos_inner - cos_outer).max(0.001);
+                (2.0, [scale, -cos_outer * scale, 0.0, 0.0])
+            },
+        };
+
+        let radiance = self.color * self.intensity;
+        GpuLight {
+            position: [self.position.x, self.position.y, self.position.z, kind],
+            direction: [self.direction.x, self.direction.y, self.direction.z, self.range.unwrap_or(0.0)],
+            color: [radiance.x, radiance.y, radiance.z, 0.0],
+            cone,
+            shadow,
+        }
+    }
+}
+
+/// Mirrors `Light` in `lights.glsl` (std430)
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct GpuLight {
+    position: [f32; 4],
+    direction: [f32; 4],
+    color: [f32; 4],
+    cone: [f32; 4],
+    shadow: [f32; 4],
+}
+
+/// Every node of the scene carrying a light, placed at the node. glTF lights shine down their local -Z.
+pub fn scene_lights(scene: &Scene) -> Vec<Light> {
+    scene.scene_nodes()
+        .filter_map(|n| n.light.map(|l| (&n.world_transform, &scene.lights[l])))
+        .map(|(world, light)| Light {
+            kind: light.kind,
+            color: light.color.into(),
+            intensity: light.intensity,
+            range: light.range,
+            position: point3(world.w.x, world.w.y, world.w.z),
+            direction: -world.z.truncate().normalize(),
+            shadow_resolution: match light.kind {
+                LightKind::Directional => CASCADE_SIZE,
+                LightKind::Point => DEFAULT_POINT_SHADOW_SIZE,
+                LightKind::Spot { .. } => SPOT_SHADOW_SIZE,
+            },
+        })
+        .collect()
+}
+
+/// The light storage buffer, binding 1 of the frame set.
+#[derive(Clone, Debug, Default)]
+pub struct LightBuffer {
+    pub buffer: AllocatedBuffer,
+    capacity: usize,
+}
+
+pub unsafe fn create_light_buffer(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()> {
+    let lights = allocate_light_buffer(INITIAL_LIGHT_CAPACITY, instance, device, data)?;
+    descriptor::write_buffer(device, data.frame.set, 1, vk::DescriptorType::STORAGE_BUFFER, lights.buffer.buffer);
+    data.frame.lights = lights;
+    Ok(())
+}
+
+unsafe fn allocate_light_buffer(capacity: usize, instance: &Instance, device: &Device, data: &EngineData) -> Result<LightBuffer> {
+    let buffer = AllocatedBuffer::allocate(
+        (size_of::<GpuLight>() * capacity) as u64,
+        vk::BufferUsageFlags::STORAGE_BUFFER,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)?;
+
+    Ok(LightBuffer { buffer, capacity })
+}
+
+/// Copies the engine's lights into the storage buffer, growing it when they no longer fit.
+///  Only safe while the GPU isn't reading the buffer.
+pub unsafe fn upload_lights(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()> {
+    if data.lights.len() > data.frame.lights.capacity {
+        let capacity = data.lights.len().next_power_of_two();
+        let lights = allocate_light_buffer(capacity, instance, device, data)?;
+        descriptor::write_buffer(device, data.frame.set, 1, vk::DescriptorType::STORAGE_BUFFER, lights.buffer.buffer);
+        data.frame.lights.buffer.destroy(device);
+        data.frame.lights = lights;
+    }
+
+    let lights = data.lights
+        .iter()
+        .enumerate()
+        .map(|(i, l)| l.to_gpu(match l.kind {
+            LightKind::Spot { .. } => data.spot_shadows.shader_slot(i),
+            _ => data.point_shadows.shader_slot(i),
+        }))
+        .collect::<Vec<_>>();
+    if !lights.is_empty() {
+        data.frame.lights.buffer.write(device, lights.as_ptr(), size_of::<GpuLight>() * lights.len())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use anyhow::{Ok, Result};
+use thiserror::Error;
+
+/// Sizes a LUT can have, it needs both ends of every axis and has to fit a 3D texture
+const SIZES: RangeInclusive<u32> = 2..=256;
+
+#[derive(Debug, Error)]
+pub enum CubeError {
+    #[error("Line {0}: {1}.")]
+    Syntax(usize, String),
+    #[error("1D LUTs aren't supported, only LUT_3D_SIZE.")]
+    Unsupported1d,
+    #[error("The .cube file has no LUT_3D_SIZE.")]
+    MissingSize,
+    #[error("LUT_3D_SIZE {0} is outside of 2..=256.")]
+    InvalidSize(u32),
+    #[error("Expected {expected} entries for a LUT of size {size}, found {found}.")]
+    EntryCount { size: u32, expected: usize, found: usize },
+}
+
+/// A 3D colour lookup table in the Adobe/Resolve .cube format.
+///  Entries are ordered with red changing fastest, then green, then blue.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CubeLut {
+    pub title: Option<String>,
+    pub size: u32,
+    pub domain_min: [f32; 3],
+    pub domain_max: [f32; 3],
+    pub entries: Vec<[f32; 3]>,
+}
+
+impl CubeLut {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut title = None;
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut entries = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            match keyword {
+                "TITLE" => title = Some(rest.trim_matches('"').to_string()),
+                "LUT_3D_SIZE" => {
+                    let value = rest
+                        .parse::<u32>()
+                        .map_err(|e| CubeError::Syntax(number, e.to_string()))?;
+                    if !SIZES.contains(&value) {
+                        return Err(CubeError::InvalidSize(value).into());
+                    }
+                    size = Some(value);
+                },
+                "LUT_1D_SIZE" => return Err(CubeError::Unsupported1d.into()),
+                "DOMAIN_MIN" => domain_min = parse_triplet(rest, number)?,
+                "DOMAIN_MAX" => domain_max = parse_triplet(rest, number)?,
+                // Resolve writes the input range this way
+                "LUT_3D_INPUT_RANGE" => {
+                    let [min, max, ..] = parse_values::<2>(rest, number)?;
+                    domain_min = [min; 3];
+                    domain_max = [max; 3];
+                },
+                _ if keyword.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.') => {
+                    entries.push(parse_triplet(line, number)?);
+                },
+                _ => return Err(CubeError::Syntax(number, format!("unknown keyword `{}`", keyword)).into()),
+            }
+        }
+
+        let size = size.ok_or(CubeError::MissingSize)?;
+        let expected = (size as usize).pow(3);
+        if entries.len() != expected {
+            return Err(CubeError::EntryCount { size, expected, found: entries.len() }.into());
+        }
+
+        Ok(Self { title, size, domain_min, domain_max, entries })
+    }
+
+    /// The LUT that changes nothing
+    pub fn identity(size: u32) -> Result<Self> {
+        if !SIZES.contains(&size) {
+            return Err(CubeError::InvalidSize(size).into());
+        }
+
+        let scale = 1.0 / (size - 1) as f32;
+        let entries = (0..size.pow(3))
+            .map(|i| {
+                let (r, g, b) = (i % size, (i / size) % size, i / (size * size));
+                [r as f32 * scale, g as f32 * scale, b as f32 * scale]
+            })
+            .collect();
+
+        Ok(Self { title: None, size, domain_min: [0.0; 3], domain_max: [1.0; 3], entries })
+    }
+}
+
+fn parse_values<const N: usize>(text: &str, line: usize) -> Result<[f32; N]> {
+    let values = text
+        .split_whitespace()
+        .map(|v| v.parse::<f32>().map_err(|e| CubeError::Syntax(line, e.to_string())))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    values
+        .try_into()
+        .map_err(|v: Vec<f32>| CubeError::Syntax(line, format!("expected {} values, found {}", N, v.len())).into())
+}
+
+fn parse_triplet(text: &str, line: usize) -> Result<[f32; 3]> {
+    parse_values::<3>(text, line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A size 2 LUT swapping red and blue, written with red changing fastest
+    const SWAP: &str = "\
+TITLE \"Swap\"
+# A comment
+LUT_3D_SIZE 2
+
+0 0 0
+0 0 1
+0 1 0
+0 1 1
+1 0 0
+1 0 1
+1 1 0
+1 1 1
+";
+
+    fn error(text: &str) -> CubeError {
+        CubeLut::parse(text)
+            .unwrap_err()
+            .downcast::<CubeError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn entries_keep_red_fastest_order() {
+        let lut = CubeLut::parse(SWAP).unwrap();
+        assert_eq!(lut.title.as_deref(), Some("Swap"));
+        assert_eq!(lut.size, 2);
+        assert_eq!(lut.domain_min, [0.0; 3]);
+        assert_eq!(lut.domain_max, [1.0; 3]);
+
+        // Entry r + g * size + b * size² holds the output for input (r, g, b)
+        let index = |r: usize, g: usize, b: usize| r + g * 2 + b * 4;
+        assert_eq!(lut.entries[index(1, 0, 0)], [0.0, 0.0, 1.0]);
+        assert_eq!(lut.entries[index(0, 1, 0)], [0.0, 1.0, 0.0]);
+        assert_eq!(lut.entries[index(0, 0, 1)], [1.0, 0.0, 0.0]);
+        assert_eq!(lut.entries[index(1, 1, 0)], [0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn identity_matches_the_file_order() {
+        let lut = CubeLut::identity(3).unwrap();
+        assert_eq!(lut.entries.len(), 27);
+        assert_eq!(lut.entries[1], [0.5, 0.0, 0.0]);
+        assert_eq!(lut.entries[3], [0.0, 0.5, 0.0]);
+        assert_eq!(lut.entries[9], [0.0, 0.0, 0.5]);
+        assert_eq!(lut.entries[26], [1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn domain_keywords_set_the_input_range() {
+        let domain = SWAP.replace("LUT_3D_SIZE 2", "LUT_3D_SIZE 2\nDOMAIN_MIN -0.5 0 0.25\nDOMAIN_MAX 2 1 4");
+        let lut = CubeLut::parse(&domain).unwrap();
+        assert_eq!(lut.domain_min, [-0.5, 0.0, 0.25]);
+        assert_eq!(lut.domain_max, [2.0, 1.0, 4.0]);
+
+        let lut = CubeLut::parse(&SWAP.replace("LUT_3D_SIZE 2", "LUT_3D_SIZE 2\nLUT_3D_INPUT_RANGE 0.1 8")).unwrap();
+        assert_eq!(lut.domain_min, [0.1; 3]);
+        assert_eq!(lut.domain_max, [8.0; 3]);
+    }
+
+    #[test]
+    fn rejects_wrong_entry_counts() {
+        let missing = SWAP.replace("1 1 1\n", "");
+        assert!(matches!(error(&missing), CubeError::EntryCount { size: 2, expected: 8, found: 7 }));
+
+        let extra = format!("{}0.5 0.5 0.5\n", SWAP);
+        assert!(matches!(error(&extra), CubeError::EntryCount { size: 2, expected: 8, found: 9 }));
+
+        let short = SWAP.replace("1 1 1", "1 1");
+        assert!(matches!(error(&short), CubeError::Syntax(12, _)));
+    }
+
+    #[test]
+    fn rejects_1d_luts() {
+        assert!(matches!(error("LUT_1D_SIZE 2\n0 0 0\n1 1 1\n"), CubeError::Unsupported1d));
+    }
+
+    #[test]
+    fn rejects_unknown_keywords() {
+        let unknown = SWAP.replace("# A comment", "LUT_3D_SHAPE cube");
+        match error(&unknown) {
+            CubeError::Syntax(line, message) => {
+                assert_eq!(line, 2);
+                assert!(message.contains("LUT_3D_SHAPE"));
+            },
+            other => panic!("Expected a syntax error, got `{}`.", other),
+        }
+    }
+
+    #[test]
+    fn rejects_sizes_out_of_range() {
+        assert!(matches!(error("LUT_3D_SIZE 1\n0 0 0\n"), CubeError::InvalidSize(1)));
+        assert!(matches!(error("LUT_3D_SIZE 257\n"), CubeError::InvalidSize(257)));
+        assert!(matches!(error("LUT_3D_SIZE two\n"), CubeError::Syntax(1, _)));
+        assert!(matches!(error("0 0 0\n"), CubeError::MissingSize));
+    }
+
+    #[test]
+    fn identity_rejects_sizes_out_of_range() {
+        for size in [0, 1, 257] {
+            let error = CubeLut::identity(size).unwrap_err();
+            assert!(matches!(error.downcast_ref::<CubeError>(), Some(CubeError::InvalidSize(s)) if *s == size));
+        }
+        assert_eq!(CubeLut::identity(2).unwrap().entries.len(), 8);
+    }
+}
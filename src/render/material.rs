@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::AllocatedBuffer;
+use super::scene::{AlphaMode, MaterialInfo, TextureRef};
+use super::texture::Texture;
+
+/// Number of texture slots per material, bindings 1 to 5 of the material set
+const TEXTURE_SLOTS: u32 = 5;
+
+/// Mirrors `MaterialParams` in `surface.glsl` and `alpha_mask.glsl` (std140)
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct MaterialUniform {
+    base_color_factor: [f32; 4],
+    emissive: [f32; 4],
+    params: [f32; 4],
+    alpha: [f32; 4],
+}
+
+/// A metallic-roughness material ready to be bound as descriptor set 1.
+#[derive(Clone, Debug, Default)]
+pub struct Material {
+    pub info: MaterialInfo,
+    pub uniform_buffer: AllocatedBuffer,
+    pub set: vk::DescriptorSet,
+}
+
+impl Material {
+    pub fn double_sided(&self) -> bool {
+        self.info.double_sided
+    }
+
+    /// Blended materials are drawn after every opaque surface, back to front
+    pub fn blended(&self) -> bool {
+        self.info.alpha_mode == AlphaMode::Blend
+    }
+
+    /// Masked materials are alpha tested, in the shadow passes too
+    pub fn masked(&self) -> bool {
+        self.info.alpha_mode == AlphaMode::Mask
+    }
+}
+
+/// Every material of the scene with the textures they sample.
+#[derive(Clone, Debug, Default)]
+pub struct MaterialLibrary {
+    pub set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    white: Texture,
+    flat_normal: Texture,
+    black: Texture,
+    textures: Vec<Texture>,
+    pub materials: Vec<Material>,
+    pub default_material: Material,
+}
+
+impl MaterialLibrary {
+    /// The material for a draw, falling back to the default one
+    pub fn get(&self, index: Option<usize>) -> &Material {
+        index
+            .and_then(|i| self.materials.get(i))
+            .unwrap_or(&self.default_material)
+    }
+}
+
+pub unsafe fn create_material_library(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let white = Texture::solid([255, 255, 255, 255], vk::Format::R8G8B8A8_UNORM, instance, device, data)?;
+    let flat_normal = Texture::solid([128, 128, 255, 255], vk::Format::R8G8B8A8_UNORM, instance, device, data)?;
+    let black = Texture::solid([0, 0, 0, 255], vk::Format::R8G8B8A8_UNORM, instance, device, data)?;
+
+    let mut bindings = vec![
+        descriptor::layout_binding(0, vk::DescriptorType::UNIFORM_BUFFER, vk::ShaderStageFlags::FRAGMENT),
+    ];
+    bindings.extend((1..=TEXTURE_SLOTS).map(|b| {
+        descriptor::layout_binding(b, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT)
+    }));
+
+    let library = &mut data.materials;
+    library.set_layout = descriptor::create_set_layout(device, &bindings)?;
+    library.white = white;
+    library.flat_normal = flat_normal;
+    library.black = black;
+
+    upload_materials(instance, device, data)
+}
+
+pub unsafe fn destroy_material_library(device: &Device, data: &mut EngineData)
+{
+    destroy_materials(device, data);
+    let library = &mut data.materials;
+    library.white.destroy(device);
+    library.flat_normal.destroy(device);
+    library.black.destroy(device);
+    device.destroy_descriptor_set_layout(library.set_layout, None);
+}
+
+unsafe fn destroy_materials(device: &Device, data: &mut EngineData)
+{
+    let library = &mut data.materials;
+    library.materials
+        .iter_mut()
+        .chain(std::iter::once(&mut library.default_material))
+        .for_each(|m| m.uniform_buffer.destroy(device));
+    library.textures
+        .iter_mut()
+        .for_each(|t| t.destroy(device));
+    device.destroy_descriptor_pool(library.descriptor_pool, None);
+
+    library.materials.clear();
+    library.textures.clear();
+}
+
+/// Replaces the GPU materials and textures with the current scene's
+pub unsafe fn upload_materials(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    destroy_materials(device, data);
+
+    // Colour slots are sampled as sRGB, data slots as linear, a texture used as both is uploaded twice.
+    //  Textures sampling the same image the same way share one upload.
+    let mut slots = HashMap::new();
+    let mut uploads = HashMap::new();
+    let mut textures = Vec::new();
+    for material in &data.scene.materials {
+        let srgb_refs = [material.base_color_texture, material.emissive_texture];
+        let linear_refs = [material.metallic_roughness_texture, material.normal_texture, material.occlusion_texture];
+        let refs = srgb_refs.iter().map(|r| (r, true)).chain(linear_refs.iter().map(|r| (r, false)));
+        for (texture, srgb) in refs.filter_map(|(r, srgb)| r.map(|r| (r.texture, srgb))) {
+            if !slots.contains_key(&(texture, srgb)) {
+                let info = &data.scene.textures[texture];
+                let upload = (info.image, info.linear_filter, info.wrap, srgb);
+                if !uploads.contains_key(&upload) {
+                    let image = &data.scene.images[info.image];
+                    uploads.insert(upload, textures.len());
+                    textures.push(Texture::from_data(image, info, srgb, instance, device, data)?);
+                }
+                slots.insert((texture, srgb), uploads[&upload]);
+            }
+        }
+    }
+
+    let set_count = data.scene.materials.len() as u32 + 1;
+    let descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::UNIFORM_BUFFER, set_count),
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, set_count * TEXTURE_SLOTS),
+    ], set_count)?;
+
+    let library = &data.materials;
+    let lookup = |r: Option<TextureRef>, srgb: bool, fallback: &Texture| {
+        r.and_then(|r| slots.get(&(r.texture, srgb)))
+            .map_or(fallback.clone(), |i| textures[*i].clone())
+    };
+
+    let mut create = |info: &MaterialInfo| -> Result<Material> {
+        let slot_textures = [
+            lookup(info.base_color_texture, true, &library.white),
+            lookup(info.metallic_roughness_texture, false, &library.white),
+            lookup(info.normal_texture, false, &library.flat_normal),
+            lookup(info.occlusion_texture, false, &library.white),
+            lookup(info.emissive_texture, true, &library.black),
+        ];
+        create_material(info, &slot_textures, descriptor_pool, library.set_layout, instance, device, data)
+    };
+
+    let default_material = create(&MaterialInfo::default())?;
+    let materials = data.scene.materials
+        .iter()
+        .map(&mut create)
+        .collect::<Result<Vec<_>>>()?;
+
+    let library = &mut data.materials;
+    library.descriptor_pool = descriptor_pool;
+    library.textures = textures;
+    library.materials = materials;
+    library.default_material = default_material;
+    Ok(())
+}
+
+unsafe fn create_material(info: &MaterialInfo, textures: &[Texture; TEXTURE_SLOTS as usize], pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout, instance: &Instance, device: &Device, data: &EngineData) -> Result<Material>
+{
+    let e = info.emissive_factor;
+    let alpha_mode = match info.alpha_mode {
+        AlphaMode::Opaque => 0.0,
+        AlphaMode::Mask => 1.0,
+        AlphaMode::Blend => 2.0,
+    };
+
+    let uniform = MaterialUniform {
+        base_color_factor: info.base_color_factor,
+        emissive: [e[0] * info.emissive_strength, e[1] * info.emissive_strength, e[2] * info.emissive_strength, 0.0],
+        params: [info.metallic_factor, info.roughness_factor, info.normal_scale, info.occlusion_strength],
+        alpha: [info.alpha_cutoff, alpha_mode, 0.0, 0.0],
+    };
+
+    let uniform_buffer = AllocatedBuffer::create(
+        &uniform,
+        size_of::<MaterialUniform>(),
+        vk::BufferUsageFlags::UNIFORM_BUFFER,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)?;
+
+    let set = descriptor::allocate_set(device, pool, set_layout)?;
+    descriptor::write_buffer(device, set, 0, vk::DescriptorType::UNIFORM_BUFFER, uniform_buffer.buffer);
+    for (i, texture) in textures.iter().enumerate() {
+        descriptor::write_image(device, set, i as u32 + 1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            texture.image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, texture.sampler);
+    }
+
+    Ok(Material { info: info.clone(), uniform_buffer, set })
+}
@@ -1,4 +1,5 @@
 use vk::{Buffer, DeviceMemory};
+use std::mem::size_of;
 use std::ptr::copy_nonoverlapping as memcpy;
 use vulkanalia::prelude::v1_3::*;
 use anyhow::{anyhow, Ok, Result};
@@ -9,6 +10,7 @@ use super::engine_data::EngineData;
 pub struct AllocatedBuffer {
     pub buffer: Buffer,
     pub buffer_memory: DeviceMemory,
+    pub size: u64,
 }
 
 impl AllocatedBuffer {
@@ -17,13 +19,22 @@ impl AllocatedBuffer {
         device.free_memory(self.buffer_memory, None);
     }
 
-    pub unsafe fn create<T>(buffer_data: *const T, buffer_len: usize, 
+    pub unsafe fn create<T>(buffer_data: *const T, buffer_len: usize,
         usage: vk::BufferUsageFlags, flags: vk::BufferCreateFlags, properties: vk::MemoryPropertyFlags,
-        instance: &Instance, device: &Device, data: &EngineData) -> Result<Self> 
+        instance: &Instance, device: &Device, data: &EngineData) -> Result<Self>
+    {
+        let buffer = Self::allocate(buffer_len as u64, usage, flags, properties, instance, device, data)?;
+        buffer.write(device, buffer_data, buffer_len)?;
+        Ok(buffer)
+    }
+
+    /// Creates a buffer and binds memory to it without uploading anything
+    pub unsafe fn allocate(size: u64, usage: vk::BufferUsageFlags, flags: vk::BufferCreateFlags,
+        properties: vk::MemoryPropertyFlags, instance: &Instance, device: &Device, data: &EngineData) -> Result<Self>
     {
         // Create the buffer
         let buffer_info = vk::BufferCreateInfo::builder()
-            .size(buffer_len as u64)
+            .size(size)
             .usage(usage)
             .flags(flags)
             .sharing_mode(vk::SharingMode::EXCLUSIVE)
@@ -42,28 +53,315 @@ impl AllocatedBuffer {
             )?)
             ;
 
-        // Allocate the memory
+        // Allocate and bind the memory
         let buffer_memory = device.allocate_memory(&memory_info, None)?;
-
-        // Bind the memory
         device.bind_buffer_memory(buffer, buffer_memory, 0)?;
+
+        Ok(Self { buffer, buffer_memory, size })
+    }
+
+    /// Copies `buffer_len` bytes into a host visible buffer
+    pub unsafe fn write<T>(&self, device: &Device, buffer_data: *const T, buffer_len: usize) -> Result<()> {
         let memory = device.map_memory(
-            buffer_memory,
+            self.buffer_memory,
             0,
-            buffer_info.size,
+            buffer_len as u64,
             vk::MemoryMapFlags::empty(),
         )?;
 
         // Copy and unmap
-        memcpy(buffer_data, memory.cast(), buffer_len);
-        device.unmap_memory(buffer_memory);
-        
-        Ok(Self { buffer, buffer_memory} )
+        memcpy(buffer_data.cast::<u8>(), memory.cast::<u8>(), buffer_len);
+        device.unmap_memory(self.buffer_memory);
+        Ok(())
+    }
+
+    /// Reads a value back from the start of a host visible buffer
+    pub unsafe fn read<T: Copy>(&self, device: &Device) -> Result<T> {
+        let memory = device.map_memory(
+            self.buffer_memory,
+            0,
+            size_of::<T>() as u64,
+            vk::MemoryMapFlags::empty(),
+        )?;
+
+        let value = memory.cast::<T>().read_unaligned();
+        device.unmap_memory(self.buffer_memory);
+        Ok(value)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AllocatedImage {
+    pub image: vk::Image,
+    pub image_memory: DeviceMemory,
+    pub image_view: vk::ImageView,
+    pub format: vk::Format,
+    pub extent: vk::Extent3D,
+}
+
+impl AllocatedImage {
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        device.destroy_image_view(self.image_view, None);
+        device.destroy_image(self.image, None);
+        device.free_memory(self.image_memory, None);
+    }
+
+    /// Creates a single mip, single layer 2D image in device local memory
+    pub unsafe fn create(extent: vk::Extent2D, format: vk::Format, usage: vk::ImageUsageFlags,
+        aspect: vk::ImageAspectFlags, instance: &Instance, device: &Device, data: &EngineData) -> Result<Self>
+    {
+        let info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::_2D)
+            .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+            .mip_levels(1)
+            .array_layers(1)
+            .format(format)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .samples(vk::SampleCountFlags::_1);
+
+        Self::from_info(&info, vk::ImageViewType::_2D, aspect, instance, device, data)
+    }
+
+    /// Creates an image from a full create info, with a view covering every mip and layer
+    pub unsafe fn from_info(info: &vk::ImageCreateInfo, view_type: vk::ImageViewType, aspect: vk::ImageAspectFlags,
+        instance: &Instance, device: &Device, data: &EngineData) -> Result<Self>
+    {
+        let image = device.create_image(info, None)?;
+
+        // Allocate and bind the memory
+        let requirements = device.get_image_memory_requirements(image);
+        let memory_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(get_memory_type_index(
+                instance,
+                data,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                requirements,
+            )?);
+
+        let image_memory = device.allocate_memory(&memory_info, None)?;
+        device.bind_image_memory(image, image_memory, 0)?;
+
+        // Create the view
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(aspect)
+            .base_mip_level(0)
+            .level_count(info.mip_levels)
+            .base_array_layer(0)
+            .layer_count(info.array_layers);
+
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(view_type)
+            .format(info.format)
+            .subresource_range(subresource_range);
+
+        let image_view = device.create_image_view(&view_info, None)?;
+
+        Ok(Self { image, image_memory, image_view, format: info.format, extent: info.extent })
+    }
+
+    pub fn extent_2d(&self) -> vk::Extent2D {
+        vk::Extent2D { width: self.extent.width, height: self.extent.height }
     }
 }
 
+/// Allocates and begins a command buffer meant to be submitted once
+pub unsafe fn begin_single_time_commands(device: &Device, data: &EngineData) -> Result<vk::CommandBuffer> {
+    let info = vk::CommandBufferAllocateInfo::builder()
+        .level(vk::CommandBufferLevel::PRIMARY)
+        .command_pool(data.command_pool)
+        .command_buffer_count(1);
+
+    let command_buffer = device.allocate_command_buffers(&info)?[0];
+
+    let info = vk::CommandBufferBeginInfo::builder()
+        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+    device.begin_command_buffer(command_buffer, &info)?;
+    Ok(command_buffer)
+}
+
+/// Submits a single time command buffer, waits for it and frees it
+pub unsafe fn end_single_time_commands(device: &Device, data: &EngineData, command_buffer: vk::CommandBuffer) -> Result<()> {
+    device.end_command_buffer(command_buffer)?;
+
+    let command_buffers = &[command_buffer];
+    let info = vk::SubmitInfo::builder()
+        .command_buffers(command_buffers);
+
+    device.queue_submit(data.graphics_queue, &[info], vk::Fence::null())?;
+    device.queue_wait_idle(data.graphics_queue)?;
+
+    device.free_command_buffers(data.command_pool, &[command_buffer]);
+    Ok(())
+}
+
+/// Records a layout transition and memory dependency for an image
+pub unsafe fn cmd_image_barrier(device: &Device, command_buffer: vk::CommandBuffer, image: vk::Image,
+    subresource_range: vk::ImageSubresourceRange, layouts: (vk::ImageLayout, vk::ImageLayout),
+    src: (vk::PipelineStageFlags, vk::AccessFlags), dst: (vk::PipelineStageFlags, vk::AccessFlags))
+{
+    let barrier = vk::ImageMemoryBarrier::builder()
+        .old_layout(layouts.0)
+        .new_layout(layouts.1)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(subresource_range)
+        .src_access_mask(src.1)
+        .dst_access_mask(dst.1);
+
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        src.0,
+        dst.0,
+        vk::DependencyFlags::empty(),
+        &[] as &[vk::MemoryBarrier],
+        &[] as &[vk::BufferMemoryBarrier],
+        &[barrier],
+    );
+}
+
+/// Records a memory dependency on a whole buffer
+pub unsafe fn cmd_buffer_barrier(device: &Device, command_buffer: vk::CommandBuffer, buffer: vk::Buffer,
+    src: (vk::PipelineStageFlags, vk::AccessFlags), dst: (vk::PipelineStageFlags, vk::AccessFlags))
+{
+    let barrier = vk::BufferMemoryBarrier::builder()
+        .src_access_mask(src.1)
+        .dst_access_mask(dst.1)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .buffer(buffer)
+        .offset(0)
+        .size(vk::WHOLE_SIZE);
+
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        src.0,
+        dst.0,
+        vk::DependencyFlags::empty(),
+        &[] as &[vk::MemoryBarrier],
+        &[barrier],
+        &[] as &[vk::ImageMemoryBarrier],
+    );
+}
+
+/// The subresource range of the first mip of the first layer of a color image
+pub fn color_subresource_range() -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1)
+        .build()
+}
+
+/// Copies the first mip of a color image back into host memory
+pub unsafe fn download_image(image: vk::Image, layout: vk::ImageLayout, extent: vk::Extent2D, texel_size: usize,
+    instance: &Instance, device: &Device, data: &EngineData) -> Result<Vec<u8>>
+{
+    download_image_levels(image, layout, extent, 1, 1, texel_size, instance, device, data)
+}
+
+/// Byte size of `mip_levels` mips of `layer_count` layers, packed mip after mip with every layer of a mip together
+pub fn image_levels_size(extent: vk::Extent2D, mip_levels: u32, layer_count: u32, texel_size: usize) -> usize {
+    (0..mip_levels)
+        .map(|mip| {
+            let width = (extent.width >> mip).max(1) as usize;
+            let height = (extent.height >> mip).max(1) as usize;
+            width * height * layer_count as usize * texel_size
+        })
+        .sum()
+}
+
+/// Copies the first `mip_levels` mips of every layer of a color image back into host memory, packed
+///  like `image_levels_size` counts them
+pub unsafe fn download_image_levels(image: vk::Image, layout: vk::ImageLayout, extent: vk::Extent2D,
+    mip_levels: u32, layer_count: u32, texel_size: usize, instance: &Instance, device: &Device, data: &EngineData)
+    -> Result<Vec<u8>>
+{
+    let size = image_levels_size(extent, mip_levels, layer_count, texel_size);
+    let mut staging = AllocatedBuffer::allocate(
+        size as u64,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)?;
+
+    let command_buffer = begin_single_time_commands(device, data)?;
+
+    // Make any previous writes visible to the copy, which reads from the general layout or moves
+    //  the image to the transfer source one and back
+    let range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(mip_levels)
+        .base_array_layer(0)
+        .layer_count(layer_count)
+        .build();
+    let copy_layout = if layout == vk::ImageLayout::GENERAL { layout } else { vk::ImageLayout::TRANSFER_SRC_OPTIMAL };
+    cmd_image_barrier(device, command_buffer, image, range,
+        (layout, copy_layout),
+        (vk::PipelineStageFlags::ALL_COMMANDS, vk::AccessFlags::MEMORY_WRITE),
+        (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ));
+
+    let regions = level_copy_regions(extent, mip_levels, layer_count, texel_size);
+    device.cmd_copy_image_to_buffer(command_buffer, image, copy_layout, staging.buffer, &regions);
+    if copy_layout != layout {
+        cmd_image_barrier(device, command_buffer, image, range,
+            (copy_layout, layout),
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::empty()),
+            (vk::PipelineStageFlags::ALL_COMMANDS, vk::AccessFlags::empty()));
+    }
+    end_single_time_commands(device, data, command_buffer)?;
+
+    // Read back the pixels
+    let mut pixels = vec![0u8; size];
+    let memory = device.map_memory(staging.buffer_memory, 0, size as u64, vk::MemoryMapFlags::empty())?;
+    memcpy(memory.cast::<u8>(), pixels.as_mut_ptr(), size);
+    device.unmap_memory(staging.buffer_memory);
+    staging.destroy(device);
+
+    Ok(pixels)
+}
+
+/// Copies of every mip between an image and a buffer packed like `image_levels_size` counts them
+pub fn level_copy_regions(extent: vk::Extent2D, mip_levels: u32, layer_count: u32, texel_size: usize)
+    -> Vec<vk::BufferImageCopy>
+{
+    let mut offset = 0;
+    (0..mip_levels)
+        .map(|mip| {
+            let subresource = vk::ImageSubresourceLayers::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .mip_level(mip)
+                .base_array_layer(0)
+                .layer_count(layer_count);
+
+            let width = (extent.width >> mip).max(1);
+            let height = (extent.height >> mip).max(1);
+            let region = vk::BufferImageCopy::builder()
+                .buffer_offset(offset as u64)
+                .buffer_row_length(0)
+                .buffer_image_height(0)
+                .image_subresource(subresource)
+                .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+                .image_extent(vk::Extent3D { width, height, depth: 1 })
+                .build();
+
+            offset += image_levels_size(vk::Extent2D { width, height }, 1, layer_count, texel_size);
+            region
+        })
+        .collect()
+}
+
 unsafe fn get_memory_type_index(instance: &Instance, data: &EngineData,
-    properties: vk::MemoryPropertyFlags, requirements: vk::MemoryRequirements, ) -> Result<u32> 
+    properties: vk::MemoryPropertyFlags, requirements: vk::MemoryRequirements, ) -> Result<u32>
 {
     let memory = instance.get_physical_device_memory_properties(data.physical_device);
     (0..memory.memory_type_count)
@@ -73,4 +371,4 @@ unsafe fn get_memory_type_index(instance: &Instance, data: &EngineData,
             suitable && memory_type.property_flags.contains(properties)
         })
         .ok_or_else(|| anyhow!("Failed to find suitable memory type."))
-}
\ No newline at end of file
+}
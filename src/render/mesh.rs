@@ -1,5 +1,5 @@
 use std::mem::size_of;
-use cgmath::{vec2, vec3, vec4};
+use cgmath::{vec2, vec3, vec4, InnerSpace, Zero};
 use vulkanalia::prelude::v1_3::*;
 use anyhow::{Ok, Result};
 
@@ -8,11 +8,12 @@ use super::{engine_data::EngineData, memory::AllocatedBuffer};
 type Vec2 = cgmath::Vector2<f32>;
 type Vec3 = cgmath::Vector3<f32>;
 type Vec4 = cgmath::Vector4<f32>;
+type Mat4 = cgmath::Matrix4<f32>;
 
 const TEST_TRIS: [Vertex; 3] = [
-    Vertex {pos: vec2( 0.0, -0.5), color: vec3(1.0, 0.0, 0.0)},
-    Vertex {pos: vec2( 0.5,  0.5), color: vec3(0.0, 1.0, 0.0)},
-    Vertex {pos: vec2(-0.5,  0.5), color: vec3(0.0, 0.0, 1.0)},
+    Vertex {pos: vec3( 0.0, -0.5, 0.0), norm: vec3(0.0, 0.0, 1.0), color: vec3(1.0, 0.0, 0.0), uv: vec2(0.5, 0.0), tangent: vec4(1.0, 0.0, 0.0, 1.0)},
+    Vertex {pos: vec3( 0.5,  0.5, 0.0), norm: vec3(0.0, 0.0, 1.0), color: vec3(0.0, 1.0, 0.0), uv: vec2(1.0, 1.0), tangent: vec4(1.0, 0.0, 0.0, 1.0)},
+    Vertex {pos: vec3(-0.5,  0.5, 0.0), norm: vec3(0.0, 0.0, 1.0), color: vec3(0.0, 0.0, 1.0), uv: vec2(0.0, 1.0), tangent: vec4(1.0, 0.0, 0.0, 1.0)},
 ];
 
 const TEST_INDS: [u32; 3] = [ 0, 1, 2 ];
@@ -20,16 +21,108 @@ const TEST_INDS: [u32; 3] = [ 0, 1, 2 ];
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct Vertex {
-    pos: Vec2,
-    // norm: Vec3,
-    color: Vec3,
-    // uv: Vec2,
+    pub pos: Vec3,
+    pub norm: Vec3,
+    pub color: Vec3,
+    pub uv: Vec2,
+    /// xyz is the tangent, w the bitangent sign
+    pub tangent: Vec4,
+}
+
+impl Vertex {
+    pub fn binding_description() -> vk::VertexInputBindingDescription {
+        vk::VertexInputBindingDescription::builder()
+            .binding(0)
+            .stride(size_of::<Vertex>() as u32)
+            .input_rate(vk::VertexInputRate::VERTEX)
+            .build()
+
+    }
+
+    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 5] {
+        let attribute = |location: u32, format: vk::Format, offset: usize| {
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(location)
+                .format(format)
+                .offset(offset as u32)
+                .build()
+        };
+
+        let pos = attribute(0, vk::Format::R32G32B32_SFLOAT, 0);
+        let norm = attribute(1, vk::Format::R32G32B32_SFLOAT, size_of::<Vec3>());
+        let color = attribute(2, vk::Format::R32G32B32_SFLOAT, size_of::<Vec3>() * 2);
+        let uv = attribute(3, vk::Format::R32G32_SFLOAT, size_of::<Vec3>() * 3);
+        let tangent = attribute(4, vk::Format::R32G32B32A32_SFLOAT, size_of::<Vec3>() * 3 + size_of::<Vec2>());
+
+        return [pos, norm, color, uv, tangent]
+    }
+}
+
+/// Replaces the normals with area weighted face normals
+pub fn generate_normals(verts: &mut [Vertex], inds: &[u32]) {
+    verts.iter_mut().for_each(|v| v.norm = Vec3::zero());
+    for tri in inds.chunks_exact(3) {
+        let [a, b, c] = [tri[0] as usize, tri[1] as usize, tri[2] as usize];
+        let n = (verts[b].pos - verts[a].pos).cross(verts[c].pos - verts[a].pos);
+        verts[a].norm += n;
+        verts[b].norm += n;
+        verts[c].norm += n;
+    }
+    for v in verts.iter_mut() {
+        v.norm = if v.norm.magnitude2() > 0.0 { v.norm.normalize() } else { vec3(0.0, 0.0, 1.0) };
+    }
+}
+
+/// Derives per vertex tangents from the UV gradients of each triangle
+pub fn generate_tangents(verts: &mut [Vertex], inds: &[u32]) {
+    let mut tangents = vec![Vec3::zero(); verts.len()];
+    let mut bitangents = vec![Vec3::zero(); verts.len()];
+    for tri in inds.chunks_exact(3) {
+        let [a, b, c] = [tri[0] as usize, tri[1] as usize, tri[2] as usize];
+        let e1 = verts[b].pos - verts[a].pos;
+        let e2 = verts[c].pos - verts[a].pos;
+        let d1 = verts[b].uv - verts[a].uv;
+        let d2 = verts[c].uv - verts[a].uv;
+        let det = d1.x * d2.y - d2.x * d1.y;
+        if det.abs() < f32::EPSILON {
+            continue;
+        }
+        let t = (e1 * d2.y - e2 * d1.y) / det;
+        let bt = (e2 * d1.x - e1 * d2.x) / det;
+        for i in [a, b, c] {
+            tangents[i] += t;
+            bitangents[i] += bt;
+        }
+    }
+
+    for (i, v) in verts.iter_mut().enumerate() {
+        // Gram-Schmidt against the normal, falling back to any perpendicular axis
+        let mut t = tangents[i] - v.norm * v.norm.dot(tangents[i]);
+        if t.magnitude2() < f32::EPSILON {
+            let axis = if v.norm.x.abs() < 0.9 { vec3(1.0, 0.0, 0.0) } else { vec3(0.0, 1.0, 0.0) };
+            t = axis - v.norm * v.norm.dot(axis);
+        }
+        let t = t.normalize();
+        let w = if v.norm.cross(t).dot(bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+        v.tangent = vec4(t.x, t.y, t.z, w);
+    }
+}
+
+/// Per draw transform, pushed to `shader.vert`. The camera lives in the frame uniforms.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct MeshPushConstants {
+    pub model: Mat4,
+    /// Where the instance was last frame, the same as `model` when it didn't move
+    pub previous_model: Mat4,
 }
 
 // const TEST_MESH: Mesh = Mesh::create(Box::new(TEST_TRIS), Box::new(TEST_INDS));
 #[derive(Clone, Debug)]
 pub struct Mesh {
     pub vertex_buffer: AllocatedBuffer,
+    pub index_buffer: AllocatedBuffer,
     verts: Box<[Vertex]>,
     inds: Box<[u32]>,
 }
@@ -37,63 +130,55 @@ pub struct Mesh {
 impl Mesh {
     pub unsafe fn destroy(&mut self, device: &Device) {
         self.vertex_buffer.destroy(device);
+        self.index_buffer.destroy(device);
     }
 
     pub fn from_vectors(verts: Vec<Vertex>, inds: Vec<u32>,
-        instance: &Instance, device: &Device, data: &EngineData,) -> Result<Self> 
+        instance: &Instance, device: &Device, data: &EngineData,) -> Result<Self>
     {
         Ok(Mesh::create(verts.into_boxed_slice(), inds.into_boxed_slice(),
             instance, device, data)?)
     }
 
     pub fn create(verts: Box<[Vertex]>, inds: Box<[u32]>,
-        instance: &Instance, device: &Device, data: &EngineData) -> Result<Self> 
+        instance: &Instance, device: &Device, data: &EngineData) -> Result<Self>
     {
         // Create the vertex buffer
         let vertex_buffer = unsafe { AllocatedBuffer::create(
-            verts.as_ptr(), 
-            size_of::<Vertex>() * verts.len(), 
+            verts.as_ptr(),
+            size_of::<Vertex>() * verts.len(),
             vk::BufferUsageFlags::VERTEX_BUFFER,
             vk::BufferCreateFlags::empty(),
             vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
             instance, device, data) }?;
 
-        Ok(Self { verts, inds, vertex_buffer })
-    }
-
-    pub fn binding_description(&self) -> vk::VertexInputBindingDescription {
-        vk::VertexInputBindingDescription::builder()
-            .binding(0)
-            .stride(size_of::<Vertex>() as u32)
-            .input_rate(vk::VertexInputRate::VERTEX)
-            .build()
+        // Create the index buffer
+        let index_buffer = unsafe { AllocatedBuffer::create(
+            inds.as_ptr(),
+            size_of::<u32>() * inds.len(),
+            vk::BufferUsageFlags::INDEX_BUFFER,
+            vk::BufferCreateFlags::empty(),
+            vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+            instance, device, data) }?;
 
+        Ok(Self { verts, inds, vertex_buffer, index_buffer })
     }
 
-    pub fn attribute_descriptions(&self) -> [vk::VertexInputAttributeDescription; 2] {
-        let pos = vk::VertexInputAttributeDescription::builder()
-            .binding(0)
-            .location(0)
-            .format(vk::Format::R32G32_SFLOAT)
-            .offset(0)
-            .build();
-
-        let color = vk::VertexInputAttributeDescription::builder()
-            .binding(0)
-            .location(1)
-            .format(vk::Format::R32G32B32_SFLOAT)
-            .offset(size_of::<Vec2>() as u32)
-            .build();
-        
-        return [pos, color]
+    /// Binds the vertex and index buffers and draws every index
+    pub unsafe fn cmd_draw(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer.buffer], &[0]);
+        device.cmd_bind_index_buffer(command_buffer, self.index_buffer.buffer, 0, vk::IndexType::UINT32);
+        device.cmd_draw_indexed(command_buffer, self.inds.len() as u32, 1, 0, 0, 0);
     }
 
     pub fn get_vertex_count(&self) -> usize { return self.verts.len(); }
     pub fn get_index_count(&self) -> usize { return self.inds.len(); }
+    pub fn vertices(&self) -> &[Vertex] { &self.verts }
+    pub fn indices(&self) -> &[u32] { &self.inds }
 }
 
 pub fn create_test_mesh(instance: &Instance, device: &Device, data: &EngineData) -> Result<Mesh> {
     let m = Mesh::create(Box::new(TEST_TRIS), Box::new(TEST_INDS),
         instance, device, data)?;
     Ok(m)
-}
\ No newline at end of file
+}
@@ -1,8 +1,43 @@
 // Public modules
+pub mod camera;
 pub mod engine;
+pub mod light;
 pub mod mesh;
+pub mod scene;
 
 // Protected modules
 //  only accessible by other render engine modules
 mod memory;
-mod engine_data;
\ No newline at end of file
+mod engine_data;
+mod atmosphere;
+mod bloom;
+mod bvh;
+mod cluster;
+mod deferred;
+mod descriptor;
+mod dof;
+mod exposure;
+mod fog;
+mod frame;
+mod gltf_loader;
+mod grading;
+mod ibl;
+mod image_io;
+mod lens;
+mod lut;
+mod material;
+mod motion_blur;
+mod pathtrace;
+mod point_shadow;
+mod post;
+mod post_aa;
+mod shader;
+mod shadow;
+mod skybox;
+mod smaa;
+mod spot_shadow;
+mod ssao;
+mod ssr;
+mod taa;
+mod texture;
+mod tonemap;
\ No newline at end of file
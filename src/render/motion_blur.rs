@@ -0,0 +1,219 @@
+use std::mem::size_of;
+use std::time::Instant;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::camera::Camera;
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::{self, AllocatedImage};
+use super::post::{self, HDR_FORMAT};
+use super::shader;
+
+/// Width and height in pixels of the velocity tiles, also the longest blur in pixels
+const TILE_SIZE: u32 = 16;
+
+/// Sample count and toggle of the motion blur. Its length follows the physical camera's shutter.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MotionBlurSettings {
+    pub enabled: bool,
+    /// Taps along the blur of every pixel
+    pub sample_count: u32,
+}
+
+impl Default for MotionBlurSettings {
+    fn default() -> Self {
+        Self { enabled: true, sample_count: 12 }
+    }
+}
+
+/// Mirrors `MotionBlur` in `motion_blur.glsl`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct MotionBlurPushConstants {
+    /// From a frame's motion in 0..1 screen units to the motion during the exposure in pixels
+    velocity_scale: [f32; 2],
+    max_blur: f32,
+    sample_count: u32,
+    near: f32,
+    far: f32,
+    seed: u32,
+}
+
+/// McGuire et al.'s tile based reconstruction filter: the largest velocity of every tile and its
+///  neighbours bounds the gather of each pixel, which weighs samples by depth and their own velocity.
+#[derive(Clone, Debug, Default)]
+pub struct MotionBlur {
+    pub settings: MotionBlurSettings,
+    last_frame: Option<Instant>,
+    frame_index: u32,
+    tile_max: AllocatedImage,
+    neighbor_max: AllocatedImage,
+    sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+    set: vk::DescriptorSet,
+    pipeline_layout: vk::PipelineLayout,
+    tile_pipeline: vk::Pipeline,
+    neighbor_pipeline: vk::Pipeline,
+    gather_pipeline: vk::Pipeline,
+}
+
+impl MotionBlur {
+    unsafe fn cmd_tile_barrier(&self, device: &Device, command_buffer: vk::CommandBuffer, image: &AllocatedImage)
+    {
+        memory::cmd_image_barrier(device, command_buffer, image.image, memory::color_subresource_range(),
+            (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ));
+    }
+
+    /// Blurs the HDR target along the velocity buffer through the scratch image, does nothing without a physical camera
+    pub unsafe fn cmd_render(&mut self, device: &Device, command_buffer: vk::CommandBuffer, camera: &Camera,
+        scratch: &AllocatedImage, hdr: &AllocatedImage)
+    {
+        let lens = match camera.physical {
+            Some(lens) if self.settings.enabled => lens,
+            _ => {
+                self.last_frame = None;
+                return;
+            },
+        };
+
+        // The shutter is open for a fraction of the time between frames, longer shutters can't blur
+        //  further than the motion the velocity buffer knows about
+        let now = Instant::now();
+        let frame_time = self.last_frame.map(|t| (now - t).as_secs_f32());
+        self.last_frame = Some(now);
+        let Some(frame_time) = frame_time.filter(|t| *t > 0.0) else {
+            return;
+        };
+        let shutter_fraction = (lens.shutter_speed / frame_time).min(1.0);
+        self.frame_index = self.frame_index.wrapping_add(1);
+
+        let extent = hdr.extent_2d();
+        let push_constants = MotionBlurPushConstants {
+            velocity_scale: [extent.width as f32 * shutter_fraction, extent.height as f32 * shutter_fraction],
+            max_blur: TILE_SIZE as f32,
+            sample_count: self.settings.sample_count.max(1),
+            near: camera.near,
+            far: camera.far,
+            seed: self.frame_index,
+        };
+
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 0, &[self.set], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, &push_constants);
+
+        // The largest velocity of every tile, then of every tile's neighbourhood
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.tile_pipeline);
+        post::cmd_dispatch_pixels(device, command_buffer, self.tile_max.extent_2d());
+        self.cmd_tile_barrier(device, command_buffer, &self.tile_max);
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.neighbor_pipeline);
+        post::cmd_dispatch_pixels(device, command_buffer, self.neighbor_max.extent_2d());
+        self.cmd_tile_barrier(device, command_buffer, &self.neighbor_max);
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.gather_pipeline);
+        post::cmd_dispatch_pixels(device, command_buffer, extent);
+        post::cmd_write_back(device, command_buffer, scratch, hdr);
+    }
+}
+
+pub unsafe fn create_motion_blur(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let blur = &mut data.motion_blur;
+    blur.settings = MotionBlurSettings::default();
+    blur.sampler = descriptor::create_sampler(device, vk::Filter::NEAREST, 0.0)?;
+    blur.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(3, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(4, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(5, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    blur.descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 3),
+        (vk::DescriptorType::STORAGE_IMAGE, 3),
+    ], 1)?;
+    blur.set = descriptor::allocate_set(device, blur.descriptor_pool, blur.set_layout)?;
+
+    blur.pipeline_layout = descriptor::create_pipeline_layout(device, &[blur.set_layout],
+        size_of::<MotionBlurPushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+    let tiles = include_bytes!(concat!(env!("OUT_DIR"), "/shader/motion_blur_tiles_comp.spv"));
+    blur.tile_pipeline = shader::create_compute_pipeline(device, &tiles[..], blur.pipeline_layout)?;
+    let neighbors = include_bytes!(concat!(env!("OUT_DIR"), "/shader/motion_blur_neighbors_comp.spv"));
+    blur.neighbor_pipeline = shader::create_compute_pipeline(device, &neighbors[..], blur.pipeline_layout)?;
+    let gather = include_bytes!(concat!(env!("OUT_DIR"), "/shader/motion_blur_comp.spv"));
+    blur.gather_pipeline = shader::create_compute_pipeline(device, &gather[..], blur.pipeline_layout)?;
+
+    create_motion_blur_targets(instance, device, data)
+}
+
+/// Creates the velocity tiles for the current swapchain size and points the descriptors at the targets
+pub unsafe fn create_motion_blur_targets(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let extent = vk::Extent2D {
+        width: data.swapchain_extent.width.div_ceil(TILE_SIZE),
+        height: data.swapchain_extent.height.div_ceil(TILE_SIZE),
+    };
+    // Two channel float formats aren't guaranteed as storage images, the HDR one is
+    let create_tiles = || AllocatedImage::create(
+        extent,
+        HDR_FORMAT,
+        vk::ImageUsageFlags::STORAGE,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data);
+    let tile_max = create_tiles()?;
+    let neighbor_max = create_tiles()?;
+
+    // The tiles stay in the general layout
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    for image in [&tile_max, &neighbor_max] {
+        memory::cmd_image_barrier(device, command_buffer, image.image, memory::color_subresource_range(),
+            (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+    }
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    let blur = &data.motion_blur;
+    descriptor::write_image(device, blur.set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        data.hdr_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, blur.sampler);
+    descriptor::write_image(device, blur.set, 1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        data.velocity_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, blur.sampler);
+    descriptor::write_image(device, blur.set, 2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        data.depth_image.image_view, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL, blur.sampler);
+    descriptor::write_image(device, blur.set, 3, vk::DescriptorType::STORAGE_IMAGE,
+        tile_max.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+    descriptor::write_image(device, blur.set, 4, vk::DescriptorType::STORAGE_IMAGE,
+        neighbor_max.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+    descriptor::write_image(device, blur.set, 5, vk::DescriptorType::STORAGE_IMAGE,
+        data.post_image.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+
+    let blur = &mut data.motion_blur;
+    blur.tile_max = tile_max;
+    blur.neighbor_max = neighbor_max;
+    Ok(())
+}
+
+pub unsafe fn destroy_motion_blur_targets(device: &Device, data: &mut EngineData)
+{
+    let blur = &mut data.motion_blur;
+    blur.tile_max.destroy(device);
+    blur.neighbor_max.destroy(device);
+}
+
+pub unsafe fn destroy_motion_blur(device: &Device, data: &mut EngineData)
+{
+    let blur = &mut data.motion_blur;
+    device.destroy_pipeline(blur.tile_pipeline, None);
+    device.destroy_pipeline(blur.neighbor_pipeline, None);
+    device.destroy_pipeline(blur.gather_pipeline, None);
+    device.destroy_pipeline_layout(blur.pipeline_layout, None);
+    device.destroy_descriptor_pool(blur.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(blur.set_layout, None);
+    device.destroy_sampler(blur.sampler, None);
+}
@@ -0,0 +1,479 @@
+use std::mem::size_of;
+use cgmath::{Matrix, Matrix3, SquareMatrix};
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::bvh::{Bvh, BvhNode};
+use super::camera::Mat4;
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::light::Light;
+use super::memory::{self, AllocatedBuffer, AllocatedImage};
+use super::post::SCENE_COLOR_ATTACHMENTS;
+use super::scene::{AlphaMode, ImageData, MaterialInfo, TextureData, TextureRef, WrapMode};
+use super::shader;
+
+pub const DEFAULT_SAMPLE_BUDGET: u32 = 1024;
+pub const DEFAULT_MAX_BOUNCES: u32 = 4;
+
+pub const ACCUMULATION_FORMAT: vk::Format = vk::Format::R32G32B32A32_SFLOAT;
+const WORKGROUP_SIZE: u32 = 8;
+
+/// A triangle as read by `pathtrace.comp`, the material index in `v0[3]`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct GpuTriangle {
+    v0: [f32; 4],
+    v1: [f32; 4],
+    v2: [f32; 4],
+}
+
+/// Mirrors `TriangleSurface` in `pathtrace.comp`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct GpuTriangleSurface {
+    normals: [[f32; 4]; 3],
+    tangents: [[f32; 4]; 3],
+    uvs: [[f32; 4]; 2],
+    color: [f32; 4],
+}
+
+/// Mirrors `Material` in `pathtrace.comp`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct GpuMaterial {
+    base_color_factor: [f32; 4],
+    emissive: [f32; 4],
+    params: [f32; 4],
+    alpha: [f32; 4],
+    textures: [i32; 4],
+}
+
+/// Mirrors `TracedTexture` in `pathtrace.comp`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct GpuTexture {
+    offset: u32,
+    width: u32,
+    height: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct CameraUniform {
+    inverse_view: Mat4,
+    inverse_projection: Mat4,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct TracePushConstants {
+    sample_index: u32,
+    max_bounces: u32,
+    triangle_count: u32,
+    seed: u32,
+}
+
+/// Progressive path tracer that accumulates one sample per pixel per dispatch into a float image.
+///  The alpha channel of the accumulation holds the number of samples taken. Paths are lit by the
+///  engine's lights and environment in the same physical units as the rasterised scene, and the
+///  display exposes them the same way. Surfaces are shaded with the raster's GGX BRDF and every
+///  material texture, read from storage buffers, so the converged image is a reference for it.
+#[derive(Clone, Debug, Default)]
+pub struct PathTracer {
+    pub sample_budget: u32,
+    pub max_bounces: u32,
+    pub sample_count: u32,
+    scene_version: usize,
+    view_projection: Option<[[f32; 4]; 4]>,
+    lighting: Option<(Vec<Light>, [f32; 3], [f32; 4])>,
+    triangle_count: u32,
+
+    pub accumulation: AllocatedImage,
+    sampler: vk::Sampler,
+    camera_buffer: AllocatedBuffer,
+    triangle_buffer: AllocatedBuffer,
+    node_buffer: AllocatedBuffer,
+    surface_buffer: AllocatedBuffer,
+    material_buffer: AllocatedBuffer,
+    texture_buffer: AllocatedBuffer,
+    texel_buffer: AllocatedBuffer,
+
+    descriptor_pool: vk::DescriptorPool,
+    trace_set_layout: vk::DescriptorSetLayout,
+    trace_set: vk::DescriptorSet,
+    trace_pipeline_layout: vk::PipelineLayout,
+    trace_pipeline: vk::Pipeline,
+    display_set_layout: vk::DescriptorSetLayout,
+    display_set: vk::DescriptorSet,
+    display_pipeline_layout: vk::PipelineLayout,
+    display_pipeline: vk::Pipeline,
+}
+
+impl PathTracer {
+    /// Throws away the accumulated samples
+    pub fn reset(&mut self) {
+        self.sample_count = 0;
+    }
+
+    pub fn is_converged(&self) -> bool {
+        self.sample_budget != 0 && self.sample_count >= self.sample_budget
+    }
+
+    /// Records a single sample per pixel into the accumulation image, reading the lights and sky from `frame_set`
+    pub unsafe fn cmd_trace(&mut self, device: &Device, command_buffer: vk::CommandBuffer, frame_set: vk::DescriptorSet) {
+        let push_constants = TracePushConstants {
+            sample_index: self.sample_count,
+            max_bounces: self.max_bounces,
+            triangle_count: self.triangle_count,
+            seed: self.scene_version as u32,
+        };
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.trace_pipeline);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.trace_pipeline_layout, 0, &[frame_set, self.trace_set], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.trace_pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, &push_constants);
+
+        let extent = self.accumulation.extent;
+        device.cmd_dispatch(command_buffer,
+            extent.width.div_ceil(WORKGROUP_SIZE),
+            extent.height.div_ceil(WORKGROUP_SIZE),
+            1);
+
+        // The next sample and the display both read what was just written
+        memory::cmd_image_barrier(device, command_buffer, self.accumulation.image,
+            memory::color_subresource_range(),
+            (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE));
+
+        self.sample_count += 1;
+    }
+
+    /// Draws the averaged accumulation into the current render pass, exposed by `frame_set`'s exposure
+    pub unsafe fn cmd_display(&self, device: &Device, command_buffer: vk::CommandBuffer, frame_set: vk::DescriptorSet,
+        extent: vk::Extent2D)
+    {
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.display_pipeline);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+            self.display_pipeline_layout, 0, &[frame_set, self.display_set], &[]);
+        shader::cmd_set_full_viewport(device, command_buffer, extent);
+        device.cmd_draw(command_buffer, 3, 1, 0, 0);
+    }
+}
+
+/// Creates the path tracer's pipelines, scene buffers and accumulation target
+pub unsafe fn create_path_tracer(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let frame_set_layout = data.frame.set_layout;
+    let tracer = &mut data.path_tracer;
+    tracer.sample_budget = DEFAULT_SAMPLE_BUDGET;
+    tracer.max_bounces = DEFAULT_MAX_BOUNCES;
+    tracer.sampler = descriptor::create_sampler(device, vk::Filter::NEAREST, 0.0)?;
+
+    // Descriptor layouts
+    tracer.trace_set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::UNIFORM_BUFFER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(2, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(3, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(4, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(5, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(6, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(7, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    tracer.display_set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT),
+    ])?;
+
+    tracer.descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::STORAGE_IMAGE, 1),
+        (vk::DescriptorType::UNIFORM_BUFFER, 1),
+        (vk::DescriptorType::STORAGE_BUFFER, 6),
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1),
+    ], 2)?;
+    tracer.trace_set = descriptor::allocate_set(device, tracer.descriptor_pool, tracer.trace_set_layout)?;
+    tracer.display_set = descriptor::allocate_set(device, tracer.descriptor_pool, tracer.display_set_layout)?;
+
+    // Trace pipeline, lit through the frame set
+    tracer.trace_pipeline_layout = descriptor::create_pipeline_layout(device,
+        &[frame_set_layout, tracer.trace_set_layout],
+        size_of::<TracePushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/pathtrace_comp.spv"));
+    tracer.trace_pipeline = shader::create_compute_pipeline(device, &comp[..], tracer.trace_pipeline_layout)?;
+
+    tracer.display_pipeline_layout = descriptor::create_pipeline_layout(device,
+        &[frame_set_layout, tracer.display_set_layout], 0, vk::ShaderStageFlags::empty())?;
+
+    // Camera uniforms
+    let camera_buffer = AllocatedBuffer::allocate(
+        size_of::<CameraUniform>() as u64,
+        vk::BufferUsageFlags::UNIFORM_BUFFER,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)?;
+    descriptor::write_buffer(device, data.path_tracer.trace_set, 1, vk::DescriptorType::UNIFORM_BUFFER, camera_buffer.buffer);
+    data.path_tracer.camera_buffer = camera_buffer;
+
+    upload_path_tracer_scene(instance, device, data)?;
+    create_path_tracer_targets(instance, device, data)?;
+    Ok(())
+}
+
+/// Creates the resources that depend on the swapchain: the accumulation image and the display pipeline
+pub unsafe fn create_path_tracer_targets(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let accumulation = AllocatedImage::create(
+        data.swapchain_extent,
+        ACCUMULATION_FORMAT,
+        vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_SRC,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+
+    // The accumulation lives in the general layout for its whole lifetime
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    memory::cmd_image_barrier(device, command_buffer, accumulation.image, memory::color_subresource_range(),
+        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+        (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE));
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    let display = include_bytes!(concat!(env!("OUT_DIR"), "/shader/pathtrace_display_frag.spv"));
+    let display_pipeline = shader::create_fullscreen_pipeline(device, &display[..],
+        data.path_tracer.display_pipeline_layout, data.render_pass, 0, SCENE_COLOR_ATTACHMENTS)?;
+
+    let tracer = &mut data.path_tracer;
+    descriptor::write_image(device, tracer.trace_set, 0, vk::DescriptorType::STORAGE_IMAGE,
+        accumulation.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+    descriptor::write_image(device, tracer.display_set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        accumulation.image_view, vk::ImageLayout::GENERAL, tracer.sampler);
+
+    tracer.accumulation = accumulation;
+    tracer.display_pipeline = display_pipeline;
+    tracer.view_projection = None;
+    tracer.reset();
+    Ok(())
+}
+
+pub unsafe fn destroy_path_tracer_targets(device: &Device, data: &mut EngineData)
+{
+    let tracer = &mut data.path_tracer;
+    device.destroy_pipeline(tracer.display_pipeline, None);
+    tracer.accumulation.destroy(device);
+}
+
+pub unsafe fn destroy_path_tracer(device: &Device, data: &mut EngineData)
+{
+    let tracer = &mut data.path_tracer;
+    tracer.camera_buffer.destroy(device);
+    tracer.triangle_buffer.destroy(device);
+    tracer.node_buffer.destroy(device);
+    tracer.surface_buffer.destroy(device);
+    tracer.material_buffer.destroy(device);
+    tracer.texture_buffer.destroy(device);
+    tracer.texel_buffer.destroy(device);
+    device.destroy_pipeline(tracer.trace_pipeline, None);
+    device.destroy_pipeline_layout(tracer.trace_pipeline_layout, None);
+    device.destroy_pipeline_layout(tracer.display_pipeline_layout, None);
+    device.destroy_descriptor_pool(tracer.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(tracer.trace_set_layout, None);
+    device.destroy_descriptor_set_layout(tracer.display_set_layout, None);
+    device.destroy_sampler(tracer.sampler, None);
+}
+
+/// Uploads the camera, rebuilds the scene if it changed and restarts accumulation when anything moved
+///  or the lighting changed. A new sky map has to reset it explicitly.
+pub unsafe fn update_path_tracer(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    if data.path_tracer.scene_version != data.scene_version {
+        upload_path_tracer_scene(instance, device, data)?;
+    }
+
+    let lighting = Some((data.lights.clone(), data.frame.ambient, data.skybox.environment_params()));
+    if data.path_tracer.lighting != lighting {
+        data.path_tracer.lighting = lighting;
+        data.path_tracer.reset();
+    }
+
+    let extent = data.swapchain_extent;
+    let aspect = extent.width as f32 / extent.height.max(1) as f32;
+    let view_projection: [[f32; 4]; 4] = (data.camera.projection(aspect) * data.camera.view()).into();
+
+    let tracer = &mut data.path_tracer;
+    if tracer.view_projection != Some(view_projection) {
+        tracer.view_projection = Some(view_projection);
+        tracer.reset();
+
+        let camera = CameraUniform {
+            inverse_view: data.camera.inverse_view(),
+            inverse_projection: data.camera.inverse_projection(aspect),
+        };
+        tracer.camera_buffer.write(device, &camera, size_of::<CameraUniform>())?;
+    }
+
+    Ok(())
+}
+
+/// The traced form of a material, the default one standing in for instances without one
+fn gpu_material(info: &MaterialInfo) -> GpuMaterial {
+    let e = info.emissive_factor;
+    let alpha_mode = match info.alpha_mode {
+        AlphaMode::Opaque => 0.0,
+        AlphaMode::Mask => 1.0,
+        AlphaMode::Blend => 2.0,
+    };
+    let texture = |r: Option<TextureRef>| r.map_or(-1, |r| r.texture as i32);
+
+    GpuMaterial {
+        base_color_factor: info.base_color_factor,
+        emissive: [e[0] * info.emissive_strength, e[1] * info.emissive_strength, e[2] * info.emissive_strength, 0.0],
+        params: [info.metallic_factor, info.roughness_factor, info.normal_scale, 0.0],
+        alpha: [info.alpha_cutoff, alpha_mode, 0.0, 0.0],
+        textures: [
+            texture(info.base_color_texture),
+            texture(info.metallic_roughness_texture),
+            texture(info.normal_texture),
+            texture(info.emissive_texture),
+        ],
+    }
+}
+
+/// Packs every texture's pixels after each other, with where they start and how they're sampled
+fn gpu_textures(images: &[ImageData], textures: &[TextureData]) -> (Vec<GpuTexture>, Vec<u32>) {
+    let wrap = |w: WrapMode| match w {
+        WrapMode::Repeat => 0,
+        WrapMode::MirroredRepeat => 1,
+        WrapMode::ClampToEdge => 2,
+    };
+
+    // Every image once, however many textures sample it
+    let mut texels = Vec::new();
+    let offsets = images
+        .iter()
+        .map(|image| {
+            let offset = texels.len() as u32;
+            texels.extend(image.pixels.chunks_exact(4).map(|p| u32::from_le_bytes([p[0], p[1], p[2], p[3]])));
+            offset
+        })
+        .collect::<Vec<_>>();
+
+    let infos = textures
+        .iter()
+        .map(|texture| {
+            let image = &images[texture.image];
+            GpuTexture {
+                offset: offsets[texture.image],
+                width: image.width,
+                height: image.height,
+                flags: texture.linear_filter as u32 | wrap(texture.wrap[0]) << 1 | wrap(texture.wrap[1]) << 3,
+            }
+        })
+        .collect();
+    (infos, texels)
+}
+
+/// A storage buffer holding `items`, Vulkan doesn't allow empty buffers so an empty list gets one default item
+unsafe fn create_storage_buffer<T: Copy + Default>(mut items: Vec<T>, instance: &Instance, device: &Device,
+    data: &EngineData) -> Result<AllocatedBuffer>
+{
+    if items.is_empty() {
+        items.push(T::default());
+    }
+    AllocatedBuffer::create(
+        items.as_ptr(),
+        size_of::<T>() * items.len(),
+        vk::BufferUsageFlags::STORAGE_BUFFER,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)
+}
+
+/// Flattens every mesh instance into world space triangles, builds a BVH over them and uploads both,
+///  along with the materials and the textures they sample
+unsafe fn upload_path_tracer_scene(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let library = &data.materials;
+    let default_material = library.materials.len();
+    let mut materials = library.materials
+        .iter()
+        .map(|m| gpu_material(&m.info))
+        .collect::<Vec<_>>();
+    materials.push(gpu_material(&library.default_material.info));
+    let (textures, texels) = gpu_textures(&data.scene.images, &data.scene.textures);
+
+    let mut positions = Vec::new();
+    let mut surfaces = Vec::new();
+    for instance in &data.instances {
+        let material = instance.material
+            .filter(|m| *m < default_material)
+            .unwrap_or(default_material);
+        let model = Matrix3::from_cols(instance.transform.x.truncate(), instance.transform.y.truncate(),
+            instance.transform.z.truncate());
+        let normal_matrix = model.invert().unwrap_or(model).transpose();
+
+        let mesh = &data.meshes[instance.mesh];
+        let vertices = mesh.vertices();
+        for tri in mesh.indices().chunks_exact(3) {
+            let v = [vertices[tri[0] as usize], vertices[tri[1] as usize], vertices[tri[2] as usize]];
+            positions.push(v.map(|v| (instance.transform * v.pos.extend(1.0)).truncate()));
+            surfaces.push((material, GpuTriangleSurface {
+                normals: v.map(|v| (normal_matrix * v.norm).extend(0.0).into()),
+                tangents: v.map(|v| (model * v.tangent.truncate()).extend(v.tangent.w).into()),
+                uvs: [
+                    [v[0].uv.x, v[0].uv.y, v[1].uv.x, v[1].uv.y],
+                    [v[2].uv.x, v[2].uv.y, 0.0, 0.0],
+                ],
+                color: ((v[0].color + v[1].color + v[2].color) / 3.0).extend(1.0).into(),
+            }));
+        }
+    }
+
+    let bvh = Bvh::build(&positions);
+    let (triangles, surfaces): (Vec<_>, Vec<_>) = bvh.indices
+        .iter()
+        .map(|i| {
+            let p = positions[*i as usize];
+            let (material, surface) = surfaces[*i as usize];
+            let triangle = GpuTriangle {
+                v0: p[0].extend(material as f32).into(),
+                v1: p[1].extend(1.0).into(),
+                v2: p[2].extend(1.0).into(),
+            };
+            (triangle, surface)
+        })
+        .unzip();
+
+    let triangle_count = triangles.len() as u32;
+    let triangle_buffer = create_storage_buffer(triangles, instance, device, data)?;
+    let node_buffer = create_storage_buffer(bvh.nodes, instance, device, data)?;
+    let surface_buffer = create_storage_buffer(surfaces, instance, device, data)?;
+    let material_buffer = create_storage_buffer(materials, instance, device, data)?;
+    let texture_buffer = create_storage_buffer(textures, instance, device, data)?;
+    let texel_buffer = create_storage_buffer(texels, instance, device, data)?;
+
+    let tracer = &mut data.path_tracer;
+    tracer.triangle_buffer.destroy(device);
+    tracer.node_buffer.destroy(device);
+    tracer.surface_buffer.destroy(device);
+    tracer.material_buffer.destroy(device);
+    tracer.texture_buffer.destroy(device);
+    tracer.texel_buffer.destroy(device);
+    let buffers = [&triangle_buffer, &node_buffer, &surface_buffer, &material_buffer, &texture_buffer, &texel_buffer];
+    for (binding, buffer) in (2..).zip(buffers) {
+        descriptor::write_buffer(device, tracer.trace_set, binding, vk::DescriptorType::STORAGE_BUFFER, buffer.buffer);
+    }
+
+    tracer.triangle_buffer = triangle_buffer;
+    tracer.node_buffer = node_buffer;
+    tracer.surface_buffer = surface_buffer;
+    tracer.material_buffer = material_buffer;
+    tracer.texture_buffer = texture_buffer;
+    tracer.texel_buffer = texel_buffer;
+    tracer.triangle_count = triangle_count;
+    tracer.scene_version = data.scene_version;
+    tracer.reset();
+    Ok(())
+}
@@ -0,0 +1,421 @@
+use std::mem::size_of;
+use cgmath::{vec3, Deg, Matrix4, SquareMatrix};
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+use log::*;
+
+use super::camera::{Camera, Mat4, Point3};
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::light::Light;
+use super::material::MaterialLibrary;
+use super::memory::{self, AllocatedBuffer, AllocatedImage};
+use super::mesh::Mesh;
+use super::scene::{LightKind, MeshInstance};
+use super::shader;
+use super::shadow::{self, ShadowSettings};
+
+/// Cube face size a point light asks for unless told otherwise
+pub const DEFAULT_POINT_SHADOW_SIZE: u32 = 512;
+
+/// The shadow budget: arrays of (face size, cube count) cubes, six layers each, largest first
+pub const POINT_SHADOW_TIERS: [(u32, usize); 3] = [(1024, 1), (512, 2), (256, 4)];
+
+/// Total number of point lights that can cast shadows at once
+const MAX_POINT_SHADOWS: usize = 7;
+
+/// Like `VULKAN_CLIP` without the Y flip, so faces land in the orientation cube map sampling expects
+const CUBE_CLIP: Mat4 = Mat4::new(
+    1.0, 0.0,       0.0, 0.0,
+    0.0, 1.0,       0.0, 0.0,
+    0.0, 0.0, 1.0 / 2.0, 0.0,
+    0.0, 0.0, 1.0 / 2.0, 1.0,
+);
+
+/// Where a point light's shadow lives: a cube of one of the tiers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PointShadowSlot {
+    pub tier: usize,
+    pub cube: usize,
+}
+
+impl PointShadowSlot {
+    /// Index of the cube across every tier, as used by the shadow pass uniforms
+    fn global_index(&self) -> usize {
+        POINT_SHADOW_TIERS[..self.tier].iter().map(|(_, n)| n).sum::<usize>() + self.cube
+    }
+}
+
+/// Mirrors `PointShadows` in `point_shadow.glsl` (std140)
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct PointShadowUniforms {
+    faces: [Mat4; 6 * MAX_POINT_SHADOWS],
+    /// Light position and far plane per cube
+    lights: [[f32; 4]; MAX_POINT_SHADOWS],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct PointShadowPushConstants {
+    model: Mat4,
+    cube: u32,
+    face: u32,
+    _padding: [u32; 2],
+}
+
+/// One tier of the shadow budget, its cubes' faces as layers of a 2D array.
+#[derive(Clone, Debug, Default)]
+struct CubeTier {
+    size: u32,
+    image: AllocatedImage,
+    /// One view per pass: a 6 layer view per cube with multiview, a view per face without
+    views: Vec<vk::ImageView>,
+    framebuffers: Vec<vk::Framebuffer>,
+}
+
+/// Cube shadow maps of the most important point lights.
+#[derive(Clone, Debug, Default)]
+pub struct PointShadowMaps {
+    /// Slot of each engine light, by light index
+    pub slots: Vec<Option<PointShadowSlot>>,
+    multiview: bool,
+    tiers: Vec<CubeTier>,
+    sampler: vk::Sampler,
+    render_pass: vk::RenderPass,
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    uniform_buffer: AllocatedBuffer,
+    // Per light shader parameters, refreshed by `update_point_shadows`
+    shader_slots: Vec<[f32; 4]>,
+}
+
+impl PointShadowMaps {
+    /// The `shadow` member of a light in the light buffer: tier (-1 for none), cube, far plane and filter disk scale
+    pub fn shader_slot(&self, light: usize) -> [f32; 4] {
+        self.shader_slots
+            .get(light)
+            .copied()
+            .unwrap_or([-1.0, 0.0, 1.0, 0.0])
+    }
+
+    /// Renders every instance into the cube of every shadowed point light
+    pub unsafe fn cmd_render(&self, device: &Device, command_buffer: vk::CommandBuffer, meshes: &[Mesh],
+        instances: &[MeshInstance], materials: &MaterialLibrary)
+    {
+        for slot in self.slots.iter().flatten() {
+            let tier = &self.tiers[slot.tier];
+            let extent = vk::Extent2D { width: tier.size, height: tier.size };
+            let passes = if self.multiview { 0..1 } else { 0..6 };
+            for face in passes {
+                let framebuffer = tier.framebuffers[slot.cube * (if self.multiview { 1 } else { 6 }) + face];
+                shadow::cmd_begin_depth_pass(device, command_buffer, self.render_pass, framebuffer, extent);
+                device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+                device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline_layout,
+                    0, &[self.set], &[]);
+                shader::cmd_set_full_viewport(device, command_buffer, extent);
+                device.cmd_set_depth_bias(command_buffer, 0.0, 0.0, 0.0);
+
+                // The fragment stage writes the distance anyway, so every caster goes through the alpha test
+                shadow::cmd_draw_casters(device, command_buffer, (self.pipeline, self.pipeline), self.pipeline_layout,
+                    1, meshes, instances, materials, |instance| {
+                        let push_constants = PointShadowPushConstants {
+                            model: instance.transform,
+                            cube: slot.global_index() as u32,
+                            face: face as u32,
+                            _padding: [0; 2],
+                        };
+                        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+                            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT, &push_constants);
+                    });
+                device.cmd_end_render_pass(command_buffer);
+            }
+        }
+    }
+}
+
+/// Hands out cubes to shadow casting point lights, most important first. A light gets the largest
+///  tier no bigger than it asked for that still has room, falling back to smaller tiers.
+fn assign_slots(lights: &[Light], camera: &Camera) -> Vec<Option<PointShadowSlot>> {
+    let mut candidates = lights
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| l.kind == LightKind::Point && l.shadow_resolution > 0)
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| {
+        shadow::shadow_importance(b.1, camera).total_cmp(&shadow::shadow_importance(a.1, camera))
+    });
+
+    let mut used = [0; POINT_SHADOW_TIERS.len()];
+    let mut slots = vec![None; lights.len()];
+    for (index, light) in candidates {
+        let first = POINT_SHADOW_TIERS
+            .iter()
+            .position(|(size, _)| *size <= light.shadow_resolution)
+            .unwrap_or(POINT_SHADOW_TIERS.len() - 1);
+        let tier = (first..POINT_SHADOW_TIERS.len()).find(|t| used[*t] < POINT_SHADOW_TIERS[*t].1);
+        if let Some(tier) = tier {
+            slots[index] = Some(PointShadowSlot { tier, cube: used[tier] });
+            used[tier] += 1;
+        }
+    }
+    slots
+}
+
+/// The six view-projections of a cube map centred on `position`, in +X, -X, +Y, -Y, +Z, -Z order
+fn cube_faces(position: Point3, near: f32, far: f32) -> [Mat4; 6] {
+    let projection = CUBE_CLIP * cgmath::perspective(Deg(90.0), 1.0, near, far);
+    let faces = [
+        (vec3(1.0, 0.0, 0.0), vec3(0.0, -1.0, 0.0)),
+        (vec3(-1.0, 0.0, 0.0), vec3(0.0, -1.0, 0.0)),
+        (vec3(0.0, 1.0, 0.0), vec3(0.0, 0.0, 1.0)),
+        (vec3(0.0, -1.0, 0.0), vec3(0.0, 0.0, -1.0)),
+        (vec3(0.0, 0.0, 1.0), vec3(0.0, -1.0, 0.0)),
+        (vec3(0.0, 0.0, -1.0), vec3(0.0, -1.0, 0.0)),
+    ];
+    faces.map(|(forward, up)| projection * Matrix4::look_to_rh(position, forward, up))
+}
+
+pub unsafe fn create_point_shadows(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let multiview = data.allow_multiview;
+    let format = shadow::get_shadow_format(instance, data)?;
+    let render_pass = shadow::create_depth_render_pass(device, format, if multiview { 0b111111 } else { 0 })?;
+    if !multiview {
+        info!("Multiview unsupported, point light shadows take six passes per light.");
+    }
+
+    let mut tiers = Vec::new();
+    for (size, count) in POINT_SHADOW_TIERS {
+        let info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::_2D)
+            .extent(vk::Extent3D { width: size, height: size, depth: 1 })
+            .mip_levels(1)
+            .array_layers(6 * count as u32)
+            .format(format)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .samples(vk::SampleCountFlags::_1);
+
+        let image = AllocatedImage::from_info(&info, vk::ImageViewType::_2D_ARRAY, vk::ImageAspectFlags::DEPTH,
+            instance, device, data)?;
+
+        let command_buffer = memory::begin_single_time_commands(device, data)?;
+        memory::cmd_image_barrier(device, command_buffer, image.image, shadow::depth_subresource_range(0, 6 * count as u32),
+            (vk::ImageLayout::UNDEFINED, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL),
+            (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+            (vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ));
+        memory::end_single_time_commands(device, data, command_buffer)?;
+
+        let views = if multiview {
+            (0..count as u32)
+                .map(|cube| shadow::create_layer_view(device, &image, vk::ImageViewType::_2D_ARRAY, cube * 6, 6))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            (0..6 * count as u32)
+                .map(|layer| shadow::create_layer_view(device, &image, vk::ImageViewType::_2D, layer, 1))
+                .collect::<Result<Vec<_>>>()?
+        };
+        let framebuffers = views
+            .iter()
+            .map(|view| shadow::create_depth_framebuffer(device, render_pass, *view, size))
+            .collect::<Result<Vec<_>>>()?;
+
+        tiers.push(CubeTier { size, image, views, framebuffers });
+    }
+
+    let uniform_buffer = AllocatedBuffer::allocate(
+        size_of::<PointShadowUniforms>() as u64,
+        vk::BufferUsageFlags::UNIFORM_BUFFER,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)?;
+
+    let stages = vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT;
+    let set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::UNIFORM_BUFFER, stages),
+    ])?;
+    let descriptor_pool = descriptor::create_pool(device, &[(vk::DescriptorType::UNIFORM_BUFFER, 1)], 1)?;
+    let set = descriptor::allocate_set(device, descriptor_pool, set_layout)?;
+    descriptor::write_buffer(device, set, 0, vk::DescriptorType::UNIFORM_BUFFER, uniform_buffer.buffer);
+
+    // Every caster binds its material as set 1 for the alpha test
+    let pipeline_layout = descriptor::create_pipeline_layout(device, &[set_layout, data.materials.set_layout],
+        size_of::<PointShadowPushConstants>() as u32, stages)?;
+    let vert = if multiview {
+        &include_bytes!(concat!(env!("OUT_DIR"), "/shader/point_shadow_multiview_vert.spv"))[..]
+    } else {
+        &include_bytes!(concat!(env!("OUT_DIR"), "/shader/point_shadow_vert.spv"))[..]
+    };
+    let frag = include_bytes!(concat!(env!("OUT_DIR"), "/shader/point_shadow_frag.spv"));
+    let pipeline = shadow::create_depth_pipeline(device, vert, Some(&frag[..]), pipeline_layout, render_pass)?;
+
+    // The scene shaders sample each tier through the frame set, picking the face themselves since
+    //  not every device has cube map arrays
+    let sampler = shadow::create_shadow_sampler(format, instance, device, data)?;
+    for (i, tier) in tiers.iter().enumerate() {
+        descriptor::write_image(device, data.frame.set, 4 + i as u32, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            tier.image.image_view, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL, sampler);
+    }
+
+    data.point_shadows = PointShadowMaps {
+        multiview,
+        tiers,
+        sampler,
+        render_pass,
+        set_layout,
+        descriptor_pool,
+        set,
+        pipeline_layout,
+        pipeline,
+        uniform_buffer,
+        ..Default::default()
+    };
+    Ok(())
+}
+
+pub unsafe fn destroy_point_shadows(device: &Device, data: &mut EngineData)
+{
+    let shadows = &mut data.point_shadows;
+    for tier in &mut shadows.tiers {
+        tier.framebuffers
+            .iter()
+            .for_each(|f| device.destroy_framebuffer(*f, None));
+        tier.views
+            .iter()
+            .for_each(|v| device.destroy_image_view(*v, None));
+        tier.image.destroy(device);
+    }
+    shadows.uniform_buffer.destroy(device);
+    device.destroy_sampler(shadows.sampler, None);
+    device.destroy_pipeline(shadows.pipeline, None);
+    device.destroy_pipeline_layout(shadows.pipeline_layout, None);
+    device.destroy_descriptor_pool(shadows.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(shadows.set_layout, None);
+    device.destroy_render_pass(shadows.render_pass, None);
+}
+
+/// Reassigns the cubes to the currently most important lights and uploads their face matrices.
+///  Must run before the lights are uploaded, which read the assignments.
+pub unsafe fn update_point_shadows(device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let settings: ShadowSettings = data.shadows.settings;
+    let slots = assign_slots(&data.lights, &data.camera);
+
+    let mut uniforms = PointShadowUniforms {
+        faces: [Mat4::identity(); 6 * MAX_POINT_SHADOWS],
+        lights: [[0.0; 4]; MAX_POINT_SHADOWS],
+    };
+    let mut shader_slots = vec![[-1.0, 0.0, 1.0, 0.0]; data.lights.len()];
+    for (index, slot) in slots.iter().enumerate() {
+        if let Some(slot) = slot {
+            let light = &data.lights[index];
+            let far = light.range.unwrap_or(settings.point_far).max(settings.point_near * 2.0);
+            let global = slot.global_index();
+            let faces = cube_faces(light.position, settings.point_near, far);
+            uniforms.faces[global * 6..global * 6 + 6].copy_from_slice(&faces);
+            uniforms.lights[global] = [light.position.x, light.position.y, light.position.z, far];
+
+            // A texel of a 90° face spans 2 / size units at unit distance
+            let disk_scale = settings.point_pcf_radius * 2.0 / POINT_SHADOW_TIERS[slot.tier].0 as f32;
+            shader_slots[index] = [slot.tier as f32, slot.cube as f32, far, disk_scale];
+        }
+    }
+
+    let shadows = &mut data.point_shadows;
+    shadows.slots = slots;
+    shadows.shader_slots = shader_slots;
+    shadows.uniform_buffer.write(device, &uniforms, size_of::<PointShadowUniforms>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{point3, vec4, InnerSpace, Vector3};
+
+    /// A point light asking for `resolution`, brighter ones matter more
+    fn point_light(intensity: f32, resolution: u32) -> Light {
+        let light = Light::point(point3(0.0, 3.0, 0.0), vec3(1.0, 1.0, 1.0), intensity, None);
+        Light { shadow_resolution: resolution, ..light }
+    }
+
+    #[test]
+    fn the_most_important_lights_get_the_largest_tiers() {
+        // Shuffled, with lights that don't cast point shadows mixed in
+        let mut lights = [50.0, 90.0, 10.0, 70.0, 30.0, 100.0, 60.0, 20.0, 80.0]
+            .map(|intensity| point_light(intensity, 1024))
+            .to_vec();
+        lights.insert(3, Light::directional(vec3(0.0, -1.0, 0.0), vec3(1.0, 1.0, 1.0), 100_000.0));
+        lights.insert(6, point_light(1000.0, 0));
+        let slots = assign_slots(&lights, &Camera::default());
+
+        let slot = |intensity: f32| {
+            let index = lights.iter().position(|l| l.intensity == intensity && l.shadow_resolution > 0);
+            slots[index.unwrap()].map(|s| (s.tier, s.cube))
+        };
+        assert_eq!(slot(100.0), Some((0, 0)));
+        assert_eq!([slot(90.0), slot(80.0)], [Some((1, 0)), Some((1, 1))]);
+        let smallest = [slot(70.0), slot(60.0), slot(50.0), slot(30.0)];
+        assert_eq!(smallest, [Some((2, 0)), Some((2, 1)), Some((2, 2)), Some((2, 3))]);
+        // Past the budget the least important go without
+        assert_eq!([slot(20.0), slot(10.0)], [None, None]);
+        assert_eq!(slots[3], None);
+        assert_eq!(slots[6], None);
+    }
+
+    #[test]
+    fn lights_never_get_a_larger_tier_than_they_asked_for() {
+        let lights = [100.0, 90.0, 80.0, 70.0, 60.0].map(|intensity| point_light(intensity, 256));
+        let slots = assign_slots(&lights, &Camera::default());
+
+        assert!(slots[..4].iter().flatten().all(|s| s.tier == 2));
+        assert_eq!(slots[..4].iter().flatten().map(|s| s.cube).collect::<Vec<_>>(), [0, 1, 2, 3]);
+        assert_eq!(slots[4], None);
+    }
+
+    #[test]
+    fn full_tiers_fall_back_to_smaller_ones() {
+        let lights = [point_light(100.0, 512), point_light(90.0, 512), point_light(80.0, 512), point_light(70.0, 2048)];
+        let slots = assign_slots(&lights, &Camera::default());
+
+        let tiers = slots.iter().map(|s| s.map(|s| s.tier)).collect::<Vec<_>>();
+        assert_eq!(tiers, [Some(1), Some(1), Some(2), Some(0)]);
+    }
+
+    /// Mirrors `cube_face_uv` in shadow.glsl
+    fn cube_face_uv(d: Vector3<f32>) -> (f32, f32, usize) {
+        let a = vec3(d.x.abs(), d.y.abs(), d.z.abs());
+        let (s, t, major, face) = if a.x >= a.y && a.x >= a.z {
+            (if d.x > 0.0 { -d.z } else { d.z }, -d.y, a.x, if d.x > 0.0 { 0 } else { 1 })
+        } else if a.y >= a.z {
+            (d.x, if d.y > 0.0 { d.z } else { -d.z }, a.y, if d.y > 0.0 { 2 } else { 3 })
+        } else {
+            (if d.z > 0.0 { d.x } else { -d.x }, -d.y, a.z, if d.z > 0.0 { 4 } else { 5 })
+        };
+        (s / major * 0.5 + 0.5, t / major * 0.5 + 0.5, face)
+    }
+
+    #[test]
+    fn shader_face_lookup_matches_the_rendered_faces() {
+        let position = point3(1.0, -2.0, 0.5);
+        let faces = cube_faces(position, 0.05, 25.0);
+        let directions = [
+            vec3(1.0, 0.2, -0.3), vec3(-1.0, -0.4, 0.1), vec3(0.3, 1.0, 0.6), vec3(-0.2, -1.0, -0.7),
+            vec3(0.5, -0.5, 1.0), vec3(-0.6, 0.3, -1.0), vec3(0.9, 0.8, 0.7), vec3(-0.1, 0.05, 0.3),
+        ];
+        for direction in directions {
+            let (u, v, face) = cube_face_uv(direction);
+            let clip = faces[face] * vec4(position.x, position.y, position.z, 1.0)
+                + faces[face] * direction.normalize().extend(0.0);
+            assert!(clip.w > 0.0, "{:?} is behind face {}", direction, face);
+            let (x, y) = (clip.x / clip.w * 0.5 + 0.5, clip.y / clip.w * 0.5 + 0.5);
+            assert!((x - u).abs() < 1e-4 && (y - v).abs() < 1e-4,
+                "{:?} lands at {:?} on face {}, the shader looks up {:?}", direction, (x, y), face, (u, v));
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::engine_data::EngineData;
+use super::memory::{self, AllocatedImage};
+
+/// Format of the target the scene is rendered into, tonemapped into the swapchain afterwards
+pub const HDR_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+/// Format of the screen space motion of every pixel since the last frame, in 0..1 screen units
+pub const VELOCITY_FORMAT: vk::Format = vk::Format::R16G16_SFLOAT;
+
+/// Format of the octahedral shading normals the screen space reflections read
+pub const NORMAL_FORMAT: vk::Format = vk::Format::R16G16_SFLOAT;
+
+/// Format of the split sum specular reflectance in rgb and the roughness in alpha, what the screen
+///  space reflections weigh the reflected light by
+pub const SPECULAR_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
+/// Color attachments of the scene pass: the HDR target, the velocity buffer, and the normals and
+///  specular reflectance
+pub const SCENE_COLOR_ATTACHMENTS: u32 = 4;
+
+/// Workgroup width and height of the post-processing compute shaders
+pub const WORKGROUP_SIZE: u32 = 8;
+
+/// Creates the scratch image post effects write into before it is copied back over the HDR target.
+///  It stays in the general layout outside of the copy.
+pub unsafe fn create_post_image(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let image = AllocatedImage::create(
+        data.swapchain_extent,
+        HDR_FORMAT,
+        vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_SRC,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    memory::cmd_image_barrier(device, command_buffer, image.image, memory::color_subresource_range(),
+        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+        (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    data.post_image = image;
+    Ok(())
+}
+
+/// Dispatches a post-processing compute shader once per pixel of `extent`
+pub unsafe fn cmd_dispatch_pixels(device: &Device, command_buffer: vk::CommandBuffer, extent: vk::Extent2D)
+{
+    device.cmd_dispatch(command_buffer,
+        extent.width.div_ceil(WORKGROUP_SIZE),
+        extent.height.div_ceil(WORKGROUP_SIZE),
+        1);
+}
+
+/// Copies what an effect wrote into the scratch image over the HDR target, which the next effect
+///  and the tonemap pass then sample
+pub unsafe fn cmd_write_back(device: &Device, command_buffer: vk::CommandBuffer, scratch: &AllocatedImage,
+    hdr: &AllocatedImage)
+{
+    let range = memory::color_subresource_range();
+    memory::cmd_image_barrier(device, command_buffer, scratch.image, range,
+        (vk::ImageLayout::GENERAL, vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+        (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ));
+    memory::cmd_image_barrier(device, command_buffer, hdr.image, range,
+        (vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::empty()),
+        (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE));
+
+    let subresource = vk::ImageSubresourceLayers::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .mip_level(0)
+        .base_array_layer(0)
+        .layer_count(1);
+
+    let region = vk::ImageCopy::builder()
+        .src_subresource(subresource)
+        .dst_subresource(subresource)
+        .extent(scratch.extent);
+
+    device.cmd_copy_image(command_buffer,
+        scratch.image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        hdr.image, vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        &[region]);
+
+    memory::cmd_image_barrier(device, command_buffer, hdr.image, range,
+        (vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+        (vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ));
+    memory::cmd_image_barrier(device, command_buffer, scratch.image, range,
+        (vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::ImageLayout::GENERAL),
+        (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::empty()),
+        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+}
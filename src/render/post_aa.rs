@@ -0,0 +1,259 @@
+use std::mem::size_of;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::{self, AllocatedImage};
+use super::post::{self, HDR_FORMAT};
+use super::scene::WrapMode;
+use super::shader;
+use super::smaa;
+use super::texture::Texture;
+
+/// Format of the SMAA edge and blend weight images, which only hold 0..1 values
+const SMAA_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
+/// Post-process anti-aliasing filters, far cheaper than the temporal anti-aliasing and without its
+///  ghosting, but unable to recover detail smaller than a pixel.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PostAaMethod
+{
+    #[default]
+    None,
+    /// Lottes' FXAA 3.11, a single pass blurring along the edges it finds
+    Fxaa,
+    /// Jimenez et al.'s SMAA 1x, three passes reconstructing the edges' geometry for sharper results
+    Smaa,
+}
+
+/// Which post-process anti-aliasing runs and how eagerly it finds edges. It runs on the
+///  rasterised scene alongside or instead of the temporal anti-aliasing.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PostAaSettings {
+    pub method: PostAaMethod,
+    /// How much FXAA softens aliasing within a pixel, 0 keeps the image sharp
+    pub fxaa_subpixel: f32,
+    /// Local contrast FXAA needs to see an edge, relative to the brightest neighbour
+    pub fxaa_edge_threshold: f32,
+    /// Luma difference SMAA needs to see an edge
+    pub smaa_edge_threshold: f32,
+}
+
+impl Default for PostAaSettings {
+    fn default() -> Self {
+        Self {
+            method: PostAaMethod::None,
+            fxaa_subpixel: 0.75,
+            fxaa_edge_threshold: 0.166,
+            smaa_edge_threshold: 0.1,
+        }
+    }
+}
+
+/// Mirrors `PostAa` in `post_aa.glsl`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct PostAaPushConstants {
+    subpixel: f32,
+    fxaa_edge_threshold: f32,
+    smaa_edge_threshold: f32,
+}
+
+/// FXAA and SMAA over the HDR target. Both work on a tonemapped copy with luma in alpha, SMAA
+///  with its own edge and blend weight images and the lookup textures built by `smaa`.
+#[derive(Clone, Debug, Default)]
+pub struct PostAa {
+    pub settings: PostAaSettings,
+    prepared: AllocatedImage,
+    edges: AllocatedImage,
+    weights: AllocatedImage,
+    area_texture: Texture,
+    search_texture: Texture,
+    sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+    set: vk::DescriptorSet,
+    pipeline_layout: vk::PipelineLayout,
+    prepare_pipeline: vk::Pipeline,
+    fxaa_pipeline: vk::Pipeline,
+    smaa_edge_pipeline: vk::Pipeline,
+    smaa_weight_pipeline: vk::Pipeline,
+    smaa_blend_pipeline: vk::Pipeline,
+}
+
+impl PostAa {
+    unsafe fn cmd_pass(&self, device: &Device, command_buffer: vk::CommandBuffer, pipeline: vk::Pipeline,
+        written: Option<&AllocatedImage>)
+    {
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, pipeline);
+        post::cmd_dispatch_pixels(device, command_buffer, self.prepared.extent_2d());
+        if let Some(image) = written {
+            memory::cmd_image_barrier(device, command_buffer, image.image, memory::color_subresource_range(),
+                (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ));
+        }
+    }
+
+    /// Anti-aliases the HDR target through the scratch image with the chosen method
+    pub unsafe fn cmd_render(&self, device: &Device, command_buffer: vk::CommandBuffer,
+        scratch: &AllocatedImage, hdr: &AllocatedImage)
+    {
+        if self.settings.method == PostAaMethod::None {
+            return;
+        }
+
+        let push_constants = PostAaPushConstants {
+            subpixel: self.settings.fxaa_subpixel.clamp(0.0, 1.0),
+            fxaa_edge_threshold: self.settings.fxaa_edge_threshold.max(0.0),
+            smaa_edge_threshold: self.settings.smaa_edge_threshold.max(0.0),
+        };
+
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 0, &[self.set], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, &push_constants);
+
+        self.cmd_pass(device, command_buffer, self.prepare_pipeline, Some(&self.prepared));
+        match self.settings.method {
+            PostAaMethod::Fxaa => {
+                self.cmd_pass(device, command_buffer, self.fxaa_pipeline, None);
+            },
+            PostAaMethod::Smaa => {
+                self.cmd_pass(device, command_buffer, self.smaa_edge_pipeline, Some(&self.edges));
+                self.cmd_pass(device, command_buffer, self.smaa_weight_pipeline, Some(&self.weights));
+                self.cmd_pass(device, command_buffer, self.smaa_blend_pipeline, None);
+            },
+            PostAaMethod::None => unreachable!(),
+        }
+        post::cmd_write_back(device, command_buffer, scratch, hdr);
+    }
+}
+
+pub unsafe fn create_post_aa(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    // The lookup textures are sampled at their first mip, the search texture without filtering
+    let area_texture = Texture::from_pixels(smaa::AREA_SIZE as u32, smaa::AREA_SIZE as u32, &smaa::area_texture(),
+        vk::Format::R8G8B8A8_UNORM, true, [WrapMode::ClampToEdge; 2], instance, device, data)?;
+    let [search_width, search_height] = smaa::SEARCH_SIZE;
+    let search_texture = Texture::from_pixels(search_width as u32, search_height as u32, &smaa::search_texture(),
+        vk::Format::R8G8B8A8_UNORM, false, [WrapMode::ClampToEdge; 2], instance, device, data)?;
+
+    let aa = &mut data.post_aa;
+    aa.settings = PostAaSettings::default();
+    aa.area_texture = area_texture;
+    aa.search_texture = search_texture;
+    aa.sampler = descriptor::create_sampler(device, vk::Filter::LINEAR, 0.0)?;
+
+    aa.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(3, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(4, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(5, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(6, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(7, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(8, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(9, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    aa.descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 6),
+        (vk::DescriptorType::STORAGE_IMAGE, 4),
+    ], 1)?;
+    aa.set = descriptor::allocate_set(device, aa.descriptor_pool, aa.set_layout)?;
+
+    // The lookup textures never change with the swapchain
+    descriptor::write_image(device, aa.set, 4, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        aa.area_texture.image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, aa.area_texture.sampler);
+    descriptor::write_image(device, aa.set, 5, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        aa.search_texture.image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, aa.search_texture.sampler);
+
+    aa.pipeline_layout = descriptor::create_pipeline_layout(device, &[aa.set_layout],
+        size_of::<PostAaPushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+    let prepare = include_bytes!(concat!(env!("OUT_DIR"), "/shader/post_aa_prepare_comp.spv"));
+    aa.prepare_pipeline = shader::create_compute_pipeline(device, &prepare[..], aa.pipeline_layout)?;
+    let fxaa = include_bytes!(concat!(env!("OUT_DIR"), "/shader/fxaa_comp.spv"));
+    aa.fxaa_pipeline = shader::create_compute_pipeline(device, &fxaa[..], aa.pipeline_layout)?;
+    let edges = include_bytes!(concat!(env!("OUT_DIR"), "/shader/smaa_edges_comp.spv"));
+    aa.smaa_edge_pipeline = shader::create_compute_pipeline(device, &edges[..], aa.pipeline_layout)?;
+    let weights = include_bytes!(concat!(env!("OUT_DIR"), "/shader/smaa_weights_comp.spv"));
+    aa.smaa_weight_pipeline = shader::create_compute_pipeline(device, &weights[..], aa.pipeline_layout)?;
+    let blend = include_bytes!(concat!(env!("OUT_DIR"), "/shader/smaa_blend_comp.spv"));
+    aa.smaa_blend_pipeline = shader::create_compute_pipeline(device, &blend[..], aa.pipeline_layout)?;
+
+    create_post_aa_targets(instance, device, data)
+}
+
+/// Creates the intermediate images for the current swapchain size and points the descriptors at the targets
+pub unsafe fn create_post_aa_targets(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let create_target = |format| AllocatedImage::create(
+        data.swapchain_extent,
+        format,
+        vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data);
+    let prepared = create_target(HDR_FORMAT)?;
+    let edges = create_target(SMAA_FORMAT)?;
+    let weights = create_target(SMAA_FORMAT)?;
+
+    // The intermediates stay in the general layout, sampled and stored alike
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    for image in [&prepared, &edges, &weights] {
+        memory::cmd_image_barrier(device, command_buffer, image.image, memory::color_subresource_range(),
+            (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+    }
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    let aa = &data.post_aa;
+    descriptor::write_image(device, aa.set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        data.hdr_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, aa.sampler);
+    descriptor::write_image(device, aa.set, 1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        prepared.image_view, vk::ImageLayout::GENERAL, aa.sampler);
+    descriptor::write_image(device, aa.set, 2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        edges.image_view, vk::ImageLayout::GENERAL, aa.sampler);
+    descriptor::write_image(device, aa.set, 3, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        weights.image_view, vk::ImageLayout::GENERAL, aa.sampler);
+    descriptor::write_image(device, aa.set, 6, vk::DescriptorType::STORAGE_IMAGE,
+        prepared.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+    descriptor::write_image(device, aa.set, 7, vk::DescriptorType::STORAGE_IMAGE,
+        edges.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+    descriptor::write_image(device, aa.set, 8, vk::DescriptorType::STORAGE_IMAGE,
+        weights.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+    descriptor::write_image(device, aa.set, 9, vk::DescriptorType::STORAGE_IMAGE,
+        data.post_image.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+
+    let aa = &mut data.post_aa;
+    aa.prepared = prepared;
+    aa.edges = edges;
+    aa.weights = weights;
+    Ok(())
+}
+
+pub unsafe fn destroy_post_aa_targets(device: &Device, data: &mut EngineData)
+{
+    let aa = &mut data.post_aa;
+    aa.prepared.destroy(device);
+    aa.edges.destroy(device);
+    aa.weights.destroy(device);
+}
+
+pub unsafe fn destroy_post_aa(device: &Device, data: &mut EngineData)
+{
+    let aa = &mut data.post_aa;
+    for pipeline in [aa.prepare_pipeline, aa.fxaa_pipeline, aa.smaa_edge_pipeline, aa.smaa_weight_pipeline,
+        aa.smaa_blend_pipeline]
+    {
+        device.destroy_pipeline(pipeline, None);
+    }
+    device.destroy_pipeline_layout(aa.pipeline_layout, None);
+    device.destroy_descriptor_pool(aa.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(aa.set_layout, None);
+    device.destroy_sampler(aa.sampler, None);
+    aa.area_texture.destroy(device);
+    aa.search_texture.destroy(device);
+}
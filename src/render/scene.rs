@@ -0,0 +1,251 @@
+use cgmath::{Deg, InnerSpace, Matrix4, Rad, SquareMatrix};
+
+use super::camera::{Camera, Mat4, Point3};
+
+/// A node of the scene hierarchy. Transforms are column major, `world_transform` is
+///  resolved from the parents by `Scene::update_transforms`.
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub name: Option<String>,
+    pub transform: Mat4,
+    pub world_transform: Mat4,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub mesh: Option<usize>,
+    pub camera: Option<usize>,
+    pub light: Option<usize>,
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self {
+            name: None,
+            transform: Mat4::identity(),
+            world_transform: Mat4::identity(),
+            parent: None,
+            children: Vec::new(),
+            mesh: None,
+            camera: None,
+            light: None,
+        }
+    }
+}
+
+/// A group of primitives drawn together by a node.
+#[derive(Clone, Debug, Default)]
+pub struct SceneMesh {
+    pub name: Option<String>,
+    pub primitives: Vec<Primitive>,
+}
+
+/// One draw: `mesh` indexes the engine's uploaded meshes.
+#[derive(Copy, Clone, Debug)]
+pub struct Primitive {
+    pub mesh: usize,
+    pub material: Option<usize>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AlphaMode {
+    Opaque,
+    Mask,
+    Blend,
+}
+
+/// A texture slot of a material, `texture` indexes `Scene::textures`.
+#[derive(Copy, Clone, Debug)]
+pub struct TextureRef {
+    pub texture: usize,
+    /// The UV set, always 0 as the importer refuses the others
+    pub tex_coord: u32,
+}
+
+/// Metallic-roughness material parameters as authored.
+#[derive(Clone, Debug)]
+pub struct MaterialInfo {
+    pub name: Option<String>,
+    pub base_color_factor: [f32; 4],
+    pub base_color_texture: Option<TextureRef>,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub metallic_roughness_texture: Option<TextureRef>,
+    pub normal_texture: Option<TextureRef>,
+    pub normal_scale: f32,
+    pub occlusion_texture: Option<TextureRef>,
+    pub occlusion_strength: f32,
+    pub emissive_factor: [f32; 3],
+    pub emissive_strength: f32,
+    pub emissive_texture: Option<TextureRef>,
+    pub alpha_mode: AlphaMode,
+    pub alpha_cutoff: f32,
+    pub double_sided: bool,
+}
+
+impl Default for MaterialInfo {
+    fn default() -> Self {
+        Self {
+            name: None,
+            base_color_factor: [1.0, 1.0, 1.0, 1.0],
+            base_color_texture: None,
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            normal_scale: 1.0,
+            occlusion_texture: None,
+            occlusion_strength: 1.0,
+            emissive_factor: [0.0, 0.0, 0.0],
+            emissive_strength: 1.0,
+            emissive_texture: None,
+            alpha_mode: AlphaMode::Opaque,
+            alpha_cutoff: 0.5,
+            double_sided: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum WrapMode {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+}
+
+/// Decoded image pixels, always 8-bit RGBA. Whether they are sRGB depends on the
+///  material slot that samples them.
+#[derive(Clone, Debug)]
+pub struct ImageData {
+    pub name: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// An image and how it's sampled, `image` indexes `Scene::images`, which several textures may
+///  share.
+#[derive(Clone, Debug)]
+pub struct TextureData {
+    pub name: Option<String>,
+    pub image: usize,
+    pub linear_filter: bool,
+    pub wrap: [WrapMode; 2],
+}
+
+/// A camera's projection, the importer refuses orthographic ones
+#[derive(Copy, Clone, Debug)]
+pub enum Projection {
+    Perspective { yfov: f32, aspect: Option<f32>, znear: f32, zfar: Option<f32> },
+}
+
+#[derive(Clone, Debug)]
+pub struct SceneCamera {
+    pub name: Option<String>,
+    pub projection: Projection,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LightKind {
+    Directional,
+    Point,
+    Spot { inner_cone_angle: f32, outer_cone_angle: f32 },
+}
+
+/// A punctual light as authored. Intensity is in candela for point and spot lights
+///  and lux for directional ones.
+#[derive(Clone, Debug)]
+pub struct SceneLight {
+    pub name: Option<String>,
+    pub kind: LightKind,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: Option<f32>,
+}
+
+/// A mesh placed in the world, flattened out of the hierarchy.
+#[derive(Copy, Clone, Debug)]
+pub struct MeshInstance {
+    pub mesh: usize,
+    pub material: Option<usize>,
+    pub transform: Mat4,
+}
+
+/// An imported scene graph with everything its nodes reference.
+#[derive(Clone, Debug, Default)]
+pub struct Scene {
+    pub nodes: Vec<Node>,
+    pub roots: Vec<usize>,
+    pub meshes: Vec<SceneMesh>,
+    pub materials: Vec<MaterialInfo>,
+    pub images: Vec<ImageData>,
+    pub textures: Vec<TextureData>,
+    pub cameras: Vec<SceneCamera>,
+    pub lights: Vec<SceneLight>,
+}
+
+impl Scene {
+    /// Resolves every node's world transform from its local transform and parents
+    pub fn update_transforms(&mut self) {
+        let mut stack = self.roots
+            .iter()
+            .map(|r| (*r, Mat4::identity()))
+            .collect::<Vec<_>>();
+
+        while let Some((index, parent)) = stack.pop() {
+            let world = parent * self.nodes[index].transform;
+            self.nodes[index].world_transform = world;
+            stack.extend(self.nodes[index].children.iter().map(|c| (*c, world)));
+        }
+    }
+
+    /// The nodes reachable from the roots in depth first order, the ones `update_transforms` placed.
+    ///  Nodes of other scenes in the file are left out.
+    pub fn scene_nodes(&self) -> impl Iterator<Item = &Node> {
+        let mut stack = self.roots.iter().rev().copied().collect::<Vec<_>>();
+        std::iter::from_fn(move || {
+            let index = stack.pop()?;
+            stack.extend(self.nodes[index].children.iter().rev());
+            Some(&self.nodes[index])
+        })
+    }
+
+    /// Flattens the hierarchy into one instance per primitive
+    pub fn instances(&self) -> Vec<MeshInstance> {
+        self.scene_nodes()
+            .filter_map(|n| n.mesh.map(|m| (n, m)))
+            .flat_map(|(node, mesh)| {
+                self.meshes[mesh].primitives.iter().map(move |p| MeshInstance {
+                    mesh: p.mesh,
+                    material: p.material,
+                    transform: node.world_transform,
+                })
+            })
+            .collect()
+    }
+
+    /// The first node carrying a camera, as an engine camera
+    pub fn first_camera(&self) -> Option<Camera> {
+        self.scene_nodes()
+            .find_map(|n| n.camera.map(|c| self.cameras[c].to_camera(&n.world_transform)))
+    }
+}
+
+impl SceneCamera {
+    /// Converts to an engine camera placed at `world`, looking down its -Z axis
+    pub fn to_camera(&self, world: &Matrix4<f32>) -> Camera {
+        let mut camera = Camera::default();
+        let position = Point3::new(world.w.x, world.w.y, world.w.z);
+        camera.position = position;
+        camera.target = position - world.z.truncate().normalize();
+        camera.up = world.y.truncate().normalize();
+
+        match self.projection {
+            Projection::Perspective { yfov, znear, zfar, .. } => {
+                camera.fov_y = Deg::from(Rad(yfov));
+                camera.near = znear;
+                camera.far = zfar.unwrap_or(camera.far.max(znear * 1000.0));
+            },
+        }
+
+        camera
+    }
+}
@@ -0,0 +1,313 @@
+use anyhow::{Ok, Result};
+use vulkanalia::prelude::v1_3::*;
+use vulkanalia::bytecode::Bytecode;
+
+use super::mesh::Vertex;
+
+/// Creates a shader module from SPIR-V bytecode
+pub unsafe fn create_shader_module(device: &Device, bytecode: &[u8],) -> Result<vk::ShaderModule>
+{
+    let bytecode = Bytecode::new(bytecode).unwrap();
+    let info = vk::ShaderModuleCreateInfo::builder()
+        .code_size(bytecode.code_size())
+        .code(bytecode.code());
+
+    Ok(device.create_shader_module(&info, None)?)
+}
+
+/// Creates a compute pipeline from a single compute shader
+pub unsafe fn create_compute_pipeline(device: &Device, bytecode: &[u8], layout: vk::PipelineLayout) -> Result<vk::Pipeline>
+{
+    let module = create_shader_module(device, bytecode)?;
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(module)
+        .name(b"main\0");
+
+    let info = vk::ComputePipelineCreateInfo::builder()
+        .stage(stage)
+        .layout(layout);
+
+    let pipeline = device
+        .create_compute_pipelines(vk::PipelineCache::null(), &[info], None)?
+        .0[0];
+
+    device.destroy_shader_module(module, None);
+    Ok(pipeline)
+}
+
+/// Creates the pipelines drawing meshes with `frag` into every one of the subpass' `color_attachment_count`
+///  color attachments, one culling back faces and one for double sided materials. `blended` ones are
+///  for alpha blended materials: they test depth without writing it and blend over the first
+///  attachment only, leaving the others to the surfaces behind.
+pub unsafe fn create_mesh_pipelines(device: &Device, frag: &[u8], layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass, subpass: u32, color_attachment_count: u32, extent: vk::Extent2D, blended: bool)
+    -> Result<(vk::Pipeline, vk::Pipeline)>
+{
+    // Shaders
+    let vert = include_bytes!(concat!(env!("OUT_DIR"), "/shader/shader_vert.spv"));
+    let vert_shader_module = create_shader_module(device, &vert[..])?;
+    let frag_shader_module = create_shader_module(device, frag)?;
+
+    // Shader stages
+    let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::VERTEX)
+        .module(vert_shader_module)
+        .name(b"main\0");
+
+    let frag_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::FRAGMENT)
+        .module(frag_shader_module)
+        .name(b"main\0");
+
+    // Input Assembly State
+    let binding_descriptions = &[Vertex::binding_description()];
+    let attribute_descriptions = Vertex::attribute_descriptions();
+    let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+        .vertex_binding_descriptions(binding_descriptions)
+        .vertex_attribute_descriptions(&attribute_descriptions);
+
+    let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+        .primitive_restart_enable(false);
+
+    // Viewports and scissors
+    let viewport = vk::Viewport::builder()
+        .x(0.0)
+        .y(0.0)
+        .width(extent.width as f32)
+        .height(extent.height as f32)
+        .min_depth(0.0)
+        .max_depth(1.0);
+
+    let scissor = vk::Rect2D::builder()
+        .offset(vk::Offset2D { x: 0, y: 0 })
+        .extent(extent);
+
+    let viewports = &[viewport];
+    let scissors = &[scissor];
+    let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+        .viewports(viewports)
+        .scissors(scissors);
+
+    // Rasterization state, double sided materials get a pipeline without culling
+    let rasterization_state = |cull_mode: vk::CullModeFlags| vk::PipelineRasterizationStateCreateInfo::builder()
+       .depth_clamp_enable(false)
+       .rasterizer_discard_enable(false)
+       .polygon_mode(vk::PolygonMode::FILL)
+       .line_width(1.0)
+       .cull_mode(cull_mode)
+       .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+       .depth_bias_enable(false)
+       .build();
+
+    let culled_state = rasterization_state(vk::CullModeFlags::BACK);
+    let double_sided_state = rasterization_state(vk::CullModeFlags::NONE);
+
+    // Multisampling state
+    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+       .sample_shading_enable(false)
+       .rasterization_samples(vk::SampleCountFlags::_1);
+
+    // Depth Stencil State
+    let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(true)
+        .depth_write_enable(!blended)
+        .depth_compare_op(vk::CompareOp::LESS)
+        .depth_bounds_test_enable(false)
+        .stencil_test_enable(false);
+
+    // Color Blend State, blending the first attachment over by the fragment's alpha
+    let attachment = vk::PipelineColorBlendAttachmentState::builder()
+       .color_write_mask(vk::ColorComponentFlags::all())
+       .blend_enable(false)
+       .build();
+    let blended_attachment = vk::PipelineColorBlendAttachmentState::builder()
+       .color_write_mask(vk::ColorComponentFlags::all())
+       .blend_enable(true)
+       .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+       .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+       .color_blend_op(vk::BlendOp::ADD)
+       .src_alpha_blend_factor(vk::BlendFactor::ONE)
+       .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+       .alpha_blend_op(vk::BlendOp::ADD)
+       .build();
+    let untouched_attachment = vk::PipelineColorBlendAttachmentState::builder()
+       .color_write_mask(vk::ColorComponentFlags::empty())
+       .blend_enable(false)
+       .build();
+
+    let attachments = (0..color_attachment_count)
+        .map(|i| match (blended, i) {
+            (false, _) => attachment,
+            (true, 0) => blended_attachment,
+            (true, _) => untouched_attachment,
+        })
+        .collect::<Vec<_>>();
+    let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+        .logic_op_enable(false)
+        .logic_op(vk::LogicOp::COPY)
+        .attachments(&attachments)
+        .blend_constants([0.0, 0.0, 0.0, 0.0]);
+    
+    // Dynamic States
+    let dynamic_states = &[
+        vk::DynamicState::VIEWPORT,
+        vk::DynamicState::LINE_WIDTH,
+    ];
+    let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+        .dynamic_states(dynamic_states);
+
+    // Creation
+    let stages = &[vert_stage, frag_stage];
+    let info = |rasterization_state: &vk::PipelineRasterizationStateCreateInfo| vk::GraphicsPipelineCreateInfo::builder()
+        .stages(stages)
+        .vertex_input_state(&vertex_input_state)
+        .input_assembly_state(&input_assembly_state)
+        .viewport_state(&viewport_state)
+        .rasterization_state(rasterization_state)
+        .multisample_state(&multisample_state)
+        .depth_stencil_state(&depth_stencil_state)
+        .color_blend_state(&color_blend_state)
+        .dynamic_state(&dynamic_state)
+        .layout(layout)
+        .render_pass(render_pass)
+        .subpass(subpass)
+        .build();
+
+    let pipelines = device
+        .create_graphics_pipelines(vk::PipelineCache::null(), &[info(&culled_state), info(&double_sided_state)], None)?
+        .0;
+
+    // Cleanup
+    device.destroy_shader_module(vert_shader_module, None);
+    device.destroy_shader_module(frag_shader_module, None);
+
+    Ok((pipelines[0], pipelines[1]))
+}
+
+/// Creates a pipeline that draws a single fullscreen triangle with the given fragment shader.
+///  The viewport and scissor are dynamic so the pipeline survives swapchain resizes. Only the first
+///  of the subpass' `color_attachment_count` color attachments is written.
+pub unsafe fn create_fullscreen_pipeline(device: &Device, frag: &[u8], layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass, subpass: u32, color_attachment_count: u32) -> Result<vk::Pipeline>
+{
+    let vert = include_bytes!(concat!(env!("OUT_DIR"), "/shader/fullscreen_vert.spv"));
+    let vert_shader_module = create_shader_module(device, &vert[..])?;
+    let frag_shader_module = create_shader_module(device, frag)?;
+
+    let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::VERTEX)
+        .module(vert_shader_module)
+        .name(b"main\0");
+
+    let frag_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::FRAGMENT)
+        .module(frag_shader_module)
+        .name(b"main\0");
+
+    // No vertex buffers, the triangle is generated from gl_VertexIndex
+    let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder();
+    let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+        .primitive_restart_enable(false);
+
+    let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+        .viewport_count(1)
+        .scissor_count(1);
+
+    let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+        .depth_clamp_enable(false)
+        .rasterizer_discard_enable(false)
+        .polygon_mode(vk::PolygonMode::FILL)
+        .line_width(1.0)
+        .cull_mode(vk::CullModeFlags::NONE)
+        .front_face(vk::FrontFace::CLOCKWISE)
+        .depth_bias_enable(false);
+
+    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+        .sample_shading_enable(false)
+        .rasterization_samples(vk::SampleCountFlags::_1);
+
+    // Fullscreen passes never test or write depth, even in passes that have a depth attachment
+    let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(false)
+        .depth_write_enable(false)
+        .depth_compare_op(vk::CompareOp::ALWAYS)
+        .depth_bounds_test_enable(false)
+        .stencil_test_enable(false);
+
+    let attachments = (0..color_attachment_count.max(1))
+        .map(|i| {
+            let mask = if i == 0 { vk::ColorComponentFlags::all() } else { vk::ColorComponentFlags::empty() };
+            vk::PipelineColorBlendAttachmentState::builder()
+                .color_write_mask(mask)
+                .blend_enable(false)
+                .build()
+        })
+        .collect::<Vec<_>>();
+
+    let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+        .logic_op_enable(false)
+        .logic_op(vk::LogicOp::COPY)
+        .attachments(&attachments)
+        .blend_constants([0.0, 0.0, 0.0, 0.0]);
+
+    let dynamic_states = &[
+        vk::DynamicState::VIEWPORT,
+        vk::DynamicState::SCISSOR,
+    ];
+    let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+        .dynamic_states(dynamic_states);
+
+    let stages = &[vert_stage, frag_stage];
+    let info = vk::GraphicsPipelineCreateInfo::builder()
+        .stages(stages)
+        .vertex_input_state(&vertex_input_state)
+        .input_assembly_state(&input_assembly_state)
+        .viewport_state(&viewport_state)
+        .rasterization_state(&rasterization_state)
+        .multisample_state(&multisample_state)
+        .depth_stencil_state(&depth_stencil_state)
+        .color_blend_state(&color_blend_state)
+        .dynamic_state(&dynamic_state)
+        .layout(layout)
+        .render_pass(render_pass)
+        .subpass(subpass);
+
+    let pipeline = device
+        .create_graphics_pipelines(vk::PipelineCache::null(), &[info], None)?
+        .0[0];
+
+    device.destroy_shader_module(vert_shader_module, None);
+    device.destroy_shader_module(frag_shader_module, None);
+
+    Ok(pipeline)
+}
+
+/// Sets a viewport and scissor covering the whole extent
+pub unsafe fn cmd_set_full_viewport(device: &Device, command_buffer: vk::CommandBuffer, extent: vk::Extent2D)
+{
+    let viewport = vk::Viewport::builder()
+        .x(0.0)
+        .y(0.0)
+        .width(extent.width as f32)
+        .height(extent.height as f32)
+        .min_depth(0.0)
+        .max_depth(1.0);
+
+    let scissor = vk::Rect2D::builder()
+        .offset(vk::Offset2D { x: 0, y: 0 })
+        .extent(extent);
+
+    device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+    device.cmd_set_scissor(command_buffer, 0, &[scissor]);
+}
+
+/// Pushes `value` as raw bytes at offset 0 of the layout's push constant range
+pub unsafe fn cmd_push_constants<T>(device: &Device, command_buffer: vk::CommandBuffer, layout: vk::PipelineLayout,
+    stages: vk::ShaderStageFlags, value: &T)
+{
+    let bytes = std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>());
+    device.cmd_push_constants(command_buffer, layout, stages, 0, bytes);
+}
@@ -0,0 +1,19 @@
+// Alpha testing of masked materials in the shadow passes, the same cut as `surface()` in
+//  surface.glsl. The includer defines MATERIAL_SET, the set the material is bound to.
+
+// Mirrors `MaterialUniform` in material.rs
+layout(set = MATERIAL_SET, binding = 0) uniform MaterialParams {
+    vec4 base_color_factor;
+    vec4 emissive;
+    vec4 params;
+    vec4 alpha;             // cutoff, mode (0 opaque, 1 mask, 2 blend)
+} material;
+
+layout(set = MATERIAL_SET, binding = 1) uniform sampler2D baseColorMap;
+
+// Discards the fragments a masked material cuts out, casting no shadow there
+void alpha_test(vec2 uv) {
+    if (material.alpha.y == 1.0 && material.base_color_factor.a * texture(baseColorMap, uv).a < material.alpha.x) {
+        discard;
+    }
+}
@@ -0,0 +1,131 @@
+// A planet's atmosphere after Hillaire's "A Scalable and Production Ready Sky and Atmosphere
+//  Rendering Technique": Rayleigh and Mie scattering with an ozone layer, precomputed into a
+//  transmittance, a multiple scattering and a sky view lookup table. Distances are in km from the
+//  planet's centre, radiance is per lux of sunlight above the atmosphere. Needs brdf.glsl.
+
+layout(push_constant) uniform Atmosphere {
+    vec4 sun_direction;     // xyz towards the sun, w = the viewer's altitude
+    vec4 rayleigh;          // rgb scattering per km at the ground, w = density scale height
+    vec4 mie;               // scattering and absorption per km at the ground, scale height, anisotropy
+    vec4 ozone;             // rgb absorption per km at the layer's peak, w = the peak's altitude
+    vec4 ground;            // rgb albedo, w = planet radius
+    float top_radius;       // where the atmosphere ends
+    float ozone_width;      // of the tent the ozone's density falls off in
+} atmosphere;
+
+layout(set = 0, binding = 0) uniform sampler2D transmittanceLut;
+layout(set = 0, binding = 1) uniform sampler2D multiscatteringLut;
+
+struct Medium {
+    vec3 rayleigh;          // scattering
+    vec3 mie;               // scattering
+    vec3 extinction;
+};
+
+Medium sample_medium(float altitude) {
+    float rayleigh_density = exp(-altitude / atmosphere.rayleigh.w);
+    float mie_density = exp(-altitude / atmosphere.mie.z);
+    float ozone_density = max(1.0 - abs(altitude - atmosphere.ozone.w) * 2.0 / atmosphere.ozone_width, 0.0);
+
+    Medium m;
+    m.rayleigh = atmosphere.rayleigh.rgb * rayleigh_density;
+    m.mie = vec3(atmosphere.mie.x * mie_density);
+    m.extinction = m.rayleigh + vec3((atmosphere.mie.x + atmosphere.mie.y) * mie_density)
+        + atmosphere.ozone.rgb * ozone_density;
+    return m;
+}
+
+float altitude_of(vec3 p) {
+    return length(p) - atmosphere.ground.w;
+}
+
+// Distance along d from p to the nearest hit ahead of it on a sphere about the centre, -1 for none
+float ray_sphere(vec3 p, vec3 d, float radius) {
+    float b = dot(p, d);
+    float c = dot(p, p) - radius * radius;
+    float h = b * b - c;
+    if (h < 0.0) {
+        return -1.0;
+    }
+    h = sqrt(h);
+    if (-b + h < 0.0) {
+        return -1.0;
+    }
+    return -b - h >= 0.0 ? -b - h : -b + h;
+}
+
+float rayleigh_phase(float cos_theta) {
+    return 3.0 / (16.0 * PI) * (1.0 + cos_theta * cos_theta);
+}
+
+// Cornette-Shanks, a Henyey-Greenstein lobe with Rayleigh's shape
+float mie_phase(float cos_theta) {
+    float g = atmosphere.mie.w;
+    float g2 = g * g;
+    float denominator = (2.0 + g2) * pow(max(1.0 + g2 - 2.0 * g * cos_theta, 1e-4), 1.5);
+    return 3.0 / (8.0 * PI) * (1.0 - g2) * (1.0 + cos_theta * cos_theta) / denominator;
+}
+
+// Bruneton's mapping of the transmittance table: the distance to the top of the atmosphere in x,
+//  the distance to the horizon in y, which puts the detail near the ground and the horizon
+vec2 transmittance_uv(float r, float mu) {
+    float bottom = atmosphere.ground.w;
+    float h = sqrt(atmosphere.top_radius * atmosphere.top_radius - bottom * bottom);
+    float rho = sqrt(max(r * r - bottom * bottom, 0.0));
+    float discriminant = r * r * (mu * mu - 1.0) + atmosphere.top_radius * atmosphere.top_radius;
+    float d = max(-r * mu + sqrt(max(discriminant, 0.0)), 0.0);
+    float d_min = atmosphere.top_radius - r;
+    float d_max = rho + h;
+    return vec2((d - d_min) / (d_max - d_min), rho / h);
+}
+
+void transmittance_params(vec2 uv, out float r, out float mu) {
+    float bottom = atmosphere.ground.w;
+    float h = sqrt(atmosphere.top_radius * atmosphere.top_radius - bottom * bottom);
+    float rho = h * uv.y;
+    r = sqrt(rho * rho + bottom * bottom);
+    float d_min = atmosphere.top_radius - r;
+    float d_max = rho + h;
+    float d = d_min + uv.x * (d_max - d_min);
+    mu = d == 0.0 ? 1.0 : (h * h - rho * rho - d * d) / (2.0 * r * d);
+    mu = clamp(mu, -1.0, 1.0);
+}
+
+// Share of the light from direction l that reaches p, none where the planet is in the way
+vec3 transmittance_towards(vec3 p, vec3 l) {
+    if (ray_sphere(p, l, atmosphere.ground.w) > 0.0) {
+        return vec3(0.0);
+    }
+    float r = length(p);
+    return textureLod(transmittanceLut, transmittance_uv(r, dot(p / r, l)), 0.0).rgb;
+}
+
+// Light scattered twice or more towards p per unit of scattering, over the sun's zenith cosine
+//  in x and the altitude in y
+vec3 multiple_scattering(vec3 p, vec3 l) {
+    float r = length(p);
+    vec2 uv = vec2(dot(p / r, l) * 0.5 + 0.5, (r - atmosphere.ground.w) / (atmosphere.top_radius - atmosphere.ground.w));
+    return textureLod(multiscatteringLut, clamp(uv, 0.0, 1.0), 0.0).rgb;
+}
+
+// Where the viewer stands, above the origin
+vec3 viewer_position() {
+    return vec3(0.0, atmosphere.ground.w + max(atmosphere.sun_direction.w, 1e-3), 0.0);
+}
+
+// The sky view table covers every direction, the azimuth about +Y from +Z towards +X in x and the
+//  elevation in y from straight up at 0 to straight down at 1. Its square root puts half the
+//  texels within a quarter of the horizon's elevation.
+vec3 sky_view_direction(vec2 uv) {
+    float azimuth = (uv.x * 2.0 - 1.0) * PI;
+    float y = 1.0 - uv.y * 2.0;
+    float elevation = sign(y) * y * y * 0.5 * PI;
+    return vec3(cos(elevation) * sin(azimuth), sin(elevation), cos(elevation) * cos(azimuth));
+}
+
+vec2 sky_view_uv(vec3 d) {
+    float azimuth = atan(d.x, d.z);
+    float elevation = asin(clamp(d.y, -1.0, 1.0));
+    float y = sign(elevation) * sqrt(abs(elevation) / (0.5 * PI));
+    return vec2(azimuth / (2.0 * PI) + 0.5, 0.5 - y * 0.5);
+}
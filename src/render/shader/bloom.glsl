@@ -0,0 +1,21 @@
+// Shared by the passes of the bloom chain
+
+layout(binding = 0) uniform sampler2D source;
+layout(binding = 1, rgba16f) uniform image2D destination;
+layout(binding = 2) uniform sampler2D hdr;
+
+layout(push_constant) uniform Bloom {
+    float source_lod;
+    float radius;
+    float intensity;
+    uint karis_average;
+    uint level_count;
+} bloom;
+
+// Texel center of an invocation in the destination, in 0..1
+bool destination_uv(out ivec2 pixel, out vec2 uv) {
+    ivec2 size = imageSize(destination);
+    pixel = ivec2(gl_GlobalInvocationID.xy);
+    uv = (vec2(pixel) + 0.5) / vec2(size);
+    return all(lessThan(pixel, size));
+}
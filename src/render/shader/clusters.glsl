@@ -0,0 +1,40 @@
+// Light lists of the froxel grid, filled by cluster_cull.comp. Every cluster holds its light
+//  count followed by the indices of its lights. Needs frame.glsl.
+
+// Mirror `CLUSTER_GRID` and `MAX_CLUSTER_LIGHTS` in cluster.rs
+const uvec3 CLUSTER_GRID = uvec3(16, 9, 24);
+const uint MAX_CLUSTER_LIGHTS = 127;
+const uint CLUSTER_STRIDE = MAX_CLUSTER_LIGHTS + 1;
+
+#ifdef CLUSTER_CULLING
+layout(std430, set = 0, binding = 7) buffer Clusters {
+#else
+layout(std430, set = 0, binding = 7) readonly buffer Clusters {
+#endif
+    uint cluster_lights[];
+};
+
+#ifdef CLUSTER_CULLING
+// Most lights an overflowing cluster had in reach, read back to warn about dropped lights
+layout(std430, set = 0, binding = 14) buffer ClusterOverflow {
+    uint cluster_overflow;
+};
+#endif
+
+// Depth slices are spaced exponentially, so clusters stay roughly cubic
+uint depth_slice(float view_depth) {
+    float slice = log(max(view_depth, 1e-4)) * frame.clusters.x - frame.clusters.y;
+    return uint(clamp(slice, 0.0, float(CLUSTER_GRID.z - 1)));
+}
+
+// View depth where a slice starts, inverting `depth_slice`
+float slice_depth(float slice) {
+    return exp((slice + frame.clusters.y) / frame.clusters.x);
+}
+
+// Offset of the cluster covering a pixel in `cluster_lights`
+uint cluster_offset(vec2 frag_coord, float view_depth) {
+    uvec2 tile = min(uvec2(frag_coord / frame.clusters.zw), CLUSTER_GRID.xy - 1);
+    uint index = tile.x + CLUSTER_GRID.x * (tile.y + CLUSTER_GRID.y * depth_slice(view_depth));
+    return index * CLUSTER_STRIDE;
+}
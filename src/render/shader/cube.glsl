@@ -0,0 +1,14 @@
+// Cube map face addressing for compute passes writing cube maps as 2D arrays
+
+// Direction through a point of a cube face, uv in 0..1, faces in Vulkan's +X, -X, +Y, -Y, +Z, -Z order
+vec3 cube_direction(vec2 uv, int face) {
+    vec2 p = uv * 2.0 - 1.0;
+    switch (face) {
+        case 0: return normalize(vec3(1.0, -p.y, -p.x));
+        case 1: return normalize(vec3(-1.0, -p.y, p.x));
+        case 2: return normalize(vec3(p.x, 1.0, p.y));
+        case 3: return normalize(vec3(p.x, -1.0, -p.y));
+        case 4: return normalize(vec3(p.x, -p.y, 1.0));
+        default: return normalize(vec3(-p.x, -p.y, -1.0));
+    }
+}
@@ -0,0 +1,39 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "frame.glsl"
+#include "brdf.glsl"
+#include "lights.glsl"
+#include "shadow.glsl"
+#include "shading.glsl"
+#include "gbuffer.glsl"
+
+// The G-buffer, read from the same pixel the previous subpass wrote
+layout(input_attachment_index = 0, set = 1, binding = 0) uniform subpassInput albedoInput;
+layout(input_attachment_index = 1, set = 1, binding = 1) uniform subpassInput normalInput;
+layout(input_attachment_index = 2, set = 1, binding = 2) uniform subpassInput materialInput;
+layout(input_attachment_index = 3, set = 1, binding = 3) uniform subpassInput emissionInput;
+layout(input_attachment_index = 4, set = 1, binding = 4) uniform subpassInput depthInput;
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    vec3 emission = subpassLoad(emissionInput).rgb;
+    float depth = subpassLoad(depthInput).r;
+    if (depth >= 1.0) {
+        outColor = vec4(emission, 1.0);
+        return;
+    }
+
+    vec4 world = frame.inverse_view_projection * vec4(fragUV * 2.0 - 1.0, depth, 1.0);
+    vec3 position = world.xyz / world.w;
+
+    vec4 normals = subpassLoad(normalInput);
+    vec2 material = subpassLoad(materialInput).rg;
+    vec3 radiance = direct_radiance(position, decode_normal(normals.xy), decode_normal(normals.zw),
+        subpassLoad(albedoInput).rgb, material.r, material.g);
+
+    outColor = vec4(radiance * frame.exposure + emission, 1.0);
+}
@@ -0,0 +1,32 @@
+// Shared by the depth of field gather and autofocus shaders
+
+layout(binding = 0) uniform sampler2D hdr;
+layout(binding = 1) uniform sampler2D depth;
+layout(binding = 2, rgba16f) uniform writeonly image2D result;
+layout(binding = 3) buffer Focus {
+    float distance;
+} focus;
+
+layout(push_constant) uniform DepthOfField {
+    float coc_scale;
+    float focus_distance;
+    float near;
+    float far;
+    float max_coc;
+    uint blade_count;
+    float blade_rotation;
+    float _padding;
+    vec2 focus_point;
+} dof;
+
+// Distance along the view axis from a 0..1 depth buffer value
+float linear_depth(float d) {
+    return dof.near * dof.far / (dof.far - d * (dof.far - dof.near));
+}
+
+// Signed circle of confusion radius in pixels, negative in front of the focus plane
+float circle_of_confusion(ivec2 pixel) {
+    float distance = linear_depth(texelFetch(depth, pixel, 0).r);
+    float coc = dof.coc_scale * (1.0 - dof.focus_distance / distance);
+    return clamp(coc, -dof.max_coc, dof.max_coc);
+}
@@ -0,0 +1,59 @@
+// Light arriving from the environment: the sky's cube map once one is loaded and the uniform sky
+//  otherwise. Surfaces are lit by it through the image based lighting's irradiance, prefiltered
+//  radiance and split sum BRDF, and reflections fall back to it where the screen doesn't know the
+//  answer. Needs frame.glsl.
+
+layout(set = 0, binding = 9) uniform samplerCube skyMap;
+layout(set = 0, binding = 10) uniform samplerCube prefilteredMap;
+layout(set = 0, binding = 11) uniform samplerCube irradianceMap;
+layout(set = 0, binding = 12) uniform sampler2D brdfLut;
+
+bool has_environment_map() {
+    return frame.environment.x > 0.0;
+}
+
+// Turns a world space direction into the sky's, which rotates about the up axis
+vec3 environment_direction(vec3 direction) {
+    float c = cos(frame.environment.y);
+    float s = sin(frame.environment.y);
+    return vec3(c * direction.x + s * direction.z, direction.y, c * direction.z - s * direction.x);
+}
+
+// The unfiltered sky, what the background shows
+vec3 sky_radiance(vec3 direction) {
+    if (!has_environment_map()) {
+        return frame.ambient.rgb;
+    }
+    return textureLod(skyMap, environment_direction(direction), 0.0).rgb * frame.environment.x;
+}
+
+// Radiance reflected along a direction by a surface of the given roughness, the sky convolved
+//  with the GGX lobe. Every mip of the prefiltered map is a step in roughness.
+vec3 environment_radiance(vec3 direction, float roughness) {
+    if (!has_environment_map()) {
+        return frame.ambient.rgb;
+    }
+    float lod = roughness * float(textureQueryLevels(prefilteredMap) - 1);
+    return textureLod(prefilteredMap, environment_direction(direction), lod).rgb * frame.environment.x;
+}
+
+// Cosine weighted average of the sky around a normal, irradiance over pi
+vec3 environment_irradiance(vec3 n) {
+    if (!has_environment_map()) {
+        return frame.ambient.rgb;
+    }
+    return textureLod(irradianceMap, environment_direction(n), 0.0).rgb * frame.environment.x;
+}
+
+// Share of the environment's light reflected towards v, the split sum's scale and bias of F0
+vec3 environment_reflectance(vec3 base_color, float metallic, float roughness, float n_dot_v) {
+    vec2 ab = textureLod(brdfLut, vec2(n_dot_v, roughness), 0.0).rg;
+    vec3 f0 = mix(vec3(0.04), base_color, metallic);
+    return f0 * ab.x + ab.y;
+}
+
+// Light from the whole environment reflected towards v, `reflectance` from environment_reflectance
+vec3 environment_lighting(vec3 n, vec3 v, vec3 base_color, float metallic, float roughness, vec3 reflectance) {
+    vec3 diffuse = (1.0 - reflectance) * (1.0 - metallic) * base_color;
+    return diffuse * environment_irradiance(n) + reflectance * environment_radiance(reflect(-v, n), roughness);
+}
@@ -0,0 +1,40 @@
+// Shared by the histogram and averaging passes of the automatic exposure
+
+const uint HISTOGRAM_BINS = 256;
+
+layout(binding = 0) uniform sampler2D hdr;
+layout(binding = 1) buffer Histogram {
+    uint bins[HISTOGRAM_BINS];
+} histogram;
+layout(binding = 2) buffer State {
+    float adapted_ev100;
+    float metered_ev100;
+} state;
+
+layout(push_constant) uniform Exposure {
+    float min_ev;
+    float max_ev;
+    float pre_exposure;
+    float low_percentile;
+    float high_percentile;
+    float speed_up;
+    float speed_down;
+    float delta_time;
+    float compensation;
+} exposure;
+
+// Bin 0 holds black pixels, which don't take part in metering. The others split min_ev..max_ev evenly.
+uint luminance_bin(float luminance) {
+    if (luminance < 1e-5) {
+        return 0;
+    }
+    // EV100 of a luminance with the reflected light meter constant K = 12.5
+    float ev = log2(luminance) + 3.0;
+    float t = clamp((ev - exposure.min_ev) / (exposure.max_ev - exposure.min_ev), 0.0, 1.0);
+    return min(uint(t * float(HISTOGRAM_BINS - 2)) + 1, HISTOGRAM_BINS - 1);
+}
+
+float bin_ev(uint bin) {
+    float t = (float(bin) - 0.5) / float(HISTOGRAM_BINS - 2);
+    return mix(exposure.min_ev, exposure.max_ev, t);
+}
@@ -0,0 +1,46 @@
+// Froxel volume of the volumetric fog: a grid of cells following the camera's frustum, its
+//  slices spaced exponentially from the near plane to the fog's reach. Needs frame.glsl.
+
+layout(push_constant) uniform Fog {
+    vec4 albedo;            // rgb share of the extinction that scatters, w = phase anisotropy
+    float density;          // extinction per metre everywhere
+    float height_density;   // extinction per metre added at and below the height fog's base
+    float height;           // world height of the height fog's base
+    float height_falloff;   // per metre above the base
+    float near;             // where the first slice starts, the camera's near plane
+    float max_distance;     // where the last slice ends
+    float history_weight;   // share of the reprojected history, 0 without one
+    float jitter;           // this frame's offset of the samples within their slices, 0..1
+} fog;
+
+// View depth at w along the slices, 0 at the near plane and 1 at the fog's reach
+float slice_depth(float w) {
+    return fog.near * pow(fog.max_distance / fog.near, w);
+}
+
+float depth_slice(float depth) {
+    return log(max(depth, fog.near) / fog.near) / log(fog.max_distance / fog.near);
+}
+
+// The camera's forward axis in world space
+vec3 view_forward() {
+    return -vec3(frame.view[0][2], frame.view[1][2], frame.view[2][2]);
+}
+
+// World space direction through a point of the screen, uv in 0..1
+vec3 view_ray(vec2 uv) {
+    vec4 far = frame.inverse_view_projection * vec4(uv * 2.0 - 1.0, 1.0, 1.0);
+    return normalize(far.xyz / far.w - frame.camera_position.xyz);
+}
+
+// Extinction per metre at a point, the uniform fog and the height fog thinning out above its base
+float fog_density(vec3 position) {
+    float above = max(position.y - fog.height, 0.0);
+    return fog.density + fog.height_density * exp(-above * fog.height_falloff);
+}
+
+// Henyey-Greenstein, cos_theta between the view ray and the direction towards the light
+float henyey_greenstein(float cos_theta, float g) {
+    float g2 = g * g;
+    return (1.0 - g2) / (4.0 * 3.14159265359 * pow(max(1.0 + g2 - 2.0 * g * cos_theta, 1e-4), 1.5));
+}
@@ -0,0 +1,38 @@
+// The forward fragment shader, compiled once looping over every light (shader.frag) and once
+//  over the lights of the fragment's cluster (clustered.frag)
+
+#include "frame.glsl"
+#include "brdf.glsl"
+#include "lights.glsl"
+#include "shadow.glsl"
+#ifdef CLUSTERED
+#include "clusters.glsl"
+#endif
+#include "shading.glsl"
+#include "screen_occlusion.glsl"
+#include "environment.glsl"
+#include "gbuffer.glsl"
+#include "surface.glsl"
+
+layout(location = 0) out vec4 outColor;
+layout(location = 1) out vec2 outVelocity;
+layout(location = 2) out vec2 outNormal;
+layout(location = 3) out vec4 outSpecular;
+
+void main() {
+    Surface s = surface();
+    float occlusion = s.occlusion * screen_occlusion();
+    vec3 v = normalize(frame.camera_position.xyz - fragPosition);
+    vec3 reflectance = environment_reflectance(s.base_color.rgb, s.metallic, s.roughness,
+        max(dot(s.normal, v), 1e-4));
+
+    vec3 radiance = direct_radiance(fragPosition, s.normal, s.geometric_normal, s.base_color.rgb,
+        s.metallic, s.roughness);
+    radiance += environment_lighting(s.normal, v, s.base_color.rgb, s.metallic, s.roughness, reflectance) * occlusion;
+    radiance += s.emissive;
+
+    outColor = vec4(radiance * frame.exposure, material.alpha.y == 2.0 ? s.base_color.a : 1.0);
+    outVelocity = screen_velocity();
+    outNormal = encode_normal(s.normal);
+    outSpecular = vec4(reflectance * occlusion, s.roughness);
+}
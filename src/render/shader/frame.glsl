@@ -0,0 +1,16 @@
+// Per frame camera values, mirrors `FrameUniforms` in frame.rs
+layout(set = 0, binding = 0) uniform Frame {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    mat4 previous_view_projection;
+    mat4 inverse_view_projection;
+    vec4 camera_position;
+    vec4 ambient;           // uniform sky luminance, cd/m²
+    vec4 jitter;            // projection offsets in NDC, this frame's in xy and the last one's in zw
+    vec4 clusters;          // depth slice scale and bias, cluster width and height in pixels
+    vec4 environment;       // sky map luminance in cd/m², 0 without one, its turn about +Y in radians
+                            //  and the sun disks' angular radius, 0 for none
+    float exposure;         // luminance to display scale
+    uint light_count;
+} frame;
@@ -0,0 +1,39 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "frame.glsl"
+#include "brdf.glsl"
+#include "gbuffer.glsl"
+#include "screen_occlusion.glsl"
+#include "environment.glsl"
+#include "surface.glsl"
+
+layout(location = 0) out vec2 outVelocity;
+layout(location = 1) out vec4 outAlbedo;
+layout(location = 2) out vec4 outNormal;
+layout(location = 3) out vec4 outMaterial;
+layout(location = 4) out vec4 outEmission;
+layout(location = 5) out vec2 outReflectionNormal;
+layout(location = 6) out vec4 outSpecular;
+
+void main() {
+    Surface s = surface();
+
+    outVelocity = screen_velocity();
+    outAlbedo = vec4(s.base_color.rgb, 1.0);
+    outNormal = vec4(encode_normal(s.normal), encode_normal(s.geometric_normal));
+    outMaterial = vec4(s.metallic, s.roughness, 0.0, 0.0);
+
+    // The environment's light is added here, only the punctual lights are left to the lighting subpass
+    float occlusion = s.occlusion * screen_occlusion();
+    vec3 v = normalize(frame.camera_position.xyz - fragPosition);
+    vec3 reflectance = environment_reflectance(s.base_color.rgb, s.metallic, s.roughness,
+        max(dot(s.normal, v), 1e-4));
+    vec3 emission = environment_lighting(s.normal, v, s.base_color.rgb, s.metallic, s.roughness, reflectance)
+        * occlusion + s.emissive;
+    outEmission = vec4(emission * frame.exposure, 1.0);
+
+    // What the screen space reflections read, like the forward pass writes them
+    outReflectionNormal = encode_normal(s.normal);
+    outSpecular = vec4(reflectance * occlusion, s.roughness);
+}
@@ -0,0 +1,22 @@
+// Packing of the G-buffer, mirrors the attachments of `deferred.rs`:
+//  albedo      rgb base colour, a unused
+//  normal      xy octahedral shading normal, zw octahedral geometric normal
+//  material    r metallic, g roughness
+//  emission    rgb exposed emission and ambient light, which don't depend on the lights
+
+vec2 octahedral_wrap(vec2 v) {
+    return (1.0 - abs(v.yx)) * vec2(v.x >= 0.0 ? 1.0 : -1.0, v.y >= 0.0 ? 1.0 : -1.0);
+}
+
+// A unit vector folded onto the octahedron and flattened into -1..1
+vec2 encode_normal(vec3 n) {
+    n /= abs(n.x) + abs(n.y) + abs(n.z);
+    return n.z >= 0.0 ? n.xy : octahedral_wrap(n.xy);
+}
+
+vec3 decode_normal(vec2 e) {
+    vec3 n = vec3(e, 1.0 - abs(e.x) - abs(e.y));
+    float t = clamp(-n.z, 0.0, 1.0);
+    n.xy += vec2(n.x >= 0.0 ? -t : t, n.y >= 0.0 ? -t : t);
+    return normalize(n);
+}
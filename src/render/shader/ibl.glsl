@@ -0,0 +1,24 @@
+// Monte Carlo integration of the sky for the image based lighting's compute passes, importance
+//  sampled and reading a mip of the source as wide as each sample's share of the lobe to keep the
+//  noise down. Needs brdf.glsl.
+
+layout(push_constant) uniform Ibl {
+    float roughness;        // of the prefiltered level being written
+    uint sample_count;
+} ibl;
+
+layout(set = 0, binding = 0) uniform samplerCube sourceMap;
+
+// The i-th of n points of the Hammersley set, the base 2 radical inverse of i against i / n
+vec2 hammersley(uint i, uint n) {
+    return vec2(float(i) / float(n), float(bitfieldReverse(i)) * 2.3283064365386963e-10);
+}
+
+// Source mip whose texels span the solid angle a sample of the given density stands for
+float source_lod(float pdf) {
+    float size = float(textureSize(sourceMap, 0).x);
+    float sample_angle = 1.0 / (float(ibl.sample_count) * pdf + 1e-6);
+    float texel_angle = 4.0 * PI / (6.0 * size * size);
+    // Biased a level up, the filtered samples overlap more smoothly
+    return max(0.5 * log2(sample_angle / texel_angle) + 1.0, 0.0);
+}
@@ -0,0 +1,68 @@
+// Punctual lights, mirrors `GpuLight` in light.rs. Needs frame.glsl for the light count.
+
+#define LIGHT_DIRECTIONAL 0.0
+#define LIGHT_POINT 1.0
+#define LIGHT_SPOT 2.0
+
+struct Light {
+    vec4 position;          // xyz, w = type
+    vec4 direction;         // direction the light travels, w = range (0 is unlimited)
+    vec4 color;             // colour times intensity, lux or candela
+    vec4 cone;              // spot angular scale and offset
+    vec4 shadow;            // point shadow tier (-1 for none), cube, far plane, filter disk scale, or
+                            //  spot shadow layer (-1 for none), 0, 0, texel size a metre from the light
+};
+
+layout(std430, set = 0, binding = 1) readonly buffer Lights {
+    Light lights[];
+};
+
+// Smooth window so lights reach exactly zero at their range, as recommended by KHR_lights_punctual
+float range_attenuation(float distance, float range) {
+    float inverse_square = 1.0 / max(distance * distance, 1e-4);
+    if (range <= 0.0) {
+        return inverse_square;
+    }
+    float ratio = distance / range;
+    return clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0) * inverse_square;
+}
+
+// Direction towards the light from `position` and the illuminance arriving there (lux), before the cosine
+vec3 light_incidence(Light light, vec3 position, out vec3 l) {
+    if (light.position.w == LIGHT_DIRECTIONAL) {
+        l = -light.direction.xyz;
+        return light.color.rgb;
+    }
+
+    vec3 to_light = light.position.xyz - position;
+    float distance = length(to_light);
+    l = to_light / max(distance, 1e-4);
+
+    float attenuation = range_attenuation(distance, light.direction.w);
+    if (light.position.w == LIGHT_SPOT) {
+        float cd = dot(light.direction.xyz, -l);
+        float angular = clamp(cd * light.cone.x + light.cone.y, 0.0, 1.0);
+        attenuation *= angular * angular;
+    }
+    return light.color.rgb * attenuation;
+}
+
+// The disks of the directional lights, for procedural skies which leave the sun out of their map.
+//  Each spreads its illuminance over the solid angle of frame.environment.z's angular radius.
+vec3 sun_disks(vec3 direction) {
+    float radius = frame.environment.z;
+    if (radius <= 0.0) {
+        return vec3(0.0);
+    }
+
+    float cos_radius = cos(radius);
+    float solid_angle = 3.14159265359 * radius * radius;
+    vec3 radiance = vec3(0.0);
+    for (uint i = 0; i < frame.light_count; i++) {
+        Light light = lights[i];
+        if (light.position.w == LIGHT_DIRECTIONAL && dot(direction, -light.direction.xyz) >= cos_radius) {
+            radiance += light.color.rgb / solid_angle;
+        }
+    }
+    return radiance;
+}
@@ -0,0 +1,33 @@
+// Shared by the motion blur tile, neighbourhood and gather shaders. Velocities are in pixels,
+//  covering the motion during the exposure.
+
+const int TILE_SIZE = 16;
+
+layout(binding = 0) uniform sampler2D hdr;
+layout(binding = 1) uniform sampler2D velocity;
+layout(binding = 2) uniform sampler2D depth;
+layout(binding = 3, rgba16f) uniform image2D tile_max;
+layout(binding = 4, rgba16f) uniform image2D neighbor_max;
+layout(binding = 5, rgba16f) uniform writeonly image2D result;
+
+layout(push_constant) uniform MotionBlur {
+    vec2 velocity_scale;
+    float max_blur;
+    uint sample_count;
+    float near;
+    float far;
+    uint seed;
+} blur;
+
+// The motion of a pixel during the exposure, no longer than the tiles can bound
+vec2 pixel_velocity(ivec2 pixel) {
+    vec2 v = texelFetch(velocity, pixel, 0).rg * blur.velocity_scale;
+    float len = length(v);
+    return len > blur.max_blur ? v * (blur.max_blur / len) : v;
+}
+
+// Distance along the view axis from a 0..1 depth buffer value
+float linear_depth(ivec2 pixel) {
+    float d = texelFetch(depth, pixel, 0).r;
+    return blur.near * blur.far / (blur.far - d * (blur.far - blur.near));
+}
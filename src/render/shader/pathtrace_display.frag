@@ -0,0 +1,18 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "frame.glsl"
+
+layout(set = 1, binding = 0) uniform sampler2D accumulation;
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+// Exposes the traced luminance like the rasterised scene, the tonemapper and grading follow. The
+//  sun is kept inside the half float range.
+void main() {
+    // Alpha holds the number of accumulated samples
+    vec4 sum = texelFetch(accumulation, ivec2(gl_FragCoord.xy), 0);
+    outColor = vec4(min(sum.rgb / max(sum.a, 1.0) * frame.exposure, vec3(6e4)), 1.0);
+}
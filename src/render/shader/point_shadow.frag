@@ -0,0 +1,19 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+// Stores the distance to the light over the far plane, so lookups don't depend on which face was hit.
+//  Masked casters are alpha tested first.
+
+#include "point_shadow.glsl"
+
+#define MATERIAL_SET 1
+#include "alpha_mask.glsl"
+
+layout(location = 0) in vec3 fragPosition;
+layout(location = 1) in vec2 fragUV;
+
+void main() {
+    alpha_test(fragUV);
+    vec4 light = point_shadows.lights[pc.cube];
+    gl_FragDepth = length(fragPosition - light.xyz) / light.w;
+}
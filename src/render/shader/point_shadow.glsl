@@ -0,0 +1,14 @@
+// Shared declarations of the point light cube shadow pass, mirrors point_shadow.rs
+
+#define MAX_POINT_SHADOWS 7
+
+layout(set = 0, binding = 0) uniform PointShadows {
+    mat4 faces[6 * MAX_POINT_SHADOWS];
+    vec4 lights[MAX_POINT_SHADOWS];     // position, far plane
+} point_shadows;
+
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    uint cube;
+    uint face;
+} pc;
@@ -0,0 +1,19 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+// One cube face per pass, for devices without multiview
+
+#include "point_shadow.glsl"
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 3) in vec2 inUV;
+
+layout(location = 0) out vec3 fragPosition;
+layout(location = 1) out vec2 fragUV;
+
+void main() {
+    vec4 world = pc.model * vec4(inPosition, 1.0);
+    gl_Position = point_shadows.faces[pc.cube * 6 + pc.face] * world;
+    fragPosition = world.xyz;
+    fragUV = inUV;
+}
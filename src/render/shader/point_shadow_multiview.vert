@@ -0,0 +1,20 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+#extension GL_EXT_multiview : require
+
+// All six cube faces in one pass, each view renders one face
+
+#include "point_shadow.glsl"
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 3) in vec2 inUV;
+
+layout(location = 0) out vec3 fragPosition;
+layout(location = 1) out vec2 fragUV;
+
+void main() {
+    vec4 world = pc.model * vec4(inPosition, 1.0);
+    gl_Position = point_shadows.faces[pc.cube * 6 + gl_ViewIndex] * world;
+    fragPosition = world.xyz;
+    fragUV = inUV;
+}
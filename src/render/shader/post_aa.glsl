@@ -0,0 +1,32 @@
+// Shared by the FXAA and SMAA passes. Both look for edges on a reversibly tonemapped copy of the
+//  HDR target, where contrast matches what ends up on screen, and undo the tonemap on the way out.
+
+layout(binding = 0) uniform sampler2D hdr;
+layout(binding = 1) uniform sampler2D prepared;
+layout(binding = 2) uniform sampler2D edges;
+layout(binding = 3) uniform sampler2D weights;
+layout(binding = 4) uniform sampler2D area_tex;
+layout(binding = 5) uniform sampler2D search_tex;
+layout(binding = 6, rgba16f) uniform writeonly image2D prepared_image;
+layout(binding = 7, rgba8) uniform writeonly image2D edges_image;
+layout(binding = 8, rgba8) uniform writeonly image2D weights_image;
+layout(binding = 9, rgba16f) uniform writeonly image2D result;
+
+layout(push_constant) uniform PostAa {
+    float subpixel;
+    float fxaa_edge_threshold;
+    float smaa_edge_threshold;
+} aa;
+
+// Lottes' reversible tonemap, bright pixels stop dominating the filtering
+vec3 tonemap(vec3 c) {
+    return c / (1.0 + max(max(c.r, c.g), c.b));
+}
+
+vec3 inverse_tonemap(vec3 c) {
+    return c / max(1.0 - max(max(c.r, c.g), c.b), 1e-4);
+}
+
+bool outside(ivec2 pixel) {
+    return any(greaterThanEqual(pixel, textureSize(prepared, 0)));
+}
@@ -0,0 +1,8 @@
+// Ambient occlusion of the pixel being shaded, computed from the depth prepass before the scene pass.
+//  White when the ambient occlusion is off.
+
+layout(set = 0, binding = 8) uniform sampler2D ambientOcclusionMap;
+
+float screen_occlusion() {
+    return texelFetch(ambientOcclusionMap, ivec2(gl_FragCoord.xy), 0).r;
+}
@@ -1,11 +1,37 @@
 #version 450
+#extension GL_GOOGLE_include_directive : require
 
-layout(location = 0) in vec2 inPosition;
-layout(location = 1) in vec3 inColor;
+#include "frame.glsl"
 
-layout(location = 0) out vec3 fragColor;
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    mat4 previous_model;
+} pc;
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inNormal;
+layout(location = 2) in vec3 inColor;
+layout(location = 3) in vec2 inUV;
+layout(location = 4) in vec4 inTangent;
+
+layout(location = 0) out vec3 fragPosition;
+layout(location = 1) out vec3 fragNormal;
+layout(location = 2) out vec3 fragColor;
+layout(location = 3) out vec2 fragUV;
+layout(location = 4) out vec4 fragTangent;
+layout(location = 5) out vec4 fragClip;
+layout(location = 6) out vec4 fragPreviousClip;
 
 void main() {
-    gl_Position = vec4(inPosition, 0.0, 1.0);
+    vec4 world = pc.model * vec4(inPosition, 1.0);
+    mat3 normal_matrix = transpose(inverse(mat3(pc.model)));
+
+    gl_Position = frame.view_projection * world;
+    fragClip = gl_Position;
+    fragPreviousClip = frame.previous_view_projection * pc.previous_model * vec4(inPosition, 1.0);
+    fragPosition = world.xyz;
+    fragNormal = normal_matrix * inNormal;
     fragColor = inColor;
+    fragUV = inUV;
+    fragTangent = vec4(mat3(pc.model) * inTangent.xyz, inTangent.w);
 }
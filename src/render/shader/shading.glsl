@@ -0,0 +1,30 @@
+// Direct lighting of a surface point by the punctual lights, shared by the forward and deferred
+//  paths. Needs frame.glsl, brdf.glsl, lights.glsl and shadow.glsl, and clusters.glsl when
+//  CLUSTERED is defined, which limits the lights to those of the fragment's cluster.
+
+vec3 direct_radiance(vec3 position, vec3 n, vec3 geometric_normal, vec3 base_color, float metallic, float roughness) {
+    vec3 v = normalize(frame.camera_position.xyz - position);
+    float view_depth = -(frame.view * vec4(position, 1.0)).z;
+
+    vec3 radiance = vec3(0.0);
+#ifdef CLUSTERED
+    uint offset = cluster_offset(gl_FragCoord.xy, view_depth);
+    uint count = cluster_lights[offset];
+    for (uint j = 0; j < count; j++) {
+        uint i = cluster_lights[offset + 1 + j];
+#else
+    for (uint i = 0; i < frame.light_count; i++) {
+#endif
+        vec3 l;
+        vec3 illuminance = light_incidence(lights[i], position, l);
+        if (casts_cascaded_shadow(i)) {
+            illuminance *= cascaded_shadow(position, geometric_normal, view_depth);
+        } else if (lights[i].position.w == LIGHT_POINT) {
+            illuminance *= point_shadow(lights[i], position);
+        } else if (lights[i].position.w == LIGHT_SPOT) {
+            illuminance *= spot_shadow(lights[i], position, geometric_normal);
+        }
+        radiance += brdf(n, v, l, base_color, metallic, roughness) * illuminance;
+    }
+    return radiance;
+}
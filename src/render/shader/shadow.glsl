@@ -0,0 +1,168 @@
+// Cascaded shadow map of the sun, cube maps of point lights and perspective maps of spot lights,
+//  mirrors `ShadowUniforms` in shadow.rs. Needs lights.glsl.
+
+#define SHADOW_CASCADES 4
+#define MAX_SPOT_SHADOWS 4
+
+layout(set = 0, binding = 2) uniform Shadows {
+    mat4 cascades[SHADOW_CASCADES];
+    vec4 splits;            // view distance each cascade ends at
+    vec4 texel_sizes;       // world size of a texel per cascade
+    vec4 params;            // normal bias in texels, PCF radius, cascade count, shadowed light (-1 none)
+    vec4 point_params;      // point light depth bias in metres
+    mat4 spots[MAX_SPOT_SHADOWS];
+} shadows;
+
+layout(set = 0, binding = 3) uniform sampler2DArrayShadow shadowMap;
+
+// One array of cube faces per resolution tier, see POINT_SHADOW_TIERS. Six 2D layers per cube
+//  rather than a cube map array, which not every device can sample.
+layout(set = 0, binding = 4) uniform sampler2DArrayShadow pointShadowMaps0;
+layout(set = 0, binding = 5) uniform sampler2DArrayShadow pointShadowMaps1;
+layout(set = 0, binding = 6) uniform sampler2DArrayShadow pointShadowMaps2;
+
+// One layer per shadowed spot light, see MAX_SPOT_SHADOWS
+layout(set = 0, binding = 13) uniform sampler2DArrayShadow spotShadowMaps;
+
+// Directions spreading the cube filter taps over a disk-ish volume around the lookup
+const vec3 POINT_SHADOW_OFFSETS[20] = vec3[](
+    vec3( 1,  1,  1), vec3( 1, -1,  1), vec3(-1, -1,  1), vec3(-1,  1,  1),
+    vec3( 1,  1, -1), vec3( 1, -1, -1), vec3(-1, -1, -1), vec3(-1,  1, -1),
+    vec3( 1,  1,  0), vec3( 1, -1,  0), vec3(-1, -1,  0), vec3(-1,  1,  0),
+    vec3( 1,  0,  1), vec3(-1,  0,  1), vec3( 1,  0, -1), vec3(-1,  0, -1),
+    vec3( 0,  1,  1), vec3( 0, -1,  1), vec3( 0, -1, -1), vec3( 0,  1, -1)
+);
+
+bool casts_cascaded_shadow(uint light) {
+    return int(shadows.params.w) == int(light);
+}
+
+// Fraction of the sun reaching `position`, `view_depth` is its distance along the camera's view axis
+float cascaded_shadow(vec3 position, vec3 normal, float view_depth) {
+    uint count = uint(shadows.params.z);
+    uint cascade = count;
+    for (uint i = 0; i < count; i++) {
+        if (view_depth < shadows.splits[i]) {
+            cascade = i;
+            break;
+        }
+    }
+    if (cascade == count) {
+        return 1.0;
+    }
+
+    // Push the receiver off its surface by a few texels of this cascade to fight acne
+    vec3 offset_position = position + normal * shadows.params.x * shadows.texel_sizes[cascade];
+    vec4 coord = shadows.cascades[cascade] * vec4(offset_position, 1.0);
+    if (coord.z >= 1.0) {
+        return 1.0;
+    }
+
+    vec2 uv = coord.xy * 0.5 + 0.5;
+    vec2 texel = 1.0 / vec2(textureSize(shadowMap, 0).xy);
+    int radius = int(shadows.params.y);
+    float lit = 0.0;
+    for (int x = -radius; x <= radius; x++) {
+        for (int y = -radius; y <= radius; y++) {
+            lit += texture(shadowMap, vec4(uv + vec2(x, y) * texel, float(cascade), coord.z));
+        }
+    }
+    float taps = float((2 * radius + 1) * (2 * radius + 1));
+    return lit / taps;
+}
+
+// The uv and face a direction from a point light lands on, faces in the +X, -X, +Y, -Y, +Z, -Z
+//  order of `cube_faces` in point_shadow.rs and oriented like a cube map's
+vec3 cube_face_uv(vec3 direction) {
+    vec3 a = abs(direction);
+    if (a.x >= a.y && a.x >= a.z) {
+        vec2 st = vec2(direction.x > 0.0 ? -direction.z : direction.z, -direction.y);
+        return vec3(st / a.x * 0.5 + 0.5, direction.x > 0.0 ? 0.0 : 1.0);
+    }
+    if (a.y >= a.z) {
+        vec2 st = vec2(direction.x, direction.y > 0.0 ? direction.z : -direction.z);
+        return vec3(st / a.y * 0.5 + 0.5, direction.y > 0.0 ? 2.0 : 3.0);
+    }
+    vec2 st = vec2(direction.z > 0.0 ? direction.x : -direction.x, -direction.y);
+    return vec3(st / a.z * 0.5 + 0.5, direction.z > 0.0 ? 4.0 : 5.0);
+}
+
+float sample_cube(sampler2DArrayShadow map, vec3 direction, float cube, float reference) {
+    vec3 face = cube_face_uv(direction);
+    // Stay half a texel inside the face, the filter would otherwise blend in the lit border
+    vec2 half_texel = 0.5 / vec2(textureSize(map, 0).xy);
+    vec2 uv = clamp(face.xy, half_texel, 1.0 - half_texel);
+    return texture(map, vec4(uv, cube * 6.0 + face.z, reference));
+}
+
+float sample_point_shadow(int tier, vec3 direction, float cube, float reference) {
+    switch (tier) {
+        case 0: return sample_cube(pointShadowMaps0, direction, cube, reference);
+        case 1: return sample_cube(pointShadowMaps1, direction, cube, reference);
+        default: return sample_cube(pointShadowMaps2, direction, cube, reference);
+    }
+}
+
+// Fraction of a point light reaching `position`
+float point_shadow(Light light, vec3 position) {
+    int tier = int(light.shadow.x);
+    if (tier < 0) {
+        return 1.0;
+    }
+
+    vec3 direction = position - light.position.xyz;
+    float distance = length(direction);
+    float reference = (distance - shadows.point_params.x) / light.shadow.z;
+    if (reference >= 1.0) {
+        return 1.0;
+    }
+
+    float disk = light.shadow.w * distance;
+    if (disk <= 0.0) {
+        return sample_point_shadow(tier, direction, light.shadow.y, reference);
+    }
+
+    float lit = 0.0;
+    for (int i = 0; i < 20; i++) {
+        vec3 offset = direction + POINT_SHADOW_OFFSETS[i] * disk;
+        lit += sample_point_shadow(tier, offset, light.shadow.y, reference);
+    }
+    return lit / 20.0;
+}
+
+// Where `position` lands in a spot light's map: uv, layer and depth, ready for spotShadowMaps.
+//  False when the light casts no shadow or the point lies outside its frustum.
+bool spot_shadow_coord(Light light, vec3 position, out vec4 coord) {
+    int layer = int(light.shadow.x);
+    if (layer < 0) {
+        return false;
+    }
+
+    vec4 clip = shadows.spots[layer] * vec4(position, 1.0);
+    if (clip.w <= 0.0 || clip.z >= clip.w) {
+        return false;
+    }
+    coord = vec4(clip.xy / clip.w * 0.5 + 0.5, float(layer), clip.z / clip.w);
+    return true;
+}
+
+// Fraction of a spot light reaching `position`
+float spot_shadow(Light light, vec3 position, vec3 normal) {
+    // Push the receiver off its surface by a few texels at its distance from the light
+    float texel_size = light.shadow.w * length(position - light.position.xyz);
+    vec4 coord;
+    if (!spot_shadow_coord(light, position + normal * shadows.params.x * texel_size, coord)) {
+        return 1.0;
+    }
+
+    vec2 texel = 1.0 / vec2(textureSize(spotShadowMaps, 0).xy);
+    int radius = int(shadows.params.y);
+    float lit = 0.0;
+    for (int x = -radius; x <= radius; x++) {
+        for (int y = -radius; y <= radius; y++) {
+            lit += texture(spotShadowMaps, vec4(coord.xy + vec2(x, y) * texel, coord.zw));
+        }
+    }
+    float taps = float((2 * radius + 1) * (2 * radius + 1));
+    return lit / taps;
+}
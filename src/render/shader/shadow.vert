@@ -0,0 +1,17 @@
+#version 450
+
+// Depth-only pass into a shadow map, the light's view-projection is premultiplied with the model
+
+layout(push_constant) uniform PushConstants {
+    mat4 light_mvp;
+} pc;
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 3) in vec2 inUV;
+
+layout(location = 0) out vec2 fragUV;
+
+void main() {
+    gl_Position = pc.light_mvp * vec4(inPosition, 1.0);
+    fragUV = inUV;
+}
@@ -0,0 +1,14 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+// Alpha tests masked casters in the cascade and spot shadow passes, opaque ones skip the fragment
+//  stage entirely
+
+#define MATERIAL_SET 0
+#include "alpha_mask.glsl"
+
+layout(location = 0) in vec2 fragUV;
+
+void main() {
+    alpha_test(fragUV);
+}
@@ -0,0 +1,16 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "frame.glsl"
+#include "lights.glsl"
+#include "environment.glsl"
+#include "skybox.glsl"
+
+layout(location = 0) out vec4 outColor;
+layout(location = 1) out vec2 outVelocity;
+
+void main() {
+    vec3 direction = sky_direction();
+    outColor = vec4(sky_color(direction), 1.0);
+    outVelocity = sky_velocity(direction);
+}
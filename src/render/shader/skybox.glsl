@@ -0,0 +1,23 @@
+// The sky seen through a pixel of the far plane triangle. Needs frame.glsl and lights.glsl.
+
+layout(location = 0) in vec2 fragUV;
+
+// World space direction through the pixel
+vec3 sky_direction() {
+    vec4 far = frame.inverse_view_projection * vec4(fragUV * 2.0 - 1.0, 1.0, 1.0);
+    return normalize(far.xyz / far.w - frame.camera_position.xyz);
+}
+
+// An infinitely far point only moves on screen when the camera turns
+vec2 sky_velocity(vec3 direction) {
+    vec4 clip = frame.view_projection * vec4(direction, 0.0);
+    vec4 previous_clip = frame.previous_view_projection * vec4(direction, 0.0);
+    vec2 ndc = clip.xy / clip.w - frame.jitter.xy;
+    vec2 previous_ndc = previous_clip.xy / previous_clip.w - frame.jitter.zw;
+    return (ndc - previous_ndc) * 0.5;
+}
+
+// What the background shows, exposed. The sun is kept inside the half float range.
+vec3 sky_color(vec3 direction) {
+    return sky_radiance(direction) * frame.exposure + min(sun_disks(direction) * frame.exposure, vec3(6e4));
+}
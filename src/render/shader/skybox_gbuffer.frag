@@ -0,0 +1,18 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "frame.glsl"
+#include "lights.glsl"
+#include "environment.glsl"
+#include "skybox.glsl"
+
+// The velocity and the G-buffer's emission, which the lighting subpass passes through where
+//  there is no geometry. The albedo, normal and material attachments aren't written.
+layout(location = 0) out vec2 outVelocity;
+layout(location = 4) out vec4 outEmission;
+
+void main() {
+    vec3 direction = sky_direction();
+    outVelocity = sky_velocity(direction);
+    outEmission = vec4(sky_color(direction), 1.0);
+}
@@ -0,0 +1,21 @@
+// Shared by the ambient occlusion compute shaders. Needs frame.glsl.
+
+// Mirrors `SsaoPushConstants` in ssao.rs
+layout(push_constant) uniform Ssao {
+    float radius;           // reach of the samples in meters
+    float intensity;        // exponent on the visibility
+    uint sample_count;
+    float sharpness;        // how strongly the blur stops at depth discontinuities
+} ssao;
+
+layout(set = 1, binding = 0) uniform sampler2D depthMap;
+layout(set = 1, binding = 1, rgba16f) uniform image2D rawImage;          // visibility, view depth
+layout(set = 1, binding = 2, rgba16f) uniform image2D occlusionImage;    // blurred visibility
+layout(set = 1, binding = 3, rgba16f) uniform writeonly image2D outImage;
+
+// View space position of the prepass depth under a screen position
+vec3 view_position(mat4 inverse_projection, vec2 uv) {
+    float depth = textureLod(depthMap, uv, 0.0).r;
+    vec4 p = inverse_projection * vec4(uv * 2.0 - 1.0, depth, 1.0);
+    return p.xyz / p.w;
+}
@@ -0,0 +1,16 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+// Depth prepass the ambient occlusion is computed from, with the scene pass' jittered camera
+
+#include "frame.glsl"
+
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+} pc;
+
+layout(location = 0) in vec3 inPosition;
+
+void main() {
+    gl_Position = frame.view_projection * pc.model * vec4(inPosition, 1.0);
+}
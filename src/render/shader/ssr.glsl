@@ -0,0 +1,31 @@
+// Shared by the screen space reflection passes. Needs frame.glsl.
+
+// Mirrors `SsrPushConstants` in ssr.rs
+layout(push_constant) uniform Ssr {
+    float source_lod;       // level the downsampling passes read
+    float max_distance;     // longest reflection ray in meters
+    float thickness;        // depth in meters surfaces are assumed to have
+    float max_roughness;    // rougher surfaces only reflect the environment
+    uint max_iterations;
+    uint level_count;       // levels of the depth and colour pyramids
+    float near;
+    float far;
+} ssr;
+
+// Every pass writes binding 1, declared by the pass itself
+layout(set = 1, binding = 0) uniform sampler2D source;
+layout(set = 1, binding = 2) uniform sampler2D hdrMap;
+layout(set = 1, binding = 3) uniform sampler2D normalMap;
+layout(set = 1, binding = 4) uniform sampler2D specularMap;
+layout(set = 1, binding = 5) uniform sampler2D hizMap;
+
+float linear_depth(float d) {
+    return ssr.near * ssr.far / (ssr.far - d * (ssr.far - ssr.near));
+}
+
+// Range of source texels covered by a destination texel, the extra row and column of odd sizes included
+void source_footprint(ivec2 pixel, ivec2 destination_size, out ivec2 first, out ivec2 last) {
+    ivec2 source_size = textureSize(source, int(ssr.source_lod));
+    first = pixel * source_size / destination_size;
+    last = min(((pixel + 1) * source_size + destination_size - 1) / destination_size - 1, source_size - 1);
+}
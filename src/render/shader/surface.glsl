@@ -0,0 +1,75 @@
+// The material of a rasterised surface, shared by the forward and G-buffer fragment shaders.
+//  Needs frame.glsl.
+
+// Mirrors `MaterialUniform` in material.rs
+layout(set = 1, binding = 0) uniform MaterialParams {
+    vec4 base_color_factor;
+    vec4 emissive;          // rgb already scaled by the emissive strength
+    vec4 params;            // metallic, roughness, normal scale, occlusion strength
+    vec4 alpha;             // cutoff, mode (0 opaque, 1 mask, 2 blend)
+} material;
+
+layout(set = 1, binding = 1) uniform sampler2D baseColorMap;
+layout(set = 1, binding = 2) uniform sampler2D metallicRoughnessMap;
+layout(set = 1, binding = 3) uniform sampler2D normalMap;
+layout(set = 1, binding = 4) uniform sampler2D occlusionMap;
+layout(set = 1, binding = 5) uniform sampler2D emissiveMap;
+
+layout(location = 0) in vec3 fragPosition;
+layout(location = 1) in vec3 fragNormal;
+layout(location = 2) in vec3 fragColor;
+layout(location = 3) in vec2 fragUV;
+layout(location = 4) in vec4 fragTangent;
+layout(location = 5) in vec4 fragClip;
+layout(location = 6) in vec4 fragPreviousClip;
+
+struct Surface {
+    vec4 base_color;
+    float metallic;
+    float roughness;
+    float occlusion;
+    vec3 emissive;
+    vec3 normal;            // with the normal map applied
+    vec3 geometric_normal;  // facing the camera, for shadow biasing
+};
+
+vec3 shading_normal() {
+    vec3 n = normalize(fragNormal);
+    if (!gl_FrontFacing) {
+        n = -n;
+    }
+    vec3 t = normalize(fragTangent.xyz - n * dot(n, fragTangent.xyz));
+    vec3 b = cross(n, t) * fragTangent.w;
+
+    vec3 tangent_normal = texture(normalMap, fragUV).xyz * 2.0 - 1.0;
+    tangent_normal.xy *= material.params.z;
+    return normalize(mat3(t, b, n) * tangent_normal);
+}
+
+// Samples the material, discarding masked out fragments
+Surface surface() {
+    Surface s;
+    s.base_color = material.base_color_factor * texture(baseColorMap, fragUV) * vec4(fragColor, 1.0);
+    if (material.alpha.y == 1.0 && s.base_color.a < material.alpha.x) {
+        discard;
+    }
+
+    // glTF packs roughness in green and metalness in blue
+    vec4 metallic_roughness = texture(metallicRoughnessMap, fragUV);
+    s.metallic = clamp(material.params.x * metallic_roughness.b, 0.0, 1.0);
+    s.roughness = clamp(material.params.y * metallic_roughness.g, 0.045, 1.0);
+    s.occlusion = mix(1.0, texture(occlusionMap, fragUV).r, material.params.w);
+    s.emissive = material.emissive.rgb * texture(emissiveMap, fragUV).rgb;
+
+    s.normal = shading_normal();
+    s.geometric_normal = normalize(gl_FrontFacing ? fragNormal : -fragNormal);
+    return s;
+}
+
+// Motion since the last frame in 0..1 screen units, from the interpolated clip positions without
+//  the anti-aliasing jitter
+vec2 screen_velocity() {
+    vec2 ndc = fragClip.xy / fragClip.w - frame.jitter.xy;
+    vec2 previous_ndc = fragPreviousClip.xy / fragPreviousClip.w - frame.jitter.zw;
+    return (ndc - previous_ndc) * 0.5;
+}
@@ -0,0 +1,200 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D hdr;
+
+layout(set = 1, binding = 0) uniform Grading {
+    mat3 white_balance;
+    vec4 lift;
+    vec4 gamma;
+    vec4 gain;
+    // Contrast, saturation, LUT strength and whether a LUT is loaded
+    vec4 params;
+    vec4 lut_domain_min;
+    // w is the LUT's size
+    vec4 lut_domain_max;
+} grading;
+layout(set = 1, binding = 1) uniform sampler3D lut;
+
+layout(push_constant) uniform Tonemap {
+    uint operator;
+    float white_point;
+    // The physical camera's vignetting and grain, all 0 without one, see `SensorConstants` in lens.rs
+    float aspect;
+    float distortion;
+    float distortion_scale;
+    float tan_half_diagonal;
+    float optical_vignetting;
+    float vignetting;
+    float grain;
+    uint seed;
+} tonemap;
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+const uint ACES = 0;
+const uint AGX = 1;
+const uint REINHARD = 2;
+const uint UNCHARTED2 = 3;
+
+const vec3 LUMINANCE = vec3(0.2126, 0.7152, 0.0722);
+
+// Krzysztof Narkowicz's curve fit of the ACES RRT + ODT
+vec3 aces(vec3 x) {
+    const float a = 2.51;
+    const float b = 0.03;
+    const float c = 2.43;
+    const float d = 0.59;
+    const float e = 0.14;
+    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), 0.0, 1.0);
+}
+
+// Polynomial fit of the AgX default contrast curve, by Benjamin Wrensch
+vec3 agx_contrast(vec3 x) {
+    vec3 x2 = x * x;
+    vec3 x4 = x2 * x2;
+    return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+}
+
+vec3 agx(vec3 x) {
+    const mat3 inset = mat3(
+        0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+        0.0784335999999992, 0.878468636469772, 0.0784336,
+        0.0792237451477643, 0.0791661274605434, 0.879142973793104);
+    const mat3 outset = mat3(
+        1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+        -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+        -0.0990297440797205, -0.0989611768448433, 1.15107367264116);
+    const float min_ev = -12.47393;
+    const float max_ev = 4.026069;
+
+    // Log2 encoding of the inset primaries, then the sigmoid
+    x = inset * x;
+    x = clamp(log2(max(x, 1e-10)), min_ev, max_ev);
+    x = (x - min_ev) / (max_ev - min_ev);
+    x = agx_contrast(x);
+
+    // Back to linear, the swapchain applies the sRGB encoding
+    x = outset * x;
+    return pow(max(x, 0.0), vec3(2.2));
+}
+
+// Extended Reinhard on luminance, which preserves hue and reaches 1 exactly at the white point
+vec3 reinhard(vec3 x, float white) {
+    float l = dot(x, LUMINANCE);
+    return x * (1.0 + l / (white * white)) / (1.0 + l);
+}
+
+// John Hable's filmic curve
+vec3 uncharted2(vec3 x) {
+    const float a = 0.15;
+    const float b = 0.50;
+    const float c = 0.10;
+    const float d = 0.20;
+    const float e = 0.02;
+    const float f = 0.30;
+    return ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f;
+}
+
+vec3 apply(vec3 x) {
+    switch (tonemap.operator) {
+        case AGX: return agx(x);
+        case UNCHARTED2: return uncharted2(x);
+        default: return aces(x);
+    }
+}
+
+// PCG hash, good enough to look like uncorrelated noise per pixel and frame
+uint hash(uint x) {
+    uint state = x * 747796405u + 2891336453u;
+    uint word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+float random(inout uint state) {
+    state = hash(state);
+    return float(state) / 4294967295.0;
+}
+
+// Darkening of the corners. Natural vignetting falls off with cos⁴ of the angle off the lens axis,
+//  optical vignetting from the barrel clipping the aperture adds to it wide open. The lens pass
+//  has already distorted the image, so the angle is taken where the pixel came from.
+float vignette(vec2 uv) {
+    float half_diagonal = sqrt(tonemap.aspect * tonemap.aspect + 1.0);
+    vec2 centered = (uv * 2.0 - 1.0) * vec2(tonemap.aspect, 1.0) / half_diagonal;
+    float r = length(centered) * (1.0 + tonemap.distortion * dot(centered, centered)) * tonemap.distortion_scale;
+
+    float cos_theta = inversesqrt(1.0 + pow(r * tonemap.tan_half_diagonal, 2.0));
+    float natural = pow(cos_theta, 4.0);
+    float optical = 1.0 - tonemap.optical_vignetting * smoothstep(0.4, 1.2, r) * 0.5;
+    return mix(1.0, natural * optical, tonemap.vignetting);
+}
+
+// Monochrome grain added to the encoded output, roughly gaussian from the sum of four uniform
+//  samples. Like film it shows most in the midtones and vanishes in the black and the white.
+vec3 add_grain(vec3 color) {
+    uint state = hash(uint(gl_FragCoord.x) + hash(uint(gl_FragCoord.y) + hash(tonemap.seed)));
+    float noise = random(state) + random(state) + random(state) + random(state) - 2.0;
+    float l = clamp(dot(color, LUMINANCE), 0.0, 1.0);
+    return clamp(color + noise * tonemap.grain * sqrt(3.0) * 4.0 * l * (1.0 - l), 0.0, 1.0);
+}
+
+vec3 srgb_encode(vec3 c) {
+    return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, greaterThan(c, vec3(0.0031308)));
+}
+
+vec3 srgb_decode(vec3 c) {
+    return mix(c / 12.92, pow((c + 0.055) / 1.055, vec3(2.4)), greaterThan(c, vec3(0.04045)));
+}
+
+// Display referred grading, on sRGB encoded values like the tools the LUTs come from
+vec3 grade(vec3 color) {
+    // Lift, gamma, gain
+    color = grading.gain.rgb * (color + grading.lift.rgb * (1.0 - color));
+    color = pow(max(color, 0.0), 1.0 / grading.gamma.rgb);
+
+    float contrast = grading.params.x;
+    float saturation = grading.params.y;
+    color = (color - 0.5) * contrast + 0.5;
+    color = mix(vec3(dot(color, LUMINANCE)), color, saturation);
+    color = clamp(color, 0.0, 1.0);
+
+    if (grading.params.w > 0.0) {
+        // Map the domain onto texel centers
+        float size = grading.lut_domain_max.w;
+        vec3 domain = (color - grading.lut_domain_min.rgb) / (grading.lut_domain_max.rgb - grading.lut_domain_min.rgb);
+        vec3 uvw = clamp(domain, 0.0, 1.0) * (size - 1.0) / size + 0.5 / size;
+        color = mix(color, texture(lut, uvw).rgb, grading.params.z);
+    }
+
+    return color;
+}
+
+void main() {
+    // The scene is already pre-exposed, so this is the radiance relative to middle grey
+    vec3 color = max(texelFetch(hdr, ivec2(gl_FragCoord.xy), 0).rgb, 0.0);
+
+    // Vignetting is light lost before it reaches the sensor, so it dims the scene ahead of the curve
+    if (tonemap.vignetting > 0.0) {
+        color *= vignette(gl_FragCoord.xy / vec2(textureSize(hdr, 0)));
+    }
+    color = max(grading.white_balance * color, 0.0);
+
+    vec3 mapped;
+    if (tonemap.operator == REINHARD) {
+        mapped = reinhard(color, tonemap.white_point);
+    } else {
+        // The other curves are normalised so the white point lands exactly on 1
+        vec3 white = apply(vec3(tonemap.white_point));
+        mapped = apply(color) / max(white, 1e-4);
+    }
+
+    // The swapchain is sRGB and encodes the output itself. Grain is the sensor's noise on the
+    //  recorded values, after the curve.
+    vec3 graded = grade(srgb_encode(clamp(mapped, 0.0, 1.0)));
+    if (tonemap.grain > 0.0) {
+        graded = add_grain(graded);
+    }
+    outColor = vec4(srgb_decode(graded), 1.0);
+}
@@ -0,0 +1,720 @@
+use std::mem::size_of;
+use cgmath::{vec3, vec4, EuclideanSpace, InnerSpace, Matrix4, MetricSpace, SquareMatrix};
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{anyhow, Ok, Result};
+
+use super::camera::{Camera, Mat4, Point3, Vec3, VULKAN_CLIP};
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::light::Light;
+use super::material::MaterialLibrary;
+use super::memory::{self, AllocatedBuffer, AllocatedImage};
+use super::mesh::{Mesh, Vertex};
+use super::scene::{LightKind, MeshInstance};
+use super::shader::{self, create_shader_module};
+use super::spot_shadow::MAX_SPOT_SHADOWS;
+
+/// Number of cascades the sun's shadow is split into
+pub const SHADOW_CASCADES: usize = 4;
+/// Resolution of each cascade
+pub const CASCADE_SIZE: u32 = 2048;
+
+/// Tunables for the sun's cascaded shadow map.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ShadowSettings {
+    /// Blend between uniform (0) and logarithmic (1) cascade splits
+    pub split_lambda: f32,
+    /// View distance the last cascade ends at, clamped to the camera's far plane
+    pub max_distance: f32,
+    /// How far behind each cascade casters are still captured, in metres
+    pub caster_distance: f32,
+    /// Constant depth bias in units of the depth format's resolution
+    pub depth_bias_constant: f32,
+    /// Depth bias scaled by the slope of the caster
+    pub depth_bias_slope: f32,
+    /// Offset of the receiver along its normal, in texels of the cascade
+    pub normal_bias: f32,
+    /// Half size of the PCF kernel in texels, 0 only uses the hardware 2x2 filter where the format has one
+    pub pcf_radius: u32,
+    /// Near plane of point light cube maps, in metres
+    pub point_near: f32,
+    /// How far point lights without a range cast shadows, in metres
+    pub point_far: f32,
+    /// Distance subtracted from the receiver before comparing against a cube map, in metres
+    pub point_depth_bias: f32,
+    /// Radius of the cube map filter disk in texels, 0 takes a single tap
+    pub point_pcf_radius: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            split_lambda: 0.75,
+            max_distance: 150.0,
+            caster_distance: 100.0,
+            depth_bias_constant: 1.25,
+            depth_bias_slope: 1.75,
+            normal_bias: 1.5,
+            pcf_radius: 1,
+            point_near: 0.05,
+            point_far: 25.0,
+            point_depth_bias: 0.05,
+            point_pcf_radius: 1.5,
+        }
+    }
+}
+
+/// One slice of the view frustum with the light's orthographic projection covering it.
+#[derive(Copy, Clone, Debug)]
+pub struct Cascade {
+    pub view_projection: Mat4,
+    /// View space distance where this cascade ends
+    pub split: f32,
+    /// World space size of a shadow map texel
+    pub texel_size: f32,
+}
+
+impl Default for Cascade {
+    fn default() -> Self {
+        Self { view_projection: Mat4::identity(), split: 0.0, texel_size: 0.0 }
+    }
+}
+
+/// Mirrors `Shadows` in `shadow.glsl` (std140)
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct ShadowUniforms {
+    cascades: [Mat4; SHADOW_CASCADES],
+    splits: [f32; 4],
+    texel_sizes: [f32; 4],
+    /// Normal bias, PCF radius, cascade count, index of the shadowed light (-1 for none)
+    params: [f32; 4],
+    /// Point light depth bias
+    point_params: [f32; 4],
+    /// View-projection of every spot light shadow map
+    spots: [Mat4; MAX_SPOT_SHADOWS],
+}
+
+/// Depth-only render targets for the sun's cascades, sampled through set 0 of the scene pipelines.
+#[derive(Clone, Debug, Default)]
+pub struct ShadowMaps {
+    pub settings: ShadowSettings,
+    pub cascades: [Cascade; SHADOW_CASCADES],
+    /// Index into the engine's lights of the light casting the cascades
+    pub light: Option<usize>,
+    image: AllocatedImage,
+    layer_views: Vec<vk::ImageView>,
+    framebuffers: Vec<vk::Framebuffer>,
+    sampler: vk::Sampler,
+    render_pass: vk::RenderPass,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    masked_pipeline: vk::Pipeline,
+    uniform_buffer: AllocatedBuffer,
+}
+
+impl ShadowMaps {
+    /// Renders every instance into every cascade. Does nothing without a shadowed light.
+    pub unsafe fn cmd_render(&self, device: &Device, command_buffer: vk::CommandBuffer, meshes: &[Mesh],
+        instances: &[MeshInstance], materials: &MaterialLibrary)
+    {
+        if self.light.is_none() {
+            return;
+        }
+
+        let extent = vk::Extent2D { width: CASCADE_SIZE, height: CASCADE_SIZE };
+        for (cascade, framebuffer) in self.cascades.iter().zip(&self.framebuffers) {
+            cmd_begin_depth_pass(device, command_buffer, self.render_pass, *framebuffer, extent);
+            shader::cmd_set_full_viewport(device, command_buffer, extent);
+            device.cmd_set_depth_bias(command_buffer, self.settings.depth_bias_constant, 0.0, self.settings.depth_bias_slope);
+
+            cmd_draw_casters(device, command_buffer, (self.pipeline, self.masked_pipeline), self.pipeline_layout, 0,
+                meshes, instances, materials, |instance| {
+                    let light_mvp = cascade.view_projection * instance.transform;
+                    shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+                        vk::ShaderStageFlags::VERTEX, &light_mvp);
+                });
+            device.cmd_end_render_pass(command_buffer);
+        }
+    }
+
+    /// Fits the cascades around the camera's frustum for a light travelling along `direction`
+    pub fn fit(&mut self, camera: &Camera, aspect: f32, direction: Vec3) {
+        let settings = self.settings;
+        let near = camera.near;
+        let far = camera.far.min(settings.max_distance).max(near * 2.0);
+
+        let forward = camera.forward();
+        let right = forward.cross(camera.up).normalize();
+        let up = right.cross(forward);
+        let tan_y = (camera.vertical_fov().0 / 2.0).to_radians().tan();
+        let tan_x = tan_y * aspect;
+
+        let light_up = if direction.y.abs() > 0.99 { vec3(0.0, 0.0, 1.0) } else { vec3(0.0, 1.0, 0.0) };
+        let texels = CASCADE_SIZE as f32 / 2.0;
+
+        let mut previous = near;
+        for (i, cascade) in self.cascades.iter_mut().enumerate() {
+            // Practical split scheme, a blend of logarithmic and uniform splits
+            let p = (i + 1) as f32 / SHADOW_CASCADES as f32;
+            let logarithmic = near * (far / near).powf(p);
+            let uniform = near + (far - near) * p;
+            let split = settings.split_lambda * logarithmic + (1.0 - settings.split_lambda) * uniform;
+
+            let corners = [previous, split]
+                .iter()
+                .flat_map(|d| {
+                    [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)].map(|(sx, sy): (f32, f32)| {
+                        camera.position + forward * *d + right * (sx * d * tan_x) + up * (sy * d * tan_y)
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            // A bounding sphere keeps the projection's size fixed as the camera turns, which stops shimmering
+            let center = Point3::centroid(&corners);
+            let radius = corners
+                .iter()
+                .map(|c| c.distance(center))
+                .fold(0.0, f32::max);
+            let radius = (radius * 16.0).ceil() / 16.0;
+
+            let eye = center - direction * (radius + settings.caster_distance);
+            let view = Matrix4::look_at_rh(eye, center, light_up);
+            let mut projection = VULKAN_CLIP
+                * cgmath::ortho(-radius, radius, -radius, radius, 0.0, 2.0 * radius + settings.caster_distance);
+
+            // Snap the world origin to a texel so the map only ever moves in whole texels
+            let origin = projection * view * vec4(0.0, 0.0, 0.0, 1.0);
+            projection.w.x += (origin.x * texels).round() / texels - origin.x;
+            projection.w.y += (origin.y * texels).round() / texels - origin.y;
+
+            *cascade = Cascade {
+                view_projection: projection * view,
+                split,
+                texel_size: 2.0 * radius / CASCADE_SIZE as f32,
+            };
+            previous = split;
+        }
+    }
+}
+
+/// Draws every instance into the current depth pass, `push` recording its push constants. Masked
+///  materials are drawn last with `pipelines.1`, which alpha tests them with the material bound at
+///  `material_set`, everything else with `pipelines.0`. Passes alpha testing every caster give the
+///  same pipeline twice.
+pub unsafe fn cmd_draw_casters(device: &Device, command_buffer: vk::CommandBuffer,
+    pipelines: (vk::Pipeline, vk::Pipeline), layout: vk::PipelineLayout, material_set: u32, meshes: &[Mesh],
+    instances: &[MeshInstance], materials: &MaterialLibrary, push: impl Fn(&MeshInstance))
+{
+    for (masked, pipeline) in [(false, pipelines.0), (true, pipelines.1)] {
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline);
+        for instance in instances {
+            let material = materials.get(instance.material);
+            if material.masked() != masked {
+                continue;
+            }
+            if pipeline == pipelines.1 {
+                device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout,
+                    material_set, &[material.set], &[]);
+            }
+            push(instance);
+            meshes[instance.mesh].cmd_draw(device, command_buffer);
+        }
+    }
+}
+
+/// How much a light's shadow matters from the camera, for handing out the point and spot shadow maps
+pub fn shadow_importance(light: &Light, camera: &Camera) -> f32 {
+    let distance2 = light.position.distance2(camera.position).max(1.0);
+    light.intensity * light.color.x.max(light.color.y).max(light.color.z) / distance2
+}
+
+/// The first directional light wanting a shadow casts the cascades
+fn shadow_light(lights: &[Light]) -> Option<usize> {
+    lights.iter().position(|l| l.kind == LightKind::Directional && l.shadow_resolution > 0)
+}
+
+pub unsafe fn create_shadow_maps(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let format = get_shadow_format(instance, data)?;
+    let info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::_2D)
+        .extent(vk::Extent3D { width: CASCADE_SIZE, height: CASCADE_SIZE, depth: 1 })
+        .mip_levels(1)
+        .array_layers(SHADOW_CASCADES as u32)
+        .format(format)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(vk::SampleCountFlags::_1);
+
+    let image = AllocatedImage::from_info(&info, vk::ImageViewType::_2D_ARRAY, vk::ImageAspectFlags::DEPTH,
+        instance, device, data)?;
+
+    // The map is sampled even on frames nothing casts, so it needs a valid layout from the start
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    memory::cmd_image_barrier(device, command_buffer, image.image, depth_subresource_range(0, SHADOW_CASCADES as u32),
+        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL),
+        (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+        (vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ));
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    let render_pass = create_depth_render_pass(device, format, 0)?;
+    let layer_views = (0..SHADOW_CASCADES as u32)
+        .map(|layer| create_layer_view(device, &image, vk::ImageViewType::_2D, layer, 1))
+        .collect::<Result<Vec<_>>>()?;
+    let framebuffers = layer_views
+        .iter()
+        .map(|view| create_depth_framebuffer(device, render_pass, *view, CASCADE_SIZE))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Masked casters bind their material as set 0 for the alpha test
+    let pipeline_layout = descriptor::create_pipeline_layout(device, &[data.materials.set_layout],
+        size_of::<Mat4>() as u32, vk::ShaderStageFlags::VERTEX)?;
+    let vert = include_bytes!(concat!(env!("OUT_DIR"), "/shader/shadow_vert.spv"));
+    let mask_frag = include_bytes!(concat!(env!("OUT_DIR"), "/shader/shadow_mask_frag.spv"));
+    let pipeline = create_depth_pipeline(device, &vert[..], None, pipeline_layout, render_pass)?;
+    let masked_pipeline = create_depth_pipeline(device, &vert[..], Some(&mask_frag[..]), pipeline_layout,
+        render_pass)?;
+
+    let sampler = create_shadow_sampler(format, instance, device, data)?;
+    let uniform_buffer = AllocatedBuffer::allocate(
+        size_of::<ShadowUniforms>() as u64,
+        vk::BufferUsageFlags::UNIFORM_BUFFER,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)?;
+
+    let set = data.frame.set;
+    descriptor::write_buffer(device, set, 2, vk::DescriptorType::UNIFORM_BUFFER, uniform_buffer.buffer);
+    descriptor::write_image(device, set, 3, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, image.image_view,
+        vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL, sampler);
+
+    data.shadows = ShadowMaps {
+        image,
+        layer_views,
+        framebuffers,
+        sampler,
+        render_pass,
+        pipeline_layout,
+        pipeline,
+        masked_pipeline,
+        uniform_buffer,
+        ..Default::default()
+    };
+    Ok(())
+}
+
+pub unsafe fn destroy_shadow_maps(device: &Device, data: &mut EngineData)
+{
+    let shadows = &mut data.shadows;
+    shadows.framebuffers
+        .iter()
+        .for_each(|f| device.destroy_framebuffer(*f, None));
+    shadows.layer_views
+        .iter()
+        .for_each(|v| device.destroy_image_view(*v, None));
+    shadows.image.destroy(device);
+    shadows.uniform_buffer.destroy(device);
+    device.destroy_sampler(shadows.sampler, None);
+    device.destroy_pipeline(shadows.pipeline, None);
+    device.destroy_pipeline(shadows.masked_pipeline, None);
+    device.destroy_pipeline_layout(shadows.pipeline_layout, None);
+    device.destroy_render_pass(shadows.render_pass, None);
+}
+
+/// Picks the shadowed light, refits the cascades to the camera and uploads them
+pub unsafe fn update_shadow_maps(device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let extent = data.swapchain_extent;
+    let aspect = extent.width as f32 / extent.height.max(1) as f32;
+    let light = shadow_light(&data.lights);
+    if let Some(index) = light {
+        let direction = data.lights[index].direction;
+        data.shadows.fit(&data.camera, aspect, direction);
+    }
+    data.shadows.light = light;
+
+    let shadows = &data.shadows;
+    let uniforms = ShadowUniforms {
+        cascades: shadows.cascades.map(|c| c.view_projection),
+        splits: shadows.cascades.map(|c| c.split),
+        texel_sizes: shadows.cascades.map(|c| c.texel_size),
+        params: [
+            shadows.settings.normal_bias,
+            shadows.settings.pcf_radius as f32,
+            SHADOW_CASCADES as f32,
+            light.map_or(-1.0, |l| l as f32),
+        ],
+        point_params: [shadows.settings.point_depth_bias, 0.0, 0.0, 0.0],
+        spots: data.spot_shadows.view_projections(),
+    };
+
+    shadows.uniform_buffer.write(device, &uniforms, size_of::<ShadowUniforms>())
+}
+
+/// Begins a depth-only render pass over the whole framebuffer, clearing to the far plane
+pub unsafe fn cmd_begin_depth_pass(device: &Device, command_buffer: vk::CommandBuffer, render_pass: vk::RenderPass,
+    framebuffer: vk::Framebuffer, extent: vk::Extent2D)
+{
+    let render_area = vk::Rect2D::builder()
+        .offset(vk::Offset2D::default())
+        .extent(extent);
+
+    let clear_values = &[vk::ClearValue {
+        depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 },
+    }];
+
+    let info = vk::RenderPassBeginInfo::builder()
+        .render_pass(render_pass)
+        .framebuffer(framebuffer)
+        .render_area(render_area)
+        .clear_values(clear_values);
+
+    device.cmd_begin_render_pass(command_buffer, &info, vk::SubpassContents::INLINE);
+}
+
+/// The best sampleable depth-only format, D16 is always available
+pub unsafe fn get_shadow_format(instance: &Instance, data: &EngineData) -> Result<vk::Format>
+{
+    let required = vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT | vk::FormatFeatureFlags::SAMPLED_IMAGE;
+    [vk::Format::D32_SFLOAT, vk::Format::D16_UNORM]
+        .iter()
+        .cloned()
+        .find(|f| {
+            instance
+                .get_physical_device_format_properties(data.physical_device, *f)
+                .optimal_tiling_features
+                .contains(required)
+        })
+        .ok_or_else(|| anyhow!("Failed to find a supported shadow map format."))
+}
+
+pub fn depth_subresource_range(base_layer: u32, layer_count: u32) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::DEPTH)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(base_layer)
+        .layer_count(layer_count)
+        .build()
+}
+
+/// A view of `layer_count` layers of a depth image, for rendering into them
+pub unsafe fn create_layer_view(device: &Device, image: &AllocatedImage, view_type: vk::ImageViewType, base_layer: u32,
+    layer_count: u32) -> Result<vk::ImageView>
+{
+    let view_info = vk::ImageViewCreateInfo::builder()
+        .image(image.image)
+        .view_type(view_type)
+        .format(image.format)
+        .subresource_range(depth_subresource_range(base_layer, layer_count));
+
+    Ok(device.create_image_view(&view_info, None)?)
+}
+
+pub unsafe fn create_depth_framebuffer(device: &Device, render_pass: vk::RenderPass, view: vk::ImageView, size: u32)
+    -> Result<vk::Framebuffer>
+{
+    let attachments = &[view];
+    let info = vk::FramebufferCreateInfo::builder()
+        .render_pass(render_pass)
+        .attachments(attachments)
+        .width(size)
+        .height(size)
+        .layers(1);
+
+    Ok(device.create_framebuffer(&info, None)?)
+}
+
+/// A render pass writing a single depth attachment that is sampled afterwards.
+///  A non-zero `view_mask` broadcasts every draw to those layers with multiview.
+pub unsafe fn create_depth_render_pass(device: &Device, format: vk::Format, view_mask: u32) -> Result<vk::RenderPass>
+{
+    let depth_attachment = vk::AttachmentDescription::builder()
+        .format(format)
+        .samples(vk::SampleCountFlags::_1)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
+
+    let depth_attachment_ref = vk::AttachmentReference::builder()
+        .attachment(0)
+        .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+    let subpass = vk::SubpassDescription::builder()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .depth_stencil_attachment(&depth_attachment_ref);
+
+    // Wait for last frame's shading to stop reading before overwriting, and finish writing before this frame's reads
+    let dependencies = &[
+        vk::SubpassDependency::builder()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+            .src_access_mask(vk::AccessFlags::SHADER_READ)
+            .dst_stage_mask(vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+            .dst_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE),
+        vk::SubpassDependency::builder()
+            .src_subpass(0)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ),
+    ];
+
+    let view_masks = &[view_mask];
+    let mut multiview_info = vk::RenderPassMultiviewCreateInfo::builder()
+        .view_masks(view_masks)
+        .correlation_masks(view_masks);
+
+    let attachments = &[depth_attachment];
+    let subpasses = &[subpass];
+    let mut info = vk::RenderPassCreateInfo::builder()
+        .attachments(attachments)
+        .subpasses(subpasses)
+        .dependencies(dependencies);
+    if view_mask != 0 {
+        info = info.push_next(&mut multiview_info);
+    }
+
+    Ok(device.create_render_pass(&info, None)?)
+}
+
+/// A pipeline drawing scene meshes into a depth-only pass with dynamic viewport and depth bias.
+///  Without a fragment shader only depth is written, with one it can alpha test.
+pub unsafe fn create_depth_pipeline(device: &Device, vert: &[u8], frag: Option<&[u8]>, layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass) -> Result<vk::Pipeline>
+{
+    let vert_shader_module = create_shader_module(device, vert)?;
+    let frag_shader_module = frag.map(|f| create_shader_module(device, f)).transpose()?;
+
+    let mut stages = vec![
+        vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::VERTEX)
+            .module(vert_shader_module)
+            .name(b"main\0")
+            .build(),
+    ];
+    if let Some(module) = frag_shader_module {
+        stages.push(vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::FRAGMENT)
+            .module(module)
+            .name(b"main\0")
+            .build());
+    }
+
+    // Only positions, and UVs for the alpha test, are read
+    let binding_descriptions = &[Vertex::binding_description()];
+    let attributes = Vertex::attribute_descriptions();
+    let attribute_descriptions = &[attributes[0], attributes[3]];
+    let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+        .vertex_binding_descriptions(binding_descriptions)
+        .vertex_attribute_descriptions(attribute_descriptions);
+
+    let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+        .primitive_restart_enable(false);
+
+    let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+        .viewport_count(1)
+        .scissor_count(1);
+
+    // Both faces cast, the bias keeps lit faces from shadowing themselves
+    let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+        .depth_clamp_enable(false)
+        .rasterizer_discard_enable(false)
+        .polygon_mode(vk::PolygonMode::FILL)
+        .line_width(1.0)
+        .cull_mode(vk::CullModeFlags::NONE)
+        .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+        .depth_bias_enable(true);
+
+    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+        .sample_shading_enable(false)
+        .rasterization_samples(vk::SampleCountFlags::_1);
+
+    let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(true)
+        .depth_write_enable(true)
+        .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL)
+        .depth_bounds_test_enable(false)
+        .stencil_test_enable(false);
+
+    let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+        .logic_op_enable(false);
+
+    let dynamic_states = &[
+        vk::DynamicState::VIEWPORT,
+        vk::DynamicState::SCISSOR,
+        vk::DynamicState::DEPTH_BIAS,
+    ];
+    let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+        .dynamic_states(dynamic_states);
+
+    let info = vk::GraphicsPipelineCreateInfo::builder()
+        .stages(&stages)
+        .vertex_input_state(&vertex_input_state)
+        .input_assembly_state(&input_assembly_state)
+        .viewport_state(&viewport_state)
+        .rasterization_state(&rasterization_state)
+        .multisample_state(&multisample_state)
+        .depth_stencil_state(&depth_stencil_state)
+        .color_blend_state(&color_blend_state)
+        .dynamic_state(&dynamic_state)
+        .layout(layout)
+        .render_pass(render_pass)
+        .subpass(0);
+
+    let pipeline = device
+        .create_graphics_pipelines(vk::PipelineCache::null(), &[info], None)?
+        .0[0];
+
+    device.destroy_shader_module(vert_shader_module, None);
+    if let Some(module) = frag_shader_module {
+        device.destroy_shader_module(module, None);
+    }
+    Ok(pipeline)
+}
+
+/// A comparison sampler, so every tap is already a bilinear 2x2 PCF where `format` can be filtered
+///  linearly, a single compare where it can't. Outside the map counts as lit.
+pub unsafe fn create_shadow_sampler(format: vk::Format, instance: &Instance, device: &Device, data: &EngineData)
+    -> Result<vk::Sampler>
+{
+    let filter = if instance
+        .get_physical_device_format_properties(data.physical_device, format)
+        .optimal_tiling_features
+        .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
+    {
+        vk::Filter::LINEAR
+    } else {
+        vk::Filter::NEAREST
+    };
+
+    let info = vk::SamplerCreateInfo::builder()
+        .mag_filter(filter)
+        .min_filter(filter)
+        .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+        .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+        .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+        .anisotropy_enable(false)
+        .max_anisotropy(1.0)
+        .border_color(vk::BorderColor::FLOAT_OPAQUE_WHITE)
+        .unnormalized_coordinates(false)
+        .compare_enable(true)
+        .compare_op(vk::CompareOp::LESS_OR_EQUAL)
+        .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+        .mip_lod_bias(0.0)
+        .min_lod(0.0)
+        .max_lod(0.0);
+
+    Ok(device.create_sampler(&info, None)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{point3, Vector4};
+
+    fn camera() -> Camera {
+        Camera {
+            position: point3(3.0, 2.0, 5.0),
+            target: point3(0.0, 1.0, 0.0),
+            near: 0.1,
+            far: 400.0,
+            ..Default::default()
+        }
+    }
+
+    fn fit(settings: ShadowSettings, camera: &Camera) -> [Cascade; SHADOW_CASCADES] {
+        let mut shadows = ShadowMaps { settings, ..Default::default() };
+        shadows.fit(camera, 16.0 / 9.0, vec3(-0.3, -1.0, -0.5).normalize());
+        shadows.cascades
+    }
+
+    fn splits(split_lambda: f32) -> Vec<f32> {
+        let settings = ShadowSettings { split_lambda, ..Default::default() };
+        fit(settings, &camera()).iter().map(|c| c.split).collect()
+    }
+
+    fn assert_close(a: &[f32], b: &[f32]) {
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).abs() <= 1e-3 * b.abs().max(1.0), "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn uniform_splits_are_evenly_spaced() {
+        let (near, far) = (0.1, 150.0);
+        let expected = (1..=SHADOW_CASCADES)
+            .map(|i| near + (far - near) * i as f32 / SHADOW_CASCADES as f32)
+            .collect::<Vec<_>>();
+        assert_close(&splits(0.0), &expected);
+    }
+
+    #[test]
+    fn logarithmic_splits_grow_geometrically() {
+        let (near, far) = (0.1f32, 150.0f32);
+        let expected = (1..=SHADOW_CASCADES)
+            .map(|i| near * (far / near).powf(i as f32 / SHADOW_CASCADES as f32))
+            .collect::<Vec<_>>();
+        assert_close(&splits(1.0), &expected);
+    }
+
+    #[test]
+    fn splits_end_at_the_shadow_distance_or_the_far_plane() {
+        let splits = splits(0.75);
+        assert!(splits.windows(2).all(|w| w[0] < w[1]));
+        assert_close(&splits[SHADOW_CASCADES - 1..], &[150.0]);
+
+        let near_camera = Camera { far: 50.0, ..camera() };
+        let cascades = fit(ShadowSettings::default(), &near_camera);
+        assert_close(&[cascades[SHADOW_CASCADES - 1].split], &[50.0]);
+    }
+
+    #[test]
+    fn cascades_cover_their_slice_of_the_frustum() {
+        let camera = camera();
+        let cascades = fit(ShadowSettings::default(), &camera);
+        let inverse = camera.inverse_projection(16.0 / 9.0);
+        let inverse_view = camera.inverse_view();
+
+        let mut previous = camera.near;
+        for cascade in &cascades {
+            for distance in [previous, cascade.split] {
+                // The slice's corners, from the view space depth back through the camera's projection
+                let depth = camera.projection(16.0 / 9.0) * Vector4::new(0.0, 0.0, -distance, 1.0);
+                for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+                    let view = inverse * Vector4::new(x, y, depth.z / depth.w, 1.0);
+                    let world = inverse_view * (view / view.w);
+                    let light = cascade.view_projection * world;
+                    assert!(light.x.abs() <= 1.0 + 1e-4 && light.y.abs() <= 1.0 + 1e-4, "{:?}", light);
+                    assert!((0.0..=1.0).contains(&light.z), "{:?}", light);
+                }
+            }
+            previous = cascade.split;
+        }
+    }
+
+    #[test]
+    fn cascades_move_in_whole_texels() {
+        let before = fit(ShadowSettings::default(), &camera());
+        let nudged = Camera { position: point3(3.013, 2.007, 5.021), target: point3(0.013, 1.007, 0.021), ..camera() };
+        let after = fit(ShadowSettings::default(), &nudged);
+
+        let texels = CASCADE_SIZE as f32 / 2.0;
+        let point = Vector4::new(1.7, -0.4, 2.9, 1.0);
+        for (before, after) in before.iter().zip(&after) {
+            assert_eq!(before.texel_size, after.texel_size);
+            let shift = (after.view_projection * point - before.view_projection * point) * texels;
+            assert!((shift.x - shift.x.round()).abs() < 1e-2, "{:?}", shift);
+            assert!((shift.y - shift.y.round()).abs() < 1e-2, "{:?}", shift);
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use std::path::Path;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{anyhow, Ok, Result};
+
+use super::deferred::GBUFFER_ATTACHMENTS;
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::ibl;
+use super::image_io;
+use super::memory::{self, AllocatedImage};
+use super::post::{HDR_FORMAT, SCENE_COLOR_ATTACHMENTS, WORKGROUP_SIZE};
+use super::shader;
+use super::texture;
+
+/// Brightness and orientation of the sky map.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SkyboxSettings {
+    /// Luminance of a sky texel of value 1, in cd/m²
+    pub intensity: f32,
+    /// Rotation of the sky about the up axis, in radians
+    pub rotation: f32,
+}
+
+impl Default for SkyboxSettings {
+    fn default() -> Self {
+        Self { intensity: 5000.0, rotation: 0.0 }
+    }
+}
+
+/// A cube map sky drawn behind the scene and sampled by everything reflecting the environment,
+///  binding 9 of the frame set. Loaded from six PNG faces or from an equirectangular Radiance
+///  or OpenEXR file, which is resampled into the cube on the GPU, or rendered by the atmosphere.
+///  Without one the background stays black and the environment is the uniform ambient.
+#[derive(Clone, Debug, Default)]
+pub struct Skybox {
+    pub settings: SkyboxSettings,
+    /// The sky in linear RGBA16F with a full mip chain, a single black texel until one is loaded
+    pub cube: AllocatedImage,
+    /// Whether `cube` holds a loaded sky rather than the placeholder
+    pub loaded: bool,
+    /// Hash of the loaded sky's pixels, naming its image based lighting in the cache. Procedural
+    ///  skies have none, their lighting is integrated again in memory.
+    pub key: Option<u64>,
+    /// Angular radius of the disks drawn for directional lights, in radians. Only procedural skies
+    ///  have them, loaded ones carry their own sun.
+    pub sun_radius: f32,
+    sampler: vk::Sampler,
+    pipeline_layout: vk::PipelineLayout,
+    /// Draws the sky after the forward pass' meshes
+    pipeline: vk::Pipeline,
+    /// Draws the sky after the meshes of the deferred pass' G-buffer subpass
+    gbuffer_pipeline: vk::Pipeline,
+}
+
+impl Skybox {
+    /// The sky's luminance, rotation and sun disks as the shaders' `frame.environment`
+    pub fn environment_params(&self) -> [f32; 4] {
+        let intensity = if self.loaded { self.settings.intensity } else { 0.0 };
+        [intensity, self.settings.rotation, self.sun_radius, 0.0]
+    }
+
+    /// Fills every pixel the meshes left at the far plane with the sky, inside the scene pass
+    pub unsafe fn cmd_draw(&self, device: &Device, command_buffer: vk::CommandBuffer, frame_set: vk::DescriptorSet,
+        extent: vk::Extent2D, deferred: bool)
+    {
+        if !self.loaded {
+            return;
+        }
+
+        let pipeline = if deferred { self.gbuffer_pipeline } else { self.pipeline };
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline);
+        shader::cmd_set_full_viewport(device, command_buffer, extent);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+            self.pipeline_layout, 0, &[frame_set], &[]);
+        device.cmd_draw(command_buffer, 3, 1, 0, 0);
+    }
+}
+
+pub unsafe fn create_skybox(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let skybox = &mut data.skybox;
+    skybox.sampler = descriptor::create_sampler(device, vk::Filter::LINEAR, vk::LOD_CLAMP_NONE)?;
+    skybox.pipeline_layout = descriptor::create_pipeline_layout(device, &[data.frame.set_layout], 0,
+        vk::ShaderStageFlags::FRAGMENT)?;
+
+    clear_skybox(instance, device, data)?;
+    create_skybox_targets(device, data)
+}
+
+/// Creates the sky pipelines for the forward and deferred passes of the current swapchain, after
+///  the deferred targets
+pub unsafe fn create_skybox_targets(device: &Device, data: &mut EngineData) -> Result<()>
+{
+    // The colour and velocity of the forward pass, the normal and specular stay cleared so the
+    //  reflections leave the sky alone
+    let frag = include_bytes!(concat!(env!("OUT_DIR"), "/shader/skybox_frag.spv"));
+    let written = (0..SCENE_COLOR_ATTACHMENTS).map(|i| i < 2).collect::<Vec<_>>();
+    let pipeline = create_sky_pipeline(device, &frag[..], data.skybox.pipeline_layout, data.render_pass,
+        &written)?;
+
+    // The velocity and the last G-buffer attachment, the emission
+    let frag = include_bytes!(concat!(env!("OUT_DIR"), "/shader/skybox_gbuffer_frag.spv"));
+    let written = (0..3 + GBUFFER_ATTACHMENTS).map(|i| i == 0 || i == GBUFFER_ATTACHMENTS).collect::<Vec<_>>();
+    let gbuffer_pipeline = create_sky_pipeline(device, &frag[..], data.skybox.pipeline_layout,
+        data.deferred.render_pass, &written)?;
+
+    data.skybox.pipeline = pipeline;
+    data.skybox.gbuffer_pipeline = gbuffer_pipeline;
+    Ok(())
+}
+
+pub unsafe fn destroy_skybox_targets(device: &Device, data: &mut EngineData)
+{
+    device.destroy_pipeline(data.skybox.pipeline, None);
+    device.destroy_pipeline(data.skybox.gbuffer_pipeline, None);
+}
+
+pub unsafe fn destroy_skybox(device: &Device, data: &mut EngineData)
+{
+    let skybox = &mut data.skybox;
+    skybox.cube.destroy(device);
+    device.destroy_sampler(skybox.sampler, None);
+    device.destroy_pipeline_layout(skybox.pipeline_layout, None);
+}
+
+/// Loads a cube map from six PNG faces in +X, -X, +Y, -Y, +Z, -Z order. The faces are sRGB
+///  encoded and have to be square and of the same size.
+pub unsafe fn load_skybox_faces(faces: &[&Path; 6], instance: &Instance, device: &Device, data: &mut EngineData)
+    -> Result<()>
+{
+    let mut size = None;
+    let mut pixels = Vec::new();
+    for face in faces {
+        let (width, height, rgba) = image_io::read_png(face)?;
+        if width != height || size.is_some_and(|size| size != width) {
+            return Err(anyhow!("Skybox face `{}` isn't square or doesn't match the others.", face.display()));
+        }
+        size = Some(width);
+
+        // Linear half floats, like the panoramas are stored
+        pixels.extend(rgba.iter().enumerate().flat_map(|(i, &c)| {
+            let c = c as f32 / 255.0;
+            let linear = if i % 4 == 3 { c } else { image_io::srgb_to_linear(c) };
+            image_io::f32_to_f16(linear).to_ne_bytes()
+        }));
+    }
+
+    let size = size.unwrap_or(1);
+    let mip_levels = 32 - size.leading_zeros();
+    let cube = create_cube(size, mip_levels, instance, device, data)?;
+    texture::upload_pixels(&cube, &pixels, 0, 6, instance, device, data)?;
+    texture::generate_mipmaps(&cube, mip_levels, 6, device, data)?;
+    set_cube(cube, true, Some(ibl::cache_key(&pixels)), device, data);
+    Ok(())
+}
+
+/// Loads an equirectangular Radiance (.hdr) or OpenEXR (.exr) panorama and resamples it into a cube map with a
+///  quarter of its width per face
+pub unsafe fn load_skybox_equirect(path: &Path, instance: &Instance, device: &Device, data: &mut EngineData)
+    -> Result<()>
+{
+    let (width, height, rgba) = image_io::read_linear_image(path)?;
+    let pixels = rgba
+        .iter()
+        .flat_map(|&c| image_io::f32_to_f16(c).to_ne_bytes())
+        .collect::<Vec<_>>();
+    let key = ibl::cache_key(&pixels);
+
+    let mut equirect = AllocatedImage::create(
+        vk::Extent2D { width, height },
+        HDR_FORMAT,
+        vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+    texture::upload_pixels(&equirect, &pixels, 0, 1, instance, device, data)?;
+    // With a single mip this only moves it to the shader read layout
+    texture::generate_mipmaps(&equirect, 1, 1, device, data)?;
+
+    let size = (width / 4).max(1);
+    let mip_levels = 32 - size.leading_zeros();
+    let cube = create_cube(size, mip_levels, instance, device, data)?;
+    convert_equirect(&equirect, &cube, device, data)?;
+    texture::generate_mipmaps(&cube, mip_levels, 6, device, data)?;
+    equirect.destroy(device);
+
+    set_cube(cube, true, Some(key), device, data);
+    Ok(())
+}
+
+/// Goes back to no sky, a black background and the uniform ambient environment
+pub unsafe fn clear_skybox(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let cube = create_cube(1, 1, instance, device, data)?;
+    let black = [image_io::f32_to_f16(0.0).to_ne_bytes(); 4 * 6].concat();
+    texture::upload_pixels(&cube, &black, 0, 6, instance, device, data)?;
+    texture::generate_mipmaps(&cube, 1, 6, device, data)?;
+    set_cube(cube, false, None, device, data);
+    Ok(())
+}
+
+/// Replaces the sky's cube, which must not be in use anymore, and points the frame set at it.
+///  `key` names a loaded sky's lighting in the cache, the placeholder has none. Any sun disks
+///  are taken down.
+pub unsafe fn set_cube(cube: AllocatedImage, key: Option<u64>, device: &Device, data: &mut EngineData)
+{
+    let skybox = &mut data.skybox;
+    skybox.cube.destroy(device);
+    skybox.cube = cube;
+    skybox.loaded = loaded;
+    skybox.key = key;
+    skybox.sun_radius = 0.0;
+    descriptor::write_image(device, data.frame.set, 9, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        skybox.cube.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, skybox.sampler);
+}
+
+/// An RGBA16F cube map, writable by compute passes, blits and copies
+pub unsafe fn create_cube(size: u32, mip_levels: u32, instance: &Instance, device: &Device, data: &EngineData)
+    -> Result<AllocatedImage>
+{
+    let info = vk::ImageCreateInfo::builder()
+        .flags(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+        .image_type(vk::ImageType::_2D)
+        .extent(vk::Extent3D { width: size, height: size, depth: 1 })
+        .mip_levels(mip_levels)
+        .array_layers(6)
+        .format(HDR_FORMAT)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC
+            | vk::ImageUsageFlags::TRANSFER_DST)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(vk::SampleCountFlags::_1);
+
+    AllocatedImage::from_info(&info, vk::ImageViewType::CUBE, vk::ImageAspectFlags::COLOR, instance, device, data)
+}
+
+/// Resamples the panorama into the top mip of the cube's faces with a compute pass that only
+///  lives for the conversion, leaving the whole cube in `TRANSFER_DST_OPTIMAL` for the mip chain
+unsafe fn convert_equirect(equirect: &AllocatedImage, cube: &AllocatedImage, device: &Device, data: &EngineData)
+    -> Result<()>
+{
+    let set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    let descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1),
+        (vk::DescriptorType::STORAGE_IMAGE, 1),
+    ], 1)?;
+    let set = descriptor::allocate_set(device, descriptor_pool, set_layout)?;
+    let pipeline_layout = descriptor::create_pipeline_layout(device, &[set_layout], 0,
+        vk::ShaderStageFlags::COMPUTE)?;
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/equirect_to_cube_comp.spv"));
+    let pipeline = shader::create_compute_pipeline(device, &comp[..], pipeline_layout)?;
+    let sampler = descriptor::create_sampler(device, vk::Filter::LINEAR, 0.0)?;
+
+    // The faces of the top mip as an array the shader can store to
+    let face_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(6);
+    let view_info = vk::ImageViewCreateInfo::builder()
+        .image(cube.image)
+        .view_type(vk::ImageViewType::_2D_ARRAY)
+        .format(cube.format)
+        .subresource_range(face_range);
+    let faces = device.create_image_view(&view_info, None)?;
+
+    descriptor::write_image(device, set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, equirect.image_view,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, sampler);
+    descriptor::write_image(device, set, 1, vk::DescriptorType::STORAGE_IMAGE, faces,
+        vk::ImageLayout::GENERAL, vk::Sampler::null());
+
+    let range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(vk::REMAINING_MIP_LEVELS)
+        .base_array_layer(0)
+        .layer_count(6)
+        .build();
+
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    memory::cmd_image_barrier(device, command_buffer, cube.image, range,
+        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+        (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+
+    device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, pipeline);
+    device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, pipeline_layout, 0, &[set], &[]);
+    let groups = (cube.extent.width + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE;
+    device.cmd_dispatch(command_buffer, groups, groups, 6);
+
+    memory::cmd_image_barrier(device, command_buffer, cube.image, range,
+        (vk::ImageLayout::GENERAL, vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+        (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::TRANSFER_WRITE));
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    device.destroy_image_view(faces, None);
+    device.destroy_sampler(sampler, None);
+    device.destroy_pipeline(pipeline, None);
+    device.destroy_pipeline_layout(pipeline_layout, None);
+    device.destroy_descriptor_pool(descriptor_pool, None);
+    device.destroy_descriptor_set_layout(set_layout, None);
+    Ok(())
+}
+
+/// A fullscreen triangle on the far plane, depth tested against the meshes without writing depth.
+///  `written` holds whether each colour attachment of the subpass is written.
+unsafe fn create_sky_pipeline(device: &Device, frag: &[u8], layout: vk::PipelineLayout, render_pass: vk::RenderPass,
+    written: &[bool]) -> Result<vk::Pipeline>
+{
+    let vert = include_bytes!(concat!(env!("OUT_DIR"), "/shader/skybox_vert.spv"));
+    let vert_shader_module = shader::create_shader_module(device, &vert[..])?;
+    let frag_shader_module = shader::create_shader_module(device, frag)?;
+
+    let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::VERTEX)
+        .module(vert_shader_module)
+        .name(b"main\0");
+
+    let frag_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::FRAGMENT)
+        .module(frag_shader_module)
+        .name(b"main\0");
+
+    let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder();
+    let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+        .primitive_restart_enable(false);
+
+    let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+        .viewport_count(1)
+        .scissor_count(1);
+
+    let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+        .depth_clamp_enable(false)
+        .rasterizer_discard_enable(false)
+        .polygon_mode(vk::PolygonMode::FILL)
+        .line_width(1.0)
+        .cull_mode(vk::CullModeFlags::NONE)
+        .front_face(vk::FrontFace::CLOCKWISE)
+        .depth_bias_enable(false);
+
+    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+        .sample_shading_enable(false)
+        .rasterization_samples(vk::SampleCountFlags::_1);
+
+    // Passes only where the depth is still the cleared far plane
+    let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(true)
+        .depth_write_enable(false)
+        .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL)
+        .depth_bounds_test_enable(false)
+        .stencil_test_enable(false);
+
+    let attachments = written
+        .iter()
+        .map(|&written| {
+            let mask = if written { vk::ColorComponentFlags::all() } else { vk::ColorComponentFlags::empty() };
+            vk::PipelineColorBlendAttachmentState::builder()
+                .color_write_mask(mask)
+                .blend_enable(false)
+                .build()
+        })
+        .collect::<Vec<_>>();
+
+    let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+        .logic_op_enable(false)
+        .logic_op(vk::LogicOp::COPY)
+        .attachments(&attachments)
+        .blend_constants([0.0, 0.0, 0.0, 0.0]);
+
+    let dynamic_states = &[
+        vk::DynamicState::VIEWPORT,
+        vk::DynamicState::SCISSOR,
+    ];
+    let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+        .dynamic_states(dynamic_states);
+
+    let stages = &[vert_stage, frag_stage];
+    let info = vk::GraphicsPipelineCreateInfo::builder()
+        .stages(stages)
+        .vertex_input_state(&vertex_input_state)
+        .input_assembly_state(&input_assembly_state)
+        .viewport_state(&viewport_state)
+        .rasterization_state(&rasterization_state)
+        .multisample_state(&multisample_state)
+        .depth_stencil_state(&depth_stencil_state)
+        .color_blend_state(&color_blend_state)
+        .dynamic_state(&dynamic_state)
+        .layout(layout)
+        .render_pass(render_pass)
+        .subpass(0);
+
+    let pipeline = device
+        .create_graphics_pipelines(vk::PipelineCache::null(), &[info], None)?
+        .0[0];
+
+    device.destroy_shader_module(vert_shader_module, None);
+    device.destroy_shader_module(frag_shader_module, None);
+
+    Ok(pipeline)
+}
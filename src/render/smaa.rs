@@ -0,0 +1,227 @@
+//! The lookup textures of SMAA 1x, generated the way Jimenez et al.'s AreaTex.py and SearchTex.py
+//!  do. Only orthogonal patterns are covered, the pass runs without diagonal and corner detection.
+
+/// Longest orthogonal distance the area texture covers, distances are stored quadratically
+pub const AREA_MAX_DISTANCE: usize = 16;
+
+/// The area texture holds 5x5 blocks, one per rounded pair of crossing edge values
+pub const AREA_SIZE: usize = AREA_MAX_DISTANCE * 5;
+
+/// Size of the search texture once cropped
+pub const SEARCH_SIZE: [usize; 2] = [64, 16];
+
+/// Below this distance U shaped patterns are smoothed, keeping them from looking blocky
+const SMOOTH_MAX_DISTANCE: f32 = 32.0;
+
+/// Where each of the 16 crossing edge patterns sits in the area texture, in blocks. A crossing edge
+///  below the line reads as 0.75 through the bilinear fetch and one above as 0.25.
+const ORTHO_PATTERN_BLOCKS: [(usize, usize); 16] = [
+    (0, 0), (3, 0), (0, 3), (3, 3),
+    (1, 0), (4, 0), (1, 3), (4, 3),
+    (0, 1), (3, 1), (0, 4), (3, 4),
+    (1, 1), (4, 1), (1, 4), (4, 4),
+];
+
+/// Area covered by the line p1 -> p2 over the pixel spanning `x`..`x + 1`, split into the parts
+///  above and below the edge
+fn line_area(p1: (f32, f32), p2: (f32, f32), x: f32) -> (f32, f32) {
+    let d = (p2.0 - p1.0, p2.1 - p1.1);
+    let (x1, x2) = (x, x + 1.0);
+    let y1 = p1.1 + d.1 * (x1 - p1.0) / d.0;
+    let y2 = p1.1 + d.1 * (x2 - p1.0) / d.0;
+
+    let inside = (x1 >= p1.0 && x1 < p2.0) || (x2 > p1.0 && x2 <= p2.0);
+    if !inside {
+        return (0.0, 0.0);
+    }
+
+    let trapezoid = y1.signum() == y2.signum() || y1.abs() < 1e-4 || y2.abs() < 1e-4;
+    if trapezoid {
+        let a = (y1 + y2) / 2.0;
+        return if a < 0.0 { (a.abs(), 0.0) } else { (0.0, a.abs()) };
+    }
+
+    // The line crosses the edge inside the pixel, leaving two triangles
+    let x = -p1.1 * d.0 / d.1 + p1.0;
+    let a1 = if x > p1.0 { y1 * x.fract() / 2.0 } else { 0.0 };
+    let a2 = if x < p2.0 { y2 * (1.0 - x.fract()) / 2.0 } else { 0.0 };
+    let a = if a1.abs() > a2.abs() { a1 } else { -a2 };
+    if a < 0.0 { (a1.abs(), a2.abs()) } else { (a2.abs(), a1.abs()) }
+}
+
+fn smooth_area(d: f32, a1: (f32, f32), a2: (f32, f32)) -> (f32, f32) {
+    let b1 = ((a1.0 * 2.0).sqrt() * 0.5, (a1.1 * 2.0).sqrt() * 0.5);
+    let b2 = ((a2.0 * 2.0).sqrt() * 0.5, (a2.1 * 2.0).sqrt() * 0.5);
+    let p = (d / SMOOTH_MAX_DISTANCE).clamp(0.0, 1.0);
+    let lerp = |a: f32, b: f32| a + (b - a) * p;
+    (lerp(b1.0, a1.0) + lerp(b2.0, a2.0), lerp(b1.1, a1.1) + lerp(b2.1, a2.1))
+}
+
+/// Coverage of the revectorised edge for a pixel `left` pixels from the pattern's left end and
+///  `right` from its right end. Bit 0 and 1 are crossing edges below the left and right ends,
+///  bit 2 and 3 crossing edges above them.
+fn ortho_area(pattern: usize, left: f32, right: f32) -> (f32, f32) {
+    let d = left + right + 1.0;
+    let (above, below) = (0.5, -0.5);
+    let half = (d / 2.0, 0.0);
+
+    match pattern {
+        // Lines without crossing edges, or crossing both ways on one side, aren't filtered
+        0 | 5 | 10 | 15 => (0.0, 0.0),
+        // L shapes only filter the half towards their crossing edge
+        1 if left <= right => line_area((0.0, below), half, left),
+        2 if left >= right => line_area(half, (d, below), left),
+        4 if left <= right => line_area((0.0, above), half, left),
+        8 if left >= right => line_area(half, (d, above), left),
+        1 | 2 | 4 | 8 => (0.0, 0.0),
+        // U shapes
+        3 => smooth_area(d, line_area((0.0, below), half, left), line_area(half, (d, below), left)),
+        12 => smooth_area(d, line_area((0.0, above), half, left), line_area(half, (d, above), left)),
+        // Z shapes, including the ones with an extra crossing edge
+        6 | 7 | 14 => line_area((0.0, above), (d, below), left),
+        9 | 11 | 13 => line_area((0.0, below), (d, above), left),
+        _ => unreachable!(),
+    }
+}
+
+/// RGBA8 pixels of the area texture, the blend weights of the two sides of the edge in red and green
+pub fn area_texture() -> Vec<u8> {
+    let mut pixels = vec![0; AREA_SIZE * AREA_SIZE * 4];
+    for (pattern, block) in ORTHO_PATTERN_BLOCKS.iter().enumerate() {
+        for y in 0..AREA_MAX_DISTANCE {
+            for x in 0..AREA_MAX_DISTANCE {
+                let (left, right) = ((x * x) as f32, (y * y) as f32);
+                let area = ortho_area(pattern, left, right);
+
+                let px = block.0 * AREA_MAX_DISTANCE + x;
+                let py = block.1 * AREA_MAX_DISTANCE + y;
+                let i = (py * AREA_SIZE + px) * 4;
+                pixels[i] = (area.0 * 255.0).round().clamp(0.0, 255.0) as u8;
+                pixels[i + 1] = (area.1 * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+    pixels
+}
+
+/// The value the bilinear fetch of a 2x2 block of edges reads, at the offsets the search uses
+fn bilinear_edges(e: [u8; 4]) -> usize {
+    // Weights of 1, 3, 7 and 21 thirty seconds
+    e[0] as usize + 3 * e[1] as usize + 7 * e[2] as usize + 21 * e[3] as usize
+}
+
+/// The edges behind a fetched value in thirty seconds, if one of the 16 combinations reads it
+fn edges_from_fetch(value: usize) -> Option<[u8; 4]> {
+    (0..16u8)
+        .map(|bits| [bits & 1, (bits >> 1) & 1, (bits >> 2) & 1, (bits >> 3) & 1])
+        .find(|e| bilinear_edges(*e) == value)
+}
+
+/// Pixels to step back after the last search step to the left
+fn delta_left(left: [u8; 4], top: [u8; 4]) -> u8 {
+    let mut d = 0;
+    if top[3] == 1 {
+        d += 1;
+    }
+    if d == 1 && top[2] == 1 && left[1] != 1 && left[3] != 1 {
+        d += 1;
+    }
+    d
+}
+
+/// Pixels to step back after the last search step to the right
+fn delta_right(left: [u8; 4], top: [u8; 4]) -> u8 {
+    let mut d = 0;
+    if top[3] == 1 && left[1] != 1 && left[3] != 1 {
+        d += 1;
+    }
+    if d == 1 && top[2] == 1 && left[0] != 1 && left[2] != 1 {
+        d += 1;
+    }
+    d
+}
+
+/// RGBA8 pixels of the search texture in red, cropped to 64x16 and flipped as the shader expects
+pub fn search_texture() -> Vec<u8> {
+    // The full texture is 66x33, searches to the left in the first half and to the right in the second
+    let full = |x: usize, y: usize| -> u8 {
+        let (fx, right) = if x < 33 { (x, false) } else { (x - 33, true) };
+        match (edges_from_fetch(fx), edges_from_fetch(y)) {
+            (Some(left), Some(top)) if right => 127 * delta_right(left, top),
+            (Some(left), Some(top)) => 127 * delta_left(left, top),
+            _ => 0,
+        }
+    };
+
+    let [width, height] = SEARCH_SIZE;
+    let mut pixels = vec![0; width * height * 4];
+    for y in 0..height {
+        for x in 0..width {
+            // Rows 17..33 of the full texture, upside down
+            pixels[(y * width + x) * 4] = full(x, 32 - y);
+        }
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Red and green of the area texture for `pattern`, `x` and `y` texels into its block
+    fn area_texel(pixels: &[u8], pattern: usize, x: usize, y: usize) -> [u8; 2] {
+        let block = ORTHO_PATTERN_BLOCKS[pattern];
+        let i = ((block.1 * AREA_MAX_DISTANCE + y) * AREA_SIZE + block.0 * AREA_MAX_DISTANCE + x) * 4;
+        [pixels[i], pixels[i + 1]]
+    }
+
+    #[test]
+    fn area_texture_matches_reference_texels() {
+        let pixels = area_texture();
+        assert_eq!(pixels.len(), AREA_SIZE * AREA_SIZE * 4);
+
+        // Lines without crossing edges are never blended
+        assert!((0..AREA_MAX_DISTANCE).all(|x| area_texel(&pixels, 0, x, x) == [0, 0]));
+
+        // A one pixel Z crosses the edge in the pixel's centre, leaving a triangle of 1/8 on each side
+        assert_eq!(area_texel(&pixels, 6, 0, 0), [32, 32]);
+        assert_eq!(area_texel(&pixels, 9, 0, 0), [32, 32]);
+
+        // A Z 10 pixels long falls from 0.5 to 0.4 over its first pixel, averaging 0.45 above the edge
+        assert_eq!(area_texel(&pixels, 6, 0, 3), [0, 115]);
+        assert_eq!(area_texel(&pixels, 9, 0, 3), [115, 0]);
+
+        // An L only blends the half towards its crossing edge
+        assert_eq!(area_texel(&pixels, 1, 2, 1), [0, 0]);
+        assert_ne!(area_texel(&pixels, 1, 1, 2), [0, 0]);
+    }
+
+    #[test]
+    fn search_texture_matches_reference_texels() {
+        let pixels = search_texture();
+        let [width, height] = SEARCH_SIZE;
+        assert_eq!(pixels.len(), width * height * 4);
+        let row = |y: usize| (0..width).map(|x| pixels[(y * width + x) * 4]).collect::<Vec<_>>();
+
+        // The left search under a full row of top edges steps back two pixels unless a crossing edge
+        //  stops it after one, so left edges in e[1] or e[3] halve the step
+        let first = row(0);
+        assert_eq!(first[..33], [
+            0xfe, 0xfe, 0x00, 0x7f, 0x7f, 0x00, 0x00, 0xfe, 0xfe, 0x00, 0x7f, 0x7f, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x7f, 0x7f, 0x00, 0x7f, 0x7f, 0x00, 0x00, 0x7f, 0x7f, 0x00, 0x7f,
+            0x7f,
+        ]);
+        // Searching right with no left edges steps back over both top edges
+        assert_eq!(first[33], 0xfe);
+
+        // Under a single top edge, in row 32 - 21, the left search always steps back one pixel
+        let single = row(11);
+        for (x, &texel) in single[..33].iter().enumerate() {
+            assert_eq!(texel, if edges_from_fetch(x).is_some() { 0x7f } else { 0x00 }, "Texel {} of row 11.", x);
+        }
+
+        // Fetches no combination of edges can produce are never looked up
+        assert!(row(2).iter().all(|&texel| texel == 0));
+        assert!(pixels.chunks(4).all(|texel| texel[1..] == [0, 0, 0]));
+    }
+}
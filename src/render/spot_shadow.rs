@@ -0,0 +1,250 @@
+use std::mem::size_of;
+use cgmath::{vec3, Matrix4, Rad, SquareMatrix};
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::camera::{Camera, Mat4, VULKAN_CLIP};
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::light::Light;
+use super::material::MaterialLibrary;
+use super::memory::{self, AllocatedImage};
+use super::mesh::Mesh;
+use super::scene::{LightKind, MeshInstance};
+use super::shader;
+use super::shadow::{self, ShadowSettings};
+
+/// Size of every spot light's shadow map, and what a spot light asks for unless told otherwise
+pub const SPOT_SHADOW_SIZE: u32 = 1024;
+
+/// Number of spot lights that can cast shadows at once, one layer of the spot shadow array each
+pub const MAX_SPOT_SHADOWS: usize = 4;
+
+/// Widest cone a single perspective map still covers without the texels stretching too far
+const MAX_SPOT_SHADOW_ANGLE: f32 = 80.0 * std::f32::consts::PI / 180.0;
+
+/// Perspective shadow maps of the most important spot lights, sitting next to the point light
+///  cubes in the frame set.
+#[derive(Clone, Debug, Default)]
+pub struct SpotShadowMaps {
+    /// Layer of each engine light, by light index
+    pub slots: Vec<Option<usize>>,
+    view_projections: Vec<Mat4>,
+    depth_bias: (f32, f32),
+    image: AllocatedImage,
+    layer_views: Vec<vk::ImageView>,
+    framebuffers: Vec<vk::Framebuffer>,
+    sampler: vk::Sampler,
+    render_pass: vk::RenderPass,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    masked_pipeline: vk::Pipeline,
+    // Per light shader parameters, refreshed by `update_spot_shadows`
+    shader_slots: Vec<[f32; 4]>,
+}
+
+impl SpotShadowMaps {
+    /// The `shadow` member of a spot light in the light buffer: layer (-1 for none) and the size of a
+    ///  texel one metre from the light
+    pub fn shader_slot(&self, light: usize) -> [f32; 4] {
+        self.shader_slots
+            .get(light)
+            .copied()
+            .unwrap_or([-1.0, 0.0, 0.0, 0.0])
+    }
+
+    /// The view-projection of every layer, identity for unused ones and before the first update
+    pub fn view_projections(&self) -> [Mat4; MAX_SPOT_SHADOWS] {
+        let mut view_projections = [Mat4::identity(); MAX_SPOT_SHADOWS];
+        for (layer, view_projection) in self.view_projections.iter().enumerate() {
+            view_projections[layer] = *view_projection;
+        }
+        view_projections
+    }
+
+    /// Renders every instance into the map of every shadowed spot light
+    pub unsafe fn cmd_render(&self, device: &Device, command_buffer: vk::CommandBuffer, meshes: &[Mesh],
+        instances: &[MeshInstance], materials: &MaterialLibrary)
+    {
+        let extent = vk::Extent2D { width: SPOT_SHADOW_SIZE, height: SPOT_SHADOW_SIZE };
+        for layer in self.slots.iter().flatten() {
+            let view_projection = self.view_projections[*layer];
+            shadow::cmd_begin_depth_pass(device, command_buffer, self.render_pass, self.framebuffers[*layer], extent);
+            shader::cmd_set_full_viewport(device, command_buffer, extent);
+            device.cmd_set_depth_bias(command_buffer, self.depth_bias.0, 0.0, self.depth_bias.1);
+
+            shadow::cmd_draw_casters(device, command_buffer, (self.pipeline, self.masked_pipeline),
+                self.pipeline_layout, 0, meshes, instances, materials, |instance| {
+                    let light_mvp = view_projection * instance.transform;
+                    shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+                        vk::ShaderStageFlags::VERTEX, &light_mvp);
+                });
+            device.cmd_end_render_pass(command_buffer);
+        }
+    }
+}
+
+/// Hands out the layers to shadow casting spot lights, most important first
+fn assign_slots(lights: &[Light], camera: &Camera) -> Vec<Option<usize>> {
+    let mut candidates = lights
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| matches!(l.kind, LightKind::Spot { .. }) && l.shadow_resolution > 0)
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| {
+        shadow::shadow_importance(b.1, camera).total_cmp(&shadow::shadow_importance(a.1, camera))
+    });
+
+    let mut slots = vec![None; lights.len()];
+    for (layer, (index, _)) in candidates.into_iter().take(MAX_SPOT_SHADOWS).enumerate() {
+        slots[index] = Some(layer);
+    }
+    slots
+}
+
+/// Half the angle the map of a spot light covers, its outer cone
+fn half_angle(light: &Light) -> f32 {
+    match light.kind {
+        LightKind::Spot { outer_cone_angle, .. } => outer_cone_angle.clamp(0.01, MAX_SPOT_SHADOW_ANGLE),
+        _ => MAX_SPOT_SHADOW_ANGLE,
+    }
+}
+
+/// A perspective projection looking down the light's cone, out to its range
+fn spot_view_projection(light: &Light, near: f32, far: f32) -> Mat4 {
+    let up = if light.direction.y.abs() > 0.99 { vec3(0.0, 0.0, 1.0) } else { vec3(0.0, 1.0, 0.0) };
+    let projection = VULKAN_CLIP * cgmath::perspective(Rad(2.0 * half_angle(light)), 1.0, near, far);
+    projection * Matrix4::look_to_rh(light.position, light.direction, up)
+}
+
+pub unsafe fn create_spot_shadows(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let format = shadow::get_shadow_format(instance, data)?;
+    let info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::_2D)
+        .extent(vk::Extent3D { width: SPOT_SHADOW_SIZE, height: SPOT_SHADOW_SIZE, depth: 1 })
+        .mip_levels(1)
+        .array_layers(MAX_SPOT_SHADOWS as u32)
+        .format(format)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(vk::SampleCountFlags::_1);
+
+    let image = AllocatedImage::from_info(&info, vk::ImageViewType::_2D_ARRAY, vk::ImageAspectFlags::DEPTH,
+        instance, device, data)?;
+
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    memory::cmd_image_barrier(device, command_buffer, image.image,
+        shadow::depth_subresource_range(0, MAX_SPOT_SHADOWS as u32),
+        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL),
+        (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+        (vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ));
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    let render_pass = shadow::create_depth_render_pass(device, format, 0)?;
+    let layer_views = (0..MAX_SPOT_SHADOWS as u32)
+        .map(|layer| shadow::create_layer_view(device, &image, vk::ImageViewType::_2D, layer, 1))
+        .collect::<Result<Vec<_>>>()?;
+    let framebuffers = layer_views
+        .iter()
+        .map(|view| shadow::create_depth_framebuffer(device, render_pass, *view, SPOT_SHADOW_SIZE))
+        .collect::<Result<Vec<_>>>()?;
+
+    // The same depth-only pass as the cascades, with the light's perspective premultiplied
+    let pipeline_layout = descriptor::create_pipeline_layout(device, &[data.materials.set_layout],
+        size_of::<Mat4>() as u32, vk::ShaderStageFlags::VERTEX)?;
+    let vert = include_bytes!(concat!(env!("OUT_DIR"), "/shader/shadow_vert.spv"));
+    let mask_frag = include_bytes!(concat!(env!("OUT_DIR"), "/shader/shadow_mask_frag.spv"));
+    let pipeline = shadow::create_depth_pipeline(device, &vert[..], None, pipeline_layout, render_pass)?;
+    let masked_pipeline = shadow::create_depth_pipeline(device, &vert[..], Some(&mask_frag[..]), pipeline_layout,
+        render_pass)?;
+
+    let sampler = shadow::create_shadow_sampler(format, instance, device, data)?;
+    descriptor::write_image(device, data.frame.set, 13, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        image.image_view, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL, sampler);
+
+    data.spot_shadows = SpotShadowMaps {
+        image,
+        layer_views,
+        framebuffers,
+        sampler,
+        render_pass,
+        pipeline_layout,
+        pipeline,
+        masked_pipeline,
+        ..Default::default()
+    };
+    Ok(())
+}
+
+pub unsafe fn destroy_spot_shadows(device: &Device, data: &mut EngineData)
+{
+    let shadows = &mut data.spot_shadows;
+    shadows.framebuffers
+        .iter()
+        .for_each(|f| device.destroy_framebuffer(*f, None));
+    shadows.layer_views
+        .iter()
+        .for_each(|v| device.destroy_image_view(*v, None));
+    shadows.image.destroy(device);
+    device.destroy_sampler(shadows.sampler, None);
+    device.destroy_pipeline(shadows.pipeline, None);
+    device.destroy_pipeline(shadows.masked_pipeline, None);
+    device.destroy_pipeline_layout(shadows.pipeline_layout, None);
+    device.destroy_render_pass(shadows.render_pass, None);
+}
+
+/// Reassigns the layers to the currently most important spot lights and fits their projections.
+///  Must run before the lights and the shadow uniforms are uploaded, which read them.
+pub fn update_spot_shadows(data: &mut EngineData)
+{
+    let settings: ShadowSettings = data.shadows.settings;
+    let slots = assign_slots(&data.lights, &data.camera);
+
+    let mut view_projections = vec![Mat4::identity(); MAX_SPOT_SHADOWS];
+    let mut shader_slots = vec![[-1.0, 0.0, 0.0, 0.0]; data.lights.len()];
+    for (index, layer) in slots.iter().enumerate() {
+        if let Some(layer) = layer {
+            let light = &data.lights[index];
+            let far = light.range.unwrap_or(settings.point_far).max(settings.point_near * 2.0);
+            view_projections[*layer] = spot_view_projection(light, settings.point_near, far);
+
+            // A texel spans 2 tan(angle) / size metres one metre down the cone
+            let texel_size = 2.0 * half_angle(light).tan() / SPOT_SHADOW_SIZE as f32;
+            shader_slots[index] = [*layer as f32, 0.0, 0.0, texel_size];
+        }
+    }
+
+    let shadows = &mut data.spot_shadows;
+    shadows.slots = slots;
+    shadows.view_projections = view_projections;
+    shadows.depth_bias = (settings.depth_bias_constant, settings.depth_bias_slope);
+    shadows.shader_slots = shader_slots;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{point3, Deg};
+
+    fn spot_light(intensity: f32) -> Light {
+        Light::spot(point3(0.0, 3.0, 0.0), vec3(0.0, -1.0, 0.0), vec3(1.0, 1.0, 1.0), intensity, None,
+            Deg(20.0), Deg(30.0))
+    }
+
+    #[test]
+    fn the_most_important_spot_lights_get_the_layers() {
+        let mut lights = [40.0, 100.0, 20.0, 80.0, 60.0, 10.0].map(spot_light).to_vec();
+        lights.insert(2, Light::point(point3(0.0, 1.0, 0.0), vec3(1.0, 1.0, 1.0), 1000.0, None));
+        lights.push(Light { shadow_resolution: 0, ..spot_light(1000.0) });
+        let slots = assign_slots(&lights, &Camera::default());
+
+        let layer = |intensity: f32| slots[lights.iter().position(|l| l.intensity == intensity).unwrap()];
+        assert_eq!([layer(100.0), layer(80.0), layer(60.0), layer(40.0)], [Some(0), Some(1), Some(2), Some(3)]);
+        // Past the layers, and for lights that aren't shadowed spots, there is no map
+        assert_eq!([layer(20.0), layer(10.0), layer(1000.0)], [None, None, None]);
+        assert_eq!(slots[lights.len() - 1], None);
+    }
+}
@@ -0,0 +1,293 @@
+use std::mem::size_of;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::camera::Mat4;
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::{self, AllocatedImage};
+use super::mesh::Mesh;
+use super::post::{self, HDR_FORMAT};
+use super::scene::MeshInstance;
+use super::shader;
+use super::shadow::{self, cmd_begin_depth_pass, create_depth_pipeline, create_depth_render_pass, get_shadow_format};
+
+/// Radius, strength and sample count of the screen space ambient occlusion.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SsaoSettings {
+    pub enabled: bool,
+    /// Reach of the occlusion in meters
+    pub radius: f32,
+    /// Exponent on the visibility, above 1 darkens creases further
+    pub intensity: f32,
+    /// Hemisphere samples per pixel
+    pub sample_count: u32,
+    /// How strongly the blur stops at depth discontinuities
+    pub blur_sharpness: f32,
+    /// Shows the unblurred occlusion instead of the lit scene
+    pub debug_view: bool,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            radius: 0.5,
+            intensity: 1.5,
+            sample_count: 16,
+            blur_sharpness: 8.0,
+            debug_view: false,
+        }
+    }
+}
+
+/// Mirrors `Ssao` in `ssao.glsl`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct SsaoPushConstants {
+    radius: f32,
+    intensity: f32,
+    sample_count: u32,
+    sharpness: f32,
+}
+
+/// Hemisphere ambient occlusion from a depth prepass, with normals reconstructed from the depth and
+///  a bilateral blur over the kernel's rotation pattern. The scene pass multiplies its ambient term
+///  with the result, through the frame set.
+#[derive(Clone, Debug, Default)]
+pub struct AmbientOcclusion {
+    pub settings: SsaoSettings,
+    depth: AllocatedImage,
+    /// Unblurred visibility and view depth
+    raw: AllocatedImage,
+    /// Blurred visibility the scene samples, white while disabled
+    occlusion: AllocatedImage,
+    render_pass: vk::RenderPass,
+    framebuffer: vk::Framebuffer,
+    depth_layout: vk::PipelineLayout,
+    depth_pipeline: vk::Pipeline,
+    sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+    set: vk::DescriptorSet,
+    pipeline_layout: vk::PipelineLayout,
+    ssao_pipeline: vk::Pipeline,
+    blur_pipeline: vk::Pipeline,
+    debug_pipeline: vk::Pipeline,
+}
+
+impl AmbientOcclusion {
+    /// Draws the depth prepass and computes this frame's occlusion, or clears it to white when disabled.
+    ///  Has to run outside the scene's render pass.
+    pub unsafe fn cmd_render(&self, device: &Device, command_buffer: vk::CommandBuffer, frame_set: vk::DescriptorSet,
+        meshes: &[Mesh], instances: &[MeshInstance])
+    {
+        let range = memory::color_subresource_range();
+
+        // Last frame's scene pass may still be sampling the occlusion
+        memory::cmd_image_barrier(device, command_buffer, self.occlusion.image, range,
+            (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ),
+            (vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::SHADER_WRITE | vk::AccessFlags::TRANSFER_WRITE));
+
+        if !self.settings.enabled {
+            let white = vk::ClearColorValue { float32: [1.0; 4] };
+            device.cmd_clear_color_image(command_buffer, self.occlusion.image, vk::ImageLayout::GENERAL,
+                &white, &[range]);
+            memory::cmd_image_barrier(device, command_buffer, self.occlusion.image, range,
+                (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+                (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+                (vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ));
+            return;
+        }
+
+        // Depth prepass with the scene pass' camera
+        let extent = self.depth.extent_2d();
+        cmd_begin_depth_pass(device, command_buffer, self.render_pass, self.framebuffer, extent);
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.depth_pipeline);
+        shader::cmd_set_full_viewport(device, command_buffer, extent);
+        device.cmd_set_depth_bias(command_buffer, 0.0, 0.0, 0.0);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.depth_layout,
+            0, &[frame_set], &[]);
+        for instance in instances {
+            shader::cmd_push_constants(device, command_buffer, self.depth_layout,
+                vk::ShaderStageFlags::VERTEX, &instance.transform);
+            meshes[instance.mesh].cmd_draw(device, command_buffer);
+        }
+        device.cmd_end_render_pass(command_buffer);
+
+        // The pass only makes its depth visible to fragment shaders
+        memory::cmd_image_barrier(device, command_buffer, self.depth.image, shadow::depth_subresource_range(0, 1),
+            (vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL),
+            (vk::PipelineStageFlags::LATE_FRAGMENT_TESTS, vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ));
+
+        let push_constants = SsaoPushConstants {
+            radius: self.settings.radius.max(1e-3),
+            intensity: self.settings.intensity.max(0.0),
+            sample_count: self.settings.sample_count.max(1),
+            sharpness: self.settings.blur_sharpness.max(0.0),
+        };
+
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 0, &[frame_set, self.set], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, &push_constants);
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.ssao_pipeline);
+        post::cmd_dispatch_pixels(device, command_buffer, extent);
+        memory::cmd_image_barrier(device, command_buffer, self.raw.image, range,
+            (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ));
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.blur_pipeline);
+        post::cmd_dispatch_pixels(device, command_buffer, extent);
+        memory::cmd_image_barrier(device, command_buffer, self.occlusion.image, range,
+            (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ));
+    }
+
+    /// Replaces the HDR target with the unblurred occlusion through the scratch image, when the debug view is on
+    pub unsafe fn cmd_debug(&self, device: &Device, command_buffer: vk::CommandBuffer, frame_set: vk::DescriptorSet,
+        scratch: &AllocatedImage, hdr: &AllocatedImage)
+    {
+        if !self.settings.enabled || !self.settings.debug_view {
+            return;
+        }
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.debug_pipeline);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 0, &[frame_set, self.set], &[]);
+        post::cmd_dispatch_pixels(device, command_buffer, hdr.extent_2d());
+        post::cmd_write_back(device, command_buffer, scratch, hdr);
+    }
+}
+
+pub unsafe fn create_ambient_occlusion(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let format = get_shadow_format(instance, data)?;
+    let ao = &mut data.ambient_occlusion;
+    ao.settings = SsaoSettings::default();
+    ao.render_pass = create_depth_render_pass(device, format, 0)?;
+    ao.depth_layout = descriptor::create_pipeline_layout(device, &[data.frame.set_layout],
+        size_of::<Mat4>() as u32, vk::ShaderStageFlags::VERTEX)?;
+    let vert = include_bytes!(concat!(env!("OUT_DIR"), "/shader/ssao_depth_vert.spv"));
+    ao.depth_pipeline = create_depth_pipeline(device, &vert[..], None, ao.depth_layout, ao.render_pass)?;
+
+    ao.sampler = descriptor::create_sampler(device, vk::Filter::NEAREST, 0.0)?;
+    ao.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(2, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(3, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    ao.descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1),
+        (vk::DescriptorType::STORAGE_IMAGE, 3),
+    ], 1)?;
+    ao.set = descriptor::allocate_set(device, ao.descriptor_pool, ao.set_layout)?;
+
+    ao.pipeline_layout = descriptor::create_pipeline_layout(device, &[data.frame.set_layout, ao.set_layout],
+        size_of::<SsaoPushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+    let ssao = include_bytes!(concat!(env!("OUT_DIR"), "/shader/ssao_comp.spv"));
+    ao.ssao_pipeline = shader::create_compute_pipeline(device, &ssao[..], ao.pipeline_layout)?;
+    let blur = include_bytes!(concat!(env!("OUT_DIR"), "/shader/ssao_blur_comp.spv"));
+    ao.blur_pipeline = shader::create_compute_pipeline(device, &blur[..], ao.pipeline_layout)?;
+    let debug = include_bytes!(concat!(env!("OUT_DIR"), "/shader/ssao_debug_comp.spv"));
+    ao.debug_pipeline = shader::create_compute_pipeline(device, &debug[..], ao.pipeline_layout)?;
+
+    create_ambient_occlusion_targets(instance, device, data)
+}
+
+/// Creates the prepass depth and the occlusion images for the current swapchain size, and points
+///  the descriptors and the frame set at them
+pub unsafe fn create_ambient_occlusion_targets(instance: &Instance, device: &Device, data: &mut EngineData)
+    -> Result<()>
+{
+    let extent = data.swapchain_extent;
+    let depth = AllocatedImage::create(
+        extent,
+        get_shadow_format(instance, data)?,
+        vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+        vk::ImageAspectFlags::DEPTH,
+        instance, device, data)?;
+
+    // Two channel float formats aren't guaranteed as storage images, the HDR one is
+    let raw = AllocatedImage::create(
+        extent,
+        HDR_FORMAT,
+        vk::ImageUsageFlags::STORAGE,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+    let occlusion = AllocatedImage::create(
+        extent,
+        HDR_FORMAT,
+        vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+
+    // The occlusion images stay in the general layout
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    for image in [&raw, &occlusion] {
+        memory::cmd_image_barrier(device, command_buffer, image.image, memory::color_subresource_range(),
+            (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+    }
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    let attachments = &[depth.image_view];
+    let info = vk::FramebufferCreateInfo::builder()
+        .render_pass(data.ambient_occlusion.render_pass)
+        .attachments(attachments)
+        .width(extent.width)
+        .height(extent.height)
+        .layers(1);
+    let framebuffer = device.create_framebuffer(&info, None)?;
+
+    let ao = &data.ambient_occlusion;
+    descriptor::write_image(device, ao.set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        depth.image_view, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL, ao.sampler);
+    descriptor::write_image(device, ao.set, 1, vk::DescriptorType::STORAGE_IMAGE,
+        raw.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+    descriptor::write_image(device, ao.set, 2, vk::DescriptorType::STORAGE_IMAGE,
+        occlusion.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+    descriptor::write_image(device, ao.set, 3, vk::DescriptorType::STORAGE_IMAGE,
+        data.post_image.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+    descriptor::write_image(device, data.frame.set, 8, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        occlusion.image_view, vk::ImageLayout::GENERAL, ao.sampler);
+
+    let ao = &mut data.ambient_occlusion;
+    ao.depth = depth;
+    ao.raw = raw;
+    ao.occlusion = occlusion;
+    ao.framebuffer = framebuffer;
+    Ok(())
+}
+
+pub unsafe fn destroy_ambient_occlusion_targets(device: &Device, data: &mut EngineData)
+{
+    let ao = &mut data.ambient_occlusion;
+    device.destroy_framebuffer(ao.framebuffer, None);
+    ao.depth.destroy(device);
+    ao.raw.destroy(device);
+    ao.occlusion.destroy(device);
+}
+
+pub unsafe fn destroy_ambient_occlusion(device: &Device, data: &mut EngineData)
+{
+    let ao = &mut data.ambient_occlusion;
+    device.destroy_pipeline(ao.ssao_pipeline, None);
+    device.destroy_pipeline(ao.blur_pipeline, None);
+    device.destroy_pipeline(ao.debug_pipeline, None);
+    device.destroy_pipeline_layout(ao.pipeline_layout, None);
+    device.destroy_descriptor_pool(ao.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(ao.set_layout, None);
+    device.destroy_sampler(ao.sampler, None);
+    device.destroy_pipeline(ao.depth_pipeline, None);
+    device.destroy_pipeline_layout(ao.depth_layout, None);
+    device.destroy_render_pass(ao.render_pass, None);
+}
@@ -0,0 +1,339 @@
+use std::mem::size_of;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::camera::Camera;
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::{self, AllocatedImage};
+use super::post::{self, HDR_FORMAT};
+use super::shader;
+
+/// Format of the closest depth pyramid, single channel 32 bit floats are always storage formats
+const HIZ_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+
+/// Reach and quality of the screen space reflections.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SsrSettings {
+    pub enabled: bool,
+    /// Longest reflection ray in meters
+    pub max_distance: f32,
+    /// Depth in meters surfaces are assumed to have, rays passing further behind them miss
+    pub thickness: f32,
+    /// Rougher surfaces only reflect the environment, reflections fade out approaching it
+    pub max_roughness: f32,
+    /// Steps through the depth pyramid before a ray gives up
+    pub max_iterations: u32,
+}
+
+impl Default for SsrSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_distance: 20.0,
+            thickness: 0.3,
+            max_roughness: 0.6,
+            max_iterations: 64,
+        }
+    }
+}
+
+/// Mirrors `Ssr` in `ssr.glsl`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct SsrPushConstants {
+    source_lod: f32,
+    max_distance: f32,
+    thickness: f32,
+    max_roughness: f32,
+    max_iterations: u32,
+    level_count: u32,
+    near: f32,
+    far: f32,
+}
+
+/// Hierarchical-Z screen space reflections. A pyramid of the closest depth lets rays skip empty
+///  space, and rough surfaces read a blurrier level of a colour pyramid, as wide as their lobe at
+///  the hit. Rays leaving the screen fall back to the environment. Every pass binds the frame set
+///  and its own set, like the bloom chain.
+#[derive(Clone, Debug, Default)]
+pub struct ScreenSpaceReflections {
+    pub settings: SsrSettings,
+    hiz: AllocatedImage,
+    hiz_views: Vec<vk::ImageView>,
+    color: AllocatedImage,
+    color_views: Vec<vk::ImageView>,
+    hiz_sets: Vec<vk::DescriptorSet>,
+    color_sets: Vec<vk::DescriptorSet>,
+    trace_set: vk::DescriptorSet,
+    sampler: vk::Sampler,
+    point_sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    hiz_pipeline: vk::Pipeline,
+    downsample_pipeline: vk::Pipeline,
+    trace_pipeline: vk::Pipeline,
+}
+
+impl ScreenSpaceReflections {
+    fn mip_extent(&self, level: usize) -> vk::Extent2D {
+        let extent = self.hiz.extent_2d();
+        vk::Extent2D {
+            width: (extent.width >> level).max(1),
+            height: (extent.height >> level).max(1),
+        }
+    }
+
+    /// Fills every level of a pyramid from the one before it, the first from the scene
+    unsafe fn cmd_pyramid(&self, device: &Device, command_buffer: vk::CommandBuffer, pipeline: vk::Pipeline,
+        image: &AllocatedImage, sets: &[vk::DescriptorSet], push_constants: &mut SsrPushConstants)
+    {
+        let range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(sets.len() as u32)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build();
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, pipeline);
+        for (level, set) in sets.iter().enumerate() {
+            push_constants.source_lod = level.saturating_sub(1) as f32;
+            device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout, 1, &[*set], &[]);
+            shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE, push_constants);
+            post::cmd_dispatch_pixels(device, command_buffer, self.mip_extent(level));
+
+            // Each level is read by the next pass and the trace
+            memory::cmd_image_barrier(device, command_buffer, image.image, range,
+                (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+                (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE));
+        }
+    }
+
+    /// Adds the reflections to the HDR target through the scratch image
+    pub unsafe fn cmd_render(&self, device: &Device, command_buffer: vk::CommandBuffer, camera: &Camera,
+        frame_set: vk::DescriptorSet, scratch: &AllocatedImage, hdr: &AllocatedImage)
+    {
+        if !self.settings.enabled || self.hiz_views.is_empty() {
+            return;
+        }
+
+        let mut push_constants = SsrPushConstants {
+            source_lod: 0.0,
+            max_distance: self.settings.max_distance.max(0.0),
+            thickness: self.settings.thickness.max(0.0),
+            max_roughness: self.settings.max_roughness.clamp(0.0, 1.0),
+            max_iterations: self.settings.max_iterations,
+            level_count: self.hiz_views.len() as u32,
+            near: camera.near,
+            far: camera.far,
+        };
+
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 0, &[frame_set], &[]);
+        self.cmd_pyramid(device, command_buffer, self.hiz_pipeline, &self.hiz, &self.hiz_sets, &mut push_constants);
+        self.cmd_pyramid(device, command_buffer, self.downsample_pipeline, &self.color, &self.color_sets,
+            &mut push_constants);
+
+        push_constants.source_lod = 0.0;
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.trace_pipeline);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 1, &[self.trace_set], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, &push_constants);
+        post::cmd_dispatch_pixels(device, command_buffer, hdr.extent_2d());
+        post::cmd_write_back(device, command_buffer, scratch, hdr);
+    }
+}
+
+pub unsafe fn create_screen_space_reflections(instance: &Instance, device: &Device, data: &mut EngineData)
+    -> Result<()>
+{
+    let ssr = &mut data.reflections;
+    ssr.settings = SsrSettings::default();
+    ssr.sampler = descriptor::create_sampler(device, vk::Filter::LINEAR, vk::LOD_CLAMP_NONE)?;
+    ssr.point_sampler = descriptor::create_sampler(device, vk::Filter::NEAREST, vk::LOD_CLAMP_NONE)?;
+    ssr.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(3, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(4, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(5, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+
+    // The trace reads the camera from the frame set
+    ssr.pipeline_layout = descriptor::create_pipeline_layout(device, &[data.frame.set_layout, ssr.set_layout],
+        size_of::<SsrPushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+    let hiz = include_bytes!(concat!(env!("OUT_DIR"), "/shader/hiz_reduce_comp.spv"));
+    ssr.hiz_pipeline = shader::create_compute_pipeline(device, &hiz[..], ssr.pipeline_layout)?;
+    let downsample = include_bytes!(concat!(env!("OUT_DIR"), "/shader/ssr_downsample_comp.spv"));
+    ssr.downsample_pipeline = shader::create_compute_pipeline(device, &downsample[..], ssr.pipeline_layout)?;
+    let trace = include_bytes!(concat!(env!("OUT_DIR"), "/shader/ssr_comp.spv"));
+    ssr.trace_pipeline = shader::create_compute_pipeline(device, &trace[..], ssr.pipeline_layout)?;
+
+    create_screen_space_reflections_targets(instance, device, data)
+}
+
+/// A full resolution pyramid down to a single texel, with a view of every level
+unsafe fn create_pyramid(format: vk::Format, levels: u32, instance: &Instance, device: &Device, data: &EngineData)
+    -> Result<(AllocatedImage, Vec<vk::ImageView>)>
+{
+    let extent = data.swapchain_extent;
+    let info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::_2D)
+        .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+        .mip_levels(levels)
+        .array_layers(1)
+        .format(format)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(vk::SampleCountFlags::_1);
+
+    let image = AllocatedImage::from_info(&info, vk::ImageViewType::_2D, vk::ImageAspectFlags::COLOR,
+        instance, device, data)?;
+
+    let views = (0..levels)
+        .map(|level| {
+            let range = vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .base_mip_level(level)
+                .level_count(1)
+                .base_array_layer(0)
+                .layer_count(1);
+            let view_info = vk::ImageViewCreateInfo::builder()
+                .image(image.image)
+                .view_type(vk::ImageViewType::_2D)
+                .format(format)
+                .subresource_range(range);
+            device.create_image_view(&view_info, None)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // The pyramid stays in the general layout, sampled and stored alike
+    let range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(levels)
+        .base_array_layer(0)
+        .layer_count(1)
+        .build();
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    memory::cmd_image_barrier(device, command_buffer, image.image, range,
+        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+        (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+        (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    Ok((image, views))
+}
+
+/// Creates the depth and colour pyramids for the current swapchain size and the sets of every pass
+pub unsafe fn create_screen_space_reflections_targets(instance: &Instance, device: &Device, data: &mut EngineData)
+    -> Result<()>
+{
+    let extent = data.swapchain_extent;
+    let levels = extent.width.max(extent.height).max(1).ilog2() + 1;
+    let (hiz, hiz_views) = create_pyramid(HIZ_FORMAT, levels, instance, device, data)?;
+    let (color, color_views) = create_pyramid(HDR_FORMAT, levels, instance, device, data)?;
+
+    // One set per level of both pyramids and the trace
+    let set_count = levels * 2 + 1;
+    let descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, levels * 2 + 5),
+        (vk::DescriptorType::STORAGE_IMAGE, set_count),
+    ], set_count)?;
+
+    let ssr = &data.reflections;
+    let write_set = |source: (vk::ImageView, vk::ImageLayout, vk::Sampler), destination: vk::ImageView|
+        -> Result<vk::DescriptorSet>
+    {
+        let set = descriptor::allocate_set(device, descriptor_pool, ssr.set_layout)?;
+        descriptor::write_image(device, set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            source.0, source.1, source.2);
+        descriptor::write_image(device, set, 1, vk::DescriptorType::STORAGE_IMAGE,
+            destination, vk::ImageLayout::GENERAL, vk::Sampler::null());
+        Ok(set)
+    };
+
+    // The first levels copy the depth buffer and the HDR target, the rest reduce the level before
+    let hiz_sets = hiz_views
+        .iter()
+        .enumerate()
+        .map(|(level, view)| {
+            let source = if level == 0 {
+                (data.depth_image.image_view, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL, ssr.point_sampler)
+            } else {
+                (hiz.image_view, vk::ImageLayout::GENERAL, ssr.point_sampler)
+            };
+            write_set(source, *view)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let color_sets = color_views
+        .iter()
+        .enumerate()
+        .map(|(level, view)| {
+            let source = if level == 0 {
+                (data.hdr_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, ssr.point_sampler)
+            } else {
+                (color.image_view, vk::ImageLayout::GENERAL, ssr.point_sampler)
+            };
+            write_set(source, *view)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let trace_set = write_set((color.image_view, vk::ImageLayout::GENERAL, ssr.sampler), data.post_image.image_view)?;
+    descriptor::write_image(device, trace_set, 2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        data.hdr_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, ssr.point_sampler);
+    descriptor::write_image(device, trace_set, 3, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        data.normal_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, ssr.point_sampler);
+    descriptor::write_image(device, trace_set, 4, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        data.specular_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, ssr.point_sampler);
+    descriptor::write_image(device, trace_set, 5, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        hiz.image_view, vk::ImageLayout::GENERAL, ssr.point_sampler);
+
+    let ssr = &mut data.reflections;
+    ssr.hiz = hiz;
+    ssr.hiz_views = hiz_views;
+    ssr.color = color;
+    ssr.color_views = color_views;
+    ssr.descriptor_pool = descriptor_pool;
+    ssr.hiz_sets = hiz_sets;
+    ssr.color_sets = color_sets;
+    ssr.trace_set = trace_set;
+    Ok(())
+}
+
+pub unsafe fn destroy_screen_space_reflections_targets(device: &Device, data: &mut EngineData)
+{
+    let ssr = &mut data.reflections;
+    device.destroy_descriptor_pool(ssr.descriptor_pool, None);
+    ssr.hiz_views
+        .iter()
+        .chain(&ssr.color_views)
+        .for_each(|v| device.destroy_image_view(*v, None));
+    ssr.hiz.destroy(device);
+    ssr.color.destroy(device);
+    ssr.hiz_views.clear();
+    ssr.color_views.clear();
+    ssr.hiz_sets.clear();
+    ssr.color_sets.clear();
+}
+
+pub unsafe fn destroy_screen_space_reflections(device: &Device, data: &mut EngineData)
+{
+    let ssr = &mut data.reflections;
+    device.destroy_pipeline(ssr.hiz_pipeline, None);
+    device.destroy_pipeline(ssr.downsample_pipeline, None);
+    device.destroy_pipeline(ssr.trace_pipeline, None);
+    device.destroy_pipeline_layout(ssr.pipeline_layout, None);
+    device.destroy_descriptor_set_layout(ssr.set_layout, None);
+    device.destroy_sampler(ssr.sampler, None);
+    device.destroy_sampler(ssr.point_sampler, None);
+}
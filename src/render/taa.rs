@@ -0,0 +1,255 @@
+use std::mem::size_of;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::camera::{Camera, Mat4};
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::memory::{self, AllocatedImage};
+use super::post::{self, HDR_FORMAT};
+use super::shader;
+
+/// Longest jitter sequence, more positions than this stop improving the edges
+const MAX_JITTER_SAMPLES: u32 = 64;
+
+/// Controls of the temporal anti-aliasing. It only applies to the rasterised scene, the path
+///  tracer antialiases as it accumulates.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TemporalAaSettings {
+    pub enabled: bool,
+    /// Number of Halton (2, 3) positions the projection cycles through
+    pub jitter_samples: u32,
+    /// Weight of the history in every resolved frame, higher is smoother and more prone to ghosting
+    pub history_weight: f32,
+}
+
+impl Default for TemporalAaSettings {
+    fn default() -> Self {
+        Self { enabled: true, jitter_samples: 8, history_weight: 0.9 }
+    }
+}
+
+/// Mirrors `Taa` in `taa.comp`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct TaaPushConstants {
+    history_weight: f32,
+    near: f32,
+    far: f32,
+    history_valid: u32,
+}
+
+/// Resolves the jittered scene against a reprojected history, which it keeps in two images
+///  taking turns being read and written.
+#[derive(Clone, Debug, Default)]
+pub struct TemporalAa {
+    pub settings: TemporalAaSettings,
+    /// Subpixel offset of this frame's projection in NDC, zero while disabled
+    pub jitter: [f32; 2],
+    /// The offset the last frame was rendered with
+    pub previous_jitter: [f32; 2],
+    jitter_index: u32,
+    history: [AllocatedImage; 2],
+    history_valid: bool,
+    current: usize,
+    sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+    sets: [vk::DescriptorSet; 2],
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+}
+
+impl TemporalAa {
+    /// Offsets a projection by this frame's jitter
+    pub fn jittered(&self, projection: Mat4) -> Mat4 {
+        Mat4::from_translation(cgmath::vec3(self.jitter[0], self.jitter[1], 0.0)) * projection
+    }
+
+    /// Throws the history away, for camera cuts
+    pub fn reset(&mut self) {
+        self.history_valid = false;
+    }
+
+    /// Resolves the HDR target against the history through the scratch image
+    pub unsafe fn cmd_render(&mut self, device: &Device, command_buffer: vk::CommandBuffer, camera: &Camera,
+        scratch: &AllocatedImage, hdr: &AllocatedImage)
+    {
+        if !self.settings.enabled {
+            self.history_valid = false;
+            return;
+        }
+
+        let push_constants = TaaPushConstants {
+            history_weight: self.settings.history_weight.clamp(0.0, 0.98),
+            near: camera.near,
+            far: camera.far,
+            history_valid: self.history_valid as u32,
+        };
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE,
+            self.pipeline_layout, 0, &[self.sets[self.current]], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE, &push_constants);
+        post::cmd_dispatch_pixels(device, command_buffer, hdr.extent_2d());
+
+        // Next frame reads what was just written
+        let written = &self.history[1 - self.current];
+        memory::cmd_image_barrier(device, command_buffer, written.image, memory::color_subresource_range(),
+            (vk::ImageLayout::GENERAL, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE));
+        post::cmd_write_back(device, command_buffer, scratch, hdr);
+
+        self.current = 1 - self.current;
+        self.history_valid = true;
+    }
+}
+
+/// Element `index` of the Halton low discrepancy sequence in `base`, in 0..1
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut result = 0.0;
+    let mut fraction = 1.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+pub unsafe fn create_temporal_aa(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let taa = &mut data.temporal_aa;
+    taa.settings = TemporalAaSettings::default();
+    taa.sampler = descriptor::create_sampler(device, vk::Filter::LINEAR, 0.0)?;
+    taa.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(3, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(4, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+        descriptor::layout_binding(5, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+    ])?;
+    taa.descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 8),
+        (vk::DescriptorType::STORAGE_IMAGE, 4),
+    ], 2)?;
+    taa.sets = [
+        descriptor::allocate_set(device, taa.descriptor_pool, taa.set_layout)?,
+        descriptor::allocate_set(device, taa.descriptor_pool, taa.set_layout)?,
+    ];
+
+    taa.pipeline_layout = descriptor::create_pipeline_layout(device, &[taa.set_layout],
+        size_of::<TaaPushConstants>() as u32, vk::ShaderStageFlags::COMPUTE)?;
+    let comp = include_bytes!(concat!(env!("OUT_DIR"), "/shader/taa_comp.spv"));
+    taa.pipeline = shader::create_compute_pipeline(device, &comp[..], taa.pipeline_layout)?;
+
+    create_temporal_aa_targets(instance, device, data)
+}
+
+/// Creates the history images for the current swapchain size, the history starts over
+pub unsafe fn create_temporal_aa_targets(instance: &Instance, device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let create_history = || AllocatedImage::create(
+        data.swapchain_extent,
+        HDR_FORMAT,
+        vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+        vk::ImageAspectFlags::COLOR,
+        instance, device, data);
+    let history = [create_history()?, create_history()?];
+
+    // The history stays in the general layout, sampled and stored alike
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    for image in &history {
+        memory::cmd_image_barrier(device, command_buffer, image.image, memory::color_subresource_range(),
+            (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+            (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE));
+    }
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    // Set i reads history i and writes the other one
+    let taa = &data.temporal_aa;
+    for (i, set) in taa.sets.iter().enumerate() {
+        descriptor::write_image(device, *set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            data.hdr_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, taa.sampler);
+        descriptor::write_image(device, *set, 1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            data.velocity_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, taa.sampler);
+        descriptor::write_image(device, *set, 2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            data.depth_image.image_view, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL, taa.sampler);
+        descriptor::write_image(device, *set, 3, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            history[i].image_view, vk::ImageLayout::GENERAL, taa.sampler);
+        descriptor::write_image(device, *set, 4, vk::DescriptorType::STORAGE_IMAGE,
+            history[1 - i].image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+        descriptor::write_image(device, *set, 5, vk::DescriptorType::STORAGE_IMAGE,
+            data.post_image.image_view, vk::ImageLayout::GENERAL, vk::Sampler::null());
+    }
+
+    let taa = &mut data.temporal_aa;
+    taa.history = history;
+    taa.history_valid = false;
+    Ok(())
+}
+
+pub unsafe fn destroy_temporal_aa_targets(device: &Device, data: &mut EngineData)
+{
+    data.temporal_aa.history
+        .iter_mut()
+        .for_each(|h| h.destroy(device));
+}
+
+pub unsafe fn destroy_temporal_aa(device: &Device, data: &mut EngineData)
+{
+    let taa = &mut data.temporal_aa;
+    device.destroy_pipeline(taa.pipeline, None);
+    device.destroy_pipeline_layout(taa.pipeline_layout, None);
+    device.destroy_descriptor_pool(taa.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(taa.set_layout, None);
+    device.destroy_sampler(taa.sampler, None);
+}
+
+/// Moves the projection to the next jitter position, before the frame uniforms are uploaded
+pub fn update_temporal_aa(data: &mut EngineData)
+{
+    let extent = data.swapchain_extent;
+    let taa = &mut data.temporal_aa;
+    taa.previous_jitter = taa.jitter;
+    if !taa.settings.enabled {
+        taa.jitter = [0.0; 2];
+        return;
+    }
+
+    // Halton skips its first element, 0, which would sit on the pixel corner
+    let samples = taa.settings.jitter_samples.clamp(1, MAX_JITTER_SAMPLES);
+    taa.jitter_index = (taa.jitter_index + 1) % samples;
+    let offset = [halton(taa.jitter_index + 1, 2) - 0.5, halton(taa.jitter_index + 1, 3) - 0.5];
+    taa.jitter = [
+        offset[0] * 2.0 / extent.width.max(1) as f32,
+        offset[1] * 2.0 / extent.height.max(1) as f32,
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks the sequence from index 0, with `expected` given as numerators over `denominator`
+    fn assert_sequence(base: u32, denominator: f32, expected: &[f32]) {
+        for (index, numerator) in expected.iter().enumerate() {
+            let (value, expected) = (halton(index as u32, base), numerator / denominator);
+            assert!((value - expected).abs() < 1e-6, "Element {} base {} is {}, not {}.", index, base, value, expected);
+        }
+    }
+
+    #[test]
+    fn base_2_matches_the_reference_sequence() {
+        assert_sequence(2, 8.0, &[0.0, 4.0, 2.0, 6.0, 1.0, 5.0, 3.0, 7.0]);
+    }
+
+    #[test]
+    fn base_3_matches_the_reference_sequence() {
+        assert_sequence(3, 9.0, &[0.0, 3.0, 6.0, 1.0, 4.0, 7.0, 2.0, 5.0, 8.0]);
+    }
+}
@@ -0,0 +1,209 @@
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::engine_data::EngineData;
+use super::memory::{self, AllocatedBuffer, AllocatedImage};
+use super::scene::{ImageData, TextureData, WrapMode};
+
+/// A sampled image with its own sampler.
+#[derive(Clone, Debug, Default)]
+pub struct Texture {
+    pub image: AllocatedImage,
+    pub sampler: vk::Sampler,
+    pub mip_levels: u32,
+}
+
+impl Texture {
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        device.destroy_sampler(self.sampler, None);
+        self.image.destroy(device);
+    }
+
+    /// Uploads a decoded scene texture, sRGB for colour data and UNORM for everything else
+    pub unsafe fn from_data(image: &ImageData, texture: &TextureData, srgb: bool, instance: &Instance, device: &Device,
+        data: &EngineData) -> Result<Self>
+    {
+        let format = if srgb { vk::Format::R8G8B8A8_SRGB } else { vk::Format::R8G8B8A8_UNORM };
+        Self::from_pixels(image.width, image.height, &image.pixels, format, texture.linear_filter, texture.wrap,
+            instance, device, data)
+    }
+
+    /// A 1x1 texture of a single colour, used for empty material slots
+    pub unsafe fn solid(rgba: [u8; 4], format: vk::Format, instance: &Instance, device: &Device, data: &EngineData) -> Result<Self> {
+        Self::from_pixels(1, 1, &rgba, format, false, [WrapMode::Repeat; 2], instance, device, data)
+    }
+
+    /// Uploads RGBA8 pixels and generates the full mip chain when the format can be blitted
+    pub unsafe fn from_pixels(width: u32, height: u32, pixels: &[u8], format: vk::Format, linear_filter: bool,
+        wrap: [WrapMode; 2], instance: &Instance, device: &Device, data: &EngineData) -> Result<Self>
+    {
+        let properties = instance.get_physical_device_format_properties(data.physical_device, format);
+        let mip_levels = if properties.optimal_tiling_features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR) {
+            32 - width.max(height).leading_zeros()
+        } else {
+            1
+        };
+
+        let info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::_2D)
+            .extent(vk::Extent3D { width, height, depth: 1 })
+            .mip_levels(mip_levels)
+            .array_layers(1)
+            .format(format)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::TRANSFER_SRC)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .samples(vk::SampleCountFlags::_1);
+
+        let image = AllocatedImage::from_info(&info, vk::ImageViewType::_2D, vk::ImageAspectFlags::COLOR,
+            instance, device, data)?;
+
+        upload_pixels(&image, pixels, 0, 1, instance, device, data)?;
+        generate_mipmaps(&image, mip_levels, 1, device, data)?;
+
+        let sampler = create_texture_sampler(device, linear_filter, wrap, mip_levels)?;
+        Ok(Self { image, sampler, mip_levels })
+    }
+}
+
+/// Copies tightly packed pixels into mip 0 of `layer_count` layers, leaving it in `TRANSFER_DST_OPTIMAL`
+pub unsafe fn upload_pixels(image: &AllocatedImage, pixels: &[u8], base_layer: u32, layer_count: u32,
+    instance: &Instance, device: &Device, data: &EngineData) -> Result<()>
+{
+    let mut staging = AllocatedBuffer::create(
+        pixels.as_ptr(),
+        pixels.len(),
+        vk::BufferUsageFlags::TRANSFER_SRC,
+        vk::BufferCreateFlags::empty(),
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        instance, device, data)?;
+
+    let range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(vk::REMAINING_MIP_LEVELS)
+        .base_array_layer(base_layer)
+        .layer_count(layer_count)
+        .build();
+
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    memory::cmd_image_barrier(device, command_buffer, image.image, range,
+        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+        (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+        (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE));
+
+    let subresource = vk::ImageSubresourceLayers::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .mip_level(0)
+        .base_array_layer(base_layer)
+        .layer_count(layer_count);
+
+    let region = vk::BufferImageCopy::builder()
+        .buffer_offset(0)
+        .buffer_row_length(0)
+        .buffer_image_height(0)
+        .image_subresource(subresource)
+        .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+        .image_extent(image.extent);
+
+    device.cmd_copy_buffer_to_image(command_buffer, staging.buffer, image.image,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL, &[region]);
+    memory::end_single_time_commands(device, data, command_buffer)?;
+
+    staging.destroy(device);
+    Ok(())
+}
+
+/// Blits each mip from the one above it and leaves every mip in `SHADER_READ_ONLY_OPTIMAL`.
+///  Expects the whole image in `TRANSFER_DST_OPTIMAL` with mip 0 filled.
+pub unsafe fn generate_mipmaps(image: &AllocatedImage, mip_levels: u32, layer_count: u32, device: &Device, data: &EngineData) -> Result<()> {
+    let command_buffer = memory::begin_single_time_commands(device, data)?;
+    let range = |mip: u32| vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(mip)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(layer_count)
+        .build();
+
+    let mut width = image.extent.width as i32;
+    let mut height = image.extent.height as i32;
+    for mip in 1..mip_levels {
+        // The previous mip becomes the blit source
+        memory::cmd_image_barrier(device, command_buffer, image.image, range(mip - 1),
+            (vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ));
+
+        let next_width = (width / 2).max(1);
+        let next_height = (height / 2).max(1);
+
+        let src_subresource = vk::ImageSubresourceLayers::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(mip - 1)
+            .base_array_layer(0)
+            .layer_count(layer_count);
+
+        let dst_subresource = vk::ImageSubresourceLayers::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(mip)
+            .base_array_layer(0)
+            .layer_count(layer_count);
+
+        let blit = vk::ImageBlit::builder()
+            .src_offsets([vk::Offset3D { x: 0, y: 0, z: 0 }, vk::Offset3D { x: width, y: height, z: 1 }])
+            .src_subresource(src_subresource)
+            .dst_offsets([vk::Offset3D { x: 0, y: 0, z: 0 }, vk::Offset3D { x: next_width, y: next_height, z: 1 }])
+            .dst_subresource(dst_subresource);
+
+        device.cmd_blit_image(command_buffer,
+            image.image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            image.image, vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[blit], vk::Filter::LINEAR);
+
+        memory::cmd_image_barrier(device, command_buffer, image.image, range(mip - 1),
+            (vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ),
+            (vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ));
+
+        width = next_width;
+        height = next_height;
+    }
+
+    // The last mip was only ever written
+    memory::cmd_image_barrier(device, command_buffer, image.image, range(mip_levels - 1),
+        (vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+        (vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ));
+
+    memory::end_single_time_commands(device, data, command_buffer)
+}
+
+unsafe fn create_texture_sampler(device: &Device, linear_filter: bool, wrap: [WrapMode; 2], mip_levels: u32) -> Result<vk::Sampler> {
+    let filter = if linear_filter { vk::Filter::LINEAR } else { vk::Filter::NEAREST };
+    let address_mode = |w: WrapMode| match w {
+        WrapMode::Repeat => vk::SamplerAddressMode::REPEAT,
+        WrapMode::MirroredRepeat => vk::SamplerAddressMode::MIRRORED_REPEAT,
+        WrapMode::ClampToEdge => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+    };
+
+    let info = vk::SamplerCreateInfo::builder()
+        .mag_filter(filter)
+        .min_filter(filter)
+        .address_mode_u(address_mode(wrap[0]))
+        .address_mode_v(address_mode(wrap[1]))
+        .address_mode_w(vk::SamplerAddressMode::REPEAT)
+        .anisotropy_enable(false)
+        .max_anisotropy(1.0)
+        .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
+        .unnormalized_coordinates(false)
+        .compare_enable(false)
+        .compare_op(vk::CompareOp::ALWAYS)
+        .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+        .mip_lod_bias(0.0)
+        .min_lod(0.0)
+        .max_lod(mip_levels as f32);
+
+    Ok(device.create_sampler(&info, None)?)
+}
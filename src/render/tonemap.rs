@@ -0,0 +1,213 @@
+use std::mem::size_of;
+use vulkanalia::prelude::v1_3::*;
+use anyhow::{Ok, Result};
+
+use super::descriptor;
+use super::engine_data::EngineData;
+use super::lens::SensorConstants;
+use super::shader;
+
+/// Curves mapping HDR radiance into the displayable 0..1 range.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TonemapOperator
+{
+    /// Narkowicz's fit of the ACES filmic reference rendering transform
+    #[default]
+    Aces,
+    /// Troy Sobotka's AgX with the default look, handles saturated highlights gracefully
+    AgX,
+    /// Extended Reinhard on luminance
+    Reinhard,
+    /// John Hable's filmic curve from Uncharted 2
+    Uncharted2,
+}
+
+/// How the HDR target is mapped to the swapchain.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TonemapSettings {
+    pub operator: TonemapOperator,
+    /// Exposed radiance that maps to pure white, every operator is normalised by its value here
+    pub white_point: f32,
+}
+
+impl Default for TonemapSettings {
+    fn default() -> Self {
+        Self { operator: TonemapOperator::Aces, white_point: 11.2 }
+    }
+}
+
+/// Mirrors `Tonemap` in `tonemap.frag`
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct TonemapPushConstants {
+    operator: u32,
+    white_point: f32,
+    sensor: SensorConstants,
+}
+
+/// The final pass, reading the HDR target and writing the swapchain image.
+#[derive(Clone, Debug, Default)]
+pub struct Tonemapper {
+    pub settings: TonemapSettings,
+    pub render_pass: vk::RenderPass,
+    framebuffers: Vec<vk::Framebuffer>,
+    sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+    set: vk::DescriptorSet,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+}
+
+impl Tonemapper {
+    /// Records the whole present pass into the given swapchain image, grading with `grading_set`. The
+    ///  camera's `sensor` vignettes the scene before the curve and adds grain after the grading.
+    pub unsafe fn cmd_draw(&self, device: &Device, command_buffer: vk::CommandBuffer, image_index: usize,
+        extent: vk::Extent2D, grading_set: vk::DescriptorSet, sensor: SensorConstants)
+    {
+        let render_area = vk::Rect2D::builder()
+            .offset(vk::Offset2D::default())
+            .extent(extent);
+
+        let info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.render_pass)
+            .framebuffer(self.framebuffers[image_index])
+            .render_area(render_area);
+
+        let push_constants = TonemapPushConstants {
+            operator: self.settings.operator as u32,
+            white_point: self.settings.white_point.max(1e-3),
+            sensor,
+        };
+
+        device.cmd_begin_render_pass(command_buffer, &info, vk::SubpassContents::INLINE);
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+            self.pipeline_layout, 0, &[self.set, grading_set], &[]);
+        shader::cmd_push_constants(device, command_buffer, self.pipeline_layout,
+            vk::ShaderStageFlags::FRAGMENT, &push_constants);
+        shader::cmd_set_full_viewport(device, command_buffer, extent);
+        device.cmd_draw(command_buffer, 3, 1, 0, 0);
+        device.cmd_end_render_pass(command_buffer);
+    }
+}
+
+pub unsafe fn create_tonemapper(device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let tonemapper = &mut data.tonemapper;
+    tonemapper.sampler = descriptor::create_sampler(device, vk::Filter::NEAREST, 0.0)?;
+    tonemapper.set_layout = descriptor::create_set_layout(device, &[
+        descriptor::layout_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT),
+    ])?;
+    tonemapper.descriptor_pool = descriptor::create_pool(device, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1),
+    ], 1)?;
+    tonemapper.set = descriptor::allocate_set(device, tonemapper.descriptor_pool, tonemapper.set_layout)?;
+    tonemapper.pipeline_layout = descriptor::create_pipeline_layout(device,
+        &[tonemapper.set_layout, data.grading.set_layout],
+        size_of::<TonemapPushConstants>() as u32, vk::ShaderStageFlags::FRAGMENT)?;
+
+    create_tonemapper_targets(device, data)
+}
+
+/// Creates the resources that depend on the swapchain: the present pass, its framebuffers and pipeline.
+///  Headless, the pass leaves the output image ready to be read back instead of presented.
+pub unsafe fn create_tonemapper_targets(device: &Device, data: &mut EngineData) -> Result<()>
+{
+    let final_layout = if data.is_headless() {
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL
+    } else {
+        vk::ImageLayout::PRESENT_SRC_KHR
+    };
+    let render_pass = create_present_render_pass(device, data.swapchain_format, final_layout)?;
+    let framebuffers = data
+        .swapchain_image_views
+        .iter()
+        .map(|view| {
+            let attachments = &[*view];
+            let info = vk::FramebufferCreateInfo::builder()
+                .render_pass(render_pass)
+                .attachments(attachments)
+                .width(data.swapchain_extent.width)
+                .height(data.swapchain_extent.height)
+                .layers(1);
+
+            device.create_framebuffer(&info, None)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let frag = include_bytes!(concat!(env!("OUT_DIR"), "/shader/tonemap_frag.spv"));
+    let pipeline = shader::create_fullscreen_pipeline(device, &frag[..], data.tonemapper.pipeline_layout,
+        render_pass, 0, 1)?;
+
+    let tonemapper = &mut data.tonemapper;
+    descriptor::write_image(device, tonemapper.set, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        data.hdr_image.image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, tonemapper.sampler);
+
+    tonemapper.render_pass = render_pass;
+    tonemapper.framebuffers = framebuffers;
+    tonemapper.pipeline = pipeline;
+    Ok(())
+}
+
+pub unsafe fn destroy_tonemapper_targets(device: &Device, data: &mut EngineData)
+{
+    let tonemapper = &mut data.tonemapper;
+    tonemapper.framebuffers
+        .iter()
+        .for_each(|f| device.destroy_framebuffer(*f, None));
+    device.destroy_pipeline(tonemapper.pipeline, None);
+    device.destroy_render_pass(tonemapper.render_pass, None);
+}
+
+pub unsafe fn destroy_tonemapper(device: &Device, data: &mut EngineData)
+{
+    let tonemapper = &mut data.tonemapper;
+    device.destroy_pipeline_layout(tonemapper.pipeline_layout, None);
+    device.destroy_descriptor_pool(tonemapper.descriptor_pool, None);
+    device.destroy_descriptor_set_layout(tonemapper.set_layout, None);
+    device.destroy_sampler(tonemapper.sampler, None);
+}
+
+/// A single color attachment pass over a swapchain image, overwriting it completely
+unsafe fn create_present_render_pass(device: &Device, format: vk::Format, final_layout: vk::ImageLayout)
+    -> Result<vk::RenderPass>
+{
+    let color_attachment = vk::AttachmentDescription::builder()
+        .format(format)
+        .samples(vk::SampleCountFlags::_1)
+        .load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(final_layout);
+
+    let color_attachment_ref = vk::AttachmentReference::builder()
+        .attachment(0)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
+    let color_attachments = &[color_attachment_ref];
+    let subpass = vk::SubpassDescription::builder()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(color_attachments);
+
+    // The swapchain image is only ready once the acquire semaphore, waited on at this stage, signals
+    let dependency = vk::SubpassDependency::builder()
+        .src_subpass(vk::SUBPASS_EXTERNAL)
+        .dst_subpass(0)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .src_access_mask(vk::AccessFlags::empty())
+        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+
+    let attachments = &[color_attachment];
+    let subpasses = &[subpass];
+    let dependencies = &[dependency];
+    let info = vk::RenderPassCreateInfo::builder()
+        .attachments(attachments)
+        .subpasses(subpasses)
+        .dependencies(dependencies);
+
+    Ok(device.create_render_pass(&info, None)?)
+}